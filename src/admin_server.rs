@@ -0,0 +1,232 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    server::{AdminCommand, AdminCommandResult},
+    server_database::UserCredentialsRaw,
+};
+
+pub type AdminCommandSender = mpsc::Sender<(AdminCommand, oneshot::Sender<AdminCommandResult>)>;
+pub type AdminCommandReceiver = mpsc::Receiver<(AdminCommand, oneshot::Sender<AdminCommandResult>)>;
+
+#[derive(Clone)]
+struct AdminState {
+    token: Arc<String>,
+    sender: AdminCommandSender,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: &'static str,
+}
+
+#[derive(Deserialize)]
+struct NewUser {
+    name: String,
+    password: String,
+    email: Option<String>,
+    /// Lets the account take a name from the reserved list
+    #[serde(default)]
+    allow_reserved: bool,
+}
+
+#[derive(Deserialize)]
+struct NewAnnouncement {
+    message: String,
+    at_epoch_secs: u64,
+}
+
+pub async fn run_admin_server(address: String, token: String, sender: AdminCommandSender) {
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Could not bind {address} to the admin API ({err}).");
+            return;
+        }
+    };
+
+    let state = AdminState {
+        token: Arc::new(token),
+        sender,
+    };
+
+    let router = Router::new()
+        .route("/users", get(list_users).post(create_user))
+        .route("/users/:name", delete(delete_user))
+        .route("/users/:name/kick", post(kick_user))
+        .route("/broadcast", post(broadcast))
+        .route(
+            "/announcements",
+            get(list_announcements).post(schedule_announcement),
+        )
+        .route("/announcements/:id", delete(cancel_announcement))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authorize))
+        .with_state(state);
+
+    info!("** Serving admin API at {address}. **");
+
+    if let Err(err) = axum::serve(listener, router).await {
+        error!("Admin API has stopped ({err}).");
+    }
+}
+
+async fn authorize(State(state): State<AdminState>, request: Request, next: Next) -> Response {
+    let expected = format!("Bearer {}", state.token);
+    let is_authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| constant_time_eq(value.as_bytes(), expected.as_bytes()));
+
+    if !is_authorized {
+        return error_response(StatusCode::UNAUTHORIZED, "unauthorized");
+    }
+
+    next.run(request).await
+}
+
+/// Takes as long wherever the values differ, so the token can't be guessed byte by byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn list_users(State(state): State<AdminState>) -> Response {
+    match send_command(&state, AdminCommand::ListUsers).await {
+        Ok(AdminCommandResult::Users(users)) => Json(users).into_response(),
+        Ok(AdminCommandResult::InternalError) => {
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "internal server error")
+        }
+        Ok(_) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "unexpected result"),
+        Err(response) => response,
+    }
+}
+
+async fn create_user(State(state): State<AdminState>, Json(new_user): Json<NewUser>) -> Response {
+    let command = AdminCommand::CreateUser {
+        user_credentials_raw: UserCredentialsRaw {
+            name: new_user.name,
+            password: new_user.password,
+            email: new_user.email,
+        },
+        allow_reserved: new_user.allow_reserved,
+    };
+    match send_command(&state, command).await {
+        Ok(AdminCommandResult::RegistrationFailed(e)) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+        result => command_response(result),
+    }
+}
+
+async fn delete_user(State(state): State<AdminState>, Path(name): Path<String>) -> Response {
+    command_response(send_command(&state, AdminCommand::DeleteUser(name)).await)
+}
+
+async fn kick_user(State(state): State<AdminState>, Path(name): Path<String>) -> Response {
+    command_response(send_command(&state, AdminCommand::KickUser(name)).await)
+}
+
+async fn broadcast(State(state): State<AdminState>, message: String) -> Response {
+    if message.is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, "empty message");
+    }
+    command_response(send_command(&state, AdminCommand::Broadcast(message)).await)
+}
+
+async fn list_announcements(State(state): State<AdminState>) -> Response {
+    match send_command(&state, AdminCommand::ListScheduledAnnouncements).await {
+        Ok(AdminCommandResult::ScheduledAnnouncements(announcements)) => {
+            Json(announcements).into_response()
+        }
+        result => command_response(result),
+    }
+}
+
+async fn schedule_announcement(
+    State(state): State<AdminState>,
+    Json(new_announcement): Json<NewAnnouncement>,
+) -> Response {
+    if new_announcement.message.is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, "empty message");
+    }
+    let command = AdminCommand::ScheduleAnnouncement {
+        message: new_announcement.message,
+        at_epoch_secs: new_announcement.at_epoch_secs,
+    };
+    match send_command(&state, command).await {
+        Ok(AdminCommandResult::Scheduled(id)) => Json(json!({ "id": id })).into_response(),
+        result => command_response(result),
+    }
+}
+
+async fn cancel_announcement(State(state): State<AdminState>, Path(id): Path<u64>) -> Response {
+    command_response(send_command(&state, AdminCommand::CancelScheduledAnnouncement(id)).await)
+}
+
+async fn send_command(
+    state: &AdminState,
+    command: AdminCommand,
+) -> Result<AdminCommandResult, Response> {
+    let (result_sender, result_receiver) = oneshot::channel();
+
+    let unavailable = || error_response(StatusCode::SERVICE_UNAVAILABLE, "chat server unavailable");
+
+    state
+        .sender
+        .send((command, result_sender))
+        .await
+        .map_err(|_| unavailable())?;
+    result_receiver.await.map_err(|_| unavailable())
+}
+
+fn command_response(result: Result<AdminCommandResult, Response>) -> Response {
+    match result {
+        Ok(AdminCommandResult::Done) => Json(json!({ "result": "ok" })).into_response(),
+        Ok(AdminCommandResult::UserNotFound) => {
+            error_response(StatusCode::NOT_FOUND, "user not found")
+        }
+        Ok(AdminCommandResult::AnnouncementNotFound) => {
+            error_response(StatusCode::NOT_FOUND, "announcement not found")
+        }
+        Ok(AdminCommandResult::InternalError) => {
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "internal server error")
+        }
+        Ok(_) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "unexpected result"),
+        Err(response) => response,
+    }
+}
+
+fn error_response(status: StatusCode, error: &'static str) -> Response {
+    (status, Json(ErrorResponse { error })).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_time_eq_compares_whole_values() {
+        assert!(constant_time_eq(b"Bearer secret", b"Bearer secret"));
+        assert!(!constant_time_eq(b"Bearer secret", b"Bearer secreT"));
+        assert!(!constant_time_eq(b"Bearer secret", b"Xearer secret"));
+        assert!(!constant_time_eq(b"Bearer secret", b"Bearer secret2"));
+        assert!(!constant_time_eq(b"", b"Bearer secret"));
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    fs,
+    net::{SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+};
+
+use serde::Serialize;
+
+use crate::{
+    check_bcrypt_cost, check_worker_threads,
+    config::{self, Config, ConfigError},
+    get_admin_options_from_config, get_admin_token, get_health_address_from_config,
+    get_ip_port_from_config, get_validation_rules_from_config, parse_content_type,
+    parse_control_character_action, parse_header_size, parse_wordlist_action,
+    server_database::ServerSQLiteDatabase,
+};
+
+/// Problem found by the self-test, printed to stderr as one JSON object per line
+#[derive(Serialize)]
+pub struct Problem {
+    source: &'static str,
+    message: String,
+}
+
+impl Problem {
+    fn config(e: ConfigError) -> Self {
+        Self {
+            source: "config",
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Validates the configuration and the database without starting the server
+pub fn run_checks() -> Vec<Problem> {
+    let mut problems = Vec::<Problem>::new();
+
+    match config::read_config() {
+        Ok(config) => check_config(&config, &mut problems),
+        Err(e) => problems.push(Problem::config(e)),
+    }
+    check_database(&mut problems);
+
+    problems
+}
+
+fn check_config(config: &Config, problems: &mut Vec<Problem>) {
+    let mut push = |result: Result<(), ConfigError>| {
+        if let Err(e) = result {
+            problems.push(Problem::config(e));
+        }
+    };
+
+    push(get_validation_rules_from_config(Some(config)).map(drop));
+
+    if let Some(security) = &config.security {
+        if let Some(cost) = security.bcrypt_cost {
+            push(check_bcrypt_cost(cost).map(drop));
+        }
+        if let Some(path) = &security.reserved_names_file {
+            push(check_readable("reserved names", path));
+        }
+    }
+
+    if let Some(worker_threads) = config
+        .runtime
+        .as_ref()
+        .and_then(|runtime| runtime.worker_threads)
+    {
+        push(check_worker_threads(worker_threads).map(drop));
+    }
+
+    if let Some(chat) = &config.chat {
+        if let Some(action) = &chat.control_characters {
+            push(parse_control_character_action(action).map(drop));
+        }
+        if let Some(action) = &chat.wordlist_action {
+            push(parse_wordlist_action(action).map(drop));
+        }
+        for content_type in chat.allowed_content_types.iter().flatten() {
+            push(parse_content_type(content_type).map(drop));
+        }
+        if let Some(path) = &chat.wordlist_file {
+            push(check_readable("wordlist", path));
+        }
+        if let Some(path) = &chat.motd_file {
+            push(check_readable("MOTD", path));
+        }
+    }
+
+    if let Some(admin) = &config.admin {
+        push(get_admin_token(admin).map(drop));
+    }
+
+    if let Some(header_bytes) = config.network.header_bytes {
+        push(parse_header_size(header_bytes).map(drop));
+    }
+
+    check_addresses(config, push);
+}
+
+fn check_readable(file_kind: &str, path: &str) -> Result<(), ConfigError> {
+    fs::read(PathBuf::from(path)).map(drop).map_err(|e| {
+        ConfigError::InvalidValue(format!("could not read {file_kind} file '{path}' ({e})"))
+    })
+}
+
+fn check_addresses(config: &Config, mut push: impl FnMut(Result<(), ConfigError>)) {
+    let (host, port) = get_ip_port_from_config(Some(config));
+    let chat_address = format!("{host}:{port}");
+
+    let mut addresses = vec![("chat", chat_address)];
+    if let Some(health_address) = get_health_address_from_config(Some(config)) {
+        addresses.push(("health", health_address));
+    }
+    if let Some(admin_options) = get_admin_options_from_config(Some(config)) {
+        addresses.push(("admin", admin_options.address));
+    }
+
+    let mut resolved = Vec::<(&str, Vec<SocketAddr>)>::new();
+    for (server, address) in &addresses {
+        match address.to_socket_addrs() {
+            Ok(socket_addresses) => resolved.push((server, socket_addresses.collect())),
+            Err(e) => push(Err(ConfigError::InvalidValue(format!(
+                "{server} address '{address}' cannot be resolved ({e})"
+            )))),
+        }
+    }
+
+    for (index, (server, socket_addresses)) in resolved.iter().enumerate() {
+        for (other_server, other_socket_addresses) in &resolved[index + 1..] {
+            if socket_addresses
+                .iter()
+                .any(|address| other_socket_addresses.contains(address))
+            {
+                push(Err(ConfigError::InvalidValue(format!(
+                    "{server} and {other_server} servers share an address"
+                ))));
+            }
+        }
+    }
+}
+
+fn check_database(problems: &mut Vec<Problem>) {
+    match ServerSQLiteDatabase::check_migrations() {
+        Ok(duplicate_names) if duplicate_names.is_empty() => {}
+        Ok(duplicate_names) => problems.push(Problem {
+            source: "database",
+            message: format!(
+                "user names differing only in case have to be resolved manually: {}",
+                duplicate_names.join(", ")
+            ),
+        }),
+        Err(e) => problems.push(Problem {
+            source: "database",
+            message: e.to_string(),
+        }),
+    }
+}
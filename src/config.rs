@@ -1,4 +1,5 @@
 use core::fmt;
+use std::collections::HashMap;
 use std::error::Error;
 use std::{error, fs};
 
@@ -7,18 +8,147 @@ use serde::Deserialize;
 #[derive(Deserialize)]
 pub struct Config {
     pub network: Network,
+    pub compression: Option<Compression>,
+    pub security: Option<Security>,
+    pub health: Option<Health>,
+    pub chat: Option<Chat>,
+    pub admin: Option<Admin>,
+    pub runtime: Option<Runtime>,
+    pub validation: Option<Validation>,
+    pub attachments: Option<Attachments>,
+    pub logging: Option<Logging>,
 }
 
 #[derive(Deserialize)]
 pub struct Network {
     pub ip: Option<String>,
     pub port: Option<u16>,
+    pub idle_timeout_secs: Option<u64>,
+    pub idle_warning_secs: Option<u64>,
+    pub max_connections_per_ip: Option<usize>,
+    pub tcp_nodelay: Option<bool>,
+    pub tcp_keepalive_secs: Option<u64>,
+    pub header_bytes: Option<u8>,
+    pub max_message_bytes: Option<usize>,
+    pub max_frame_read_secs: Option<u64>,
+    pub max_pending_frames: Option<usize>,
+    pub slow_consumer_grace_secs: Option<u64>,
+    pub require_handshake: Option<bool>,
+    pub shutdown_grace_secs: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct Compression {
+    pub enabled: Option<bool>,
+    pub threshold: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct Security {
+    pub bcrypt_cost: Option<u32>,
+    pub max_registrations_per_ip: Option<usize>,
+    pub registration_window_secs: Option<u64>,
+    pub rename_cooldown_secs: Option<u64>,
+    pub max_renames: Option<usize>,
+    pub rename_window_secs: Option<u64>,
+    pub require_email: Option<bool>,
+    pub reserved_names: Option<Vec<String>>,
+    pub reserved_names_file: Option<String>,
+    pub auto_login_on_register: Option<bool>,
+    pub max_session_secs: Option<u64>,
+    pub disconnect_expired_sessions: Option<bool>,
+    pub frame_hmac_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Validation {
+    pub name_min: Option<usize>,
+    pub name_max: Option<usize>,
+    pub password_min: Option<usize>,
+    pub password_max: Option<usize>,
+    pub allow_dots: Option<bool>,
+    pub allow_underscores: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct Chat {
+    pub max_roster_entries: Option<usize>,
+    pub message_retention: Option<usize>,
+    pub persist_messages: Option<bool>,
+    pub delivery_reports: Option<bool>,
+    pub history_retention_days: Option<u64>,
+    pub history_prune_interval_secs: Option<u64>,
+    pub admins: Option<Vec<String>>,
+    pub max_reports_per_user: Option<usize>,
+    pub report_window_secs: Option<u64>,
+    pub public_server_stats: Option<bool>,
+    pub stats_broadcast_interval_secs: Option<u64>,
+    pub stats_broadcast_admins_only: Option<bool>,
+    pub motd: Option<String>,
+    pub motd_file: Option<String>,
+    pub wordlist_file: Option<String>,
+    pub wordlist_action: Option<String>,
+    pub wordlist_whole_words: Option<bool>,
+    pub control_characters: Option<String>,
+    pub allow_newlines: Option<bool>,
+    pub middleware_order: Option<Vec<String>>,
+    pub whois_admins_only: Option<bool>,
+    pub whois_show_ip: Option<bool>,
+    pub rooms: Option<Vec<String>>,
+    pub private_rooms: Option<Vec<String>>,
+    pub list_empty_rooms: Option<bool>,
+    pub max_rooms_per_user: Option<usize>,
+    pub room_history_default: Option<usize>,
+    pub room_history_limits: Option<HashMap<String, usize>>,
+    pub history_page_max: Option<usize>,
+    pub rejoin_grace_secs: Option<u64>,
+    pub presence_batch_window_ms: Option<u64>,
+    pub dedup_window_secs: Option<u64>,
+    pub dedup_max_entries: Option<usize>,
+    pub duplicate_cooldown_secs: Option<u64>,
+    pub allowed_content_types: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+pub struct Attachments {
+    pub max_size_bytes: Option<usize>,
+    pub allowed_mime_types: Option<Vec<String>>,
+    pub storage_dir: Option<String>,
+    pub retention_days: Option<u64>,
+    pub cleanup_interval_secs: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct Logging {
+    pub file: Option<String>,
+    pub max_size_mb: Option<u64>,
+    pub keep_files: Option<usize>,
+    pub spans: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct Admin {
+    pub ip: Option<String>,
+    pub port: Option<u16>,
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Runtime {
+    pub worker_threads: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct Health {
+    pub ip: Option<String>,
+    pub port: Option<u16>,
 }
 
 #[derive(Debug)]
 pub enum ConfigError {
     FileNotFound,
     MalformedConfig(toml::de::Error),
+    InvalidValue(String),
 }
 
 impl fmt::Display for ConfigError {
@@ -30,6 +160,9 @@ impl fmt::Display for ConfigError {
             ConfigError::MalformedConfig(ref e) => {
                 write!(f, "{e}")
             }
+            ConfigError::InvalidValue(ref message) => {
+                write!(f, "invalid configuration value: {message}")
+            }
         }
     }
 }
@@ -39,6 +172,7 @@ impl Error for ConfigError {
         match *self {
             ConfigError::FileNotFound => None,
             ConfigError::MalformedConfig(ref e) => Some(e),
+            ConfigError::InvalidValue(_) => None,
         }
     }
 }
@@ -0,0 +1,33 @@
+use std::sync::Arc;
+
+use log::{error, info, warn};
+use tokio::{
+    io::{stdin, AsyncBufReadExt, BufReader},
+    sync::Mutex,
+};
+
+use crate::{server::ChatServer, server_database::ServerDatabase};
+
+pub async fn console_loop<T: ServerDatabase>(chat_server: Arc<Mutex<ChatServer<T>>>) {
+    let mut lines = BufReader::new(stdin()).lines();
+
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => return,
+            Err(err) => {
+                error!("Could not read a console command ({err}).");
+                return;
+            }
+        };
+
+        match line.trim() {
+            "" => {}
+            "/reload-motd" => {
+                chat_server.lock().await.reload_motd();
+                info!("MOTD has been reloaded.");
+            }
+            command => warn!("Unknown console command '{command}'."),
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use log::{error, info};
+use serde::Serialize;
+use tokio::{net::TcpListener, sync::Mutex};
+
+use crate::{server::ChatServer, server_database::ServerDatabase};
+
+#[derive(Serialize)]
+struct HealthResponse {
+    status: &'static str,
+    online_users: usize,
+    uptime_secs: u64,
+}
+
+pub async fn run_health_server<T: ServerDatabase + Send + 'static>(
+    address: String,
+    chat_server: Arc<Mutex<ChatServer<T>>>,
+) {
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Could not bind {address} to the health endpoint ({err}).");
+            return;
+        }
+    };
+
+    let router = Router::new()
+        .route("/healthz", get(health::<T>))
+        .with_state(chat_server);
+
+    info!("** Serving health endpoint at {address}. **");
+
+    if let Err(err) = axum::serve(listener, router).await {
+        error!("Health endpoint has stopped ({err}).");
+    }
+}
+
+async fn health<T: ServerDatabase + Send + 'static>(
+    State(chat_server): State<Arc<Mutex<ChatServer<T>>>>,
+) -> (StatusCode, Json<HealthResponse>) {
+    let chat_server = chat_server.lock().await;
+
+    (
+        StatusCode::OK,
+        Json(HealthResponse {
+            status: "ok",
+            online_users: chat_server.online_users_count(),
+            uptime_secs: chat_server.uptime().as_secs(),
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        time::sleep,
+    };
+
+    use super::*;
+    use crate::server::tests as chat;
+
+    /// Port which was free a moment ago, the endpoint binds the address by itself
+    fn free_address() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    async fn get(address: &str, path: &str) -> String {
+        // Endpoint is started in the background, so the first attempts may find nothing listening
+        let mut stream = loop {
+            match TcpStream::connect(address).await {
+                Ok(stream) => break stream,
+                Err(_) => sleep(Duration::from_millis(10)).await,
+            }
+        };
+        let request =
+            format!("GET {path} HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn health_endpoint_reports_online_users() {
+        let mut chat_server = chat::chat_server(chat::options());
+        chat_server.on_user_connect("alice".to_string(), "127.0.0.1:4000".parse().unwrap());
+        for request in ["Registration", "Authentication"] {
+            let request = serde_json::json!({ request: { "user_credentials_raw":
+                { "name": "AliceAlice", "password": "password1", "email": null } } });
+            chat_server.on_user_message("alice".to_string(), request.to_string().as_bytes());
+        }
+        let address = free_address();
+        let server = tokio::spawn(run_health_server(
+            address.clone(),
+            Arc::new(Mutex::new(chat_server)),
+        ));
+
+        let response = get(&address, "/healthz").await;
+        server.abort();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{head}");
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["online_users"], 1);
+        assert!(body["uptime_secs"].is_u64());
+    }
+}
@@ -1,41 +1,952 @@
-use std::{io::Write, time::SystemTime};
+use std::{
+    env, fs,
+    io::{self, IsTerminal, Write},
+    num::NonZeroUsize,
+    path::PathBuf,
+    process,
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime},
+};
 
-use env_logger::fmt::Color;
-use log::{error, warn, LevelFilter};
+use env_logger::{fmt::Color, Target, WriteStyle};
+use log::{error, info, warn, LevelFilter};
+use message_filter::{
+    ControlCharacterAction, ControlCharacterFilter, WordlistAction, WordlistFilter,
+};
+use middleware::{MessageMiddleware, MiddlewareChain};
+use pwhash::bcrypt;
+use rolling_file::{LogWriter, RollingFile};
 
-use server::ChatServer;
-use server_database::ServerSQLiteDatabase;
-use tcp_server::ChatTcpServer;
+use config::{Admin, Chat, Config, ConfigError};
+use server::{
+    AttachmentOptions, ChatServer, ChatServerOptions, ContentType, HistoryPageLimits, MessageDedup,
+    Motd, RegistrationLimit, RenameLimit, ReportLimit, RoomHistoryLimits, SessionLimit,
+    WhoisOptions,
+};
+use server_database::{ServerDatabase, ServerSQLiteDatabase};
+use tcp_server::{
+    AdminOptions, AttachmentRetention, ChatTcpServer, FrameFormat, HeaderSize, HistoryRetention,
+    SlowConsumerLimit, SocketOptions, StatsBroadcast, TcpServerOptions,
+};
 use time::{format_description::parse, OffsetDateTime};
-use user_service::UserService;
+use tokio::runtime::{self, Runtime};
+#[cfg(unix)]
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::Mutex,
+};
+use user_service::{OnConflict, ReservedNames, UserService, UserServiceOptions, ValidationRules};
 
+mod admin_server;
+mod check;
 mod config;
+mod console;
+mod health_server;
+mod message_filter;
+mod middleware;
+mod rolling_file;
 mod server;
 mod server_database;
 mod tcp_server;
 mod user_service;
+mod user_transfer;
 
-fn get_ip_port_from_config() -> (String, u16) {
-    let config_obj = config::read_config();
+fn read_config() -> Option<Config> {
+    config_or_default(config::read_config())
+}
+
+fn config_or_default(config: Result<Config, ConfigError>) -> Option<Config> {
+    match config {
+        Ok(config) => Some(config),
+        Err(e) => {
+            error!("{e}.");
+            warn!("Using default configuration values.");
+            None
+        }
+    }
+}
 
+fn get_ip_port_from_config(config: Option<&Config>) -> (String, u16) {
     const DEFAULT_HOST: &str = "127.0.0.1";
     const DEFAULT_PORT: u16 = 6969;
 
-    if config_obj.is_err() {
-        error!("{e}.", e = config_obj.err().unwrap());
-        warn!("Using default values for ip and port.");
+    let Some(config) = config else {
         return (DEFAULT_HOST.to_string(), DEFAULT_PORT);
-    }
-    let config_obj = config_obj.unwrap();
+    };
 
-    let host = config_obj.network.ip.unwrap_or(DEFAULT_HOST.to_string());
-    let port = config_obj.network.port.unwrap_or(DEFAULT_PORT);
+    let host = config
+        .network
+        .ip
+        .clone()
+        .unwrap_or(DEFAULT_HOST.to_string());
+    let port = config.network.port.unwrap_or(DEFAULT_PORT);
 
     (host, port)
 }
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
-async fn main() -> Result<(), ()> {
+fn get_idle_timeout_from_config(config: Option<&Config>) -> Option<Duration> {
+    const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 5 * 60;
+
+    let idle_timeout_secs = config
+        .and_then(|config| config.network.idle_timeout_secs)
+        .unwrap_or(DEFAULT_IDLE_TIMEOUT_SECS);
+
+    // Zero disables the idle timeout
+    if idle_timeout_secs == 0 {
+        return None;
+    }
+
+    Some(Duration::from_secs(idle_timeout_secs))
+}
+
+fn get_socket_options_from_config(config: Option<&Config>) -> SocketOptions {
+    let network = config.map(|config| &config.network);
+
+    SocketOptions {
+        nodelay: network
+            .and_then(|network| network.tcp_nodelay)
+            .unwrap_or(true),
+        // Zero disables keepalive as well
+        keepalive: network
+            .and_then(|network| network.tcp_keepalive_secs)
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs),
+    }
+}
+
+fn get_idle_warning_from_config(config: Option<&Config>) -> Option<Duration> {
+    // Zero disables the warning as well
+    let idle_warning_secs = config?.network.idle_warning_secs.filter(|secs| *secs > 0)?;
+    let idle_warning = Duration::from_secs(idle_warning_secs);
+
+    if get_idle_timeout_from_config(config).is_some_and(|idle_timeout| idle_warning >= idle_timeout)
+    {
+        warn!("Idle warning of {idle_warning_secs} seconds is not shorter than the idle timeout, ignoring it.");
+        return None;
+    }
+
+    Some(idle_warning)
+}
+
+fn get_shutdown_grace_from_config(config: Option<&Config>) -> Option<Duration> {
+    // Zero waits for the queued frames however long it takes, like leaving it unset
+    config?
+        .network
+        .shutdown_grace_secs
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+}
+
+fn get_max_connections_per_ip_from_config(config: Option<&Config>) -> Option<usize> {
+    const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 8;
+
+    let max_connections_per_ip = config
+        .and_then(|config| config.network.max_connections_per_ip)
+        .unwrap_or(DEFAULT_MAX_CONNECTIONS_PER_IP);
+
+    // Zero disables the limit
+    if max_connections_per_ip == 0 {
+        return None;
+    }
+
+    Some(max_connections_per_ip)
+}
+
+fn get_slow_consumer_limit_from_config(config: Option<&Config>) -> Option<SlowConsumerLimit> {
+    const DEFAULT_MAX_PENDING_FRAMES: usize = 1024;
+    const DEFAULT_GRACE_SECS: u64 = 10;
+
+    let network = config.map(|config| &config.network);
+
+    let max_pending_frames = network
+        .and_then(|network| network.max_pending_frames)
+        .unwrap_or(DEFAULT_MAX_PENDING_FRAMES);
+
+    // Zero disables the limit
+    if max_pending_frames == 0 {
+        return None;
+    }
+
+    Some(SlowConsumerLimit {
+        max_pending_frames,
+        grace: Duration::from_secs(
+            network
+                .and_then(|network| network.slow_consumer_grace_secs)
+                .unwrap_or(DEFAULT_GRACE_SECS),
+        ),
+    })
+}
+
+fn get_room_history_from_config(config: Option<&Config>) -> RoomHistoryLimits {
+    const DEFAULT_ROOM_HISTORY: usize = 100;
+
+    let chat = config.and_then(|config| config.chat.as_ref());
+
+    RoomHistoryLimits {
+        default: chat
+            .and_then(|chat| chat.room_history_default)
+            .unwrap_or(DEFAULT_ROOM_HISTORY),
+        limits: chat
+            .and_then(|chat| chat.room_history_limits.clone())
+            .unwrap_or_default(),
+    }
+}
+
+fn get_history_page_from_config(config: Option<&Config>) -> HistoryPageLimits {
+    const DEFAULT_HISTORY_PAGE_MAX: usize = 100;
+
+    HistoryPageLimits {
+        max_messages: config
+            .and_then(|config| config.chat.as_ref())
+            .and_then(|chat| chat.history_page_max)
+            .unwrap_or(DEFAULT_HISTORY_PAGE_MAX),
+        max_frame_bytes: get_frame_format_from_config(config).max_body_size,
+    }
+}
+
+fn get_stats_broadcast_from_config(config: Option<&Config>) -> Option<StatsBroadcast> {
+    let chat = config?.chat.as_ref()?;
+
+    // Zero disables the broadcast as well
+    let interval_secs = chat
+        .stats_broadcast_interval_secs
+        .filter(|secs| *secs > 0)?;
+
+    Some(StatsBroadcast {
+        interval: Duration::from_secs(interval_secs),
+        admins_only: chat.stats_broadcast_admins_only.unwrap_or(false),
+    })
+}
+
+fn get_frame_format_from_config(config: Option<&Config>) -> FrameFormat {
+    // Same as the limit of the original 4 byte header
+    const DEFAULT_MAX_MESSAGE_BYTES: usize = (1 << 31) - 1;
+
+    let network = config.map(|config| &config.network);
+
+    let header_size = match network
+        .and_then(|network| network.header_bytes)
+        .map_or(Ok(HeaderSize::Four), parse_header_size)
+    {
+        Ok(header_size) => header_size,
+        Err(e) => {
+            error!("{e}.");
+            warn!("Using 4 byte frame headers.");
+            HeaderSize::Four
+        }
+    };
+
+    let header_max = usize::try_from(header_size.max_body_size()).unwrap_or(usize::MAX);
+    let max_message_bytes = network
+        .and_then(|network| network.max_message_bytes)
+        .unwrap_or(DEFAULT_MAX_MESSAGE_BYTES);
+    if max_message_bytes > header_max && network.is_some_and(|n| n.max_message_bytes.is_some()) {
+        warn!("Maximum message size {max_message_bytes} does not fit the frame header, using {header_max}.");
+    }
+
+    // Empty key is treated as no key, as it would only pretend to protect the frames
+    let signing_key = config
+        .and_then(|config| config.security.as_ref())
+        .and_then(|security| security.frame_hmac_key.as_deref())
+        .filter(|key| !key.is_empty())
+        .map(|key| Arc::from(key.as_bytes()));
+
+    // Zero disables the limit as well
+    let max_read_duration = network
+        .and_then(|network| network.max_frame_read_secs)
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs);
+
+    FrameFormat {
+        header_size,
+        max_body_size: max_message_bytes.min(header_max),
+        signing_key,
+        max_read_duration,
+    }
+}
+
+fn parse_header_size(header_bytes: u8) -> Result<HeaderSize, ConfigError> {
+    match header_bytes {
+        2 => Ok(HeaderSize::Two),
+        4 => Ok(HeaderSize::Four),
+        8 => Ok(HeaderSize::Eight),
+        header_bytes => Err(ConfigError::InvalidValue(format!(
+            "frame header size {header_bytes} is not supported, should be 2, 4 or 8"
+        ))),
+    }
+}
+
+fn get_require_handshake_from_config(config: Option<&Config>) -> bool {
+    config
+        .and_then(|config| config.network.require_handshake)
+        .unwrap_or(false)
+}
+
+fn get_health_address_from_config(config: Option<&Config>) -> Option<String> {
+    const DEFAULT_HOST: &str = "127.0.0.1";
+    const DEFAULT_PORT: u16 = 6970;
+
+    let health = config?.health.as_ref()?;
+
+    let host = health.ip.clone().unwrap_or(DEFAULT_HOST.to_string());
+    let port = health.port.unwrap_or(DEFAULT_PORT);
+
+    Some(format!("{host}:{port}"))
+}
+
+fn get_admin_options_from_config(config: Option<&Config>) -> Option<AdminOptions> {
+    const DEFAULT_HOST: &str = "127.0.0.1";
+    const DEFAULT_PORT: u16 = 6971;
+
+    let admin = config?.admin.as_ref()?;
+
+    let token = match get_admin_token(admin) {
+        Ok(token) => token,
+        Err(e) => {
+            error!("{e}.");
+            warn!("Admin API is disabled.");
+            return None;
+        }
+    };
+
+    let host = admin.ip.clone().unwrap_or(DEFAULT_HOST.to_string());
+    let port = admin.port.unwrap_or(DEFAULT_PORT);
+
+    Some(AdminOptions {
+        address: format!("{host}:{port}"),
+        token,
+    })
+}
+
+fn get_admin_token(admin: &Admin) -> Result<String, ConfigError> {
+    admin
+        .token
+        .clone()
+        .filter(|token| !token.is_empty())
+        .ok_or_else(|| ConfigError::InvalidValue("admin API requires a non-empty token".into()))
+}
+
+fn get_compression_threshold_from_config(config: Option<&Config>) -> Option<usize> {
+    const DEFAULT_THRESHOLD: usize = 1024;
+
+    let compression = config?.compression.as_ref()?;
+    if !compression.enabled.unwrap_or(false) {
+        return None;
+    }
+
+    Some(compression.threshold.unwrap_or(DEFAULT_THRESHOLD))
+}
+
+fn get_max_roster_entries_from_config(config: Option<&Config>) -> usize {
+    const DEFAULT_MAX_ROSTER_ENTRIES: usize = 100;
+
+    config
+        .and_then(|config| config.chat.as_ref())
+        .and_then(|chat| chat.max_roster_entries)
+        .unwrap_or(DEFAULT_MAX_ROSTER_ENTRIES)
+}
+
+fn get_message_retention_from_config(config: Option<&Config>) -> usize {
+    const DEFAULT_MESSAGE_RETENTION: usize = 100;
+
+    config
+        .and_then(|config| config.chat.as_ref())
+        .and_then(|chat| chat.message_retention)
+        .unwrap_or(DEFAULT_MESSAGE_RETENTION)
+}
+
+fn get_persist_messages_from_config(config: Option<&Config>) -> bool {
+    config
+        .and_then(|config| config.chat.as_ref())
+        .and_then(|chat| chat.persist_messages)
+        .unwrap_or(false)
+}
+
+fn get_delivery_reports_from_config(config: Option<&Config>) -> bool {
+    config
+        .and_then(|config| config.chat.as_ref())
+        .and_then(|chat| chat.delivery_reports)
+        .unwrap_or(false)
+}
+
+fn get_history_retention_from_config(config: Option<&Config>) -> Option<HistoryRetention> {
+    const DEFAULT_PRUNE_INTERVAL_SECS: u64 = 60 * 60;
+    const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+    // Without persistence there is no history to prune
+    if !get_persist_messages_from_config(config) {
+        return None;
+    }
+    let chat = config?.chat.as_ref()?;
+
+    // Zero keeps the history forever
+    let retention_days = chat.history_retention_days.filter(|days| *days > 0)?;
+    let prune_interval_secs = chat
+        .history_prune_interval_secs
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_PRUNE_INTERVAL_SECS);
+
+    Some(HistoryRetention {
+        max_age: Duration::from_secs(retention_days * SECS_PER_DAY),
+        prune_interval: Duration::from_secs(prune_interval_secs),
+    })
+}
+
+fn get_attachment_retention_from_config(config: Option<&Config>) -> Option<AttachmentRetention> {
+    const DEFAULT_CLEANUP_INTERVAL_SECS: u64 = 60 * 60;
+    const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+    let attachments = config?.attachments.as_ref()?;
+    // Without a storage directory attachments are only broadcast, nothing is left to clean up
+    let storage_dir = attachments.storage_dir.as_ref()?;
+
+    // Zero keeps the attachments forever
+    let retention_days = attachments.retention_days.filter(|days| *days > 0)?;
+    let cleanup_interval_secs = attachments
+        .cleanup_interval_secs
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_CLEANUP_INTERVAL_SECS);
+
+    Some(AttachmentRetention {
+        storage_dir: PathBuf::from(storage_dir),
+        max_age: Duration::from_secs(retention_days * SECS_PER_DAY),
+        cleanup_interval: Duration::from_secs(cleanup_interval_secs),
+    })
+}
+
+fn get_motd_from_config(config: Option<&Config>) -> Option<Motd> {
+    let chat = config?.chat.as_ref()?;
+
+    if let Some(path) = &chat.motd_file {
+        return Some(Motd::File(PathBuf::from(path)));
+    }
+    chat.motd.clone().map(Motd::Text)
+}
+
+fn get_message_middleware_from_config(config: Option<&Config>) -> MiddlewareChain {
+    const DEFAULT_ORDER: [&str; 2] = ["control_characters", "wordlist"];
+
+    let Some(chat) = config.and_then(|config| config.chat.as_ref()) else {
+        return MiddlewareChain::default();
+    };
+
+    let order = chat
+        .middleware_order
+        .clone()
+        .unwrap_or_else(|| DEFAULT_ORDER.iter().map(|name| name.to_string()).collect());
+
+    let mut middleware = Vec::<Box<dyn MessageMiddleware + Send>>::new();
+    for name in order {
+        match name.as_str() {
+            "control_characters" => {
+                middleware.extend(get_control_character_filter_from_config(chat));
+            }
+            "wordlist" => middleware.extend(get_wordlist_filter_from_config(chat)),
+            _ => {
+                error!("Message middleware '{name}' is unknown, should be 'control_characters' or 'wordlist'.");
+                warn!("Skipping middleware '{name}'.");
+            }
+        }
+    }
+
+    MiddlewareChain::new(middleware)
+}
+
+fn get_control_character_filter_from_config(
+    chat: &Chat,
+) -> Option<Box<dyn MessageMiddleware + Send>> {
+    let control_character_action = match chat
+        .control_characters
+        .as_deref()
+        .map_or(Ok(None), parse_control_character_action)
+    {
+        Ok(action) => action,
+        Err(e) => {
+            error!("{e}.");
+            warn!("Stripping control characters.");
+            Some(ControlCharacterAction::Strip)
+        }
+    };
+
+    control_character_action.map(|action| {
+        Box::new(ControlCharacterFilter::new(
+            action,
+            chat.allow_newlines.unwrap_or(true),
+        )) as Box<dyn MessageMiddleware + Send>
+    })
+}
+
+fn get_wordlist_filter_from_config(chat: &Chat) -> Option<Box<dyn MessageMiddleware + Send>> {
+    let path = chat.wordlist_file.as_ref()?;
+    let action = match chat
+        .wordlist_action
+        .as_deref()
+        .map_or(Ok(WordlistAction::Mask), parse_wordlist_action)
+    {
+        Ok(action) => action,
+        Err(e) => {
+            error!("{e}.");
+            warn!("Masking words from the wordlist.");
+            WordlistAction::Mask
+        }
+    };
+
+    let whole_words = chat.wordlist_whole_words.unwrap_or(true);
+
+    match WordlistFilter::from_file(&PathBuf::from(path), action, whole_words) {
+        Ok(wordlist_filter) => Some(Box::new(wordlist_filter)),
+        Err(e) => {
+            error!("Could not read wordlist from '{path}' ({e}).");
+            warn!("Wordlist filter is disabled.");
+            None
+        }
+    }
+}
+
+fn parse_control_character_action(
+    action: &str,
+) -> Result<Option<ControlCharacterAction>, ConfigError> {
+    match action {
+        "allow" => Ok(None),
+        "strip" => Ok(Some(ControlCharacterAction::Strip)),
+        "reject" => Ok(Some(ControlCharacterAction::Reject)),
+        action => Err(ConfigError::InvalidValue(format!(
+            "control characters policy '{action}' is unknown, should be 'allow', 'strip' or 'reject'"
+        ))),
+    }
+}
+
+fn parse_wordlist_action(action: &str) -> Result<WordlistAction, ConfigError> {
+    match action {
+        "mask" => Ok(WordlistAction::Mask),
+        "reject" => Ok(WordlistAction::Reject),
+        "drop" => Ok(WordlistAction::Drop),
+        action => Err(ConfigError::InvalidValue(format!(
+            "wordlist action '{action}' is unknown, should be 'mask', 'reject' or 'drop'"
+        ))),
+    }
+}
+
+fn get_attachment_options_from_config(config: Option<&Config>) -> AttachmentOptions {
+    const DEFAULT_MAX_SIZE: usize = 256 * 1024;
+    const DEFAULT_ALLOWED_MIME_TYPES: [&str; 4] =
+        ["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+    let attachments = config.and_then(|config| config.attachments.as_ref());
+
+    AttachmentOptions {
+        max_size: attachments
+            .and_then(|attachments| attachments.max_size_bytes)
+            .unwrap_or(DEFAULT_MAX_SIZE),
+        allowed_mime_types: attachments
+            .and_then(|attachments| attachments.allowed_mime_types.clone())
+            .unwrap_or_else(|| {
+                DEFAULT_ALLOWED_MIME_TYPES
+                    .iter()
+                    .map(|mime| mime.to_string())
+                    .collect()
+            }),
+        storage_dir: attachments
+            .and_then(|attachments| attachments.storage_dir.as_ref())
+            .map(PathBuf::from),
+    }
+}
+
+fn get_whois_options_from_config(config: Option<&Config>) -> WhoisOptions {
+    let chat = config.and_then(|config| config.chat.as_ref());
+
+    WhoisOptions {
+        admins_only: chat
+            .and_then(|chat| chat.whois_admins_only)
+            .unwrap_or(false),
+        show_ip: chat.and_then(|chat| chat.whois_show_ip).unwrap_or(false),
+    }
+}
+
+fn get_rooms_from_config(config: Option<&Config>) -> Vec<String> {
+    const DEFAULT_ROOM: &str = "general";
+
+    config
+        .and_then(|config| config.chat.as_ref())
+        .and_then(|chat| chat.rooms.clone())
+        .unwrap_or_else(|| vec![DEFAULT_ROOM.to_string()])
+}
+
+fn get_private_rooms_from_config(config: Option<&Config>) -> Vec<String> {
+    config
+        .and_then(|config| config.chat.as_ref())
+        .and_then(|chat| chat.private_rooms.clone())
+        .unwrap_or_default()
+}
+
+fn get_list_empty_rooms_from_config(config: Option<&Config>) -> bool {
+    config
+        .and_then(|config| config.chat.as_ref())
+        .and_then(|chat| chat.list_empty_rooms)
+        .unwrap_or(true)
+}
+
+fn get_max_rooms_per_user_from_config(config: Option<&Config>) -> usize {
+    const DEFAULT_MAX_ROOMS_PER_USER: usize = 10;
+
+    config
+        .and_then(|config| config.chat.as_ref())
+        .and_then(|chat| chat.max_rooms_per_user)
+        .unwrap_or(DEFAULT_MAX_ROOMS_PER_USER)
+}
+
+fn get_allowed_content_types_from_config(config: Option<&Config>) -> Vec<ContentType> {
+    let Some(content_types) = config
+        .and_then(|config| config.chat.as_ref())
+        .and_then(|chat| chat.allowed_content_types.as_ref())
+    else {
+        return vec![ContentType::Plain];
+    };
+
+    content_types
+        .iter()
+        .filter_map(|content_type| match parse_content_type(content_type) {
+            Ok(content_type) => Some(content_type),
+            Err(e) => {
+                error!("{e}.");
+                warn!("Ignoring content type '{content_type}'.");
+                None
+            }
+        })
+        .collect()
+}
+
+fn parse_content_type(content_type: &str) -> Result<ContentType, ConfigError> {
+    match content_type {
+        "plain" => Ok(ContentType::Plain),
+        "markdown" => Ok(ContentType::Markdown),
+        content_type => Err(ConfigError::InvalidValue(format!(
+            "content type '{content_type}' is unknown, should be 'plain' or 'markdown'"
+        ))),
+    }
+}
+
+fn get_admins_from_config(config: Option<&Config>) -> Vec<String> {
+    config
+        .and_then(|config| config.chat.as_ref())
+        .and_then(|chat| chat.admins.clone())
+        .unwrap_or_default()
+}
+
+fn get_public_server_stats_from_config(config: Option<&Config>) -> bool {
+    config
+        .and_then(|config| config.chat.as_ref())
+        .and_then(|chat| chat.public_server_stats)
+        .unwrap_or(false)
+}
+
+fn get_registration_limit_from_config(config: Option<&Config>) -> Option<RegistrationLimit> {
+    const DEFAULT_WINDOW_SECS: u64 = 60 * 60;
+
+    let security = config?.security.as_ref()?;
+
+    let max_registrations = security.max_registrations_per_ip.filter(|max| *max > 0)?;
+    let window_secs = security
+        .registration_window_secs
+        .unwrap_or(DEFAULT_WINDOW_SECS);
+
+    Some(RegistrationLimit {
+        max_registrations,
+        window: Duration::from_secs(window_secs),
+    })
+}
+
+fn get_duplicate_cooldown_from_config(config: Option<&Config>) -> Option<Duration> {
+    // Zero disables the cooldown as well
+    config?
+        .chat
+        .as_ref()?
+        .duplicate_cooldown_secs
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+}
+
+fn get_rename_limit_from_config(config: Option<&Config>) -> Option<RenameLimit> {
+    const DEFAULT_WINDOW_SECS: u64 = 60 * 60;
+
+    let security = config?.security.as_ref()?;
+
+    let cooldown_secs = security.rename_cooldown_secs.unwrap_or(0);
+    // Zero allows any number of renames
+    let max_renames = security.max_renames.filter(|max| *max > 0);
+    if cooldown_secs == 0 && max_renames.is_none() {
+        return None;
+    }
+
+    Some(RenameLimit {
+        cooldown: Duration::from_secs(cooldown_secs),
+        max_renames,
+        window: Duration::from_secs(security.rename_window_secs.unwrap_or(DEFAULT_WINDOW_SECS)),
+    })
+}
+
+fn get_report_limit_from_config(config: Option<&Config>) -> Option<ReportLimit> {
+    const DEFAULT_MAX_REPORTS: usize = 5;
+    const DEFAULT_WINDOW_SECS: u64 = 60 * 60;
+
+    let chat = config.and_then(|config| config.chat.as_ref());
+
+    // Zero disables the limit
+    let max_reports = chat
+        .and_then(|chat| chat.max_reports_per_user)
+        .unwrap_or(DEFAULT_MAX_REPORTS);
+    if max_reports == 0 {
+        return None;
+    }
+    let window_secs = chat
+        .and_then(|chat| chat.report_window_secs)
+        .unwrap_or(DEFAULT_WINDOW_SECS);
+
+    Some(ReportLimit {
+        max_reports,
+        window: Duration::from_secs(window_secs),
+    })
+}
+
+fn get_session_limit_from_config(config: Option<&Config>) -> Option<SessionLimit> {
+    let security = config?.security.as_ref()?;
+
+    // Zero disables the session limit as well
+    let max_session_secs = security.max_session_secs.filter(|secs| *secs > 0)?;
+
+    Some(SessionLimit {
+        max_duration: Duration::from_secs(max_session_secs),
+        disconnect: security.disconnect_expired_sessions.unwrap_or(false),
+    })
+}
+
+fn get_rejoin_grace_from_config(config: Option<&Config>) -> Option<Duration> {
+    config?
+        .chat
+        .as_ref()?
+        .rejoin_grace_secs
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+}
+
+fn get_presence_batch_window_from_config(config: Option<&Config>) -> Option<Duration> {
+    config?
+        .chat
+        .as_ref()?
+        .presence_batch_window_ms
+        .filter(|millis| *millis > 0)
+        .map(Duration::from_millis)
+}
+
+fn get_message_dedup_from_config(config: Option<&Config>) -> Option<MessageDedup> {
+    const DEFAULT_MAX_ENTRIES: usize = 10_000;
+
+    let chat = config?.chat.as_ref()?;
+
+    // Zero disables deduplication as well
+    let window_secs = chat.dedup_window_secs.filter(|secs| *secs > 0)?;
+
+    Some(MessageDedup {
+        window: Duration::from_secs(window_secs),
+        max_entries: chat.dedup_max_entries.unwrap_or(DEFAULT_MAX_ENTRIES),
+    })
+}
+
+fn get_auto_login_on_register_from_config(config: Option<&Config>) -> bool {
+    config
+        .and_then(|config| config.security.as_ref())
+        .and_then(|security| security.auto_login_on_register)
+        .unwrap_or(false)
+}
+
+fn get_require_email_from_config(config: Option<&Config>) -> bool {
+    config
+        .and_then(|config| config.security.as_ref())
+        .and_then(|security| security.require_email)
+        .unwrap_or(false)
+}
+
+fn get_reserved_names_from_config(config: Option<&Config>) -> ReservedNames {
+    let Some(security) = config.and_then(|config| config.security.as_ref()) else {
+        return ReservedNames::default();
+    };
+
+    let mut patterns = security.reserved_names.clone().unwrap_or_default();
+    if let Some(path) = &security.reserved_names_file {
+        // One name or pattern per line
+        match fs::read_to_string(path) {
+            Ok(contents) => patterns.extend(contents.lines().map(str::to_string)),
+            Err(e) => error!("Could not read reserved names from '{path}' ({e})."),
+        }
+    }
+
+    ReservedNames::new(patterns)
+}
+
+fn get_bcrypt_cost_from_config(config: Option<&Config>) -> u32 {
+    let Some(cost) = config
+        .and_then(|config| config.security.as_ref())
+        .and_then(|security| security.bcrypt_cost)
+    else {
+        return bcrypt::DEFAULT_COST;
+    };
+
+    check_bcrypt_cost(cost).unwrap_or_else(|e| {
+        error!("{e}.");
+        warn!("Using default bcrypt cost.");
+        bcrypt::DEFAULT_COST
+    })
+}
+
+fn check_bcrypt_cost(cost: u32) -> Result<u32, ConfigError> {
+    if !(bcrypt::MIN_COST..=bcrypt::MAX_COST).contains(&cost) {
+        return Err(ConfigError::InvalidValue(format!(
+            "bcrypt cost {cost} is out of range, should be between {} and {}",
+            bcrypt::MIN_COST,
+            bcrypt::MAX_COST
+        )));
+    }
+
+    Ok(cost)
+}
+
+fn get_validation_rules_from_config(
+    config: Option<&Config>,
+) -> Result<ValidationRules, ConfigError> {
+    // bcrypt ignores everything past the first 72 bytes of a password
+    const BCRYPT_MAX_PASSWORD_BYTES: usize = 72;
+
+    let defaults = ValidationRules::default();
+    let Some(validation) = config.and_then(|config| config.validation.as_ref()) else {
+        return Ok(defaults);
+    };
+
+    let rules = ValidationRules {
+        name_min: validation.name_min.unwrap_or(defaults.name_min),
+        name_max: validation.name_max.unwrap_or(defaults.name_max),
+        password_min: validation.password_min.unwrap_or(defaults.password_min),
+        password_max: validation.password_max.unwrap_or(defaults.password_max),
+        allow_dots: validation.allow_dots.unwrap_or(defaults.allow_dots),
+        allow_underscores: validation
+            .allow_underscores
+            .unwrap_or(defaults.allow_underscores),
+    };
+
+    if rules.name_max == 0 || rules.name_min > rules.name_max {
+        return Err(ConfigError::InvalidValue(format!(
+            "name length bounds {}..={} are out of range",
+            rules.name_min, rules.name_max
+        )));
+    }
+    if rules.password_max == 0 || rules.password_min > rules.password_max {
+        return Err(ConfigError::InvalidValue(format!(
+            "password length bounds {}..={} are out of range",
+            rules.password_min, rules.password_max
+        )));
+    }
+    if rules.password_max > BCRYPT_MAX_PASSWORD_BYTES {
+        return Err(ConfigError::InvalidValue(format!(
+            "password maximum length {} is above the bcrypt limit of {BCRYPT_MAX_PASSWORD_BYTES}",
+            rules.password_max
+        )));
+    }
+
+    Ok(rules)
+}
+
+fn get_worker_threads_from_config(config: Option<&Config>) -> usize {
+    let default_worker_threads = thread::available_parallelism()
+        .map(NonZeroUsize::get)
+        .unwrap_or(1);
+
+    let Some(worker_threads) = config
+        .and_then(|config| config.runtime.as_ref())
+        .and_then(|runtime| runtime.worker_threads)
+    else {
+        return default_worker_threads;
+    };
+
+    check_worker_threads(worker_threads).unwrap_or_else(|e| {
+        error!("{e}.");
+        warn!("Using default worker threads count.");
+        default_worker_threads
+    })
+}
+
+fn check_worker_threads(worker_threads: usize) -> Result<usize, ConfigError> {
+    if worker_threads < 1 {
+        return Err(ConfigError::InvalidValue(
+            "worker threads count should be at least 1".into(),
+        ));
+    }
+
+    Ok(worker_threads)
+}
+
+fn build_runtime(worker_threads: usize) -> io::Result<Runtime> {
+    runtime::Builder::new_multi_thread()
+        .worker_threads(worker_threads)
+        .enable_all()
+        .build()
+}
+
+fn get_log_file_from_config(config: Option<&Config>) -> Option<RollingFile> {
+    const DEFAULT_MAX_SIZE_MB: u64 = 10;
+    const DEFAULT_KEEP_FILES: usize = 5;
+
+    let logging = config?.logging.as_ref()?;
+    let path = logging.file.as_ref()?;
+
+    let max_size_mb = logging
+        .max_size_mb
+        .filter(|max_size_mb| *max_size_mb > 0)
+        .unwrap_or(DEFAULT_MAX_SIZE_MB);
+    let keep_files = logging.keep_files.unwrap_or(DEFAULT_KEEP_FILES);
+
+    match RollingFile::open(PathBuf::from(path), max_size_mb * 1024 * 1024, keep_files) {
+        Ok(rolling_file) => Some(rolling_file),
+        Err(e) => {
+            // Logger is not initialized yet
+            eprintln!("Could not open log file '{path}' ({e}), logging to the console only.");
+            None
+        }
+    }
+}
+
+fn get_spans_from_config(config: Option<&Config>) -> bool {
+    config
+        .and_then(|config| config.logging.as_ref())
+        .and_then(|logging| logging.spans)
+        .unwrap_or(false)
+}
+
+/// Writes events with the fields of their spans, `log` records of other modules are forwarded to it
+fn init_tracing(config: Option<&Config>) {
+    let subscriber = tracing_subscriber::fmt().with_max_level(tracing::Level::TRACE);
+
+    match get_log_file_from_config(config) {
+        Some(log_file) => {
+            let log_writer = LogWriter::new(log_file);
+            let ansi = log_writer.is_console_terminal();
+            subscriber
+                .with_ansi(ansi)
+                .with_writer(std::sync::Mutex::new(log_writer))
+                .init();
+        }
+        None => subscriber
+            .with_ansi(io::stderr().is_terminal())
+            .with_writer(io::stderr)
+            .init(),
+    }
+}
+
+fn init_logger(config: Option<&Config>) {
+    if get_spans_from_config(config) {
+        init_tracing(config);
+        return;
+    }
+
     let mut logger_builder = env_logger::builder();
     logger_builder
         .filter_level(LevelFilter::max())
@@ -68,17 +979,461 @@ async fn main() -> Result<(), ()> {
                 style.value(record.level()),
                 record.args()
             )
-        })
-        .init();
+        });
 
-    let sqlite_database = ServerSQLiteDatabase::default();
-    let user_service = UserService::new(sqlite_database);
-    let chat_server = ChatServer::new(user_service);
+    if let Some(log_file) = get_log_file_from_config(config) {
+        let log_writer = LogWriter::new(log_file);
+        // Color codes are stripped from the file, the console keeps them only if it is a terminal
+        let write_style = if log_writer.is_console_terminal() {
+            WriteStyle::Always
+        } else {
+            WriteStyle::Never
+        };
+        logger_builder
+            .write_style(write_style)
+            .target(Target::Pipe(Box::new(log_writer)));
+    }
 
-    let (host, port) = get_ip_port_from_config();
-    let tcp_chat_server = ChatTcpServer::create_async(&host, port, chat_server).await?;
+    logger_builder.init();
+}
+
+fn main() -> Result<(), ()> {
+    if env::args().skip(1).any(|arg| arg == "--check") {
+        let problems = check::run_checks();
+        for problem in &problems {
+            eprintln!("{}", serde_json::to_string(problem).unwrap());
+        }
+        process::exit(if problems.is_empty() { 0 } else { 1 });
+    }
+
+    // Logging is configured from the file, so its errors can only be reported afterwards
+    let config = config::read_config();
+    init_logger(config.as_ref().ok());
+    let config = config_or_default(config);
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some(command @ ("export-users" | "import-users")) = args.first().map(String::as_str) {
+        let result = run_user_transfer(command, &args[1..], config.as_ref());
+        process::exit(if result.is_ok() { 0 } else { 1 });
+    }
+
+    let worker_threads = get_worker_threads_from_config(config.as_ref());
+
+    info!("Using {worker_threads} worker threads.");
+
+    let runtime = build_runtime(worker_threads).map_err(|err| {
+        error!("Could not build the async runtime ({err}).");
+    })?;
+
+    let result = runtime.block_on(run(config));
+
+    // Console reads stdin on a blocking thread, which cannot be interrupted, so don't wait for it
+    runtime.shutdown_background();
+
+    result
+}
+
+fn open_database() -> Result<ServerSQLiteDatabase, ()> {
+    ServerSQLiteDatabase::try_new().map_err(|e| {
+        error!("Could not open the database, it may be corrupted or inaccessible ({e}).");
+    })
+}
+
+/// Runs the user export or import without starting any server
+fn run_user_transfer(command: &str, args: &[String], config: Option<&Config>) -> Result<(), ()> {
+    let flag_value = |flag: &str| {
+        args.iter()
+            .position(|arg| arg == flag)
+            .and_then(|index| args.get(index + 1))
+    };
+
+    let validation_rules = get_validation_rules_from_config(config).map_err(|e| {
+        error!("{e}.");
+    })?;
+    let user_service = UserService::new(
+        open_database()?,
+        get_user_service_options_from_config(config),
+        validation_rules,
+    );
+
+    if command == "export-users" {
+        let Some(path) = flag_value("--out") else {
+            error!("Usage: export-users --out <file>.");
+            return Err(());
+        };
+        let count = user_transfer::export_users(&user_service, path).map_err(|e| {
+            error!("Could not export users to '{path}' ({e}).");
+        })?;
+        info!("Exported {count} users to '{path}'.");
+        return Ok(());
+    }
+
+    let Some(path) = flag_value("--in") else {
+        error!("Usage: import-users --in <file> [--on-conflict skip|fail|overwrite].");
+        return Err(());
+    };
+    let on_conflict = match flag_value("--on-conflict").map(String::as_str) {
+        None | Some("skip") => OnConflict::Skip,
+        Some("fail") => OnConflict::Fail,
+        Some("overwrite") => OnConflict::Overwrite,
+        Some(value) => {
+            error!(
+                "Conflict policy '{value}' is unknown, should be 'skip', 'fail' or 'overwrite'."
+            );
+            return Err(());
+        }
+    };
+
+    let summary = user_transfer::import_users(&user_service, path, on_conflict).map_err(|e| {
+        error!("Could not import users from '{path}', none were imported ({e}).");
+    })?;
+    for name in &summary.skipped {
+        warn!("Skipped user '{name}', the name is already taken.");
+    }
+    info!(
+        "Imported {} users from '{path}', skipped {}.",
+        summary.imported,
+        summary.skipped.len()
+    );
+    Ok(())
+}
+
+fn get_user_service_options_from_config(config: Option<&Config>) -> UserServiceOptions {
+    UserServiceOptions {
+        bcrypt_cost: get_bcrypt_cost_from_config(config),
+        require_email: get_require_email_from_config(config),
+        reserved_names: get_reserved_names_from_config(config),
+    }
+}
+
+fn get_chat_server_options_from_config(config: Option<&Config>) -> ChatServerOptions {
+    ChatServerOptions {
+        compression: get_compression_threshold_from_config(config).is_some(),
+        max_roster_entries: get_max_roster_entries_from_config(config),
+        message_retention: get_message_retention_from_config(config),
+        persist_messages: get_persist_messages_from_config(config),
+        delivery_reports: get_delivery_reports_from_config(config),
+        admins: get_admins_from_config(config),
+        public_server_stats: get_public_server_stats_from_config(config),
+        registration_limit: get_registration_limit_from_config(config),
+        report_limit: get_report_limit_from_config(config),
+        rename_limit: get_rename_limit_from_config(config),
+        auto_login_on_register: get_auto_login_on_register_from_config(config),
+        motd: get_motd_from_config(config),
+        session_limit: get_session_limit_from_config(config),
+        rejoin_grace: get_rejoin_grace_from_config(config),
+        presence_batch_window: get_presence_batch_window_from_config(config),
+        message_dedup: get_message_dedup_from_config(config),
+        duplicate_cooldown: get_duplicate_cooldown_from_config(config),
+        message_middleware: get_message_middleware_from_config(config),
+        attachments: get_attachment_options_from_config(config),
+        whois: get_whois_options_from_config(config),
+        rooms: get_rooms_from_config(config),
+        private_rooms: get_private_rooms_from_config(config),
+        list_empty_rooms: get_list_empty_rooms_from_config(config),
+        max_rooms_per_user: get_max_rooms_per_user_from_config(config),
+        room_history: get_room_history_from_config(config),
+        history_page: get_history_page_from_config(config),
+        allowed_content_types: get_allowed_content_types_from_config(config),
+        require_handshake: get_require_handshake_from_config(config),
+    }
+}
+
+fn get_tcp_server_options_from_config(config: Option<&Config>) -> TcpServerOptions {
+    TcpServerOptions {
+        compression_threshold: get_compression_threshold_from_config(config),
+        health_address: get_health_address_from_config(config),
+        admin: get_admin_options_from_config(config),
+        idle_timeout: get_idle_timeout_from_config(config),
+        idle_warning: get_idle_warning_from_config(config),
+        max_connections_per_ip: get_max_connections_per_ip_from_config(config),
+        socket_options: get_socket_options_from_config(config),
+        slow_consumer_limit: get_slow_consumer_limit_from_config(config),
+        history_retention: get_history_retention_from_config(config),
+        attachment_retention: get_attachment_retention_from_config(config),
+        frame_format: get_frame_format_from_config(config),
+        stats_broadcast: get_stats_broadcast_from_config(config),
+        shutdown_grace: get_shutdown_grace_from_config(config),
+        console: true,
+    }
+}
+
+#[cfg(unix)]
+async fn reload_config_on_hangup<T: ServerDatabase>(
+    chat_server: Arc<Mutex<ChatServer<T>>>,
+    config: Option<Config>,
+) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            error!("Could not listen for SIGHUP ({err}).");
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        warn!("** Detected SIGHUP, reloading the configuration... **");
+
+        let new_config = read_config();
+        apply_reloaded_config(
+            &mut *chat_server.lock().await,
+            config.as_ref(),
+            new_config.as_ref(),
+        );
+
+        info!("** Configuration has been reloaded. **");
+    }
+}
+
+/// Applies the new configuration to the running chat server, settings which need a restart keep
+/// the values from `config`
+#[cfg_attr(not(unix), allow(dead_code))]
+fn apply_reloaded_config<T: ServerDatabase>(
+    chat_server: &mut ChatServer<T>,
+    config: Option<&Config>,
+    new_config: Option<&Config>,
+) {
+    if get_ip_port_from_config(config) != get_ip_port_from_config(new_config) {
+        warn!("Changed network address requires restart, ignoring it.");
+    }
+    if get_worker_threads_from_config(config) != get_worker_threads_from_config(new_config) {
+        warn!("Changed worker threads count requires restart, ignoring it.");
+    }
+    if get_tcp_server_options_from_config(config) != get_tcp_server_options_from_config(new_config)
+    {
+        warn!("Changed network options require restart, ignoring them.");
+    }
+
+    // Invalid bounds would be refused on startup, so the ones in use are kept
+    let validation_rules = get_validation_rules_from_config(new_config)
+        .inspect_err(|e| error!("{e}, keeping the current validation rules."))
+        .ok();
+    let user_service_options = get_user_service_options_from_config(new_config);
+    let chat_server_options = get_chat_server_options_from_config(new_config);
+
+    info!(
+        "Using bcrypt cost factor {}.",
+        user_service_options.bcrypt_cost
+    );
+
+    chat_server.reload_options(chat_server_options, user_service_options, validation_rules);
+}
+
+async fn run(config: Option<Config>) -> Result<(), ()> {
+    let validation_rules = get_validation_rules_from_config(config.as_ref()).map_err(|e| {
+        error!("{e}.");
+    })?;
+    let user_service_options = get_user_service_options_from_config(config.as_ref());
+
+    info!(
+        "Using bcrypt cost factor {}.",
+        user_service_options.bcrypt_cost
+    );
+
+    let sqlite_database = open_database()?;
+    let user_service = UserService::new(sqlite_database, user_service_options, validation_rules);
+    let chat_server = ChatServer::new(
+        user_service,
+        get_chat_server_options_from_config(config.as_ref()),
+        None,
+    );
+
+    let (host, port) = get_ip_port_from_config(config.as_ref());
+    let tcp_chat_server = ChatTcpServer::create_async(
+        &host,
+        port,
+        chat_server,
+        get_tcp_server_options_from_config(config.as_ref()),
+    )
+    .await?;
+
+    #[cfg(unix)]
+    tokio::spawn(reload_config_on_hangup(
+        tcp_chat_server.chat_server(),
+        config,
+    ));
 
     tcp_chat_server.run().await;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn default_worker_threads() -> usize {
+        thread::available_parallelism().map_or(1, NonZeroUsize::get)
+    }
+
+    #[test]
+    fn worker_threads_come_from_config() {
+        let config = config("[network]\n[runtime]\nworker_threads = 3");
+
+        assert_eq!(get_worker_threads_from_config(Some(&config)), 3);
+    }
+
+    #[test]
+    fn missing_or_zero_worker_threads_use_available_parallelism() {
+        let zero = config("[network]\n[runtime]\nworker_threads = 0");
+        let missing = config("[network]");
+
+        assert_eq!(
+            get_worker_threads_from_config(Some(&zero)),
+            default_worker_threads()
+        );
+        assert_eq!(
+            get_worker_threads_from_config(Some(&missing)),
+            default_worker_threads()
+        );
+        assert_eq!(
+            get_worker_threads_from_config(None),
+            default_worker_threads()
+        );
+    }
+
+    #[test]
+    fn built_runtime_runs_spawned_tasks() {
+        let runtime = build_runtime(2).unwrap();
+
+        let result = runtime.block_on(async { tokio::spawn(async { 831 }).await });
+
+        assert_eq!(result.unwrap(), 831);
+    }
+
+    #[test]
+    fn frame_headers_default_to_four_bytes() {
+        let frame_format = get_frame_format_from_config(Some(&config("[network]")));
+
+        assert_eq!(frame_format.header_size, HeaderSize::Four);
+        assert_eq!(frame_format.max_body_size, (1 << 31) - 1);
+    }
+
+    #[test]
+    fn message_size_is_capped_by_the_header_width() {
+        let config = config("[network]\nheader_bytes = 2\nmax_message_bytes = 1000000");
+
+        let frame_format = get_frame_format_from_config(Some(&config));
+
+        assert_eq!(frame_format.header_size, HeaderSize::Two);
+        assert_eq!(frame_format.max_body_size, (1 << 15) - 1);
+    }
+
+    #[test]
+    fn message_size_below_the_header_limit_is_kept() {
+        let config = config("[network]\nheader_bytes = 8\nmax_message_bytes = 4096");
+
+        let frame_format = get_frame_format_from_config(Some(&config));
+
+        assert_eq!(frame_format.header_size, HeaderSize::Eight);
+        assert_eq!(frame_format.max_body_size, 4096);
+    }
+
+    #[test]
+    fn unsupported_header_width_falls_back_to_four_bytes() {
+        assert!(parse_header_size(3).is_err());
+
+        let config = config("[network]\nheader_bytes = 3");
+        let frame_format = get_frame_format_from_config(Some(&config));
+
+        assert_eq!(frame_format.header_size, HeaderSize::Four);
+    }
+
+    #[test]
+    fn zero_max_frame_read_disables_the_limit() {
+        let limited = config("[network]\nmax_frame_read_secs = 5");
+        let disabled = config("[network]\nmax_frame_read_secs = 0");
+
+        assert_eq!(
+            get_frame_format_from_config(Some(&limited)).max_read_duration,
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            get_frame_format_from_config(Some(&disabled)).max_read_duration,
+            None
+        );
+    }
+
+    fn register<T: ServerDatabase>(
+        chat_server: &mut ChatServer<T>,
+        user_id: &str,
+        name: &str,
+    ) -> serde_json::Value {
+        request(
+            chat_server,
+            user_id,
+            serde_json::json!({ "Registration": { "user_credentials_raw": {
+                "name": name,
+                "password": "password1",
+                "email": null,
+            } } }),
+        )
+    }
+
+    /// Response to the request sent from a new session
+    fn request<T: ServerDatabase>(
+        chat_server: &mut ChatServer<T>,
+        user_id: &str,
+        request: serde_json::Value,
+    ) -> serde_json::Value {
+        chat_server.on_user_connect(user_id.to_string(), "127.0.0.1:4000".parse().unwrap());
+        let commands = chat_server
+            .on_user_message(user_id.to_string(), request.to_string().as_bytes())
+            .unwrap();
+        let Some(server::ChatServerResponseCommand::SendToSome(_, response)) = commands.first()
+        else {
+            panic!("request should be answered");
+        };
+        serde_json::from_slice(response).unwrap()
+    }
+
+    #[test]
+    fn reload_applies_validation_rules_and_keeps_restart_only_settings() {
+        let startup_config = config("[network]\nport = 6969\n[compression]\nenabled = true");
+        let mut chat_server = server::tests::chat_server(ChatServerOptions {
+            compression: true,
+            ..server::tests::options()
+        });
+        let registered = register(&mut chat_server, "before", "EightChr");
+        assert_eq!(registered["RegistrationResult"]["result"], true);
+
+        let new_config = config("[network]\nport = 7070\n[validation]\nname_min = 10");
+        apply_reloaded_config(&mut chat_server, Some(&startup_config), Some(&new_config));
+
+        assert_eq!(
+            register(&mut chat_server, "short", "EightCh2")["RegistrationResult"],
+            serde_json::json!({
+                "result": false,
+                "error": { "IncorrectName": { "IncorrectLength": [10, 32] } },
+            })
+        );
+        let registered = register(&mut chat_server, "long", "TenCharsOk");
+        assert_eq!(registered["RegistrationResult"]["result"], true);
+        // Compression is negotiated per connection, so the reload can't turn it off
+        let handshake = request(
+            &mut chat_server,
+            "handshake",
+            serde_json::json!({ "Handshake": { "compression": true } }),
+        );
+        assert_eq!(handshake["HandshakeResult"]["compression"], true);
+    }
+
+    #[test]
+    fn reload_with_invalid_validation_rules_keeps_the_current_ones() {
+        let mut chat_server = server::tests::chat_server(server::tests::options());
+        let reloaded = config("[network]\n[validation]\nname_min = 10");
+        apply_reloaded_config(&mut chat_server, None, Some(&reloaded));
+
+        let invalid = config("[network]\n[validation]\nname_min = 40");
+        apply_reloaded_config(&mut chat_server, None, Some(&invalid));
+
+        let registered = register(&mut chat_server, "short", "EightChr");
+        assert_eq!(registered["RegistrationResult"]["result"], false);
+        let registered = register(&mut chat_server, "long", "TenCharsOk");
+        assert_eq!(registered["RegistrationResult"]["result"], true);
+    }
+}
@@ -0,0 +1,457 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs, io,
+    path::Path,
+};
+
+use crate::middleware::{MessageContext, MessageMiddleware, MiddlewareResult};
+
+#[derive(Clone, Copy)]
+pub enum WordlistAction {
+    Mask,
+    Reject,
+    Drop,
+}
+
+/// Case folding applied alike to listed words and messages, char for char so matches map back onto
+/// the message
+fn fold_case(ch: char) -> char {
+    ch.to_lowercase().next().unwrap_or(ch)
+}
+
+struct MatcherNode {
+    next: HashMap<char, usize>,
+    /// Node of the longest proper suffix which is also in the trie
+    fail: usize,
+    /// Length in chars of the listed word ending here, if any
+    word_len: Option<usize>,
+    /// Nearest node along the failure links where a listed word ends
+    output: Option<usize>,
+}
+
+impl MatcherNode {
+    fn new() -> Self {
+        Self {
+            next: HashMap::new(),
+            fail: 0,
+            word_len: None,
+            output: None,
+        }
+    }
+}
+
+/// Aho-Corasick automaton over the case folded listed words, scanning a message visits each char
+/// once no matter how many words are listed
+struct WordMatcher {
+    nodes: Vec<MatcherNode>,
+}
+
+impl WordMatcher {
+    fn new<'a>(words: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut nodes = vec![MatcherNode::new()];
+        for word in words {
+            let mut node = 0;
+            let mut len = 0;
+            for ch in word.chars().map(fold_case) {
+                node = match nodes[node].next.get(&ch) {
+                    Some(&next) => next,
+                    None => {
+                        nodes.push(MatcherNode::new());
+                        let next = nodes.len() - 1;
+                        nodes[node].next.insert(ch, next);
+                        next
+                    }
+                };
+                len += 1;
+            }
+            if node != 0 {
+                nodes[node].word_len = Some(len);
+            }
+        }
+
+        // Breadth first, so the failure links of shallower nodes are known when they are followed
+        let mut queue: VecDeque<usize> = nodes[0].next.values().copied().collect();
+        while let Some(node) = queue.pop_front() {
+            let children: Vec<(char, usize)> = nodes[node]
+                .next
+                .iter()
+                .map(|(&ch, &child)| (ch, child))
+                .collect();
+            for (ch, child) in children {
+                let mut fail = nodes[node].fail;
+                let child_fail = loop {
+                    match nodes[fail].next.get(&ch) {
+                        Some(&next) => break next,
+                        None if fail == 0 => break 0,
+                        None => fail = nodes[fail].fail,
+                    }
+                };
+                nodes[child].fail = child_fail;
+                nodes[child].output = if nodes[child_fail].word_len.is_some() {
+                    Some(child_fail)
+                } else {
+                    nodes[child_fail].output
+                };
+                queue.push_back(child);
+            }
+        }
+
+        Self { nodes }
+    }
+
+    fn step(&self, mut node: usize, ch: char) -> usize {
+        loop {
+            if let Some(&next) = self.nodes[node].next.get(&ch) {
+                return next;
+            }
+            if node == 0 {
+                return 0;
+            }
+            node = self.nodes[node].fail;
+        }
+    }
+
+    /// Whether the whole text is a listed word
+    fn is_listed(&self, text: &str) -> bool {
+        let mut node = 0;
+        for ch in text.chars().map(fold_case) {
+            match self.nodes[node].next.get(&ch) {
+                Some(&next) => node = next,
+                None => return false,
+            }
+        }
+        self.nodes[node].word_len.is_some()
+    }
+
+    /// Calls `on_match` with the start and end char positions of every listed word in the text
+    fn find_all(&self, text: &str, mut on_match: impl FnMut(usize, usize)) {
+        let mut node = 0;
+        for (position, ch) in text.chars().map(fold_case).enumerate() {
+            node = self.step(node, ch);
+            let end = position + 1;
+            let mut output = self.nodes[node]
+                .word_len
+                .map(|_| node)
+                .or(self.nodes[node].output);
+            while let Some(found) = output {
+                if let Some(len) = self.nodes[found].word_len {
+                    on_match(end - len, end);
+                }
+                output = self.nodes[found].output;
+            }
+        }
+    }
+}
+
+pub struct WordlistFilter {
+    matcher: WordMatcher,
+    action: WordlistAction,
+    /// Otherwise listed words are matched anywhere, including inside longer words
+    whole_words: bool,
+}
+
+impl WordlistFilter {
+    /// Reads one word per line, empty lines and lines starting with '#' are skipped
+    pub fn from_file(path: &Path, action: WordlistAction, whole_words: bool) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let words = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+
+        Ok(Self::new(words, action, whole_words))
+    }
+
+    fn new<'a>(
+        words: impl IntoIterator<Item = &'a str>,
+        action: WordlistAction,
+        whole_words: bool,
+    ) -> Self {
+        Self {
+            matcher: WordMatcher::new(words),
+            action,
+            whole_words,
+        }
+    }
+
+    fn word_spans(text: &str) -> Vec<(usize, usize)> {
+        let mut spans = Vec::<(usize, usize)>::new();
+        let mut start = None;
+        for (index, ch) in text.char_indices() {
+            match (ch.is_alphanumeric(), start) {
+                (true, None) => start = Some(index),
+                (false, Some(word_start)) => {
+                    spans.push((word_start, index));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(word_start) = start {
+            spans.push((word_start, text.len()));
+        }
+        spans
+    }
+
+    /// Finds listed words anywhere in the text, the longest one wins where several start at once
+    fn substring_spans(&self, text: &str) -> Vec<(usize, usize)> {
+        let offsets: Vec<usize> = text.char_indices().map(|(index, _)| index).collect();
+        let mut longest_end = vec![None::<usize>; offsets.len()];
+        self.matcher.find_all(text, |start, end| {
+            longest_end[start] = longest_end[start].max(Some(end));
+        });
+
+        let mut spans = Vec::<(usize, usize)>::new();
+        let mut position = 0;
+        while position < offsets.len() {
+            match longest_end[position] {
+                Some(end) => {
+                    let end_offset = offsets.get(end).copied().unwrap_or(text.len());
+                    spans.push((offsets[position], end_offset));
+                    position = end;
+                }
+                None => position += 1,
+            }
+        }
+        spans
+    }
+}
+
+impl MessageMiddleware for WordlistFilter {
+    fn process(&self, ctx: &mut MessageContext) -> MiddlewareResult {
+        let text = &ctx.message;
+        let listed_spans: Vec<(usize, usize)> = if self.whole_words {
+            Self::word_spans(text)
+                .into_iter()
+                .filter(|(start, end)| self.matcher.is_listed(&text[*start..*end]))
+                .collect()
+        } else {
+            self.substring_spans(text)
+        };
+
+        if listed_spans.is_empty() {
+            return MiddlewareResult::Continue;
+        }
+
+        match self.action {
+            WordlistAction::Reject => {
+                MiddlewareResult::Reject("message contains a disallowed word".to_string())
+            }
+            WordlistAction::Drop => MiddlewareResult::Drop,
+            WordlistAction::Mask => {
+                let mut masked = String::with_capacity(text.len());
+                let mut last_end = 0;
+                for (start, end) in listed_spans {
+                    masked.push_str(&text[last_end..start]);
+                    masked.extend(text[start..end].chars().map(|_| '*'));
+                    last_end = end;
+                }
+                masked.push_str(&text[last_end..]);
+                MiddlewareResult::Replace(masked)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum ControlCharacterAction {
+    Strip,
+    Reject,
+}
+
+/// Keeps terminal escape sequences and other control characters out of chat messages
+pub struct ControlCharacterFilter {
+    action: ControlCharacterAction,
+    allow_newlines: bool,
+}
+
+impl ControlCharacterFilter {
+    pub fn new(action: ControlCharacterAction, allow_newlines: bool) -> Self {
+        Self {
+            action,
+            allow_newlines,
+        }
+    }
+
+    fn is_allowed(&self, ch: char) -> bool {
+        !ch.is_control() || (ch == '\n' && self.allow_newlines)
+    }
+
+    fn strip(&self, text: &str) -> String {
+        let mut stripped = String::with_capacity(text.len());
+        let mut chars = text.chars().peekable();
+        while let Some(ch) = chars.next() {
+            if ch != '\x1b' {
+                if self.is_allowed(ch) {
+                    stripped.push(ch);
+                }
+                continue;
+            }
+
+            match chars.next() {
+                // CSI sequences end with a byte in the '@'..='~' range
+                Some('[') => {
+                    for ch in chars.by_ref() {
+                        if ('@'..='~').contains(&ch) {
+                            break;
+                        }
+                    }
+                }
+                // OSC sequences end with BEL or ESC '\'
+                Some(']') => {
+                    while let Some(ch) = chars.next() {
+                        if ch == '\x07' {
+                            break;
+                        }
+                        if ch == '\x1b' && chars.peek() == Some(&'\\') {
+                            chars.next();
+                            break;
+                        }
+                    }
+                }
+                // Any other escape sequence consists of a single character
+                _ => {}
+            }
+        }
+        stripped
+    }
+}
+
+impl MessageMiddleware for ControlCharacterFilter {
+    fn process(&self, ctx: &mut MessageContext) -> MiddlewareResult {
+        if ctx.message.chars().all(|ch| self.is_allowed(ch)) {
+            return MiddlewareResult::Continue;
+        }
+
+        match self.action {
+            ControlCharacterAction::Reject => {
+                MiddlewareResult::Reject("message contains control characters".to_string())
+            }
+            ControlCharacterAction::Strip => MiddlewareResult::Replace(self.strip(&ctx.message)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(middleware: &impl MessageMiddleware, message: &str) -> MiddlewareResult {
+        let mut ctx = MessageContext {
+            sender: "alice01",
+            message: message.to_string(),
+        };
+        middleware.process(&mut ctx)
+    }
+
+    fn masked(filter: &WordlistFilter, message: &str) -> Option<String> {
+        match run(filter, message) {
+            MiddlewareResult::Replace(message) => Some(message),
+            MiddlewareResult::Continue => None,
+            _ => panic!("masking filter should only replace messages"),
+        }
+    }
+
+    #[test]
+    fn substrings_are_masked_inside_longer_words() {
+        let filter = WordlistFilter::new(["ass"], WordlistAction::Mask, false);
+
+        assert_eq!(
+            masked(&filter, "first class"),
+            Some("first cl***".to_string())
+        );
+        assert_eq!(masked(&filter, "nothing here"), None);
+    }
+
+    #[test]
+    fn whole_words_leave_longer_words_alone() {
+        let filter = WordlistFilter::new(["ass"], WordlistAction::Mask, true);
+
+        assert_eq!(masked(&filter, "first class"), None);
+        assert_eq!(masked(&filter, "you ass!"), Some("you ***!".to_string()));
+    }
+
+    #[test]
+    fn longest_word_wins_where_several_start_at_once() {
+        let filter = WordlistFilter::new(["bad", "badword", "word"], WordlistAction::Mask, false);
+
+        assert_eq!(
+            masked(&filter, "a badwords b"),
+            Some("a *******s b".to_string())
+        );
+        assert_eq!(masked(&filter, "xbadwor"), Some("x***wor".to_string()));
+    }
+
+    #[test]
+    fn overlapping_suffixes_are_found() {
+        // "he" ends inside "she" and "hers" starts inside it, both are only reached by failure links
+        let filter = WordlistFilter::new(["he", "she", "hers"], WordlistAction::Mask, false);
+
+        assert_eq!(masked(&filter, "ushers"), Some("u***rs".to_string()));
+        assert_eq!(masked(&filter, "hers"), Some("****".to_string()));
+    }
+
+    #[test]
+    fn words_and_messages_are_folded_alike() {
+        let filter = WordlistFilter::new(["ÄRGER", "Spam"], WordlistAction::Mask, false);
+
+        assert_eq!(
+            masked(&filter, "so ein Ärger"),
+            Some("so ein *****".to_string())
+        );
+        assert_eq!(masked(&filter, "SPAM spam"), Some("**** ****".to_string()));
+
+        let whole_words = WordlistFilter::new(["ärger"], WordlistAction::Mask, true);
+        assert_eq!(masked(&whole_words, "ÄRGER!"), Some("*****!".to_string()));
+    }
+
+    #[test]
+    fn reject_and_drop_stop_listed_messages() {
+        let reject = WordlistFilter::new(["spam"], WordlistAction::Reject, false);
+        let drop = WordlistFilter::new(["spam"], WordlistAction::Drop, true);
+
+        assert!(matches!(
+            run(&reject, "buy SPAM now"),
+            MiddlewareResult::Reject(_)
+        ));
+        assert!(matches!(run(&reject, "hello"), MiddlewareResult::Continue));
+        assert!(matches!(run(&drop, "spam"), MiddlewareResult::Drop));
+        assert!(matches!(run(&drop, "spammer"), MiddlewareResult::Continue));
+    }
+
+    #[test]
+    fn escape_sequences_are_stripped_whole() {
+        let filter = ControlCharacterFilter::new(ControlCharacterAction::Strip, true);
+
+        let stripped = |message| match run(&filter, message) {
+            MiddlewareResult::Replace(message) => message,
+            _ => panic!("control characters should be stripped"),
+        };
+        assert_eq!(stripped("\x1b[31mred\x1b[0m text"), "red text");
+        assert_eq!(stripped("\x1b]0;new title\x07hello"), "hello");
+        assert_eq!(stripped("\x1b]8;;http://x\x1b\\link"), "link");
+        assert_eq!(stripped("bell\x07 and\ttab"), "bell andtab");
+        assert!(matches!(
+            run(&filter, "two\nlines"),
+            MiddlewareResult::Continue
+        ));
+    }
+
+    #[test]
+    fn escape_sequences_are_rejected() {
+        let filter = ControlCharacterFilter::new(ControlCharacterAction::Reject, false);
+
+        assert!(matches!(
+            run(&filter, "\x1b[2Jcleared screen"),
+            MiddlewareResult::Reject(_)
+        ));
+        assert!(matches!(
+            run(&filter, "two\nlines"),
+            MiddlewareResult::Reject(_)
+        ));
+        assert!(matches!(
+            run(&filter, "plain text"),
+            MiddlewareResult::Continue
+        ));
+    }
+}
@@ -0,0 +1,135 @@
+/// Message passed through the middleware chain, rewritten in place as it goes
+pub struct MessageContext<'a> {
+    // Not used by the built-in filters, available to custom middleware
+    #[allow(dead_code)]
+    pub sender: &'a str,
+    pub message: String,
+}
+
+pub enum MiddlewareResult {
+    Continue,
+    /// Stops the chain, the reason is sent back to the sender
+    Reject(String),
+    Replace(String),
+    /// Stops the chain without telling the sender
+    Drop,
+}
+
+pub enum MessageRejection {
+    Rejected(String),
+    Dropped,
+}
+
+/// Inspects messages before they are broadcast.
+///
+/// Middleware is called while the chat server is locked, so it must return quickly.
+pub trait MessageMiddleware {
+    fn process(&self, ctx: &mut MessageContext) -> MiddlewareResult;
+}
+
+/// Middleware applied in order, each one sees the message rewritten by the previous ones
+#[derive(Default)]
+pub struct MiddlewareChain {
+    middleware: Vec<Box<dyn MessageMiddleware + Send>>,
+}
+
+impl MiddlewareChain {
+    pub fn new(middleware: Vec<Box<dyn MessageMiddleware + Send>>) -> Self {
+        Self { middleware }
+    }
+
+    /// Returns the final message, or why the middleware which stopped it did so
+    pub fn run(&self, mut ctx: MessageContext) -> Result<String, MessageRejection> {
+        for middleware in &self.middleware {
+            match middleware.process(&mut ctx) {
+                MiddlewareResult::Continue => {}
+                MiddlewareResult::Replace(message) => ctx.message = message,
+                MiddlewareResult::Reject(reason) => return Err(MessageRejection::Rejected(reason)),
+                MiddlewareResult::Drop => return Err(MessageRejection::Dropped),
+            }
+        }
+
+        Ok(ctx.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Records the messages it sees, then answers as told
+    struct Scripted {
+        seen: Arc<Mutex<Vec<String>>>,
+        result: fn(&str) -> MiddlewareResult,
+    }
+
+    impl MessageMiddleware for Scripted {
+        fn process(&self, ctx: &mut MessageContext) -> MiddlewareResult {
+            self.seen.lock().unwrap().push(ctx.message.clone());
+            (self.result)(&ctx.message)
+        }
+    }
+
+    fn scripted(
+        result: fn(&str) -> MiddlewareResult,
+    ) -> (Box<dyn MessageMiddleware + Send>, Arc<Mutex<Vec<String>>>) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let middleware = Scripted {
+            seen: seen.clone(),
+            result,
+        };
+        (Box::new(middleware), seen)
+    }
+
+    fn run(chain: &MiddlewareChain, message: &str) -> Result<String, MessageRejection> {
+        chain.run(MessageContext {
+            sender: "alice01",
+            message: message.to_string(),
+        })
+    }
+
+    #[test]
+    fn empty_chain_passes_messages_through() {
+        let chain = MiddlewareChain::default();
+
+        assert!(matches!(run(&chain, "hello"), Ok(message) if message == "hello"));
+    }
+
+    #[test]
+    fn rewritten_message_is_seen_by_the_next_middleware() {
+        let (upper, _) = scripted(|message| MiddlewareResult::Replace(message.to_uppercase()));
+        let (pass, seen) = scripted(|_| MiddlewareResult::Continue);
+        let chain = MiddlewareChain::new(vec![upper, pass]);
+
+        assert!(matches!(run(&chain, "hello"), Ok(message) if message == "HELLO"));
+        assert_eq!(*seen.lock().unwrap(), vec!["HELLO".to_string()]);
+    }
+
+    #[test]
+    fn rejection_stops_the_chain_with_its_reason() {
+        let (reject, _) = scripted(|_| MiddlewareResult::Reject("no thanks".to_string()));
+        let (after, seen) = scripted(|_| MiddlewareResult::Continue);
+        let chain = MiddlewareChain::new(vec![reject, after]);
+
+        assert!(matches!(
+            run(&chain, "hello"),
+            Err(MessageRejection::Rejected(reason)) if reason == "no thanks"
+        ));
+        assert!(seen.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn drop_stops_the_chain_silently() {
+        let (drop, _) = scripted(|_| MiddlewareResult::Drop);
+        let (after, seen) = scripted(|_| MiddlewareResult::Continue);
+        let chain = MiddlewareChain::new(vec![drop, after]);
+
+        assert!(matches!(
+            run(&chain, "hello"),
+            Err(MessageRejection::Dropped)
+        ));
+        assert!(seen.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, IsTerminal, Stderr, Write},
+    path::PathBuf,
+};
+
+/// Log file which is renamed with a numeric suffix once it grows past the size limit
+pub struct RollingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RollingFile {
+    pub fn open(path: PathBuf, max_size: u64, keep_files: usize) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            max_size,
+            keep_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.keep_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // The oldest file is overwritten by the one before it
+            for index in (1..self.keep_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RollingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Writes log records to the console and, without color codes, to a rolling file
+pub struct LogWriter {
+    console: Stderr,
+    file: Option<RollingFile>,
+}
+
+impl LogWriter {
+    pub fn new(file: RollingFile) -> Self {
+        Self {
+            console: io::stderr(),
+            file: Some(file),
+        }
+    }
+
+    pub fn is_console_terminal(&self) -> bool {
+        self.console.is_terminal()
+    }
+
+    fn strip_color_codes(buf: &[u8]) -> Vec<u8> {
+        let mut stripped = Vec::with_capacity(buf.len());
+        let mut bytes = buf.iter();
+        while let Some(&byte) = bytes.next() {
+            if byte != 0x1b {
+                stripped.push(byte);
+                continue;
+            }
+            // Color codes are CSI sequences, which end with a byte in the '@'..='~' range
+            if bytes.next() == Some(&b'[') {
+                for &byte in bytes.by_ref() {
+                    if (b'@'..=b'~').contains(&byte) {
+                        break;
+                    }
+                }
+            }
+        }
+        stripped
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(file) = &mut self.file {
+            if let Err(e) = file.write_all(&Self::strip_color_codes(buf)) {
+                // Logging must go on, so the console becomes the only destination
+                let _ = writeln!(
+                    self.console,
+                    "Could not write to the log file ({e}), logging to the console only."
+                );
+                self.file = None;
+            }
+        }
+        self.console.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(file) = &mut self.file {
+            let _ = file.flush();
+        }
+        self.console.flush()
+    }
+}
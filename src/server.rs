@@ -1,23 +1,104 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    fmt, fs, mem,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use log::info;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use serde::{Deserialize, Serialize};
 use serde_json::from_str;
+use tokio::time::Instant;
+use tracing::{error, info, warn, Span};
+use uuid::Uuid;
 
 use crate::{
-    server_database::{ServerDatabase, UserCredentialsRaw},
-    user_service::{AuthenticationError, RegistrationError, UserService},
+    middleware::{MessageContext, MessageRejection, MiddlewareChain},
+    server_database::{
+        AbuseReport, AuditAction, AuditEvent, AuditOutcome, PersistedMessage, ServerDatabase,
+        UserCredentialsRaw,
+    },
+    user_service::{
+        AuthenticationError, RegistrationError, RenameError, UserService, UserServiceOptions,
+        ValidationRules,
+    },
 };
 
 pub enum ChatServerResponseCommand {
-    SendToAll(Vec<u8>),
-    SendToAllExcept(String, Vec<u8>),
-    SendToSome(Vec<String>, Vec<u8>),
-    DisconnectUser(String),
+    SendToAll(Arc<[u8]>),
+    #[allow(dead_code)]
+    SendToAllExcept(String, Arc<[u8]>),
+    SendToSome(Vec<String>, Arc<[u8]>),
+    /// Sender learns from `delivery_report_message` how many of the other recipients got the message
+    SendToSomeWithReport {
+        recipients: Vec<String>,
+        message: Arc<[u8]>,
+        sender_id: String,
+        server_msg_id: u64,
+    },
+    /// Optional payload is written to the connection before it is closed
+    DisconnectUser(String, Option<Arc<[u8]>>),
+    EnableCompression(String),
+}
+
+pub enum AdminCommand {
+    ListUsers,
+    DeleteUser(String),
+    KickUser(String),
+    Broadcast(String),
+    ScheduleAnnouncement {
+        message: String,
+        at_epoch_secs: u64,
+    },
+    ListScheduledAnnouncements,
+    CancelScheduledAnnouncement(u64),
+    CreateUser {
+        user_credentials_raw: UserCredentialsRaw,
+        allow_reserved: bool,
+    },
+}
+
+pub enum AdminCommandResult {
+    Users(Vec<RegisteredUser>),
+    Scheduled(u64),
+    ScheduledAnnouncements(Vec<ScheduledAnnouncement>),
+    Done,
+    UserNotFound,
+    AnnouncementNotFound,
+    RegistrationFailed(RegistrationError),
+    InternalError,
+}
+
+#[derive(Serialize)]
+pub struct RegisteredUser {
+    pub name: String,
+    pub online: bool,
+    /// Peer addresses of the online sessions, only ever shown to administrators
+    pub addresses: Vec<String>,
+}
+
+/// Announcement broadcast once its time has come, kept only while the server runs
+#[derive(Clone, Serialize)]
+pub struct ScheduledAnnouncement {
+    pub id: u64,
+    pub message: String,
+    pub at_epoch_secs: u64,
+}
+
+struct PendingAnnouncement {
+    // Monotonic, so changes of the wall clock don't move it
+    deadline: Instant,
+    announcement: ScheduledAnnouncement,
 }
 
 #[derive(Serialize, Deserialize)]
 enum ChatRequest {
+    Handshake {
+        compression: bool,
+    },
     Authentication {
         user_credentials_raw: UserCredentialsRaw,
     },
@@ -26,11 +107,113 @@ enum ChatRequest {
     },
     Message {
         message: String,
+        // Clients that predate content types send plain text
+        #[serde(default)]
+        content_type: ContentType,
+        /// Lets the server recognize a message resent after a reconnect
+        #[serde(default)]
+        client_msg_id: Option<String>,
+        /// Only members of the room receive the message, everyone does without it
+        #[serde(default)]
+        room: Option<String>,
+    },
+    EditMessage {
+        server_msg_id: u64,
+        new_text: String,
+    },
+    DeleteMessage {
+        server_msg_id: u64,
+    },
+    RenameAccount {
+        new_name: String,
+        password: String,
+    },
+    Attachment {
+        filename: String,
+        mime: String,
+        size: usize,
+        data_base64: String,
+    },
+    FetchAttachment {
+        id: String,
+    },
+    DirectMessage {
+        to: String,
+        message: String,
+    },
+    MarkRead {
+        server_msg_id: u64,
+    },
+    Whois {
+        user_name: String,
+    },
+    Profile {
+        user_name: String,
+    },
+    Report {
+        target_user: String,
+        reason: String,
+        #[serde(default)]
+        message_id: Option<u64>,
     },
+    JoinRoom {
+        room: String,
+    },
+    LeaveRoom {
+        room: String,
+    },
+    RoomMembers {
+        room: String,
+    },
+    /// Pages backwards through the persisted messages of the room, or of no room without it
+    History {
+        #[serde(default)]
+        room: Option<String>,
+        before_id: Option<u64>,
+        limit: u32,
+    },
+    ListRooms,
+    Block {
+        user_name: String,
+    },
+    Unblock {
+        user_name: String,
+    },
+    ListBlocked,
+    ClearBlocked,
+    SetVisible {
+        visible: bool,
+    },
+    ListSessions,
+    RevokeSession {
+        connection_id: String,
+    },
+    OnlineCount,
+    ServerStats,
+    /// Admins only, live statistics are pushed while subscribed
+    SubscribeStats {
+        enabled: bool,
+    },
+    Disconnect,
+}
+
+#[derive(Deserialize)]
+struct ChatRequestEnvelope {
+    request_id: Option<u64>,
+    request: ChatRequest,
+}
+
+#[derive(Serialize)]
+struct ChatResponseEnvelope<'a> {
+    request_id: u64,
+    response: &'a ChatResponse,
 }
 
 #[derive(Serialize, Deserialize)]
 enum ChatResponse {
+    HandshakeResult {
+        compression: bool,
+    },
     AuthenticationResult {
         result: bool,
         error: Option<AuthenticationError>,
@@ -40,252 +223,5551 @@ enum ChatResponse {
         error: Option<RegistrationError>,
     },
     Message {
+        server_msg_id: u64,
         user_name: String,
         message: String,
+        content_type: ContentType,
+        room: Option<String>,
+    },
+    MessageAccepted {
+        server_msg_id: u64,
+        client_msg_id: String,
+    },
+    /// Recipients other than the sender whose connection has or hasn't been written the message
+    DeliveryReport {
+        message_id: u64,
+        delivered: usize,
+        failed: usize,
+    },
+    MessageEdited {
+        server_msg_id: u64,
+        new_text: String,
+    },
+    Mention {
+        from: String,
+        message: String,
+        timestamp: u64,
+    },
+    MessageDeleted {
+        server_msg_id: u64,
+    },
+    Attachment {
+        user_name: String,
+        filename: String,
+        mime: String,
+        data: String,
+    },
+    AttachmentStored {
+        id: String,
+        from: String,
+        filename: String,
+        mime: String,
+        size: usize,
+    },
+    AttachmentData {
+        id: String,
+        filename: String,
+        mime: String,
+        data: String,
+    },
+    DirectMessage {
+        server_msg_id: u64,
+        from: String,
+        message: String,
+    },
+    DirectMessageSent {
+        server_msg_id: u64,
+        to: String,
+    },
+    ReadReceipt {
+        server_msg_id: u64,
+        reader: String,
+        timestamp: u64,
+    },
+    Whois {
+        user_name: String,
+        connected_since: u64,
+        status: String,
+        rooms: Vec<String>,
+        ip: Option<String>,
+    },
+    Profile {
+        user_name: String,
+        registered_at: Option<u64>,
+        is_online: bool,
+    },
+    ReportAccepted,
+    /// Sent to the online administrators
+    ModAlert {
+        report: ReportAlert,
+    },
+    RoomJoined {
+        room: String,
+    },
+    RoomLeft {
+        room: String,
+    },
+    RoomMembers {
+        room: String,
+        members: Vec<String>,
+    },
+    RoomList {
+        rooms: Vec<RoomInfo>,
     },
     Connection {
         user_name: String,
         is_connected: bool,
+        online_count: usize,
+    },
+    /// Presence changes gathered during a batch window, in place of separate `Connection` notices
+    ConnectionBatch {
+        joined: Vec<String>,
+        left: Vec<String>,
+        online_count: usize,
+    },
+    OnlineCount {
+        online_count: usize,
+        rooms: Vec<RoomInfo>,
+    },
+    BlockedList {
+        users: Vec<String>,
+    },
+    VisibilityChanged {
+        visible: bool,
+    },
+    /// Live sessions of the requesting user, including the one asking
+    Sessions {
+        sessions: Vec<SessionInfo>,
+    },
+    SessionRevoked {
+        connection_id: String,
+    },
+    Roster {
+        users: Vec<String>,
+        total_count: usize,
+    },
+    Announcement {
+        message: String,
     },
+    /// Latest persisted messages of the room, oldest first, sent after joining it
+    RoomHistory {
+        room: String,
+        messages: Vec<HistoryMessage>,
+    },
+    /// Page of persisted messages, oldest first, `next_before_id` continues with the older ones
+    History {
+        room: Option<String>,
+        messages: Vec<HistoryMessage>,
+        has_more: bool,
+        next_before_id: Option<u64>,
+    },
+    /// Sent instead of the roster to a user who has reconnected within the rejoin grace window
+    StateSync {
+        users: Vec<String>,
+        /// Messages broadcast while the user was away, as far as they are still retained
+        messages: Vec<SyncedMessage>,
+    },
+    /// Pushed periodically when the stats broadcast is enabled
+    Stats {
+        online_users: usize,
+        rooms: Vec<RoomInfo>,
+        messages_since_start: u64,
+    },
+    StatsSubscription {
+        enabled: bool,
+    },
+    /// Pushed to subscribed admins whenever the numbers change
+    LiveStats {
+        connections: usize,
+        authenticated_users: usize,
+        messages_per_sec: f64,
+    },
+    ServerStats {
+        uptime_secs: u64,
+        connections: usize,
+        authenticated_users: usize,
+        messages_processed: u64,
+        version: String,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+        context: Option<String>,
+    },
+    Goodbye,
+    Disconnected {
+        reason: String,
+    },
+    SessionExpired,
+    /// Connection is dropped after this long unless the client sends something
+    IdleWarning {
+        seconds_remaining: u64,
+    },
+    Motd {
+        text: String,
+    },
+    RenameAccountResult {
+        result: bool,
+        error: Option<RenameError>,
+    },
+    UserRenamed {
+        old_name: String,
+        new_name: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum ContentType {
+    #[default]
+    Plain,
+    Markdown,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HistoryMessage {
+    /// Matches the id of edits and deletions, absent for messages persisted before it was recorded
+    server_msg_id: Option<u64>,
+    timestamp: u64,
+    user_name: String,
+    message: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReportAlert {
+    timestamp: u64,
+    reporter: String,
+    target_user: String,
+    reason: String,
+    message_id: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SyncedMessage {
+    server_msg_id: u64,
+    user_name: String,
+    message: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SessionInfo {
+    connection_id: String,
+    address: String,
+    connected_since: u64,
+    current: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RoomInfo {
+    name: String,
+    member_count: usize,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+enum ErrorCode {
+    ProtocolError,
+    NotAuthenticated,
+    AlreadyAuthenticated,
+    PermissionDenied,
+    MessageNotFound,
+    NotMessageAuthor,
+    MessageRejected,
+    AttachmentTooLarge,
+    AttachmentTypeNotAllowed,
+    MalformedAttachment,
+    AttachmentNotFound,
+    UserNotOnline,
+    NotMessageRecipient,
+    RoomNotFound,
+    NotRoomMember,
+    RoomLimitReached,
+    ContentTypeNotAllowed,
+    TooManyConnections,
+    UserNotFound,
+    InternalError,
+    TooManyReports,
+    DuplicateMessage,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCode::ProtocolError => write!(f, "request violates the protocol"),
+            ErrorCode::NotAuthenticated => write!(f, "request requires authentication"),
+            ErrorCode::AlreadyAuthenticated => write!(f, "already authenticated"),
+            ErrorCode::PermissionDenied => write!(f, "permission denied"),
+            ErrorCode::MessageNotFound => write!(f, "message not found"),
+            ErrorCode::NotMessageAuthor => write!(f, "message belongs to another user"),
+            ErrorCode::MessageRejected => write!(f, "message was rejected"),
+            ErrorCode::AttachmentTooLarge => write!(f, "attachment is too large"),
+            ErrorCode::AttachmentTypeNotAllowed => write!(f, "attachment type is not allowed"),
+            ErrorCode::MalformedAttachment => write!(f, "attachment is malformed"),
+            ErrorCode::AttachmentNotFound => write!(f, "attachment does not exist"),
+            ErrorCode::UserNotOnline => write!(f, "user is not online"),
+            ErrorCode::NotMessageRecipient => write!(f, "message was sent to another user"),
+            ErrorCode::RoomNotFound => write!(f, "room does not exist"),
+            ErrorCode::NotRoomMember => write!(f, "not a member of the room"),
+            ErrorCode::RoomLimitReached => write!(f, "too many rooms joined"),
+            ErrorCode::ContentTypeNotAllowed => write!(f, "content type is not allowed"),
+            ErrorCode::TooManyConnections => write!(f, "too many connections from the address"),
+            ErrorCode::UserNotFound => write!(f, "user does not exist"),
+            ErrorCode::InternalError => write!(f, "request could not be processed"),
+            ErrorCode::TooManyReports => write!(f, "too many reports"),
+            ErrorCode::DuplicateMessage => write!(f, "same message was sent moments ago"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum HandshakeState {
+    Pending,
+    Completed,
+    /// Client sent another request first, as clients predating the handshake do
+    Legacy,
 }
 
 struct UserData {
     authenticated: bool,
     name: Option<String>,
+    last_active: Instant,
+    authenticated_at: Option<Instant>,
+    // Unix timestamp, as it is only reported to clients
+    connected_since: u64,
+    rooms: BTreeSet<String>,
+    // Names whose messages are not delivered to this session, compared regardless of case
+    blocked: BTreeSet<String>,
+    handshake: HandshakeState,
+    address: SocketAddr,
+    // Invisible sessions are left out of rosters, counts and presence notices
+    visible: bool,
+    last_rename: Option<Instant>,
+    // Renames of this session within the rename window, oldest first
+    renames: Vec<Instant>,
+    stats_subscribed: bool,
+}
+
+struct StoredMessage {
+    id: u64,
+    author: String,
+    text: String,
+    room: Option<String>,
+}
+
+struct SeenClientMessage {
+    user_name: String,
+    client_msg_id: String,
+    server_msg_id: u64,
+    seen_at: Instant,
+}
+
+/// Presence changes held back while a batch window is open
+#[derive(Default)]
+struct PresenceBatch {
+    opened_at: Option<Instant>,
+    joined: Vec<String>,
+    left: Vec<String>,
+}
+
+/// Authenticated user whose leave is held back in case they reconnect
+struct Departure {
+    user_name: String,
+    since: Instant,
+    // Messages from this id on were broadcast while the user was away
+    next_message_id: u64,
+}
+
+struct StoredDirectMessage {
+    id: u64,
+    sender: String,
+    recipient: String,
+    read: bool,
 }
 
 struct ChatState {
     users: HashMap<String, UserData>,
+    messages_processed: u64,
+    registrations: HashMap<IpAddr, Vec<Instant>>,
+    // Keyed by the lowercase name of the reporter
+    reports: HashMap<String, Vec<Instant>>,
+    // Normalized text of the last message of each user, keyed by the lowercase name
+    last_messages: HashMap<String, (String, Instant)>,
+    recent_messages: VecDeque<StoredMessage>,
+    // Only what is needed to route read receipts back to the sender
+    recent_direct_messages: VecDeque<StoredDirectMessage>,
+    // Oldest first, so expired entries are always at the front
+    seen_client_messages: VecDeque<SeenClientMessage>,
+    // Keyed by the lowercase name
+    departures: HashMap<String, Departure>,
+    presence_batch: PresenceBatch,
+    // Ordered by id, which is the order they were scheduled in
+    scheduled_announcements: Vec<PendingAnnouncement>,
+    pending_attachments: Vec<PendingAttachment>,
+    next_announcement_id: u64,
+    next_message_id: u64,
+    // Id of the request being processed, echoed back in direct responses
+    request_id: Option<u64>,
+    // Session whose request is being processed, told when a response to others can't be serialized
+    requester: Option<String>,
+    motd: Option<String>,
+    // Messages processed as of the previous live stats sample, to derive the rate from
+    live_stats_sample: (Instant, u64),
+    last_live_stats: Option<(usize, usize, f64)>,
+}
+
+pub struct ChatServerOptions {
+    pub compression: bool,
+    pub max_roster_entries: usize,
+    pub message_retention: usize,
+    pub persist_messages: bool,
+    pub delivery_reports: bool,
+    pub admins: Vec<String>,
+    pub public_server_stats: bool,
+    pub registration_limit: Option<RegistrationLimit>,
+    pub report_limit: Option<ReportLimit>,
+    pub rename_limit: Option<RenameLimit>,
+    pub auto_login_on_register: bool,
+    pub motd: Option<Motd>,
+    pub session_limit: Option<SessionLimit>,
+    /// How long the leave of a disconnected user is delayed, a reconnect within it is not announced
+    pub rejoin_grace: Option<Duration>,
+    /// Presence changes following another one within this window are sent together as one batch
+    pub presence_batch_window: Option<Duration>,
+    pub message_dedup: Option<MessageDedup>,
+    /// Message with the same text as the previous one of the user within it is dropped
+    pub duplicate_cooldown: Option<Duration>,
+    pub message_middleware: MiddlewareChain,
+    pub attachments: AttachmentOptions,
+    pub whois: WhoisOptions,
+    pub rooms: Vec<String>,
+    /// Members of these rooms can only be listed by other members
+    pub private_rooms: Vec<String>,
+    pub list_empty_rooms: bool,
+    pub max_rooms_per_user: usize,
+    pub room_history: RoomHistoryLimits,
+    pub history_page: HistoryPageLimits,
+    pub allowed_content_types: Vec<ContentType>,
+    pub require_handshake: bool,
+}
+
+/// Number of persisted messages kept and replayed per room
+pub struct RoomHistoryLimits {
+    pub default: usize,
+    pub limits: HashMap<String, usize>,
+}
+
+impl RoomHistoryLimits {
+    fn limit(&self, room: &str) -> usize {
+        self.limits.get(room).copied().unwrap_or(self.default)
+    }
+}
+
+pub struct HistoryPageLimits {
+    /// Caps the limit requested by clients
+    pub max_messages: usize,
+    /// Pages end early rather than grow past it, unless a single message alone doesn't fit
+    pub max_frame_bytes: usize,
+}
+
+pub struct WhoisOptions {
+    pub admins_only: bool,
+    pub show_ip: bool,
+}
+
+pub struct AttachmentOptions {
+    pub max_size: usize,
+    pub allowed_mime_types: Vec<String>,
+    /// When set, attachments are stored here and only their metadata is broadcast
+    pub storage_dir: Option<PathBuf>,
+}
+
+/// Metadata kept next to a stored attachment's contents
+#[derive(Serialize, Deserialize)]
+struct StoredAttachment {
+    from: String,
+    filename: String,
+    mime: String,
+    size: usize,
+}
+
+/// Attachment accepted by the chat server, written to disk by the caller without holding the server
+pub struct PendingAttachment {
+    user_id: String,
+    request_id: Option<u64>,
+    storage_dir: PathBuf,
+    id: String,
+    metadata: StoredAttachment,
+    data: Vec<u8>,
+}
+
+impl PendingAttachment {
+    pub fn store(&self) -> std::io::Result<()> {
+        fs::create_dir_all(&self.storage_dir)?;
+        fs::write(self.storage_dir.join(&self.id), &self.data)?;
+        // Metadata goes last, so an attachment is only visible once its contents are written
+        fs::write(
+            self.storage_dir.join(format!("{}.json", self.id)),
+            serde_json::to_vec(&self.metadata)?,
+        )
+    }
+}
+
+/// Removes the attachments stored before `max_age` ago, returns how many were removed
+pub fn remove_expired_attachments(storage_dir: &Path, max_age: Duration) -> std::io::Result<usize> {
+    let Some(cutoff) = SystemTime::now().checked_sub(max_age) else {
+        return Ok(0);
+    };
+    let is_expired = |path: &Path| {
+        fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| modified < cutoff)
+    };
+
+    let entries = match fs::read_dir(storage_dir) {
+        Ok(entries) => entries,
+        // Nothing has been stored yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+
+    // Metadata goes first, so an attachment stops being visible before its contents are removed
+    let mut removed = 0;
+    for path in paths
+        .iter()
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+    {
+        if is_expired(path) {
+            fs::remove_file(path)?;
+            removed += 1;
+        }
+    }
+    // Contents without metadata are either expired now or left over from a failed store
+    for path in paths.iter().filter(|path| path.extension().is_none()) {
+        if !path.with_extension("json").exists() && is_expired(path) {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(removed)
+}
+
+pub enum Motd {
+    Text(String),
+    File(PathBuf),
+}
+
+pub struct RegistrationLimit {
+    pub max_registrations: usize,
+    pub window: Duration,
+}
+
+/// Limits how often a session can rename its account, sparing others a flood of rename notices
+pub struct RenameLimit {
+    pub cooldown: Duration,
+    pub max_renames: Option<usize>,
+    pub window: Duration,
+}
+
+pub struct ReportLimit {
+    pub max_reports: usize,
+    pub window: Duration,
+}
+
+pub struct MessageDedup {
+    pub window: Duration,
+    pub max_entries: usize,
+}
+
+pub struct SessionLimit {
+    pub max_duration: Duration,
+    /// Whether to close the connection of an expired session instead of waiting for re-authentication
+    pub disconnect: bool,
+}
+
+/// Observes chat activity without being able to change it.
+///
+/// Hooks are called while the chat server is locked, so they must return quickly. Anything slow,
+/// like writing to another store, should be handed over to a dedicated task through a channel.
+pub trait ChatEventHandler {
+    fn on_user_authenticated(&mut self, _name: &str) {}
+    fn on_message(&mut self, _sender: &str, _text: &str) {}
+    fn on_user_left(&mut self, _name: &str) {}
 }
 
 pub struct ChatServer<T: ServerDatabase> {
     state: ChatState,
     user_service: UserService<T>,
+    options: ChatServerOptions,
+    event_handler: Option<Box<dyn ChatEventHandler + Send>>,
+    started_at: Instant,
 }
 
 impl<T: ServerDatabase> ChatServer<T> {
-    pub fn new(user_service: UserService<T>) -> Self {
-        Self {
+    pub fn new(
+        user_service: UserService<T>,
+        options: ChatServerOptions,
+        event_handler: Option<Box<dyn ChatEventHandler + Send>>,
+    ) -> Self {
+        let mut chat_server = Self {
             state: ChatState {
                 users: HashMap::new(),
+                messages_processed: 0,
+                registrations: HashMap::new(),
+                reports: HashMap::new(),
+                last_messages: HashMap::new(),
+                recent_messages: VecDeque::new(),
+                recent_direct_messages: VecDeque::new(),
+                seen_client_messages: VecDeque::new(),
+                departures: HashMap::new(),
+                presence_batch: PresenceBatch::default(),
+                scheduled_announcements: Vec::new(),
+                pending_attachments: Vec::new(),
+                next_announcement_id: 0,
+                next_message_id: 0,
+                request_id: None,
+                requester: None,
+                motd: None,
+                live_stats_sample: (Instant::now(), 0),
+                last_live_stats: None,
             },
             user_service,
+            options,
+            event_handler,
+            started_at: Instant::now(),
+        };
+        // Ids of persisted messages stay unique across restarts
+        if let Ok(Some(last_id)) = chat_server.user_service.get_last_server_msg_id() {
+            chat_server.state.next_message_id = last_id + 1;
         }
+        chat_server.reload_motd();
+        chat_server
+    }
+    pub fn online_users_count(&self) -> usize {
+        self.state
+            .users
+            .values()
+            .filter(|user_data| user_data.authenticated)
+            .count()
     }
-    pub fn on_user_connect(&mut self, user_id: String) {
-        info!("User {user_id} has connected.");
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+    pub fn on_user_connect(
+        &mut self,
+        user_id: String,
+        address: SocketAddr,
+    ) -> Vec<ChatServerResponseCommand> {
+        info!("User {user_id} has connected from {address}.");
+        let mut commands = Vec::<ChatServerResponseCommand>::new();
+        // Connection layer never reuses a live id, so an existing entry can only be a leftover
+        if self.state.users.contains_key(&user_id) {
+            warn!("User {user_id} is still known from an earlier connection, forgetting it.");
+            commands.extend(self.on_user_disconnect(user_id.clone()));
+        }
         self.state.users.insert(
-            user_id,
+            user_id.clone(),
             UserData {
                 authenticated: false,
                 name: None,
+                last_active: Instant::now(),
+                authenticated_at: None,
+                connected_since: unix_timestamp(),
+                rooms: BTreeSet::new(),
+                blocked: BTreeSet::new(),
+                handshake: HandshakeState::Pending,
+                address,
+                visible: true,
+                last_rename: None,
+                renames: Vec::new(),
+                stats_subscribed: false,
             },
         );
+
+        if let Some(text) = self.state.motd.clone() {
+            commands.push(self.make_response_to_user(&user_id, &ChatResponse::Motd { text }));
+        }
+        commands
+    }
+    /// Forgets the users whose connections have already been dropped by the connection layer
+    pub fn forget_users(&mut self, user_ids: Vec<String>) -> Vec<ChatServerResponseCommand> {
+        let mut commands = Vec::<ChatServerResponseCommand>::new();
+        for user_id in user_ids {
+            if self.state.users.contains_key(&user_id) {
+                info!("Connection of user {user_id} is gone, forgetting the user.");
+                commands.extend(self.on_user_disconnect(user_id));
+            }
+        }
+        commands
+    }
+    pub fn reload_options(
+        &mut self,
+        options: ChatServerOptions,
+        user_service_options: UserServiceOptions,
+        // Rules in use are kept without new ones
+        validation_rules: Option<ValidationRules>,
+    ) {
+        // Compression is negotiated with already connected clients, so it can't change live
+        let compression = self.options.compression;
+        self.options = ChatServerOptions {
+            compression,
+            ..options
+        };
+        self.user_service.set_options(user_service_options);
+        if let Some(validation_rules) = validation_rules {
+            self.user_service.set_validation_rules(validation_rules);
+        }
+        self.reload_motd();
+    }
+    pub fn reload_motd(&mut self) {
+        self.state.motd = match &self.options.motd {
+            Some(Motd::Text(text)) => Some(text.clone()),
+            Some(Motd::File(path)) => match fs::read_to_string(path) {
+                Ok(text) => {
+                    info!("Loaded MOTD from '{}'.", path.display());
+                    Some(text)
+                }
+                Err(e) => {
+                    error!("Could not read MOTD from '{}' ({e}).", path.display());
+                    self.state.motd.take()
+                }
+            },
+            None => None,
+        };
     }
     pub fn on_user_disconnect(&mut self, user_id: String) -> Option<ChatServerResponseCommand> {
-        let user = self.state.users.get_mut(&user_id)?;
+        let user = self.state.users.remove(&user_id)?;
 
         if user.authenticated {
-            let user_name = user.name.as_ref().unwrap();
+            let user_name = user.name.unwrap();
 
-            info!("User {user_id} with name {user_name} has disconnected.");
+            info!(
+                "User {user_id} with name {user_name} has disconnected from {}.",
+                user.address
+            );
+            // Nobody has seen an invisible session, so nobody is told it has gone
+            if !user.visible {
+                if let Some(event_handler) = &mut self.event_handler {
+                    event_handler.on_user_left(&user_name);
+                }
+                return None;
+            }
+            // Others still see the user through the remaining sessions
+            if self.is_name_listed(&user_name) {
+                return None;
+            }
+            // Leave is announced only once the last session is gone for longer than the grace window
+            if self.options.rejoin_grace.is_some() {
+                self.state.departures.insert(
+                    user_name.to_ascii_lowercase(),
+                    Departure {
+                        user_name,
+                        since: Instant::now(),
+                        next_message_id: self.state.next_message_id,
+                    },
+                );
+                return None;
+            }
+            if let Some(event_handler) = &mut self.event_handler {
+                event_handler.on_user_left(&user_name);
+            }
 
-            Some(Self::make_response_to_all(&ChatResponse::Connection {
-                user_name: user_name.clone(),
+            if !self.coalesce_presence(&user_name, false) {
+                return None;
+            }
+            Some(self.make_response_to_all(&ChatResponse::Connection {
+                user_name,
                 is_connected: false,
+                online_count: self.online_names_count(),
             }))
         } else {
-            info!("User {user_id} has disconnected.");
-            self.state.users.remove(&user_id);
+            info!("User {user_id} has disconnected from {}.", user.address);
             None
         }
     }
-    pub fn on_user_message(
-        &mut self,
-        user_id: String,
-        message: &[u8],
-    ) -> Option<Vec<ChatServerResponseCommand>> {
-        let request = Self::message_to_request(message)?;
-        let is_authenticated = self.state.users.get(&user_id)?.authenticated;
-
-        if is_authenticated {
-            self.process_request_authenticated(&user_id, request)
-        } else {
-            self.process_request_unauthenticated(&user_id, request)
-        }
+    pub fn on_shutdown(&self) -> ChatServerResponseCommand {
+        info!("Saying goodbye to all users.");
+        self.make_response_to_all(&ChatResponse::Goodbye)
     }
+    pub fn expire_departures(&mut self) -> Vec<ChatServerResponseCommand> {
+        // Grace window might have been disabled by a reload, then everyone pending leaves now
+        let grace = self.options.rejoin_grace.unwrap_or_default();
 
-    fn process_request_authenticated(
-        &mut self,
-        user_id: &str,
-        request: ChatRequest,
-    ) -> Option<Vec<ChatServerResponseCommand>> {
-        if let ChatRequest::Message { message } = request {
-            let user_name = self.state.users.get(user_id)?.name.as_ref()?;
-
-            info!("User {user_id} with name {user_name} has sent message '{message}'.",);
+        let expired: Vec<String> = self
+            .state
+            .departures
+            .iter()
+            .filter(|(_, departure)| departure.since.elapsed() >= grace)
+            .map(|(key, _)| key.clone())
+            .collect();
 
-            let response = ChatResponse::Message {
-                user_name: user_name.to_string(),
-                message,
-            };
+        let mut commands = Vec::<ChatServerResponseCommand>::new();
+        for key in expired {
+            let Departure { user_name, .. } = self.state.departures.remove(&key).unwrap();
 
-            return Some(vec![self.make_response_to_all_authenticated(
-                user_id,
-                Some(user_id),
-                &response,
-            )]);
+            info!("User {user_name} has not reconnected in time, announcing the leave.");
+            if let Some(event_handler) = &mut self.event_handler {
+                event_handler.on_user_left(&user_name);
+            }
+            if !self.coalesce_presence(&user_name, false) {
+                continue;
+            }
+            commands.push(self.make_response_to_all(&ChatResponse::Connection {
+                user_name,
+                is_connected: false,
+                online_count: self.online_names_count(),
+            }));
         }
-        None
+        commands
     }
-    fn process_request_unauthenticated(
-        &mut self,
-        user_id: &str,
-        request: ChatRequest,
-    ) -> Option<Vec<ChatServerResponseCommand>> {
-        match request {
-            ChatRequest::Authentication {
-                user_credentials_raw,
-            } => self.authenticate(user_id, &user_credentials_raw),
-            ChatRequest::Registration {
-                user_credentials_raw,
-            } => self.register(user_id, &user_credentials_raw),
-            ChatRequest::Message { message: _ } => None,
+    pub fn flush_presence_batch(&mut self) -> Vec<ChatServerResponseCommand> {
+        let Some(opened_at) = self.state.presence_batch.opened_at else {
+            return vec![];
+        };
+        if opened_at.elapsed() < self.options.presence_batch_window.unwrap_or_default() {
+            return vec![];
         }
-    }
 
-    fn register(
-        &mut self,
-        user_id: &str,
-        user_credentials_raw: &UserCredentialsRaw,
-    ) -> Option<Vec<ChatServerResponseCommand>> {
-        match self.user_service.add_user(user_credentials_raw) {
-            Ok(_) => {
-                info!(
-                    "User {user_id} has registered with name '{}'.",
-                    user_credentials_raw.name
-                );
+        let batch = &mut self.state.presence_batch;
+        let joined = mem::take(&mut batch.joined);
+        let left = mem::take(&mut batch.left);
+        // A burst may still be going on, so a window that collected something is reopened
+        batch.opened_at = if joined.is_empty() && left.is_empty() {
+            None
+        } else {
+            Some(Instant::now())
+        };
 
-                Some(vec![Self::make_response_to_user(
-                    user_id,
-                    &ChatResponse::RegistrationResult {
-                        result: true,
-                        error: None,
-                    },
-                )])
+        let online_count = self.online_names_count();
+        let response = match (joined.len(), left.len()) {
+            (0, 0) => return vec![],
+            (1, 0) | (0, 1) => ChatResponse::Connection {
+                is_connected: left.is_empty(),
+                user_name: joined.into_iter().chain(left).next().unwrap(),
+                online_count,
+            },
+            _ => ChatResponse::ConnectionBatch {
+                joined,
+                left,
+                online_count,
+            },
+        };
+        vec![self.make_response_to_authenticated(&response)]
+    }
+    pub fn broadcast_stats(&self, admins_only: bool) -> ChatServerResponseCommand {
+        let response = ChatResponse::Stats {
+            online_users: self.online_names_count(),
+            rooms: self.room_infos(),
+            messages_since_start: self.state.messages_processed,
+        };
+
+        self.make_response_to_matching(None, &response, |user_data| {
+            user_data.authenticated
+                && (!admins_only
+                    || user_data
+                        .name
+                        .as_deref()
+                        .is_some_and(|user_name| self.is_admin(user_name)))
+        })
+    }
+    /// Samples the statistics, returns nothing unless they changed and someone is subscribed
+    pub fn broadcast_live_stats(&mut self) -> Option<ChatServerResponseCommand> {
+        let (sampled_at, sampled_messages) = self.state.live_stats_sample;
+        let elapsed = sampled_at.elapsed().as_secs_f64();
+        let messages_per_sec = if elapsed > 0.0 {
+            (self.state.messages_processed - sampled_messages) as f64 / elapsed
+        } else {
+            0.0
+        };
+        self.state.live_stats_sample = (Instant::now(), self.state.messages_processed);
+
+        let stats = (
+            self.state.users.len(),
+            self.online_users_count(),
+            // Rounded, so jitter of the sampling interval alone is not reported as a change
+            (messages_per_sec * 100.0).round() / 100.0,
+        );
+        if self.state.last_live_stats == Some(stats) {
+            return None;
+        }
+        self.state.last_live_stats = Some(stats);
+
+        let recipients =
+            self.matching_user_ids(None, |user_data| self.is_stats_subscriber(user_data));
+        if recipients.is_empty() {
+            return None;
+        }
+
+        let (connections, authenticated_users, messages_per_sec) = stats;
+        Some(self.make_response_to_some(
+            recipients,
+            &ChatResponse::LiveStats {
+                connections,
+                authenticated_users,
+                messages_per_sec,
+            },
+        ))
+    }
+    pub fn next_announcement_deadline(&self) -> Option<Instant> {
+        self.state
+            .scheduled_announcements
+            .iter()
+            .map(|pending| pending.deadline)
+            .min()
+    }
+    pub fn deliver_scheduled_announcements(&mut self) -> Vec<ChatServerResponseCommand> {
+        let now = Instant::now();
+        let (due, pending) = mem::take(&mut self.state.scheduled_announcements)
+            .into_iter()
+            .partition::<Vec<_>, _>(|pending| pending.deadline <= now);
+        self.state.scheduled_announcements = pending;
+
+        due.into_iter()
+            .map(|pending| {
+                let ScheduledAnnouncement { id, message, .. } = pending.announcement;
+                info!("Broadcasting scheduled announcement {id} '{message}'.");
+                self.make_response_to_authenticated(&ChatResponse::Announcement { message })
+            })
+            .collect()
+    }
+    pub fn expire_sessions(&mut self) -> Vec<ChatServerResponseCommand> {
+        let Some(session_limit) = &self.options.session_limit else {
+            return vec![];
+        };
+        let max_duration = session_limit.max_duration;
+        let disconnect = session_limit.disconnect;
+
+        let mut expired = Vec::<(String, String, bool)>::new();
+        for (user_id, user_data) in self.state.users.iter_mut() {
+            let Some(authenticated_at) = user_data.authenticated_at else {
+                continue;
+            };
+            if authenticated_at.elapsed() < max_duration {
+                continue;
+            }
+
+            user_data.authenticated = false;
+            user_data.authenticated_at = None;
+            // Logging in again starts out visible
+            let was_visible = mem::replace(&mut user_data.visible, true);
+            expired.push((user_id.clone(), user_data.name.take().unwrap(), was_visible));
+        }
+
+        let mut commands = Vec::<ChatServerResponseCommand>::new();
+        for (user_id, user_name, was_visible) in expired {
+            info!("Session of user {user_id} with name {user_name} has expired.");
+            if let Some(event_handler) = &mut self.event_handler {
+                event_handler.on_user_left(&user_name);
             }
+
+            commands.push(self.make_response_to_user(&user_id, &ChatResponse::SessionExpired));
+            if disconnect {
+                commands.push(ChatServerResponseCommand::DisconnectUser(user_id, None));
+            }
+            if !was_visible || !self.coalesce_presence(&user_name, false) {
+                continue;
+            }
+            commands.push(
+                self.make_response_to_authenticated(&ChatResponse::Connection {
+                    user_name,
+                    is_connected: false,
+                    online_count: self.online_names_count(),
+                }),
+            );
+        }
+        commands
+    }
+    pub fn on_user_message(
+        &mut self,
+        user_id: String,
+        message: &[u8],
+    ) -> Option<Vec<ChatServerResponseCommand>> {
+        let ChatRequestEnvelope {
+            request_id,
+            request,
+        } = match Self::message_to_request(message) {
+            Ok(envelope) => envelope,
             Err(e) => {
-                info!(
-                    "User {user_id} could not register with name '{}', disconnecting.",
-                    user_credentials_raw.name
+                info!("User {user_id} has sent a malformed request ({e}).");
+
+                return Some(vec![self.make_error_response(
+                    &user_id,
+                    ErrorCode::ProtocolError,
+                    Some(e),
+                )]);
+            }
+        };
+
+        self.state.request_id = request_id;
+        self.state.requester = Some(user_id.clone());
+        let response_commands = self.process_request(user_id, request);
+        self.state.request_id = None;
+        self.state.requester = None;
+
+        response_commands
+    }
+
+    fn process_request(
+        &mut self,
+        user_id: String,
+        request: ChatRequest,
+    ) -> Option<Vec<ChatServerResponseCommand>> {
+        match request {
+            ChatRequest::Handshake { compression } => {
+                return Some(self.handshake(&user_id, compression));
+            }
+            ChatRequest::Disconnect => {
+                info!("User {user_id} has requested to disconnect.");
+
+                return Some(vec![
+                    self.make_response_to_user(&user_id, &ChatResponse::Goodbye),
+                    ChatServerResponseCommand::DisconnectUser(user_id, None),
+                ]);
+            }
+            _ => {}
+        }
+
+        let user_data = self.state.users.get_mut(&user_id)?;
+        if user_data.handshake == HandshakeState::Pending {
+            if self.options.require_handshake {
+                info!("User {user_id} has sent a request before the handshake.");
+
+                return Some(vec![self.make_error_response(
+                    &user_id,
+                    ErrorCode::ProtocolError,
+                    Some("handshake required".to_string()),
+                )]);
+            }
+
+            info!("User {user_id} has skipped the handshake, treating it as a legacy client.");
+            user_data.handshake = HandshakeState::Legacy;
+        }
+
+        let is_authenticated = user_data.authenticated;
+
+        if is_authenticated {
+            self.process_request_authenticated(&user_id, request)
+        } else {
+            self.process_request_unauthenticated(&user_id, request)
+        }
+    }
+
+    pub fn on_admin_command(
+        &mut self,
+        command: AdminCommand,
+    ) -> (AdminCommandResult, Vec<ChatServerResponseCommand>) {
+        match command {
+            AdminCommand::ListUsers => {
+                let Ok(user_names) = self.user_service.get_user_names() else {
+                    return (AdminCommandResult::InternalError, vec![]);
+                };
+                // One pass over the sessions instead of one per registered account
+                let mut addresses_by_name = HashMap::<String, Vec<String>>::new();
+                for user_data in self.state.users.values() {
+                    if let (true, Some(name)) = (user_data.authenticated, &user_data.name) {
+                        addresses_by_name
+                            .entry(name.to_ascii_lowercase())
+                            .or_default()
+                            .push(user_data.address.to_string());
+                    }
+                }
+                let users = user_names
+                    .into_iter()
+                    .map(|name| {
+                        let addresses = addresses_by_name
+                            .remove(&name.to_ascii_lowercase())
+                            .unwrap_or_default();
+                        RegisteredUser {
+                            online: !addresses.is_empty(),
+                            name,
+                            addresses,
+                        }
+                    })
+                    .collect();
+                (AdminCommandResult::Users(users), vec![])
+            }
+            AdminCommand::DeleteUser(name) => {
+                match self.user_service.delete_user(&name) {
+                    Ok(true) => {}
+                    Ok(false) => return (AdminCommandResult::UserNotFound, vec![]),
+                    Err(_) => return (AdminCommandResult::InternalError, vec![]),
+                }
+
+                info!("Admin has deleted user '{name}'.");
+                self.audit(
+                    AuditAction::AccountDeletion,
+                    &name,
+                    None,
+                    AuditOutcome::Success,
                 );
 
-                Some(vec![Self::make_response_to_user(
-                    user_id,
-                    &ChatResponse::RegistrationResult {
-                        result: false,
-                        error: Some(e),
-                    },
-                )])
+                (
+                    AdminCommandResult::Done,
+                    self.disconnect_user_by_name(&name, "account has been deleted"),
+                )
+            }
+            AdminCommand::KickUser(name) => {
+                let commands = self.disconnect_user_by_name(&name, "kicked by an administrator");
+                if commands.is_empty() {
+                    return (AdminCommandResult::UserNotFound, vec![]);
+                }
+
+                info!("Admin has kicked user '{name}'.");
+                self.audit(AuditAction::Kick, &name, None, AuditOutcome::Success);
+
+                (AdminCommandResult::Done, commands)
+            }
+            AdminCommand::Broadcast(message) => {
+                info!("Admin has broadcast announcement '{message}'.");
+
+                (
+                    AdminCommandResult::Done,
+                    vec![self
+                        .make_response_to_authenticated(&ChatResponse::Announcement { message })],
+                )
+            }
+            AdminCommand::ScheduleAnnouncement {
+                message,
+                at_epoch_secs,
+            } => {
+                let id = self.state.next_announcement_id;
+                self.state.next_announcement_id += 1;
+
+                info!("Admin has scheduled announcement {id} '{message}' for {at_epoch_secs}.");
+                // Times in the past are due right away
+                let delay = Duration::from_secs(at_epoch_secs.saturating_sub(unix_timestamp()));
+                self.state
+                    .scheduled_announcements
+                    .push(PendingAnnouncement {
+                        deadline: Instant::now() + delay,
+                        announcement: ScheduledAnnouncement {
+                            id,
+                            message,
+                            at_epoch_secs,
+                        },
+                    });
+
+                (AdminCommandResult::Scheduled(id), vec![])
+            }
+            AdminCommand::ListScheduledAnnouncements => (
+                AdminCommandResult::ScheduledAnnouncements(
+                    self.state
+                        .scheduled_announcements
+                        .iter()
+                        .map(|pending| pending.announcement.clone())
+                        .collect(),
+                ),
+                vec![],
+            ),
+            AdminCommand::CancelScheduledAnnouncement(id) => {
+                let scheduled = &mut self.state.scheduled_announcements;
+                let Some(index) = scheduled
+                    .iter()
+                    .position(|pending| pending.announcement.id == id)
+                else {
+                    return (AdminCommandResult::AnnouncementNotFound, vec![]);
+                };
+                scheduled.remove(index);
+
+                info!("Admin has cancelled scheduled announcement {id}.");
+
+                (AdminCommandResult::Done, vec![])
+            }
+            AdminCommand::CreateUser {
+                user_credentials_raw,
+                allow_reserved,
+            } => {
+                let name = &user_credentials_raw.name;
+                match self
+                    .user_service
+                    .add_user(&user_credentials_raw, allow_reserved)
+                {
+                    Ok(_) => {
+                        info!("Admin has created user '{name}'.");
+                        self.audit(AuditAction::Registration, name, None, AuditOutcome::Success);
+                        (AdminCommandResult::Done, vec![])
+                    }
+                    Err(e) => {
+                        info!("Admin could not create user '{name}' ({e}).");
+                        (AdminCommandResult::RegistrationFailed(e), vec![])
+                    }
+                }
             }
         }
     }
 
-    fn authenticate(
+    fn find_user_ids_by_name(&self, name: &str) -> Vec<String> {
+        self.matching_user_ids(None, |user_data| {
+            user_data.authenticated
+                && user_data
+                    .name
+                    .as_ref()
+                    .is_some_and(|user_name| user_name.eq_ignore_ascii_case(name))
+        })
+    }
+
+    fn disconnect_user_by_name(&self, name: &str, reason: &str) -> Vec<ChatServerResponseCommand> {
+        let message = Self::serialize_response(&ChatResponse::Disconnected {
+            reason: reason.to_string(),
+        });
+
+        self.find_user_ids_by_name(name)
+            .into_iter()
+            .map(|user_id| {
+                ChatServerResponseCommand::DisconnectUser(user_id, Some(message.clone()))
+            })
+            .collect()
+    }
+
+    fn process_request_authenticated(
         &mut self,
         user_id: &str,
-        user_credentials_raw: &UserCredentialsRaw,
+        request: ChatRequest,
     ) -> Option<Vec<ChatServerResponseCommand>> {
-        match self.user_service.authenticate_user(user_credentials_raw) {
-            Ok(_) => {
-                let user_data = self.state.users.get_mut(user_id)?;
-                user_data.authenticated = true;
-                user_data.name = Some(user_credentials_raw.name.clone());
+        match request {
+            ChatRequest::Message { content_type, .. }
+                if !self.options.allowed_content_types.contains(&content_type) =>
+            {
+                info!("User {user_id} has sent a message with disallowed content type {content_type:?}.");
 
+                Some(vec![self.make_error_response(
+                    user_id,
+                    ErrorCode::ContentTypeNotAllowed,
+                    Some(format!("{content_type:?}")),
+                )])
+            }
+            ChatRequest::Message {
+                room: Some(room), ..
+            } if !self.is_room_member(user_id, &room) => {
                 info!(
-                    "User {user_id} has authenticated with name '{}'.",
-                    user_credentials_raw.name
+                    "User {user_id} has sent a message to room '{room}' without being its member."
                 );
 
-                Some(vec![
-                    Self::make_response_to_user(
-                        user_id,
-                        &ChatResponse::AuthenticationResult {
-                            result: true,
-                            error: None,
-                        },
-                    ),
-                    self.make_response_to_all_authenticated(
+                Some(vec![self.make_error_response(
+                    user_id,
+                    ErrorCode::NotRoomMember,
+                    Some(room),
+                )])
+            }
+            ChatRequest::Message {
+                message,
+                content_type,
+                client_msg_id,
+                room,
+            } => {
+                if let Some(client_msg_id) = client_msg_id.as_deref() {
+                    if let Some(server_msg_id) =
+                        self.find_seen_client_message(user_id, client_msg_id)
+                    {
+                        info!("User {user_id} has resent message {server_msg_id}, not broadcasting it again.");
+
+                        // Acknowledged again, as the first acknowledgement may have been lost
+                        return Some(vec![self.make_response_to_user(
+                            user_id,
+                            &ChatResponse::MessageAccepted {
+                                server_msg_id,
+                                client_msg_id: client_msg_id.to_string(),
+                            },
+                        )]);
+                    }
+                }
+
+                if self.is_repeated_message(user_id, &message) {
+                    info!(
+                        "User {user_id} has repeated a message within the cooldown, dropping it."
+                    );
+
+                    return Some(vec![self.make_error_response(
                         user_id,
+                        ErrorCode::DuplicateMessage,
                         None,
-                        &ChatResponse::Connection {
-                            user_name: user_credentials_raw.name.clone(),
-                            is_connected: true,
-                        },
-                    ),
-                ])
-            }
-            Err(e) => {
-                info!(
-                    "User {user_id} could not authenticate with name '{}'.",
-                    user_credentials_raw.name
-                );
+                    )]);
+                }
 
-                Some(vec![Self::make_response_to_user(
+                match self.filter_message(user_id, message) {
+                    Ok(message) => {
+                        self.send_message(user_id, message, content_type, client_msg_id, room)
+                    }
+                    Err(rejection) => Some(self.make_rejection_response(user_id, rejection)),
+                }
+            }
+            ChatRequest::EditMessage {
+                server_msg_id,
+                new_text,
+            } => match self.filter_message(user_id, new_text) {
+                Ok(new_text) => self.edit_message(user_id, server_msg_id, new_text),
+                Err(rejection) => Some(self.make_rejection_response(user_id, rejection)),
+            },
+            ChatRequest::DeleteMessage { server_msg_id } => {
+                self.delete_message(user_id, server_msg_id)
+            }
+            ChatRequest::RenameAccount { new_name, password } => {
+                self.rename(user_id, &new_name, &password)
+            }
+            ChatRequest::Attachment {
+                filename,
+                mime,
+                size,
+                data_base64,
+            } => self.send_attachment(user_id, filename, mime, size, data_base64),
+            ChatRequest::FetchAttachment { id } => Some(vec![self.fetch_attachment(user_id, &id)]),
+            ChatRequest::DirectMessage { to, message } => {
+                match self.filter_message(user_id, message) {
+                    Ok(message) => self.send_direct_message(user_id, &to, message),
+                    Err(rejection) => Some(self.make_rejection_response(user_id, rejection)),
+                }
+            }
+            ChatRequest::MarkRead { server_msg_id } => self.mark_read(user_id, server_msg_id),
+            ChatRequest::Whois { user_name } => Some(vec![self.whois(user_id, &user_name)?]),
+            ChatRequest::Profile { user_name } => Some(vec![self.profile(user_id, &user_name)]),
+            ChatRequest::Report {
+                target_user,
+                reason,
+                message_id,
+            } => Some(self.report(user_id, target_user, reason, message_id)?),
+            ChatRequest::JoinRoom { room } => self.join_room(user_id, room),
+            ChatRequest::LeaveRoom { room } => Some(vec![self.leave_room(user_id, room)?]),
+            ChatRequest::RoomMembers { room } => Some(vec![self.list_room_members(user_id, room)]),
+            ChatRequest::History {
+                room,
+                before_id,
+                limit,
+            } => Some(vec![self.history(user_id, room, before_id, limit)]),
+            ChatRequest::ListRooms => Some(vec![self.list_rooms(user_id)]),
+            ChatRequest::Block { user_name } => Some(vec![self.block(user_id, user_name)?]),
+            ChatRequest::Unblock { user_name } => Some(vec![self.unblock(user_id, &user_name)?]),
+            ChatRequest::ListBlocked => Some(vec![self.list_blocked(user_id)?]),
+            ChatRequest::ClearBlocked => Some(vec![self.clear_blocked(user_id)?]),
+            ChatRequest::SetVisible { visible } => self.set_visible(user_id, visible),
+            ChatRequest::ListSessions => Some(vec![self.list_sessions(user_id)?]),
+            ChatRequest::RevokeSession { connection_id } => {
+                self.revoke_session(user_id, connection_id)
+            }
+            ChatRequest::OnlineCount => Some(vec![self.online_count(user_id)]),
+            ChatRequest::ServerStats => Some(vec![self.server_stats(user_id)?]),
+            ChatRequest::SubscribeStats { enabled } => self.subscribe_stats(user_id, enabled),
+            ChatRequest::Authentication { .. } | ChatRequest::Registration { .. } => {
+                Some(vec![self.make_error_response(
                     user_id,
-                    &ChatResponse::AuthenticationResult {
-                        result: false,
-                        error: Some(e),
-                    },
+                    ErrorCode::AlreadyAuthenticated,
+                    None,
                 )])
             }
+            ChatRequest::Handshake { .. } | ChatRequest::Disconnect => None,
         }
     }
+    /// Remembers the message as the last one of the user unless it repeats the last one
+    fn is_repeated_message(&mut self, user_id: &str, text: &str) -> bool {
+        let Some(cooldown) = self.options.duplicate_cooldown else {
+            return false;
+        };
+        let Some(user_name) = self
+            .state
+            .users
+            .get(user_id)
+            .and_then(|user_data| user_data.name.as_deref())
+        else {
+            return false;
+        };
+        let key = user_name.to_ascii_lowercase();
 
-    fn message_to_request(message: &[u8]) -> Option<ChatRequest> {
-        if let Ok(message) = String::from_utf8(message.to_vec()) {
-            if let Ok(message) = from_str::<ChatRequest>(&message) {
-                Some(message)
-            } else {
-                None
-            }
-        } else {
-            None
+        // Drop messages outside of the cooldown for all users to keep the map bounded
+        self.state
+            .last_messages
+            .retain(|_, (_, sent_at)| sent_at.elapsed() < cooldown);
+
+        // Differences in case and spacing don't make a message new
+        let normalized = text
+            .split_whitespace()
+            .map(str::to_lowercase)
+            .collect::<Vec<_>>()
+            .join(" ");
+        if self
+            .state
+            .last_messages
+            .get(&key)
+            .is_some_and(|(last_text, _)| *last_text == normalized)
+        {
+            return true;
         }
-    }
 
-    fn make_response_to_user(user_id: &str, response: &ChatResponse) -> ChatServerResponseCommand {
-        let message = serde_json::to_string(response).unwrap();
-        ChatServerResponseCommand::SendToSome(vec![user_id.to_string()], message.into_bytes())
+        self.state
+            .last_messages
+            .insert(key, (normalized, Instant::now()));
+        false
     }
 
-    fn make_response_to_all(response: &ChatResponse) -> ChatServerResponseCommand {
-        let message = serde_json::to_string(response).unwrap();
-        ChatServerResponseCommand::SendToAll(message.into_bytes())
-    }
+    fn filter_message(&self, user_id: &str, text: String) -> Result<String, MessageRejection> {
+        let Some(sender) = self
+            .state
+            .users
+            .get(user_id)
+            .and_then(|user_data| user_data.name.as_deref())
+        else {
+            return Ok(text);
+        };
 
-    fn make_response_to_all_authenticated(
-        &self,
-        sender_user_id: &str,
-        sender: Option<&str>,
-        response: &ChatResponse,
-    ) -> ChatServerResponseCommand {
-        let users = {
-            let mut authenticated_users = Vec::<String>::new();
-            for (user_id, user_data) in &self.state.users {
-                if user_id == sender_user_id {
-                    continue;
+        self.options
+            .message_middleware
+            .run(MessageContext {
+                sender,
+                message: text,
+            })
+            .inspect_err(|rejection| match rejection {
+                MessageRejection::Rejected(reason) => {
+                    info!("User {user_id} with name {sender} had a message rejected ({reason}).");
                 }
-                if user_data.authenticated {
-                    authenticated_users.push(user_id.to_string());
+                MessageRejection::Dropped => {
+                    info!("User {user_id} with name {sender} had a message dropped.");
                 }
+            })
+    }
+
+    fn make_rejection_response(
+        &self,
+        user_id: &str,
+        rejection: MessageRejection,
+    ) -> Vec<ChatServerResponseCommand> {
+        match rejection {
+            MessageRejection::Rejected(reason) => {
+                vec![self.make_error_response(user_id, ErrorCode::MessageRejected, Some(reason))]
+            }
+            // Dropped messages are not acknowledged, so the sender can't tell them apart
+            MessageRejection::Dropped => vec![],
+        }
+    }
+
+    fn send_message(
+        &mut self,
+        user_id: &str,
+        message: String,
+        content_type: ContentType,
+        client_msg_id: Option<String>,
+        room: Option<String>,
+    ) -> Option<Vec<ChatServerResponseCommand>> {
+        let user_data = self.state.users.get_mut(user_id)?;
+        user_data.last_active = Instant::now();
+        // Authenticated sessions always have a name, a missing one is reported rather than ignored
+        let Some(user_name) = user_data.name.clone() else {
+            warn!("User {user_id} has sent a message without a name, rejecting it.");
+            return Some(vec![self.make_error_response(
+                user_id,
+                ErrorCode::NotAuthenticated,
+                Some("authenticate before sending messages".to_string()),
+            )]);
+        };
+
+        info!("User {user_id} with name {user_name} has sent message '{message}'.",);
+        if let Some(event_handler) = &mut self.event_handler {
+            event_handler.on_message(&user_name, &message);
+        }
+
+        self.state.messages_processed += 1;
+
+        let server_msg_id = self.state.next_message_id;
+        self.state.next_message_id += 1;
+
+        self.state.recent_messages.push_back(StoredMessage {
+            id: server_msg_id,
+            author: user_name.clone(),
+            text: message.clone(),
+            room: room.clone(),
+        });
+        while self.state.recent_messages.len() > self.options.message_retention {
+            self.state.recent_messages.pop_front();
+        }
+        if self.options.persist_messages {
+            self.user_service.append_message(PersistedMessage {
+                server_msg_id: Some(server_msg_id),
+                timestamp: unix_timestamp(),
+                author: user_name.clone(),
+                text: message.clone(),
+                room: room.clone(),
+            });
+            if let Some(room) = &room {
+                let keep = self.options.room_history.limit(room);
+                self.user_service.trim_room_messages(room, keep);
+            }
+        }
+
+        let accepted = client_msg_id.map(|client_msg_id| {
+            self.remember_client_message(&user_name, &client_msg_id, server_msg_id);
+            ChatResponse::MessageAccepted {
+                server_msg_id,
+                client_msg_id,
             }
-            if let Some(sender) = sender {
-                authenticated_users.push(sender.to_string());
+        });
+
+        let mentioned_user_ids: Vec<String> = Self::extract_mentions(&message)
+            .iter()
+            .filter(|mentioned_name| !mentioned_name.eq_ignore_ascii_case(&user_name))
+            .flat_map(|mentioned_name| self.find_user_ids_by_name(mentioned_name))
+            .filter(|mentioned_user_id| !self.is_blocked_by(mentioned_user_id, &user_name))
+            .filter(|mentioned_user_id| {
+                room.as_ref()
+                    .is_none_or(|room| self.is_room_member(mentioned_user_id, room))
+            })
+            .collect::<BTreeSet<String>>()
+            .into_iter()
+            .collect();
+        let mention = ChatResponse::Mention {
+            from: user_name.clone(),
+            message: message.clone(),
+            timestamp: unix_timestamp(),
+        };
+
+        let response = ChatResponse::Message {
+            server_msg_id,
+            user_name,
+            message,
+            content_type,
+            room: room.clone(),
+        };
+
+        let mut audience = self.make_response_to_audience(user_id, room.as_deref(), &response)?;
+        if self.options.delivery_reports {
+            if let ChatServerResponseCommand::SendToSome(recipients, message) = audience {
+                audience = ChatServerResponseCommand::SendToSomeWithReport {
+                    recipients,
+                    message,
+                    sender_id: user_id.to_string(),
+                    server_msg_id,
+                };
             }
-            authenticated_users
+        }
+        let mut commands = vec![audience];
+        if let Some(accepted) = accepted {
+            commands.push(self.make_response_to_user(user_id, &accepted));
+        }
+        if !mentioned_user_ids.is_empty() {
+            commands.push(self.make_response_to_some(mentioned_user_ids, &mention));
+        }
+        Some(commands)
+    }
+
+    /// Returns the id the message was broadcast with, if it has been seen within the window
+    fn find_seen_client_message(&mut self, user_id: &str, client_msg_id: &str) -> Option<u64> {
+        let dedup = self.options.message_dedup.as_ref()?;
+        let now = Instant::now();
+        while self
+            .state
+            .seen_client_messages
+            .front()
+            .is_some_and(|seen| now.duration_since(seen.seen_at) > dedup.window)
+        {
+            self.state.seen_client_messages.pop_front();
+        }
+
+        let user_name = self.state.users.get(user_id)?.name.as_deref()?;
+        self.state
+            .seen_client_messages
+            .iter()
+            .find(|seen| seen.client_msg_id == client_msg_id && seen.user_name == user_name)
+            .map(|seen| seen.server_msg_id)
+    }
+
+    fn remember_client_message(
+        &mut self,
+        user_name: &str,
+        client_msg_id: &str,
+        server_msg_id: u64,
+    ) {
+        let Some(dedup) = &self.options.message_dedup else {
+            return;
+        };
+
+        self.state
+            .seen_client_messages
+            .push_back(SeenClientMessage {
+                user_name: user_name.to_string(),
+                client_msg_id: client_msg_id.to_string(),
+                server_msg_id,
+                seen_at: Instant::now(),
+            });
+        while self.state.seen_client_messages.len() > dedup.max_entries {
+            self.state.seen_client_messages.pop_front();
+        }
+    }
+
+    fn send_direct_message(
+        &mut self,
+        user_id: &str,
+        to: &str,
+        message: String,
+    ) -> Option<Vec<ChatServerResponseCommand>> {
+        let recipient_user_ids = self.find_user_ids_by_name(to);
+        if recipient_user_ids.is_empty() {
+            return Some(vec![self.make_error_response(
+                user_id,
+                ErrorCode::UserNotOnline,
+                Some(to.to_string()),
+            )]);
+        }
+
+        let user_data = self.state.users.get_mut(user_id)?;
+        user_data.last_active = Instant::now();
+        let user_name = user_data.name.clone()?;
+
+        // Name as it was registered, which may differ in case from the requested one
+        let recipient = self.state.users.get(&recipient_user_ids[0])?.name.clone()?;
+
+        // Sender is not told about being blocked, the message just isn't delivered
+        let recipient_user_ids: Vec<String> = recipient_user_ids
+            .into_iter()
+            .filter(|recipient_user_id| !self.is_blocked_by(recipient_user_id, &user_name))
+            .collect();
+
+        info!("User {user_id} with name {user_name} has sent a direct message to {recipient}.");
+
+        self.state.messages_processed += 1;
+
+        let server_msg_id = self.state.next_message_id;
+        self.state.next_message_id += 1;
+
+        self.state
+            .recent_direct_messages
+            .push_back(StoredDirectMessage {
+                id: server_msg_id,
+                sender: user_name.clone(),
+                recipient: recipient.clone(),
+                read: false,
+            });
+        while self.state.recent_direct_messages.len() > self.options.message_retention {
+            self.state.recent_direct_messages.pop_front();
+        }
+
+        Some(vec![
+            self.make_response_to_some(
+                recipient_user_ids,
+                &ChatResponse::DirectMessage {
+                    server_msg_id,
+                    from: user_name,
+                    message,
+                },
+            ),
+            self.make_response_to_user(
+                user_id,
+                &ChatResponse::DirectMessageSent {
+                    server_msg_id,
+                    to: recipient,
+                },
+            ),
+        ])
+    }
+
+    fn mark_read(
+        &mut self,
+        user_id: &str,
+        server_msg_id: u64,
+    ) -> Option<Vec<ChatServerResponseCommand>> {
+        let user_name = self.state.users.get(user_id)?.name.clone()?;
+
+        let Some(direct_message) = self
+            .state
+            .recent_direct_messages
+            .iter_mut()
+            .find(|direct_message| direct_message.id == server_msg_id)
+        else {
+            return Some(vec![self.make_error_response(
+                user_id,
+                ErrorCode::MessageNotFound,
+                None,
+            )]);
         };
-        let message = serde_json::to_string(response).unwrap();
-        ChatServerResponseCommand::SendToSome(users, message.into_bytes())
+
+        if direct_message.recipient != user_name {
+            return Some(vec![self.make_error_response(
+                user_id,
+                ErrorCode::NotMessageRecipient,
+                None,
+            )]);
+        }
+        // Clients may mark a message read from several sessions, the sender is told only once
+        if direct_message.read {
+            return None;
+        }
+        direct_message.read = true;
+        let sender = direct_message.sender.clone();
+
+        info!("User {user_id} with name {user_name} has read direct message {server_msg_id}.");
+
+        let sender_user_ids = self.find_user_ids_by_name(&sender);
+        if sender_user_ids.is_empty() {
+            return None;
+        }
+
+        Some(vec![self.make_response_to_some(
+            sender_user_ids,
+            &ChatResponse::ReadReceipt {
+                server_msg_id,
+                reader: user_name,
+                timestamp: unix_timestamp(),
+            },
+        )])
+    }
+
+    fn send_attachment(
+        &mut self,
+        user_id: &str,
+        filename: String,
+        mime: String,
+        size: usize,
+        data_base64: String,
+    ) -> Option<Vec<ChatServerResponseCommand>> {
+        // Attachments are binary, so text filters don't apply to them
+        let data = match self.verify_attachment(&filename, &mime, size, &data_base64) {
+            Ok(data) => data,
+            Err(code) => {
+                info!("User {user_id} has sent an invalid attachment '{filename}' ({code}).");
+
+                return Some(vec![self.make_error_response(user_id, code, None)]);
+            }
+        };
+
+        let user_data = self.state.users.get_mut(user_id)?;
+        user_data.last_active = Instant::now();
+        let user_name = user_data.name.clone()?;
+
+        info!("User {user_id} with name {user_name} has sent attachment '{filename}' ({mime}, {size} bytes).");
+
+        self.state.messages_processed += 1;
+
+        let Some(storage_dir) = &self.options.attachments.storage_dir else {
+            return Some(vec![self.make_response_to_all_not_blocking(
+                user_id,
+                &ChatResponse::Attachment {
+                    user_name,
+                    filename,
+                    mime,
+                    data: data_base64,
+                },
+            )?]);
+        };
+
+        // Writing is left to the caller, so other sessions don't wait for the disk
+        self.state.pending_attachments.push(PendingAttachment {
+            user_id: user_id.to_string(),
+            request_id: self.state.request_id,
+            storage_dir: storage_dir.clone(),
+            id: Uuid::new_v4().to_string(),
+            metadata: StoredAttachment {
+                from: user_name,
+                filename,
+                mime,
+                size,
+            },
+            data,
+        });
+
+        Some(vec![])
+    }
+
+    /// Attachments accepted since the last call, to be stored and passed to `on_attachment_stored`
+    pub fn take_pending_attachments(&mut self) -> Vec<PendingAttachment> {
+        mem::take(&mut self.state.pending_attachments)
+    }
+
+    pub fn on_attachment_stored(
+        &mut self,
+        attachment: PendingAttachment,
+        result: std::io::Result<()>,
+    ) -> Vec<ChatServerResponseCommand> {
+        let PendingAttachment {
+            user_id,
+            request_id,
+            id,
+            metadata,
+            ..
+        } = attachment;
+
+        // Answered as if the request was still being processed
+        self.state.request_id = request_id;
+        self.state.requester = Some(user_id.clone());
+        let response = match result {
+            Ok(()) => {
+                // The sender may have left while the attachment was written, it is announced anyway
+                let recipients = self.matching_user_ids(None, |user_data| {
+                    user_data.authenticated && !Self::blocks(user_data, &metadata.from)
+                });
+                self.make_response_to_some(
+                    recipients,
+                    &ChatResponse::AttachmentStored {
+                        id,
+                        from: metadata.from,
+                        filename: metadata.filename,
+                        mime: metadata.mime,
+                        size: metadata.size,
+                    },
+                )
+            }
+            Err(err) => {
+                error!(
+                    "Failed to store attachment '{}' from user {user_id}: {err}",
+                    metadata.filename
+                );
+                self.make_error_response(&user_id, ErrorCode::InternalError, None)
+            }
+        };
+        self.state.request_id = None;
+        self.state.requester = None;
+
+        vec![response]
+    }
+
+    fn fetch_attachment(&self, user_id: &str, id: &str) -> ChatServerResponseCommand {
+        let Some(storage_dir) = &self.options.attachments.storage_dir else {
+            return self.make_error_response(
+                user_id,
+                ErrorCode::AttachmentNotFound,
+                Some(id.to_string()),
+            );
+        };
+        // Only ids the server generated are accepted, which also keeps the path inside the directory
+        let Ok(id) = Uuid::parse_str(id).map(|id| id.to_string()) else {
+            return self.make_error_response(
+                user_id,
+                ErrorCode::AttachmentNotFound,
+                Some(id.to_string()),
+            );
+        };
+
+        let metadata = match fs::read(storage_dir.join(format!("{id}.json"))) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                return self.make_error_response(user_id, ErrorCode::AttachmentNotFound, Some(id))
+            }
+        };
+        let (metadata, data) = match (
+            serde_json::from_slice::<StoredAttachment>(&metadata),
+            fs::read(storage_dir.join(&id)),
+        ) {
+            (Ok(metadata), Ok(data)) => (metadata, data),
+            (Err(err), _) => {
+                error!("Stored attachment {id} has invalid metadata: {err}");
+                return self.make_error_response(user_id, ErrorCode::InternalError, None);
+            }
+            (_, Err(err)) => {
+                error!("Failed to read stored attachment {id}: {err}");
+                return self.make_error_response(user_id, ErrorCode::InternalError, None);
+            }
+        };
+
+        info!("User {user_id} has fetched attachment {id}.");
+
+        self.make_response_to_user(
+            user_id,
+            &ChatResponse::AttachmentData {
+                id,
+                filename: metadata.filename,
+                mime: metadata.mime,
+                data: BASE64.encode(data),
+            },
+        )
+    }
+
+    fn verify_attachment(
+        &self,
+        filename: &str,
+        mime: &str,
+        size: usize,
+        data_base64: &str,
+    ) -> Result<Vec<u8>, ErrorCode> {
+        let options = &self.options.attachments;
+
+        // Checked before decoding, so oversized payloads are not decoded at all
+        if size > options.max_size || data_base64.len() > options.max_size.div_ceil(3) * 4 {
+            return Err(ErrorCode::AttachmentTooLarge);
+        }
+        if !options
+            .allowed_mime_types
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(mime))
+        {
+            return Err(ErrorCode::AttachmentTypeNotAllowed);
+        }
+        if filename.is_empty() || filename.len() > 255 || filename.chars().any(char::is_control) {
+            return Err(ErrorCode::MalformedAttachment);
+        }
+
+        let data = BASE64
+            .decode(data_base64)
+            .map_err(|_| ErrorCode::MalformedAttachment)?;
+        if data.len() != size {
+            return Err(ErrorCode::MalformedAttachment);
+        }
+
+        Ok(data)
+    }
+
+    fn extract_mentions(message: &str) -> Vec<String> {
+        let mut mentions = Vec::<String>::new();
+        for (_, rest) in message
+            .match_indices('@')
+            .map(|(i, _)| message.split_at(i + 1))
+        {
+            let mention: String = rest
+                .chars()
+                .take_while(|ch| ch.is_ascii_alphanumeric() || *ch == '.' || *ch == '_')
+                .collect();
+            // Trailing dots are most likely punctuation rather than a part of the name
+            let mention = mention.trim_end_matches('.');
+            // Names are compared regardless of case, so are the mentions
+            if !mention.is_empty() && !mentions.iter().any(|m| m.eq_ignore_ascii_case(mention)) {
+                mentions.push(mention.to_string());
+            }
+        }
+        mentions
+    }
+
+    fn edit_message(
+        &mut self,
+        user_id: &str,
+        server_msg_id: u64,
+        new_text: String,
+    ) -> Option<Vec<ChatServerResponseCommand>> {
+        let stored_message = match self.find_own_message(user_id, server_msg_id) {
+            Ok(index) => &mut self.state.recent_messages[index],
+            Err(error) => return Some(vec![self.make_error_response(user_id, error, None)]),
+        };
+        stored_message.text = new_text.clone();
+        let room = stored_message.room.clone();
+        if self.options.persist_messages {
+            self.user_service.update_message(server_msg_id, &new_text);
+        }
+
+        info!("User {user_id} has edited message {server_msg_id} to '{new_text}'.");
+
+        Some(vec![self.make_response_to_audience(
+            user_id,
+            room.as_deref(),
+            &ChatResponse::MessageEdited {
+                server_msg_id,
+                new_text,
+            },
+        )?])
+    }
+
+    fn delete_message(
+        &mut self,
+        user_id: &str,
+        server_msg_id: u64,
+    ) -> Option<Vec<ChatServerResponseCommand>> {
+        let room = match self.find_own_message(user_id, server_msg_id) {
+            Ok(index) => self.state.recent_messages.remove(index)?.room,
+            Err(error) => return Some(vec![self.make_error_response(user_id, error, None)]),
+        };
+        if self.options.persist_messages {
+            self.user_service.delete_message(server_msg_id);
+        }
+
+        info!("User {user_id} has deleted message {server_msg_id}.");
+
+        let response = ChatResponse::MessageDeleted { server_msg_id };
+        Some(vec![match room {
+            Some(room) => self.make_response_to_matching(None, &response, |user_data| {
+                user_data.authenticated && user_data.rooms.contains(&room)
+            }),
+            None => self.make_response_to_all_authenticated(user_id, Some(user_id), &response),
+        }])
+    }
+
+    fn find_own_message(&self, user_id: &str, server_msg_id: u64) -> Result<usize, ErrorCode> {
+        let user_name = self
+            .state
+            .users
+            .get(user_id)
+            .and_then(|user_data| user_data.name.as_ref());
+
+        let index = self
+            .state
+            .recent_messages
+            .iter()
+            .position(|stored_message| stored_message.id == server_msg_id)
+            .ok_or(ErrorCode::MessageNotFound)?;
+
+        if user_name != Some(&self.state.recent_messages[index].author) {
+            return Err(ErrorCode::NotMessageAuthor);
+        }
+
+        Ok(index)
+    }
+
+    fn process_request_unauthenticated(
+        &mut self,
+        user_id: &str,
+        request: ChatRequest,
+    ) -> Option<Vec<ChatServerResponseCommand>> {
+        match request {
+            ChatRequest::Authentication {
+                user_credentials_raw,
+            } => self.authenticate(user_id, &user_credentials_raw),
+            ChatRequest::Registration {
+                user_credentials_raw,
+            } => self.register(user_id, &user_credentials_raw),
+            ChatRequest::Handshake { .. } | ChatRequest::Disconnect => None,
+            ChatRequest::Message { .. } => Some(vec![self.make_error_response(
+                user_id,
+                ErrorCode::NotAuthenticated,
+                Some("authenticate before sending messages".to_string()),
+            )]),
+            _ => Some(vec![self.make_error_response(
+                user_id,
+                ErrorCode::NotAuthenticated,
+                None,
+            )]),
+        }
+    }
+
+    fn handshake(&mut self, user_id: &str, compression: bool) -> Vec<ChatServerResponseCommand> {
+        let compression = compression && self.options.compression;
+
+        if let Some(user_data) = self.state.users.get_mut(user_id) {
+            user_data.handshake = HandshakeState::Completed;
+        }
+
+        info!("User {user_id} has completed the handshake (compression: {compression}).");
+
+        let mut commands = vec![
+            self.make_response_to_user(user_id, &ChatResponse::HandshakeResult { compression })
+        ];
+        if compression {
+            commands.push(ChatServerResponseCommand::EnableCompression(
+                user_id.to_string(),
+            ));
+        }
+        commands
+    }
+
+    fn register(
+        &mut self,
+        user_id: &str,
+        user_credentials_raw: &UserCredentialsRaw,
+    ) -> Option<Vec<ChatServerResponseCommand>> {
+        let ip = self.state.users.get(user_id)?.address.ip();
+
+        let result = if self.is_registration_limit_reached(ip) {
+            Err(RegistrationError::TooManyRegistrations)
+        } else {
+            self.user_service.add_user(user_credentials_raw, false)
+        };
+
+        match result {
+            Ok(_) => {
+                if self.options.registration_limit.is_some() {
+                    self.state
+                        .registrations
+                        .entry(ip)
+                        .or_default()
+                        .push(Instant::now());
+                }
+
+                info!(
+                    "User {user_id} has registered with name '{}'.",
+                    user_credentials_raw.name
+                );
+                self.audit(
+                    AuditAction::Registration,
+                    &user_credentials_raw.name,
+                    Some(ip),
+                    AuditOutcome::Success,
+                );
+
+                let mut commands = vec![self.make_response_to_user(
+                    user_id,
+                    &ChatResponse::RegistrationResult {
+                        result: true,
+                        error: None,
+                    },
+                )];
+                if self.options.auto_login_on_register {
+                    commands.extend(self.log_in(user_id, user_credentials_raw.name.clone(), ip)?);
+                }
+                Some(commands)
+            }
+            Err(e) => {
+                info!(
+                    "User {user_id} could not register with name '{}', disconnecting.",
+                    user_credentials_raw.name
+                );
+                self.audit(
+                    AuditAction::Registration,
+                    &user_credentials_raw.name,
+                    Some(ip),
+                    AuditOutcome::Failure,
+                );
+
+                Some(vec![self.make_response_to_user(
+                    user_id,
+                    &ChatResponse::RegistrationResult {
+                        result: false,
+                        error: Some(e),
+                    },
+                )])
+            }
+        }
+    }
+
+    fn is_registration_limit_reached(&mut self, ip: IpAddr) -> bool {
+        let Some(registration_limit) = &self.options.registration_limit else {
+            return false;
+        };
+
+        // Drop registrations outside of the window for all addresses to keep the map bounded
+        let window = registration_limit.window;
+        self.state.registrations.retain(|_, registrations| {
+            registrations.retain(|registered_at| registered_at.elapsed() < window);
+            !registrations.is_empty()
+        });
+
+        self.state
+            .registrations
+            .get(&ip)
+            .is_some_and(|registrations| {
+                registrations.len() >= registration_limit.max_registrations
+            })
+    }
+
+    fn authenticate(
+        &mut self,
+        user_id: &str,
+        user_credentials_raw: &UserCredentialsRaw,
+    ) -> Option<Vec<ChatServerResponseCommand>> {
+        let ip = self.state.users.get(user_id)?.address.ip();
+
+        match self.user_service.authenticate_user(user_credentials_raw) {
+            Ok(user_name) => self.log_in(user_id, user_name, ip),
+            Err(e) => {
+                info!(
+                    "User {user_id} from {ip} could not authenticate with name '{}'.",
+                    user_credentials_raw.name
+                );
+                self.audit(
+                    AuditAction::Login,
+                    &user_credentials_raw.name,
+                    Some(ip),
+                    AuditOutcome::Failure,
+                );
+
+                Some(vec![self.make_response_to_user(
+                    user_id,
+                    &ChatResponse::AuthenticationResult {
+                        result: false,
+                        error: Some(e),
+                    },
+                )])
+            }
+        }
+    }
+
+    fn log_in(
+        &mut self,
+        user_id: &str,
+        user_name: String,
+        ip: IpAddr,
+    ) -> Option<Vec<ChatServerResponseCommand>> {
+        let user_data = self.state.users.get_mut(user_id)?;
+        user_data.authenticated = true;
+        user_data.name = Some(user_name.clone());
+        user_data.last_active = Instant::now();
+        user_data.authenticated_at = Some(Instant::now());
+
+        info!("User {user_id} from {ip} has authenticated with name '{user_name}'.");
+        // Connection callbacks run inside the span of the connection
+        Span::current().record("user_name", user_name.as_str());
+        self.audit(
+            AuditAction::Login,
+            &user_name,
+            Some(ip),
+            AuditOutcome::Success,
+        );
+
+        let mut commands = vec![self.make_response_to_user(
+            user_id,
+            &ChatResponse::AuthenticationResult {
+                result: true,
+                error: None,
+            },
+        )];
+        // Others have never seen the user leave, so they don't need to see them join either
+        let departure = self
+            .state
+            .departures
+            .remove(&user_name.to_ascii_lowercase());
+        let is_rejoin = departure.is_some();
+        if is_rejoin {
+            info!("User {user_id} has rejoined as '{user_name}' within the grace window.");
+        } else if let Some(event_handler) = &mut self.event_handler {
+            event_handler.on_user_authenticated(&user_name);
+        }
+        if !is_rejoin && self.coalesce_presence(&user_name, true) {
+            commands.push(self.make_response_to_all_authenticated(
+                user_id,
+                None,
+                &ChatResponse::Connection {
+                    user_name,
+                    is_connected: true,
+                    online_count: self.online_names_count(),
+                },
+            ));
+        }
+        let roster = match departure {
+            Some(departure) => self.make_state_sync(departure.next_message_id),
+            None => self.make_roster(),
+        };
+        commands.push(self.make_response_to_user(user_id, &roster));
+
+        Some(commands)
+    }
+
+    fn rename(
+        &mut self,
+        user_id: &str,
+        new_name: &str,
+        password: &str,
+    ) -> Option<Vec<ChatServerResponseCommand>> {
+        let user_data = self.state.users.get(user_id)?;
+        let ip = user_data.address.ip();
+        let old_name = user_data.name.clone()?;
+
+        let result = if self.is_rename_limit_reached(user_id) {
+            Err(RenameError::TooManyRenames)
+        } else {
+            self.user_service.rename_user(&old_name, new_name, password)
+        };
+
+        match result {
+            Ok(_) => {
+                if let Some(user_data) = self.state.users.get_mut(user_id) {
+                    user_data.last_rename = Some(Instant::now());
+                    user_data.renames.push(Instant::now());
+                }
+
+                self.rename_in_state(&old_name, new_name);
+
+                info!("User {user_id} has renamed from '{old_name}' to '{new_name}'.");
+                Span::current().record("user_name", new_name);
+                self.audit(
+                    AuditAction::Rename,
+                    &old_name,
+                    Some(ip),
+                    AuditOutcome::Success,
+                );
+
+                Some(vec![
+                    self.make_response_to_user(
+                        user_id,
+                        &ChatResponse::RenameAccountResult {
+                            result: true,
+                            error: None,
+                        },
+                    ),
+                    self.make_response_to_all_authenticated(
+                        user_id,
+                        Some(user_id),
+                        &ChatResponse::UserRenamed {
+                            old_name,
+                            new_name: new_name.to_string(),
+                        },
+                    ),
+                ])
+            }
+            Err(e) => {
+                info!("User {user_id} could not rename from '{old_name}' to '{new_name}' ({e}).");
+                self.audit(
+                    AuditAction::Rename,
+                    &old_name,
+                    Some(ip),
+                    AuditOutcome::Failure,
+                );
+
+                Some(vec![self.make_response_to_user(
+                    user_id,
+                    &ChatResponse::RenameAccountResult {
+                        result: false,
+                        error: Some(e),
+                    },
+                )])
+            }
+        }
+    }
+
+    /// Moves everything kept under the old name to the new one
+    fn rename_in_state(&mut self, old_name: &str, new_name: &str) {
+        let state = &mut self.state;
+        let old_key = old_name.to_ascii_lowercase();
+        let new_key = new_name.to_ascii_lowercase();
+
+        for user_data in state.users.values_mut() {
+            if user_data.name.as_deref() == Some(old_name) {
+                user_data.name = Some(new_name.to_string());
+            }
+            // Blocks follow the account, so a rename doesn't lift them
+            let blocked_before = user_data.blocked.len();
+            user_data
+                .blocked
+                .retain(|blocked_name| !blocked_name.eq_ignore_ascii_case(old_name));
+            if user_data.blocked.len() != blocked_before {
+                user_data.blocked.insert(new_name.to_string());
+            }
+        }
+        for stored_message in state.recent_messages.iter_mut() {
+            if stored_message.author == old_name {
+                stored_message.author = new_name.to_string();
+            }
+        }
+        for direct_message in state.recent_direct_messages.iter_mut() {
+            if direct_message.sender == old_name {
+                direct_message.sender = new_name.to_string();
+            }
+            if direct_message.recipient == old_name {
+                direct_message.recipient = new_name.to_string();
+            }
+        }
+        for seen in state.seen_client_messages.iter_mut() {
+            if seen.user_name == old_name {
+                seen.user_name = new_name.to_string();
+            }
+        }
+        if let Some(mut departure) = state.departures.remove(&old_key) {
+            departure.user_name = new_name.to_string();
+            state.departures.insert(new_key.clone(), departure);
+        }
+        if let Some(last_message) = state.last_messages.remove(&old_key) {
+            state.last_messages.insert(new_key.clone(), last_message);
+        }
+        if let Some(reports) = state.reports.remove(&old_key) {
+            state.reports.insert(new_key, reports);
+        }
+    }
+
+    fn is_rename_limit_reached(&mut self, user_id: &str) -> bool {
+        let Some(rename_limit) = &self.options.rename_limit else {
+            return false;
+        };
+        let Some(user_data) = self.state.users.get_mut(user_id) else {
+            return false;
+        };
+
+        let window = rename_limit.window;
+        user_data
+            .renames
+            .retain(|renamed_at| renamed_at.elapsed() < window);
+
+        user_data
+            .last_rename
+            .is_some_and(|last_rename| last_rename.elapsed() < rename_limit.cooldown)
+            || rename_limit
+                .max_renames
+                .is_some_and(|max_renames| user_data.renames.len() >= max_renames)
+    }
+
+    fn server_stats(&self, user_id: &str) -> Option<ChatServerResponseCommand> {
+        let user_name = self.state.users.get(user_id)?.name.as_ref()?;
+
+        if !self.options.public_server_stats && !self.is_admin(user_name) {
+            info!("User {user_id} with name {user_name} was denied server statistics.");
+
+            return Some(self.make_error_response(user_id, ErrorCode::PermissionDenied, None));
+        }
+
+        Some(self.make_response_to_user(
+            user_id,
+            &ChatResponse::ServerStats {
+                uptime_secs: self.uptime().as_secs(),
+                connections: self.state.users.len(),
+                authenticated_users: self.online_users_count(),
+                messages_processed: self.state.messages_processed,
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            },
+        ))
+    }
+
+    fn subscribe_stats(
+        &mut self,
+        user_id: &str,
+        enabled: bool,
+    ) -> Option<Vec<ChatServerResponseCommand>> {
+        let user_name = self.state.users.get(user_id)?.name.clone()?;
+
+        if !self.is_admin(&user_name) {
+            info!("User {user_id} with name {user_name} was denied the live statistics.");
+
+            return Some(vec![self.make_error_response(
+                user_id,
+                ErrorCode::PermissionDenied,
+                None,
+            )]);
+        }
+
+        self.state.users.get_mut(user_id)?.stats_subscribed = enabled;
+
+        info!("User {user_id} with name {user_name} has set the live statistics subscription to {enabled}.");
+
+        let mut commands =
+            vec![self.make_response_to_user(user_id, &ChatResponse::StatsSubscription { enabled })];
+        if enabled {
+            // New subscribers get the current numbers right away, not only after the next change
+            commands.push(
+                self.make_response_to_user(
+                    user_id,
+                    &ChatResponse::LiveStats {
+                        connections: self.state.users.len(),
+                        authenticated_users: self.online_users_count(),
+                        messages_per_sec: self
+                            .state
+                            .last_live_stats
+                            .map_or(0.0, |(_, _, messages_per_sec)| messages_per_sec),
+                    },
+                ),
+            );
+        }
+        Some(commands)
+    }
+
+    fn is_stats_subscriber(&self, user_data: &UserData) -> bool {
+        // Admin rights can be taken away by a reload, so they are checked on every push
+        user_data.stats_subscribed
+            && user_data
+                .name
+                .as_deref()
+                .is_some_and(|user_name| self.is_admin(user_name))
+    }
+
+    fn audit(
+        &self,
+        action: AuditAction,
+        user_name: &str,
+        ip: Option<IpAddr>,
+        outcome: AuditOutcome,
+    ) {
+        self.user_service.append_audit(AuditEvent {
+            timestamp: unix_timestamp(),
+            action,
+            user_name: user_name.to_string(),
+            ip,
+            outcome,
+        });
+    }
+
+    fn whois(&self, user_id: &str, target_name: &str) -> Option<ChatServerResponseCommand> {
+        let user_name = self.state.users.get(user_id)?.name.as_ref()?;
+
+        if self.options.whois.admins_only && !self.is_admin(user_name) {
+            info!("User {user_id} with name {user_name} was denied whois of {target_name}.");
+
+            return Some(self.make_error_response(user_id, ErrorCode::PermissionDenied, None));
+        }
+
+        // With several sessions, the longest-lived one is reported
+        let Some(target) = self
+            .find_user_ids_by_name(target_name)
+            .iter()
+            .filter_map(|target_id| self.state.users.get(target_id))
+            .filter(|user_data| user_data.visible)
+            .min_by_key(|user_data| user_data.connected_since)
+        else {
+            return Some(self.make_error_response(
+                user_id,
+                ErrorCode::UserNotOnline,
+                Some(target_name.to_string()),
+            ));
+        };
+
+        Some(
+            self.make_response_to_user(
+                user_id,
+                &ChatResponse::Whois {
+                    user_name: target.name.clone()?,
+                    connected_since: target.connected_since,
+                    status: "online".to_string(),
+                    rooms: target.rooms.iter().cloned().collect(),
+                    ip: self
+                        .options
+                        .whois
+                        .show_ip
+                        .then(|| target.address.ip().to_string()),
+                },
+            ),
+        )
+    }
+
+    fn profile(&self, user_id: &str, target_name: &str) -> ChatServerResponseCommand {
+        let profile = match self.user_service.get_profile(target_name) {
+            Ok(Some(profile)) => profile,
+            Ok(None) => {
+                return self.make_error_response(
+                    user_id,
+                    ErrorCode::UserNotFound,
+                    Some(target_name.to_string()),
+                )
+            }
+            Err(_) => return self.make_error_response(user_id, ErrorCode::InternalError, None),
+        };
+
+        self.make_response_to_user(
+            user_id,
+            &ChatResponse::Profile {
+                is_online: self.is_name_listed(&profile.name),
+                user_name: profile.name,
+                registered_at: profile.registered_at,
+            },
+        )
+    }
+
+    fn report(
+        &mut self,
+        user_id: &str,
+        target_user: String,
+        reason: String,
+        message_id: Option<u64>,
+    ) -> Option<Vec<ChatServerResponseCommand>> {
+        let reporter = self.state.users.get(user_id)?.name.clone()?;
+
+        if self.is_report_limit_reached(&reporter) {
+            info!("User {user_id} with name {reporter} has sent too many reports.");
+            return Some(vec![self.make_error_response(
+                user_id,
+                ErrorCode::TooManyReports,
+                None,
+            )]);
+        }
+        match self.user_service.user_exists(&target_user) {
+            Ok(true) => {}
+            Ok(false) => {
+                return Some(vec![self.make_error_response(
+                    user_id,
+                    ErrorCode::UserNotFound,
+                    Some(target_user),
+                )])
+            }
+            Err(_) => {
+                return Some(vec![self.make_error_response(
+                    user_id,
+                    ErrorCode::InternalError,
+                    None,
+                )])
+            }
+        }
+
+        let report = AbuseReport {
+            timestamp: unix_timestamp(),
+            reporter,
+            target_user,
+            reason,
+            message_id,
+        };
+        if self.user_service.add_report(&report).is_err() {
+            return Some(vec![self.make_error_response(
+                user_id,
+                ErrorCode::InternalError,
+                None,
+            )]);
+        }
+        if self.options.report_limit.is_some() {
+            self.state
+                .reports
+                .entry(report.reporter.to_ascii_lowercase())
+                .or_default()
+                .push(Instant::now());
+        }
+
+        info!(
+            "User {user_id} with name {} has reported '{}' ({}).",
+            report.reporter, report.target_user, report.reason
+        );
+
+        let alert = ChatResponse::ModAlert {
+            report: ReportAlert {
+                timestamp: report.timestamp,
+                reporter: report.reporter,
+                target_user: report.target_user,
+                reason: report.reason,
+                message_id: report.message_id,
+            },
+        };
+        Some(vec![
+            self.make_response_to_user(user_id, &ChatResponse::ReportAccepted),
+            self.make_response_to_matching(None, &alert, |user_data| {
+                user_data.authenticated
+                    && user_data
+                        .name
+                        .as_deref()
+                        .is_some_and(|user_name| self.is_admin(user_name))
+            }),
+        ])
+    }
+
+    fn is_report_limit_reached(&mut self, reporter: &str) -> bool {
+        let Some(report_limit) = &self.options.report_limit else {
+            return false;
+        };
+
+        // Drop reports outside of the window for all users to keep the map bounded
+        let window = report_limit.window;
+        self.state.reports.retain(|_, reports| {
+            reports.retain(|reported_at| reported_at.elapsed() < window);
+            !reports.is_empty()
+        });
+
+        self.state
+            .reports
+            .get(&reporter.to_ascii_lowercase())
+            .is_some_and(|reports| reports.len() >= report_limit.max_reports)
+    }
+
+    fn join_room(&mut self, user_id: &str, room: String) -> Option<Vec<ChatServerResponseCommand>> {
+        if !self.options.rooms.contains(&room) {
+            return Some(vec![self.make_error_response(
+                user_id,
+                ErrorCode::RoomNotFound,
+                Some(room),
+            )]);
+        }
+
+        let max_rooms = self.options.max_rooms_per_user;
+        let user_data = self.state.users.get_mut(user_id)?;
+        // Joining a room again is a no-op, so it never counts against the limit
+        if !user_data.rooms.contains(&room) && user_data.rooms.len() >= max_rooms {
+            return Some(vec![self.make_error_response(
+                user_id,
+                ErrorCode::RoomLimitReached,
+                Some(format!("at most {max_rooms} rooms can be joined")),
+            )]);
+        }
+        user_data.rooms.insert(room.clone());
+
+        info!("User {user_id} has joined room '{room}'.");
+
+        let mut commands =
+            vec![self
+                .make_response_to_user(user_id, &ChatResponse::RoomJoined { room: room.clone() })];
+        if self.options.persist_messages {
+            let limit = self.options.room_history.limit(&room);
+            // History is a courtesy, the room is joined even if it can't be read
+            if let Ok(persisted_messages) = self.user_service.get_room_messages(&room, limit) {
+                let messages = persisted_messages
+                    .into_iter()
+                    .map(|persisted_message| HistoryMessage {
+                        server_msg_id: persisted_message.server_msg_id,
+                        timestamp: persisted_message.timestamp,
+                        user_name: persisted_message.author,
+                        message: persisted_message.text,
+                    })
+                    .collect();
+                commands.push(
+                    self.make_response_to_user(
+                        user_id,
+                        &ChatResponse::RoomHistory { room, messages },
+                    ),
+                );
+            }
+        }
+        Some(commands)
+    }
+
+    fn leave_room(&mut self, user_id: &str, room: String) -> Option<ChatServerResponseCommand> {
+        let user_data = self.state.users.get_mut(user_id)?;
+        if !user_data.rooms.remove(&room) {
+            return Some(self.make_error_response(user_id, ErrorCode::NotRoomMember, Some(room)));
+        }
+
+        info!("User {user_id} has left room '{room}'.");
+
+        Some(self.make_response_to_user(user_id, &ChatResponse::RoomLeft { room }))
+    }
+
+    fn list_room_members(&self, user_id: &str, room: String) -> ChatServerResponseCommand {
+        if !self.options.rooms.contains(&room) {
+            return self.make_error_response(user_id, ErrorCode::RoomNotFound, Some(room));
+        }
+        if self.options.private_rooms.contains(&room) && !self.is_room_member(user_id, &room) {
+            info!("User {user_id} was denied the members of private room '{room}'.");
+            return self.make_error_response(user_id, ErrorCode::NotRoomMember, Some(room));
+        }
+
+        let members = self
+            .room_members(&room)
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+
+        self.make_response_to_user(user_id, &ChatResponse::RoomMembers { room, members })
+    }
+
+    fn history(
+        &self,
+        user_id: &str,
+        room: Option<String>,
+        before_id: Option<u64>,
+        limit: u32,
+    ) -> ChatServerResponseCommand {
+        if let Some(room) = &room {
+            if !self.options.rooms.contains(room) {
+                return self.make_error_response(
+                    user_id,
+                    ErrorCode::RoomNotFound,
+                    Some(room.clone()),
+                );
+            }
+            if !self.is_room_member(user_id, room) {
+                return self.make_error_response(
+                    user_id,
+                    ErrorCode::NotRoomMember,
+                    Some(room.clone()),
+                );
+            }
+        }
+
+        let limit = (limit as usize).clamp(1, self.options.history_page.max_messages.max(1));
+        // One more than requested tells whether older messages remain
+        let persisted_messages = if self.options.persist_messages {
+            self.user_service
+                .get_messages_before(room.as_deref(), before_id, limit + 1)
+                .unwrap_or_default()
+        } else {
+            vec![]
+        };
+
+        // Widest form of the page without messages, each message adds itself and a comma
+        let mut frame_bytes = self
+            .serialize_response_to_user(&ChatResponse::History {
+                room: room.clone(),
+                messages: vec![],
+                has_more: true,
+                next_before_id: Some(u64::MAX),
+            })
+            .len();
+        let mut messages = Vec::<HistoryMessage>::new();
+        let mut next_before_id = None;
+        for (index, (id, persisted_message)) in persisted_messages.iter().enumerate() {
+            if index == limit {
+                break;
+            }
+            let message = HistoryMessage {
+                server_msg_id: persisted_message.server_msg_id,
+                timestamp: persisted_message.timestamp,
+                user_name: persisted_message.author.clone(),
+                message: persisted_message.text.clone(),
+            };
+            // Only an estimate, an unserializable message ends up as an internal error anyway
+            let message_bytes = serde_json::to_vec(&message).map_or(0, |bytes| bytes.len()) + 1;
+            if frame_bytes + message_bytes > self.options.history_page.max_frame_bytes {
+                if !messages.is_empty() {
+                    break;
+                }
+                warn!("Persisted message {id} does not fit a frame, sending it alone.");
+            }
+            frame_bytes += message_bytes;
+            messages.push(message);
+            next_before_id = Some(*id);
+        }
+        let has_more = messages.len() < persisted_messages.len();
+        messages.reverse();
+
+        self.make_response_to_user(
+            user_id,
+            &ChatResponse::History {
+                room,
+                messages,
+                has_more,
+                next_before_id: next_before_id.filter(|_| has_more),
+            },
+        )
+    }
+
+    fn list_rooms(&self, user_id: &str) -> ChatServerResponseCommand {
+        let rooms = self
+            .options
+            .rooms
+            .iter()
+            .map(|room| RoomInfo {
+                name: room.clone(),
+                member_count: self.room_members(room).len(),
+            })
+            .filter(|room_info| self.options.list_empty_rooms || room_info.member_count > 0)
+            .collect();
+
+        self.make_response_to_user(user_id, &ChatResponse::RoomList { rooms })
+    }
+
+    fn block(&mut self, user_id: &str, user_name: String) -> Option<ChatServerResponseCommand> {
+        let user_data = self.state.users.get_mut(user_id)?;
+        if !Self::blocks(user_data, &user_name) {
+            info!("User {user_id} has blocked '{user_name}'.");
+            user_data.blocked.insert(user_name);
+        }
+
+        self.list_blocked(user_id)
+    }
+
+    fn unblock(&mut self, user_id: &str, user_name: &str) -> Option<ChatServerResponseCommand> {
+        let user_data = self.state.users.get_mut(user_id)?;
+        user_data
+            .blocked
+            .retain(|blocked_name| !blocked_name.eq_ignore_ascii_case(user_name));
+
+        info!("User {user_id} has unblocked '{user_name}'.");
+
+        self.list_blocked(user_id)
+    }
+
+    fn list_blocked(&self, user_id: &str) -> Option<ChatServerResponseCommand> {
+        let users = self
+            .state
+            .users
+            .get(user_id)?
+            .blocked
+            .iter()
+            .cloned()
+            .collect();
+
+        Some(self.make_response_to_user(user_id, &ChatResponse::BlockedList { users }))
+    }
+
+    fn clear_blocked(&mut self, user_id: &str) -> Option<ChatServerResponseCommand> {
+        self.state.users.get_mut(user_id)?.blocked.clear();
+
+        info!("User {user_id} has cleared the blocked list.");
+
+        self.list_blocked(user_id)
+    }
+
+    fn set_visible(
+        &mut self,
+        user_id: &str,
+        visible: bool,
+    ) -> Option<Vec<ChatServerResponseCommand>> {
+        let user_data = self.state.users.get(user_id)?;
+        let user_name = user_data.name.clone()?;
+        let was_listed = self.is_name_listed(&user_name);
+
+        self.state.users.get_mut(user_id)?.visible = visible;
+
+        info!("User {user_id} has set their visibility to {visible}.");
+
+        let mut commands =
+            vec![self.make_response_to_user(user_id, &ChatResponse::VisibilityChanged { visible })];
+        // Other sessions of the user may keep the name listed either way
+        if self.is_name_listed(&user_name) != was_listed
+            && self.coalesce_presence(&user_name, visible)
+        {
+            commands.push(self.make_response_to_all_authenticated(
+                user_id,
+                None,
+                &ChatResponse::Connection {
+                    user_name,
+                    is_connected: visible,
+                    online_count: self.online_names_count(),
+                },
+            ));
+        }
+        Some(commands)
+    }
+
+    fn list_sessions(&self, user_id: &str) -> Option<ChatServerResponseCommand> {
+        let user_name = self.state.users.get(user_id)?.name.as_deref()?;
+
+        let mut sessions: Vec<SessionInfo> = self
+            .find_user_ids_by_name(user_name)
+            .into_iter()
+            .filter_map(|session_id| {
+                let user_data = self.state.users.get(&session_id)?;
+                Some(SessionInfo {
+                    current: session_id == user_id,
+                    address: user_data.address.to_string(),
+                    connected_since: user_data.connected_since,
+                    connection_id: session_id,
+                })
+            })
+            .collect();
+        sessions.sort_by_key(|session| session.connected_since);
+
+        Some(self.make_response_to_user(user_id, &ChatResponse::Sessions { sessions }))
+    }
+
+    fn revoke_session(
+        &self,
+        user_id: &str,
+        connection_id: String,
+    ) -> Option<Vec<ChatServerResponseCommand>> {
+        let user_name = self.state.users.get(user_id)?.name.as_deref()?;
+
+        // Sessions of others are indistinguishable from unknown ones, so their ids can't be probed
+        if !self
+            .find_user_ids_by_name(user_name)
+            .contains(&connection_id)
+        {
+            info!("User {user_id} was denied revoking session {connection_id}.");
+            return Some(vec![self.make_error_response(
+                user_id,
+                ErrorCode::PermissionDenied,
+                Some(connection_id),
+            )]);
+        }
+
+        info!("User {user_id} has revoked session {connection_id}.");
+
+        let reason = Self::serialize_response(&ChatResponse::Disconnected {
+            reason: "session has been revoked".to_string(),
+        });
+        Some(vec![
+            self.make_response_to_user(
+                user_id,
+                &ChatResponse::SessionRevoked {
+                    connection_id: connection_id.clone(),
+                },
+            ),
+            ChatServerResponseCommand::DisconnectUser(connection_id, Some(reason)),
+        ])
+    }
+
+    fn online_count(&self, user_id: &str) -> ChatServerResponseCommand {
+        self.make_response_to_user(
+            user_id,
+            &ChatResponse::OnlineCount {
+                online_count: self.online_names_count(),
+                rooms: self.room_infos(),
+            },
+        )
+    }
+
+    /// Every configured room, including the empty ones
+    fn room_infos(&self) -> Vec<RoomInfo> {
+        self.options
+            .rooms
+            .iter()
+            .map(|room| RoomInfo {
+                name: room.clone(),
+                member_count: self.room_members(room).len(),
+            })
+            .collect()
+    }
+
+    /// Users with several sessions are counted once, unlike in `online_users_count`
+    fn online_names_count(&self) -> usize {
+        self.state
+            .users
+            .values()
+            .filter(|user_data| Self::is_listed(user_data))
+            .filter_map(|user_data| user_data.name.as_deref())
+            .collect::<BTreeSet<&str>>()
+            .len()
+    }
+
+    /// Names of the authenticated users in the room, each counted once regardless of sessions
+    fn room_members(&self, room: &str) -> BTreeSet<&str> {
+        self.state
+            .users
+            .values()
+            .filter(|user_data| Self::is_listed(user_data) && user_data.rooms.contains(room))
+            .filter_map(|user_data| user_data.name.as_deref())
+            .collect()
+    }
+
+    fn is_listed(user_data: &UserData) -> bool {
+        user_data.authenticated && user_data.visible
+    }
+
+    /// Whether any session of the user is shown to others
+    fn is_name_listed(&self, user_name: &str) -> bool {
+        self.state.users.values().any(|user_data| {
+            Self::is_listed(user_data)
+                && user_data
+                    .name
+                    .as_ref()
+                    .is_some_and(|name| name.eq_ignore_ascii_case(user_name))
+        })
+    }
+
+    fn is_admin(&self, user_name: &str) -> bool {
+        self.options
+            .admins
+            .iter()
+            .any(|admin| admin.eq_ignore_ascii_case(user_name))
+    }
+
+    fn make_state_sync(&self, since_message_id: u64) -> ChatResponse {
+        let (users, _) = self.roster_users();
+        let messages = self
+            .state
+            .recent_messages
+            .iter()
+            // Rooms are joined anew by every session, so room messages are not synced
+            .filter(|stored_message| {
+                stored_message.id >= since_message_id && stored_message.room.is_none()
+            })
+            .map(|stored_message| SyncedMessage {
+                server_msg_id: stored_message.id,
+                user_name: stored_message.author.clone(),
+                message: stored_message.text.clone(),
+            })
+            .collect();
+
+        ChatResponse::StateSync { users, messages }
+    }
+
+    fn make_roster(&self) -> ChatResponse {
+        let (users, total_count) = self.roster_users();
+        ChatResponse::Roster { users, total_count }
+    }
+
+    /// Returns the names shown in the roster and how many there are without the size limit
+    fn roster_users(&self) -> (Vec<String>, usize) {
+        let mut authenticated_users: Vec<&UserData> = self
+            .state
+            .users
+            .values()
+            .filter(|user_data| Self::is_listed(user_data))
+            .collect();
+        // Most recently active users go first, ties are broken by name to keep the order stable
+        authenticated_users.sort_by(|a, b| {
+            b.last_active
+                .cmp(&a.last_active)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+
+        let mut seen_names = HashSet::<&str>::new();
+        let mut users = Vec::<String>::new();
+        for user_data in authenticated_users {
+            let user_name = user_data.name.as_ref().unwrap();
+            if seen_names.insert(user_name) {
+                users.push(user_name.clone());
+            }
+        }
+
+        let total_count = users.len();
+        users.truncate(self.options.max_roster_entries);
+
+        (users, total_count)
+    }
+
+    fn message_to_request(message: &[u8]) -> Result<ChatRequestEnvelope, String> {
+        // Only the offset is reported, echoing the raw bytes back could leak them into client logs
+        let message = str::from_utf8(message)
+            .map_err(|e| format!("invalid UTF-8 at byte {}", e.valid_up_to()))?;
+        // Clients that do not use request ids send bare requests
+        match from_str::<ChatRequest>(message) {
+            Ok(request) => Ok(ChatRequestEnvelope {
+                request_id: None,
+                request,
+            }),
+            Err(e) => from_str::<ChatRequestEnvelope>(message).map_err(|_| e.to_string()),
+        }
+    }
+
+    /// Sent to connections refused before they reach the chat server
+    pub fn too_many_connections_message() -> Arc<[u8]> {
+        let code = ErrorCode::TooManyConnections;
+        Self::serialize_response(&ChatResponse::Error {
+            code,
+            message: code.to_string(),
+            context: None,
+        })
+    }
+
+    /// Sent to connections about to be dropped for being idle
+    pub fn idle_warning_message(remaining: Duration) -> Arc<[u8]> {
+        Self::serialize_response(&ChatResponse::IdleWarning {
+            seconds_remaining: remaining.as_secs(),
+        })
+    }
+
+    /// For responses with a single recipient, who gets an internal error if it can't be serialized
+    fn serialize_response<R: Serialize>(response: &R) -> Arc<[u8]> {
+        serialize_or_internal_error(response)
+    }
+
+    /// Recipients get nothing if the response can't be serialized, only the requester is told
+    fn make_response_to_some<R: Serialize>(
+        &self,
+        recipients: Vec<String>,
+        response: &R,
+    ) -> ChatServerResponseCommand {
+        Self::response_or_internal_error(self.state.requester.as_deref(), recipients, response)
+    }
+
+    fn response_or_internal_error<R: Serialize>(
+        requester: Option<&str>,
+        recipients: Vec<String>,
+        response: &R,
+    ) -> ChatServerResponseCommand {
+        match serialize(response) {
+            Ok(message) => ChatServerResponseCommand::SendToSome(recipients, message),
+            Err(err) => {
+                error!(
+                    "Failed to serialize a response for {} recipients ({err}).",
+                    recipients.len()
+                );
+                ChatServerResponseCommand::SendToSome(
+                    requester.map(str::to_string).into_iter().collect(),
+                    INTERNAL_ERROR.as_bytes().into(),
+                )
+            }
+        }
+    }
+
+    fn serialize_response_to_user(&self, response: &ChatResponse) -> Arc<[u8]> {
+        match self.state.request_id {
+            Some(request_id) => Self::serialize_response(&ChatResponseEnvelope {
+                request_id,
+                response,
+            }),
+            None => Self::serialize_response(response),
+        }
+    }
+
+    fn make_response_to_user(
+        &self,
+        user_id: &str,
+        response: &ChatResponse,
+    ) -> ChatServerResponseCommand {
+        let message = self.serialize_response_to_user(response);
+        ChatServerResponseCommand::SendToSome(vec![user_id.to_string()], message)
+    }
+
+    fn make_error_response(
+        &self,
+        user_id: &str,
+        code: ErrorCode,
+        context: Option<String>,
+    ) -> ChatServerResponseCommand {
+        self.make_response_to_user(
+            user_id,
+            &ChatResponse::Error {
+                code,
+                message: code.to_string(),
+                context,
+            },
+        )
+    }
+
+    fn make_response_to_all(&self, response: &ChatResponse) -> ChatServerResponseCommand {
+        match serialize(response) {
+            Ok(message) => ChatServerResponseCommand::SendToAll(message),
+            Err(_) => {
+                Self::response_or_internal_error(self.state.requester.as_deref(), vec![], response)
+            }
+        }
+    }
+
+    /// Returns whether the presence change should be announced right away, otherwise it joins the batch
+    fn coalesce_presence(&mut self, user_name: &str, is_connected: bool) -> bool {
+        if self.options.presence_batch_window.is_none() {
+            return true;
+        }
+
+        let batch = &mut self.state.presence_batch;
+        if batch.opened_at.is_none() {
+            batch.opened_at = Some(Instant::now());
+            return true;
+        }
+
+        let (same, opposite) = if is_connected {
+            (&mut batch.joined, &mut batch.left)
+        } else {
+            (&mut batch.left, &mut batch.joined)
+        };
+        // Leaving and coming back within the same batch cancels out
+        match opposite.iter().position(|name| name == user_name) {
+            Some(index) => {
+                opposite.remove(index);
+            }
+            None => same.push(user_name.to_string()),
+        }
+        false
+    }
+
+    fn make_response_to_authenticated(&self, response: &ChatResponse) -> ChatServerResponseCommand {
+        self.make_response_to_matching(None, response, |user_data| user_data.authenticated)
+    }
+
+    fn make_response_to_all_authenticated(
+        &self,
+        sender_user_id: &str,
+        sender: Option<&str>,
+        response: &ChatResponse,
+    ) -> ChatServerResponseCommand {
+        let mut users =
+            self.matching_user_ids(Some(sender_user_id), |user_data| user_data.authenticated);
+        if let Some(sender) = sender {
+            users.push(sender.to_string());
+        }
+        self.make_response_to_some(users, response)
+    }
+
+    /// Sends the response to every authenticated user, including the sender, unless they block the sender
+    fn make_response_to_all_not_blocking(
+        &self,
+        sender_user_id: &str,
+        response: &ChatResponse,
+    ) -> Option<ChatServerResponseCommand> {
+        self.make_response_to_audience(sender_user_id, None, response)
+    }
+
+    /// Like `make_response_to_all_not_blocking`, but limited to the members of the room if there is one
+    fn make_response_to_audience(
+        &self,
+        sender_user_id: &str,
+        room: Option<&str>,
+        response: &ChatResponse,
+    ) -> Option<ChatServerResponseCommand> {
+        let sender_name = self.state.users.get(sender_user_id)?.name.as_deref()?;
+
+        Some(self.make_response_to_matching(None, response, |user_data| {
+            user_data.authenticated
+                && !Self::blocks(user_data, sender_name)
+                && room.is_none_or(|room| user_data.rooms.contains(room))
+        }))
+    }
+
+    fn is_room_member(&self, user_id: &str, room: &str) -> bool {
+        self.state
+            .users
+            .get(user_id)
+            .is_some_and(|user_data| user_data.rooms.contains(room))
+    }
+
+    fn is_blocked_by(&self, user_id: &str, sender_name: &str) -> bool {
+        self.state
+            .users
+            .get(user_id)
+            .is_some_and(|user_data| Self::blocks(user_data, sender_name))
+    }
+
+    fn blocks(user_data: &UserData, sender_name: &str) -> bool {
+        user_data
+            .blocked
+            .iter()
+            .any(|blocked_name| blocked_name.eq_ignore_ascii_case(sender_name))
+    }
+
+    /// Sends the response to every user accepted by the predicate, except for the sender
+    fn make_response_to_matching<F: Fn(&UserData) -> bool>(
+        &self,
+        sender_user_id: Option<&str>,
+        response: &ChatResponse,
+        predicate: F,
+    ) -> ChatServerResponseCommand {
+        self.make_response_to_some(self.matching_user_ids(sender_user_id, predicate), response)
+    }
+
+    fn matching_user_ids<F: Fn(&UserData) -> bool>(
+        &self,
+        except_user_id: Option<&str>,
+        predicate: F,
+    ) -> Vec<String> {
+        self.state
+            .users
+            .iter()
+            .filter(|(user_id, _)| Some(user_id.as_str()) != except_user_id)
+            .filter(|(_, user_data)| predicate(user_data))
+            .map(|(user_id, _)| user_id.clone())
+            .collect()
+    }
+}
+
+pub fn delivery_report_message(server_msg_id: u64, delivered: usize, failed: usize) -> Arc<[u8]> {
+    let response = ChatResponse::DeliveryReport {
+        message_id: server_msg_id,
+        delivered,
+        failed,
+    };
+    serialize_or_internal_error(&response)
+}
+
+const INTERNAL_ERROR: &str = r#"{"Error":{"code":"InternalError","message":"request could not be processed","context":null}}"#;
+
+fn serialize<R: Serialize>(response: &R) -> Result<Arc<[u8]>, serde_json::Error> {
+    serde_json::to_string(response).map(|message| message.into_bytes().into())
+}
+
+/// Serialization failure is not worth a panic, the recipient gets an internal error in place of the response
+fn serialize_or_internal_error<R: Serialize>(response: &R) -> Arc<[u8]> {
+    serialize(response).unwrap_or_else(|err| {
+        error!("Failed to serialize a response ({err}), sending an internal error instead.");
+        INTERNAL_ERROR.as_bytes().into()
+    })
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::Path;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{
+        server_database::{self, ServerSQLiteDatabase},
+        user_service::ReservedNames,
+    };
+
+    type TestChatServer = ChatServer<ServerSQLiteDatabase>;
+
+    const ADMIN: &str = "AdminUser";
+    const PASSWORD: &str = "password1";
+
+    pub(crate) fn options() -> ChatServerOptions {
+        ChatServerOptions {
+            compression: false,
+            max_roster_entries: 100,
+            message_retention: 100,
+            persist_messages: false,
+            delivery_reports: false,
+            admins: vec![ADMIN.to_string()],
+            public_server_stats: false,
+            registration_limit: None,
+            report_limit: None,
+            rename_limit: None,
+            auto_login_on_register: false,
+            motd: None,
+            session_limit: None,
+            rejoin_grace: None,
+            presence_batch_window: None,
+            message_dedup: None,
+            duplicate_cooldown: None,
+            message_middleware: MiddlewareChain::default(),
+            attachments: AttachmentOptions {
+                max_size: 1024,
+                allowed_mime_types: vec!["image/png".to_string()],
+                storage_dir: None,
+            },
+            whois: WhoisOptions {
+                admins_only: false,
+                show_ip: false,
+            },
+            rooms: vec!["lobby".to_string()],
+            private_rooms: vec![],
+            list_empty_rooms: true,
+            max_rooms_per_user: 10,
+            room_history: RoomHistoryLimits {
+                default: 10,
+                limits: HashMap::new(),
+            },
+            history_page: HistoryPageLimits {
+                max_messages: 50,
+                max_frame_bytes: 64 * 1024,
+            },
+            allowed_content_types: vec![ContentType::Plain],
+            require_handshake: false,
+        }
+    }
+
+    pub(crate) fn chat_server(options: ChatServerOptions) -> TestChatServer {
+        let database = ServerSQLiteDatabase::try_open(Path::new(":memory:")).unwrap();
+        chat_server_with_database(options, database)
+    }
+
+    /// Sessions the chat server knows about, authenticated or not
+    pub(crate) fn session_count(server: &TestChatServer) -> usize {
+        server.state.users.len()
+    }
+
+    /// Clones of the database share its connection, so the test can look into what was written
+    fn chat_server_with_database(
+        options: ChatServerOptions,
+        database: ServerSQLiteDatabase,
+    ) -> TestChatServer {
+        let user_service = UserService::new(
+            database,
+            UserServiceOptions {
+                // Lowest cost bcrypt accepts, tests don't need strong hashes
+                bcrypt_cost: 4,
+                require_email: false,
+                reserved_names: ReservedNames::default(),
+            },
+            ValidationRules::default(),
+        );
+        ChatServer::new(user_service, options, None)
+    }
+
+    fn request(
+        server: &mut TestChatServer,
+        user_id: &str,
+        request: Value,
+    ) -> Vec<ChatServerResponseCommand> {
+        server
+            .on_user_message(user_id.to_string(), request.to_string().as_bytes())
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    fn credentials(name: &str) -> Value {
+        json!({ "user_credentials_raw": { "name": name, "password": PASSWORD, "email": null } })
+    }
+
+    /// Connects the session and registers the user unless it already exists, then logs in
+    fn log_in(server: &mut TestChatServer, user_id: &str, name: &str) {
+        server.on_user_connect(user_id.to_string(), "127.0.0.1:4000".parse().unwrap());
+        request(
+            server,
+            user_id,
+            json!({ "Registration": credentials(name) }),
+        );
+        request(
+            server,
+            user_id,
+            json!({ "Authentication": credentials(name) }),
+        );
+        assert!(
+            server.state.users[user_id].authenticated,
+            "{name} should be logged in"
+        );
+    }
+
+    /// Responses the session would receive from the commands, in order
+    fn received(commands: &[ChatServerResponseCommand], user_id: &str) -> Vec<Value> {
+        commands
+            .iter()
+            .filter_map(|command| match command {
+                ChatServerResponseCommand::SendToAll(message) => Some(message),
+                ChatServerResponseCommand::SendToSome(recipients, message)
+                | ChatServerResponseCommand::SendToSomeWithReport {
+                    recipients,
+                    message,
+                    ..
+                } => recipients.iter().any(|id| id == user_id).then_some(message),
+                _ => None,
+            })
+            .map(|message| serde_json::from_slice(message).unwrap())
+            .collect()
+    }
+
+    fn schedule_announcement(server: &mut TestChatServer, message: &str, in_secs: u64) -> u64 {
+        let (result, _) = server.on_admin_command(AdminCommand::ScheduleAnnouncement {
+            message: message.to_string(),
+            at_epoch_secs: unix_timestamp() + in_secs,
+        });
+        let AdminCommandResult::Scheduled(id) = result else {
+            panic!("announcement should be scheduled");
+        };
+        id
+    }
+
+    #[test]
+    fn listed_users_collect_sessions_regardless_of_case() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "alice-phone", "alicealice");
+        log_in(&mut server, "bob", "BobBobBob");
+        server.on_user_disconnect("bob".to_string());
+
+        let (AdminCommandResult::Users(users), _) =
+            server.on_admin_command(AdminCommand::ListUsers)
+        else {
+            panic!("users should be listed");
+        };
+
+        let summary: Vec<(&str, bool, usize)> = users
+            .iter()
+            .map(|user| (user.name.as_str(), user.online, user.addresses.len()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![("AliceAlice", true, 2), ("BobBobBob", false, 0)]
+        );
+    }
+
+    #[test]
+    fn roster_lists_each_user_once() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "alice-phone", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+
+        let (mut users, total_count) = server.roster_users();
+        users.sort();
+
+        assert_eq!(users, vec!["AliceAlice", "BobBobBob"]);
+        assert_eq!(total_count, 2);
+    }
+
+    #[test]
+    fn roster_over_the_limit_reports_the_full_count() {
+        let mut server = chat_server(ChatServerOptions {
+            max_roster_entries: 2,
+            ..options()
+        });
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+        log_in(&mut server, "carol", "CarolCarol");
+
+        server.on_user_connect("dave".to_string(), "127.0.0.1:4000".parse().unwrap());
+        request(
+            &mut server,
+            "dave",
+            json!({ "Registration": credentials("DaveDaveDave") }),
+        );
+        let commands = request(
+            &mut server,
+            "dave",
+            json!({ "Authentication": credentials("DaveDaveDave") }),
+        );
+
+        let roster = received(&commands, "dave")
+            .into_iter()
+            .find_map(|response| response.get("Roster").cloned())
+            .unwrap();
+        assert_eq!(roster["total_count"], 4);
+        let users: HashSet<&str> = roster["users"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|user| user.as_str().unwrap())
+            .collect();
+        assert_eq!(users.len(), 2);
+        assert!(users.is_subset(&HashSet::from([
+            "AliceAlice",
+            "BobBobBob",
+            "CarolCarol",
+            "DaveDaveDave"
+        ])));
+    }
+
+    fn temp_dir(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("chat-{test}-{}", std::process::id()))
+    }
+
+    fn with_storage_dir(storage_dir: &Path) -> ChatServerOptions {
+        let mut options = options();
+        options.attachments.storage_dir = Some(storage_dir.to_path_buf());
+        options
+    }
+
+    fn send_attachment(server: &mut TestChatServer, user_id: &str, data: &[u8]) {
+        let commands = request(
+            server,
+            user_id,
+            json!({ "request_id": 7, "request": { "Attachment": {
+                "filename": "dot.png",
+                "mime": "image/png",
+                "size": data.len(),
+                "data_base64": BASE64.encode(data),
+            } } }),
+        );
+        // Nothing is announced before the attachment is written
+        assert!(commands.is_empty());
+    }
+
+    fn store_pending(server: &mut TestChatServer) -> Vec<ChatServerResponseCommand> {
+        server
+            .take_pending_attachments()
+            .into_iter()
+            .flat_map(|attachment| {
+                let result = attachment.store();
+                server.on_attachment_stored(attachment, result)
+            })
+            .collect()
+    }
+
+    fn fetch(server: &mut TestChatServer, user_id: &str, id: &str) -> Value {
+        let commands = request(server, user_id, json!({ "FetchAttachment": { "id": id } }));
+        received(&commands, user_id).remove(0)
+    }
+
+    #[test]
+    fn attachment_is_announced_once_written() {
+        let storage_dir = temp_dir("attachments-written");
+        let mut server = chat_server(with_storage_dir(&storage_dir));
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+
+        send_attachment(&mut server, "alice", b"not really a png");
+        let commands = store_pending(&mut server);
+        assert!(server.take_pending_attachments().is_empty());
+
+        let announcement = &received(&commands, "bob")[0]["AttachmentStored"];
+        assert_eq!(announcement["from"], "AliceAlice");
+        assert_eq!(announcement["size"], 16);
+        let id = announcement["id"].as_str().unwrap().to_string();
+        let fetched = fetch(&mut server, "bob", &id);
+        fs::remove_dir_all(&storage_dir).unwrap();
+
+        assert_eq!(
+            fetched["AttachmentData"]["data"],
+            BASE64.encode(b"not really a png")
+        );
+    }
+
+    #[test]
+    fn fetched_attachment_has_the_uploaded_bytes() {
+        let storage_dir = temp_dir("attachments-round-trip");
+        let mut server = chat_server(with_storage_dir(&storage_dir));
+        log_in(&mut server, "alice", "AliceAlice");
+        // Every byte value, up to the size limit
+        let data: Vec<u8> = (0..=255u8).cycle().take(1024).collect();
+
+        send_attachment(&mut server, "alice", &data);
+        let commands = store_pending(&mut server);
+        let id = received(&commands, "alice")[0]["AttachmentStored"]["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let fetched = fetch(&mut server, "alice", &id);
+        fs::remove_dir_all(&storage_dir).unwrap();
+
+        let fetched = &fetched["AttachmentData"];
+        assert_eq!(fetched["id"], id.as_str());
+        assert_eq!(fetched["filename"], "dot.png");
+        assert_eq!(fetched["mime"], "image/png");
+        let fetched_data = BASE64.decode(fetched["data"].as_str().unwrap()).unwrap();
+        assert_eq!(fetched_data, data);
+    }
+
+    #[test]
+    fn oversize_attachment_is_refused() {
+        let storage_dir = temp_dir("attachments-oversize");
+        let mut server = chat_server(with_storage_dir(&storage_dir));
+        log_in(&mut server, "alice", "AliceAlice");
+        let data = vec![0u8; 1100];
+
+        // Stated size within the limit doesn't let larger data through
+        for size in [data.len(), 1024] {
+            let commands = request(
+                &mut server,
+                "alice",
+                json!({ "Attachment": {
+                    "filename": "dot.png",
+                    "mime": "image/png",
+                    "size": size,
+                    "data_base64": BASE64.encode(&data),
+                } }),
+            );
+
+            let responses = received(&commands, "alice");
+            assert_eq!(responses.len(), 1);
+            assert_eq!(responses[0]["Error"]["code"], "AttachmentTooLarge");
+        }
+        assert!(server.take_pending_attachments().is_empty());
+        assert!(!storage_dir.exists());
+    }
+
+    #[test]
+    fn failed_store_is_reported_to_the_sender_only() {
+        // Directory can't be created where a file already is
+        let blocker = temp_dir("attachments-blocker");
+        fs::write(&blocker, b"").unwrap();
+        let mut server = chat_server(with_storage_dir(&blocker.join("attachments")));
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+
+        send_attachment(&mut server, "alice", b"data");
+        let commands = store_pending(&mut server);
+        fs::remove_file(&blocker).unwrap();
+
+        assert!(received(&commands, "bob").is_empty());
+        let responses = received(&commands, "alice");
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0]["request_id"], 7);
+        assert_eq!(responses[0]["response"]["Error"]["code"], "InternalError");
+    }
+
+    #[test]
+    fn expired_attachments_are_removed() {
+        let storage_dir = temp_dir("attachments-expired");
+        let mut server = chat_server(with_storage_dir(&storage_dir));
+        log_in(&mut server, "alice", "AliceAlice");
+        let mut stored_ids = Vec::new();
+        for data in [b"old", b"new"] {
+            send_attachment(&mut server, "alice", data);
+            let commands = store_pending(&mut server);
+            let announcement = &received(&commands, "alice")[0]["AttachmentStored"];
+            stored_ids.push(announcement["id"].as_str().unwrap().to_string());
+        }
+        let [old_id, new_id] = &stored_ids[..] else {
+            unreachable!();
+        };
+        let two_days_ago = SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60);
+        for path in [
+            storage_dir.join(old_id),
+            storage_dir.join(format!("{old_id}.json")),
+        ] {
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(two_days_ago)
+                .unwrap();
+        }
+
+        let removed = remove_expired_attachments(&storage_dir, Duration::from_secs(24 * 60 * 60));
+        let old = fetch(&mut server, "alice", old_id);
+        let new = fetch(&mut server, "alice", new_id);
+        let old_contents_left = storage_dir.join(old_id).exists();
+        fs::remove_dir_all(&storage_dir).unwrap();
+
+        assert_eq!(removed.unwrap(), 1);
+        assert_eq!(old["Error"]["code"], "AttachmentNotFound");
+        assert!(!old_contents_left);
+        assert_eq!(new["AttachmentData"]["data"], BASE64.encode(b"new"));
+    }
+
+    #[test]
+    fn blocks_survive_a_rename() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+        log_in(&mut server, "carol", "CarolCarol");
+        request(
+            &mut server,
+            "alice",
+            json!({ "Block": { "user_name": "bobbobbob" } }),
+        );
+
+        request(
+            &mut server,
+            "bob",
+            json!({ "RenameAccount": { "new_name": "RobertBob", "password": PASSWORD } }),
+        );
+        assert_eq!(server.state.users["bob"].name.as_deref(), Some("RobertBob"));
+
+        let commands = request(
+            &mut server,
+            "bob",
+            json!({ "Message": { "message": "hi" } }),
+        );
+        assert!(received(&commands, "alice").is_empty());
+        assert_eq!(received(&commands, "carol").len(), 1);
+
+        let commands = request(&mut server, "alice", json!("ListBlocked"));
+        assert_eq!(
+            received(&commands, "alice"),
+            vec![json!({ "BlockedList": { "users": ["RobertBob"] } })]
+        );
+    }
+
+    fn blocked_list(commands: &[ChatServerResponseCommand], user_id: &str) -> Vec<String> {
+        let responses = received(commands, user_id);
+        assert_eq!(responses.len(), 1);
+        let mut users: Vec<String> = responses[0]["BlockedList"]["users"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|user| user.as_str().unwrap().to_string())
+            .collect();
+        users.sort();
+        users
+    }
+
+    #[test]
+    fn blocked_users_are_listed_to_the_blocker_only() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+        log_in(&mut server, "carol", "CarolCarol");
+        for user_name in ["CarolCarol", "BobBobBob"] {
+            request(
+                &mut server,
+                "alice",
+                json!({ "Block": { "user_name": user_name } }),
+            );
+        }
+
+        let commands = request(&mut server, "alice", json!("ListBlocked"));
+        assert_eq!(
+            blocked_list(&commands, "alice"),
+            vec!["BobBobBob", "CarolCarol"]
+        );
+        assert!(received(&commands, "bob").is_empty());
+
+        let commands = request(&mut server, "bob", json!("ListBlocked"));
+        assert!(blocked_list(&commands, "bob").is_empty());
+    }
+
+    #[test]
+    fn cleared_blocks_let_messages_through() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+        request(
+            &mut server,
+            "alice",
+            json!({ "Block": { "user_name": "BobBobBob" } }),
+        );
+        let commands = request(
+            &mut server,
+            "bob",
+            json!({ "Message": { "message": "hi" } }),
+        );
+        assert!(received(&commands, "alice").is_empty());
+
+        let commands = request(&mut server, "alice", json!("ClearBlocked"));
+        assert!(blocked_list(&commands, "alice").is_empty());
+        let commands = request(&mut server, "alice", json!("ListBlocked"));
+        assert!(blocked_list(&commands, "alice").is_empty());
+
+        let commands = request(
+            &mut server,
+            "bob",
+            json!({ "Message": { "message": "hi" } }),
+        );
+        assert_eq!(received(&commands, "alice")[0]["Message"]["message"], "hi");
+    }
+
+    #[test]
+    fn edits_and_deletions_reach_persisted_messages() {
+        let mut server = chat_server(ChatServerOptions {
+            persist_messages: true,
+            ..options()
+        });
+        log_in(&mut server, "alice", "AliceAlice");
+        let mut server_msg_ids = Vec::new();
+        for message in ["first", "second"] {
+            let commands = request(
+                &mut server,
+                "alice",
+                json!({ "Message": { "message": message } }),
+            );
+            let responses = received(&commands, "alice");
+            let sent = responses
+                .iter()
+                .find_map(|response| response.get("Message"))
+                .unwrap();
+            server_msg_ids.push(sent["server_msg_id"].as_u64().unwrap());
+        }
+
+        request(
+            &mut server,
+            "alice",
+            json!({ "EditMessage": { "server_msg_id": server_msg_ids[0], "new_text": "edited" } }),
+        );
+        request(
+            &mut server,
+            "alice",
+            json!({ "DeleteMessage": { "server_msg_id": server_msg_ids[1] } }),
+        );
+
+        let persisted = server
+            .user_service
+            .get_messages_before(None, None, 10)
+            .unwrap();
+        let persisted: Vec<(Option<u64>, &str)> = persisted
+            .iter()
+            .map(|(_, message)| (message.server_msg_id, message.text.as_str()))
+            .collect();
+        assert_eq!(persisted, vec![(Some(server_msg_ids[0]), "edited")]);
+    }
+
+    #[test]
+    fn profile_of_offline_user_has_registration_time() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "alice", "AliceAlice");
+        let before = unix_timestamp();
+        server
+            .user_service
+            .add_user(
+                &UserCredentialsRaw {
+                    name: "BobBobBob".to_string(),
+                    password: PASSWORD.to_string(),
+                    email: None,
+                },
+                false,
+            )
+            .unwrap();
+
+        let commands = request(
+            &mut server,
+            "alice",
+            json!({ "Profile": { "user_name": "bobbobbob" } }),
+        );
+        let responses = received(&commands, "alice");
+
+        let profile = &responses[0]["Profile"];
+        assert_eq!(profile["user_name"], "BobBobBob");
+        assert_eq!(profile["is_online"], false);
+        let registered_at = profile["registered_at"].as_u64().unwrap();
+        assert!((before..=unix_timestamp()).contains(&registered_at));
+    }
+
+    #[test]
+    fn profile_of_unknown_user_is_not_found() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "alice", "AliceAlice");
+
+        let commands = request(
+            &mut server,
+            "alice",
+            json!({ "Profile": { "user_name": "NoSuchUser" } }),
+        );
+        let responses = received(&commands, "alice");
+
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0]["Error"]["code"], "UserNotFound");
+        assert_eq!(responses[0]["Error"]["context"], "NoSuchUser");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn scheduled_announcement_is_delivered_at_its_time() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "alice", "AliceAlice");
+        schedule_announcement(&mut server, "maintenance soon", 60);
+
+        let deadline = server.next_announcement_deadline().unwrap();
+        tokio::time::advance(Duration::from_secs(59)).await;
+        assert!(server.deliver_scheduled_announcements().is_empty());
+
+        tokio::time::sleep_until(deadline).await;
+        let commands = server.deliver_scheduled_announcements();
+        assert_eq!(
+            received(&commands, "alice"),
+            vec![json!({ "Announcement": { "message": "maintenance soon" } })]
+        );
+        assert!(server.next_announcement_deadline().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_announcement_is_not_delivered() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "alice", "AliceAlice");
+        let id = schedule_announcement(&mut server, "never mind", 60);
+
+        let (result, _) = server.on_admin_command(AdminCommand::CancelScheduledAnnouncement(id));
+        assert!(matches!(result, AdminCommandResult::Done));
+
+        tokio::time::advance(Duration::from_secs(120)).await;
+        assert!(server.next_announcement_deadline().is_none());
+        assert!(server.deliver_scheduled_announcements().is_empty());
+    }
+
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: serde::Serializer>(&self, _serializer: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("not serializable"))
+        }
+    }
+
+    #[test]
+    fn unserializable_broadcast_only_reaches_requester() {
+        let command = TestChatServer::response_or_internal_error(
+            Some("requester"),
+            vec!["requester".to_string(), "other".to_string()],
+            &Unserializable,
+        );
+
+        let ChatServerResponseCommand::SendToSome(recipients, message) = command else {
+            panic!("response should be sent to some users");
+        };
+        assert_eq!(recipients, vec!["requester".to_string()]);
+        assert_eq!(&*message, INTERNAL_ERROR.as_bytes());
+    }
+
+    #[test]
+    fn unserializable_response_without_requester_reaches_nobody() {
+        let command = TestChatServer::response_or_internal_error(
+            None,
+            vec!["other".to_string()],
+            &Unserializable,
+        );
+
+        let ChatServerResponseCommand::SendToSome(recipients, _) = command else {
+            panic!("response should be sent to some users");
+        };
+        assert!(recipients.is_empty());
+    }
+
+    #[test]
+    fn internal_error_fallback_is_a_valid_error_response() {
+        let response: ChatResponse = serde_json::from_str(INTERNAL_ERROR).unwrap();
+
+        assert!(matches!(
+            response,
+            ChatResponse::Error {
+                code: ErrorCode::InternalError,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn mentions_are_deduplicated_regardless_of_case() {
+        assert_eq!(
+            TestChatServer::extract_mentions("@Alice @alice and @ALICE, @bob."),
+            vec!["Alice".to_string(), "bob".to_string()]
+        );
+    }
+
+    fn with_session_limit(disconnect: bool) -> ChatServerOptions {
+        ChatServerOptions {
+            session_limit: Some(SessionLimit {
+                max_duration: Duration::from_secs(60 * 60),
+                disconnect,
+            }),
+            ..options()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn session_is_logged_out_after_its_maximum_duration() {
+        let mut server = chat_server(with_session_limit(false));
+        log_in(&mut server, "alice", "AliceAlice");
+        tokio::time::advance(Duration::from_secs(30 * 60)).await;
+        log_in(&mut server, "bob", "BobBobBob");
+
+        tokio::time::advance(Duration::from_secs(30 * 60 - 1)).await;
+        assert!(server.expire_sessions().is_empty());
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        let commands = server.expire_sessions();
+        assert_eq!(received(&commands, "alice"), vec![json!("SessionExpired")]);
+        assert_eq!(
+            received(&commands, "bob"),
+            vec![json!({ "Connection": {
+                "user_name": "AliceAlice",
+                "is_connected": false,
+                "online_count": 1,
+            } })]
+        );
+        assert!(!commands
+            .iter()
+            .any(|command| matches!(command, ChatServerResponseCommand::DisconnectUser(..))));
+
+        let commands = request(
+            &mut server,
+            "alice",
+            json!({ "Message": { "message": "still here?" } }),
+        );
+        assert_eq!(
+            received(&commands, "alice")[0]["Error"]["code"],
+            "NotAuthenticated"
+        );
+        assert!(server.state.users["bob"].authenticated);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_session_can_be_disconnected() {
+        let mut server = chat_server(with_session_limit(true));
+        log_in(&mut server, "alice", "AliceAlice");
+
+        tokio::time::advance(Duration::from_secs(60 * 60)).await;
+        let commands = server.expire_sessions();
+
+        assert_eq!(received(&commands, "alice"), vec![json!("SessionExpired")]);
+        assert!(commands.iter().any(|command| matches!(
+            command,
+            ChatServerResponseCommand::DisconnectUser(user_id, None) if user_id == "alice"
+        )));
+    }
+
+    fn with_rejoin_grace() -> ChatServerOptions {
+        ChatServerOptions {
+            rejoin_grace: Some(Duration::from_secs(30)),
+            ..options()
+        }
+    }
+
+    /// Logs an already registered user in from a new session
+    fn reconnect(
+        server: &mut TestChatServer,
+        user_id: &str,
+        name: &str,
+    ) -> Vec<ChatServerResponseCommand> {
+        server.on_user_connect(user_id.to_string(), "127.0.0.1:4001".parse().unwrap());
+        request(
+            server,
+            user_id,
+            json!({ "Authentication": credentials(name) }),
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rejoin_within_the_grace_window_is_not_announced() {
+        let mut server = chat_server(with_rejoin_grace());
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+
+        assert!(server.on_user_disconnect("alice".to_string()).is_none());
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(server.expire_departures().is_empty());
+        let commands = request(
+            &mut server,
+            "bob",
+            json!({ "Message": { "message": "where did you go?" } }),
+        );
+        let server_msg_id = received(&commands, "bob")[0]["Message"]["server_msg_id"].clone();
+
+        let commands = reconnect(&mut server, "alice-again", "AliceAlice");
+        assert!(received(&commands, "bob").is_empty());
+        assert_eq!(
+            received(&commands, "alice-again")[1],
+            json!({ "StateSync": {
+                "users": ["AliceAlice", "BobBobBob"],
+                "messages": [{
+                    "server_msg_id": server_msg_id,
+                    "user_name": "BobBobBob",
+                    "message": "where did you go?",
+                }],
+            } })
+        );
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(server.expire_departures().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rapid_rejoins_each_sync_only_what_was_missed() {
+        let mut server = chat_server(with_rejoin_grace());
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+
+        let mut session = "alice".to_string();
+        for rejoin in 0..2 {
+            assert!(server.on_user_disconnect(session.clone()).is_none());
+            tokio::time::advance(Duration::from_secs(1)).await;
+            let message = format!("missed {rejoin}");
+            let commands = request(
+                &mut server,
+                "bob",
+                json!({ "Message": { "message": message } }),
+            );
+            let server_msg_id = received(&commands, "bob")[0]["Message"]["server_msg_id"].clone();
+
+            session = format!("alice-{rejoin}");
+            let commands = reconnect(&mut server, &session, "AliceAlice");
+            assert!(received(&commands, "bob").is_empty());
+            assert_eq!(
+                received(&commands, &session)[1]["StateSync"]["messages"],
+                json!([{
+                    "server_msg_id": server_msg_id,
+                    "user_name": "BobBobBob",
+                    "message": message,
+                }])
+            );
+            assert!(server.state.departures.is_empty());
+        }
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(server.expire_departures().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn leave_is_announced_once_the_grace_window_has_passed() {
+        let mut server = chat_server(with_rejoin_grace());
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+
+        assert!(server.on_user_disconnect("alice".to_string()).is_none());
+        tokio::time::advance(Duration::from_secs(29)).await;
+        assert!(server.expire_departures().is_empty());
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        let commands = server.expire_departures();
+        assert_eq!(
+            received(&commands, "bob"),
+            vec![json!({ "Connection": {
+                "user_name": "AliceAlice",
+                "is_connected": false,
+                "online_count": 1,
+            } })]
+        );
+
+        let commands = reconnect(&mut server, "alice-again", "AliceAlice");
+        assert_eq!(
+            received(&commands, "bob"),
+            vec![json!({ "Connection": {
+                "user_name": "AliceAlice",
+                "is_connected": true,
+                "online_count": 2,
+            } })]
+        );
+        assert!(received(&commands, "alice-again")[1]
+            .get("Roster")
+            .is_some());
+    }
+
+    #[test]
+    fn closing_one_of_several_sessions_is_not_announced() {
+        for options in [options(), with_rejoin_grace()] {
+            let has_grace = options.rejoin_grace.is_some();
+            let mut server = chat_server(options);
+            log_in(&mut server, "alice", "AliceAlice");
+            log_in(&mut server, "alice-phone", "AliceAlice");
+            log_in(&mut server, "bob", "BobBobBob");
+
+            assert!(server.on_user_disconnect("alice".to_string()).is_none());
+            assert!(server.state.departures.is_empty());
+
+            let command = server.on_user_disconnect("alice-phone".to_string());
+            assert_eq!(command.is_some(), !has_grace);
+            assert_eq!(server.state.departures.len(), usize::from(has_grace));
+        }
+    }
+
+    #[test]
+    fn bare_request_is_answered_without_request_id() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "alice", "AliceAlice");
+
+        let commands = request(&mut server, "alice", json!("OnlineCount"));
+
+        let received = received(&commands, "alice");
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["OnlineCount"]["online_count"], 1);
+        assert!(received[0].get("request_id").is_none());
+    }
+
+    #[test]
+    fn request_id_is_echoed_only_on_direct_responses() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+
+        let commands = request(
+            &mut server,
+            "alice",
+            json!({ "request_id": 5, "request": { "Message": { "message": "hi" } } }),
+        );
+        for user_id in ["alice", "bob"] {
+            let received = received(&commands, user_id);
+            assert_eq!(received.len(), 1);
+            assert_eq!(received[0]["Message"]["message"], "hi");
+            assert!(received[0].get("request_id").is_none());
+        }
+
+        let commands = request(
+            &mut server,
+            "alice",
+            json!({ "request_id": 6, "request": "OnlineCount" }),
+        );
+        let received = received(&commands, "alice");
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["request_id"], 6);
+        assert_eq!(received[0]["response"]["OnlineCount"]["online_count"], 2);
+    }
+
+    fn server_stats(server: &mut TestChatServer) -> Value {
+        let commands = request(server, "admin", json!("ServerStats"));
+        received(&commands, "admin").remove(0)["ServerStats"].clone()
+    }
+
+    #[test]
+    fn server_stats_count_connections_and_messages() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "admin", ADMIN);
+        let before = server_stats(&mut server);
+        assert_eq!(before["connections"], 1);
+        assert_eq!(before["authenticated_users"], 1);
+        assert_eq!(before["messages_processed"], 0);
+
+        server.on_user_connect("guest".to_string(), "127.0.0.1:4000".parse().unwrap());
+        log_in(&mut server, "bob", "BobBobBob");
+        for message in ["one", "two"] {
+            request(
+                &mut server,
+                "bob",
+                json!({ "Message": { "message": message } }),
+            );
+        }
+        let after = server_stats(&mut server);
+
+        assert_eq!(after["connections"], 3);
+        assert_eq!(after["authenticated_users"], 2);
+        assert_eq!(after["messages_processed"], 2);
+    }
+
+    fn register_from(server: &mut TestChatServer, address: &str, name: &str) -> Value {
+        let user_id = format!("{name}-connection");
+        server.on_user_connect(user_id.clone(), address.parse().unwrap());
+        let commands = request(
+            server,
+            &user_id,
+            json!({ "Registration": credentials(name) }),
+        );
+        received(&commands, &user_id).remove(0)["RegistrationResult"].clone()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn registrations_are_limited_per_address_within_the_window() {
+        let mut server = chat_server(ChatServerOptions {
+            registration_limit: Some(RegistrationLimit {
+                max_registrations: 2,
+                window: Duration::from_secs(60),
+            }),
+            ..options()
+        });
+        for name in ["AliceAlice", "BobBobBob"] {
+            assert_eq!(
+                register_from(&mut server, "127.0.0.1:4000", name)["result"],
+                true
+            );
+        }
+
+        let refused = register_from(&mut server, "127.0.0.1:4001", "CarolCarol");
+        assert_eq!(refused["result"], false);
+        assert_eq!(refused["error"], "TooManyRegistrations");
+        // Other addresses have their own count
+        let other = register_from(&mut server, "127.0.0.2:4000", "DaveDaveDave");
+        assert_eq!(other["result"], true);
+
+        tokio::time::advance(Duration::from_secs(61)).await;
+        let after_window = register_from(&mut server, "127.0.0.1:4002", "CarolCarol");
+        assert_eq!(after_window["result"], true);
+    }
+
+    #[test]
+    fn failed_requests_map_to_their_error_codes() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+        let cases = [
+            (
+                json!({ "Authentication": credentials("AliceAlice") }),
+                "AlreadyAuthenticated",
+                "already authenticated",
+            ),
+            (
+                json!({ "DirectMessage": { "to": "NobodyHere", "message": "hi" } }),
+                "UserNotOnline",
+                "user is not online",
+            ),
+            (
+                json!({ "MarkRead": { "server_msg_id": 999 } }),
+                "MessageNotFound",
+                "message not found",
+            ),
+            (
+                json!({ "JoinRoom": { "room": "nowhere" } }),
+                "RoomNotFound",
+                "room does not exist",
+            ),
+            (
+                json!({ "LeaveRoom": { "room": "lobby" } }),
+                "NotRoomMember",
+                "not a member of the room",
+            ),
+            (
+                json!("ServerStats"),
+                "PermissionDenied",
+                "permission denied",
+            ),
+        ];
+
+        for (request_json, code, message) in cases {
+            let commands = request(&mut server, "alice", request_json.clone());
+
+            assert!(received(&commands, "bob").is_empty());
+            let responses = received(&commands, "alice");
+            assert_eq!(responses.len(), 1, "{request_json}");
+            assert_eq!(responses[0]["Error"]["code"], code, "{request_json}");
+            assert_eq!(responses[0]["Error"]["message"], message, "{request_json}");
+        }
+
+        let commands = server
+            .on_user_message("alice".to_string(), b"not json")
+            .unwrap();
+        assert_eq!(
+            received(&commands, "alice")[0]["Error"]["code"],
+            "ProtocolError"
+        );
+    }
+
+    fn motd_on_connect(server: &mut TestChatServer, user_id: &str) -> Vec<Value> {
+        let commands =
+            server.on_user_connect(user_id.to_string(), "127.0.0.1:4000".parse().unwrap());
+        received(&commands, user_id)
+    }
+
+    #[test]
+    fn reloaded_motd_file_is_shown_to_new_connections() {
+        let motd_path = temp_dir("motd-reload");
+        fs::write(&motd_path, "First MOTD").unwrap();
+        let mut server = chat_server(ChatServerOptions {
+            motd: Some(Motd::File(motd_path.clone())),
+            ..options()
+        });
+        assert_eq!(
+            motd_on_connect(&mut server, "alice"),
+            vec![json!({ "Motd": { "text": "First MOTD" } })]
+        );
+
+        fs::write(&motd_path, "Second MOTD").unwrap();
+        // Not read again before the reload
+        assert_eq!(
+            motd_on_connect(&mut server, "bob"),
+            vec![json!({ "Motd": { "text": "First MOTD" } })]
+        );
+        server.reload_motd();
+        assert_eq!(
+            motd_on_connect(&mut server, "carol"),
+            vec![json!({ "Motd": { "text": "Second MOTD" } })]
+        );
+
+        // Last MOTD read is kept when the file can't be read
+        fs::remove_file(&motd_path).unwrap();
+        server.reload_motd();
+        assert_eq!(
+            motd_on_connect(&mut server, "dave"),
+            vec![json!({ "Motd": { "text": "Second MOTD" } })]
+        );
+    }
+
+    #[test]
+    fn failed_authentication_is_audited() {
+        let database = ServerSQLiteDatabase::try_open(Path::new(":memory:")).unwrap();
+        let mut server = chat_server_with_database(options(), database.clone());
+        log_in(&mut server, "alice", "AliceAlice");
+        server.on_user_connect("mallory".to_string(), "10.0.0.5:4000".parse().unwrap());
+
+        for name in ["AliceAlice", "NobodyHere"] {
+            let commands = request(
+                &mut server,
+                "mallory",
+                json!({ "Authentication": { "user_credentials_raw":
+                    { "name": name, "password": "wrong-password", "email": null } } }),
+            );
+            assert_eq!(
+                received(&commands, "mallory")[0]["AuthenticationResult"]["result"],
+                false
+            );
+        }
+
+        let failures: Vec<_> = server_database::tests::audit_log(&database)
+            .into_iter()
+            .filter(|(_, _, _, outcome)| outcome == "failure")
+            .collect();
+        let expected_address = Some("10.0.0.5".to_string());
+        assert_eq!(
+            failures,
+            vec![
+                (
+                    "login".to_string(),
+                    "AliceAlice".to_string(),
+                    expected_address.clone(),
+                    "failure".to_string()
+                ),
+                (
+                    "login".to_string(),
+                    "NobodyHere".to_string(),
+                    expected_address,
+                    "failure".to_string()
+                ),
+            ]
+        );
+    }
+
+    /// Records every hook call, shared with the test through the mutex
+    struct RecordingEventHandler {
+        events: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl ChatEventHandler for RecordingEventHandler {
+        fn on_user_authenticated(&mut self, name: &str) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("authenticated {name}"));
+        }
+
+        fn on_message(&mut self, sender: &str, text: &str) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("message {sender}: {text}"));
+        }
+
+        fn on_user_left(&mut self, name: &str) {
+            self.events.lock().unwrap().push(format!("left {name}"));
+        }
+    }
+
+    #[test]
+    fn event_handler_sees_the_session_in_order() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut server = chat_server(options());
+        server.event_handler = Some(Box::new(RecordingEventHandler {
+            events: events.clone(),
+        }));
+
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+        server.on_user_connect("guest".to_string(), "127.0.0.1:4000".parse().unwrap());
+        request(
+            &mut server,
+            "guest",
+            json!({ "Message": { "message": "unseen" } }),
+        );
+        request(
+            &mut server,
+            "alice",
+            json!({ "Message": { "message": "hi" } }),
+        );
+        reconnect(&mut server, "alice-phone", "AliceAlice");
+        server.on_user_disconnect("guest".to_string());
+        // Alice is still there through the other session
+        server.on_user_disconnect("alice".to_string());
+        server.on_user_disconnect("bob".to_string());
+        server.on_user_disconnect("alice-phone".to_string());
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                "authenticated AliceAlice",
+                "authenticated BobBobBob",
+                "message AliceAlice: hi",
+                "authenticated AliceAlice",
+                "left BobBobBob",
+                "left AliceAlice",
+            ]
+        );
+    }
+
+    fn sorted(mut user_ids: Vec<String>) -> Vec<String> {
+        user_ids.sort();
+        user_ids
+    }
+
+    #[test]
+    fn predicates_pick_matching_users() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "admin", ADMIN);
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+        server.on_user_connect("guest".to_string(), "127.0.0.1:4000".parse().unwrap());
+        for user_id in ["admin", "alice"] {
+            request(
+                &mut server,
+                user_id,
+                json!({ "JoinRoom": { "room": "lobby" } }),
+            );
+        }
+
+        let admins = server.matching_user_ids(None, |user_data| {
+            user_data
+                .name
+                .as_deref()
+                .is_some_and(|name| server.is_admin(name))
+        });
+        assert_eq!(admins, vec!["admin"]);
+
+        let lobby = server.matching_user_ids(None, |user_data| {
+            user_data.authenticated && user_data.rooms.contains("lobby")
+        });
+        assert_eq!(sorted(lobby), vec!["admin", "alice"]);
+
+        let everyone_but_alice =
+            server.matching_user_ids(Some("alice"), |user_data| user_data.authenticated);
+        assert_eq!(sorted(everyone_but_alice), vec!["admin", "bob"]);
+
+        let nobody = server.matching_user_ids(None, |user_data| {
+            user_data.authenticated && user_data.name.is_none()
+        });
+        assert!(nobody.is_empty());
+    }
+
+    #[test]
+    fn matching_response_reaches_only_matching_users() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "admin", ADMIN);
+        log_in(&mut server, "alice", "AliceAlice");
+        server.on_user_connect("guest".to_string(), "127.0.0.1:4000".parse().unwrap());
+
+        let command =
+            server.make_response_to_matching(Some("admin"), &ChatResponse::Goodbye, |user_data| {
+                user_data.authenticated
+            });
+        let commands = [command];
+
+        assert_eq!(received(&commands, "alice"), vec![json!("Goodbye")]);
+        assert!(received(&commands, "admin").is_empty());
+        assert!(received(&commands, "guest").is_empty());
+    }
+
+    fn whois(server: &mut TestChatServer, user_id: &str, target_name: &str) -> Value {
+        let commands = request(
+            server,
+            user_id,
+            json!({ "Whois": { "user_name": target_name } }),
+        );
+        received(&commands, user_id).remove(0)
+    }
+
+    #[test]
+    fn whois_describes_an_online_user() {
+        let mut server = chat_server(ChatServerOptions {
+            whois: WhoisOptions {
+                admins_only: false,
+                show_ip: true,
+            },
+            ..options()
+        });
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+        request(
+            &mut server,
+            "bob",
+            json!({ "JoinRoom": { "room": "lobby" } }),
+        );
+
+        let whois = whois(&mut server, "alice", "bobbobbob");
+
+        let whois = &whois["Whois"];
+        assert_eq!(whois["user_name"], "BobBobBob");
+        assert_eq!(whois["status"], "online");
+        assert_eq!(whois["rooms"], json!(["lobby"]));
+        assert_eq!(whois["ip"], "127.0.0.1");
+        assert_eq!(
+            whois["connected_since"],
+            server.state.users["bob"].connected_since
+        );
+    }
+
+    #[test]
+    fn whois_of_an_offline_user_is_refused() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+        server.on_user_disconnect("bob".to_string());
+
+        for target_name in ["BobBobBob", "NeverRegistered"] {
+            let whois = whois(&mut server, "alice", target_name);
+
+            assert_eq!(whois["Error"]["code"], "UserNotOnline");
+            assert_eq!(whois["Error"]["context"], target_name);
+        }
+    }
+
+    fn room_list(server: &mut TestChatServer, user_id: &str) -> Value {
+        let commands = request(server, user_id, json!("ListRooms"));
+        received(&commands, user_id).remove(0)["RoomList"]["rooms"].clone()
+    }
+
+    #[test]
+    fn room_list_counts_the_members_of_each_room() {
+        let rooms = vec![
+            "lobby".to_string(),
+            "games".to_string(),
+            "attic".to_string(),
+        ];
+        let mut server = chat_server(ChatServerOptions {
+            rooms: rooms.clone(),
+            ..options()
+        });
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+        log_in(&mut server, "carol", "CarolCarol");
+        for (user_id, room) in [("alice", "lobby"), ("bob", "games"), ("carol", "lobby")] {
+            request(
+                &mut server,
+                user_id,
+                json!({ "JoinRoom": { "room": room } }),
+            );
+        }
+
+        assert_eq!(
+            room_list(&mut server, "alice"),
+            json!([
+                { "name": "lobby", "member_count": 2 },
+                { "name": "games", "member_count": 1 },
+                { "name": "attic", "member_count": 0 },
+            ])
+        );
+
+        let mut server = chat_server(ChatServerOptions {
+            rooms,
+            list_empty_rooms: false,
+            ..options()
+        });
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+        request(
+            &mut server,
+            "alice",
+            json!({ "JoinRoom": { "room": "lobby" } }),
+        );
+        request(
+            &mut server,
+            "bob",
+            json!({ "JoinRoom": { "room": "games" } }),
+        );
+
+        assert_eq!(
+            room_list(&mut server, "bob"),
+            json!([
+                { "name": "lobby", "member_count": 1 },
+                { "name": "games", "member_count": 1 },
+            ])
+        );
+    }
+
+    #[test]
+    fn only_allowed_content_types_are_broadcast() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+
+        let commands = request(
+            &mut server,
+            "alice",
+            json!({ "Message": { "message": "plain", "content_type": "Plain" } }),
+        );
+        let message = &received(&commands, "bob")[0]["Message"];
+        assert_eq!(message["message"], "plain");
+        assert_eq!(message["content_type"], "Plain");
+
+        let commands = request(
+            &mut server,
+            "alice",
+            json!({ "Message": { "message": "**bold**", "content_type": "Markdown" } }),
+        );
+        assert!(received(&commands, "bob").is_empty());
+        let error = &received(&commands, "alice")[0]["Error"];
+        assert_eq!(error["code"], "ContentTypeNotAllowed");
+        assert_eq!(error["context"], "Markdown");
+    }
+
+    #[test]
+    fn requests_before_a_required_handshake_are_refused() {
+        let mut server = chat_server(ChatServerOptions {
+            require_handshake: true,
+            ..options()
+        });
+        server.on_user_connect("alice".to_string(), "127.0.0.1:4000".parse().unwrap());
+
+        let commands = request(
+            &mut server,
+            "alice",
+            json!({ "Registration": credentials("AliceAlice") }),
+        );
+        let error = &received(&commands, "alice")[0]["Error"];
+        assert_eq!(error["code"], "ProtocolError");
+        assert_eq!(error["context"], "handshake required");
+
+        let commands = request(
+            &mut server,
+            "alice",
+            json!({ "Handshake": { "compression": false } }),
+        );
+        assert_eq!(
+            received(&commands, "alice"),
+            vec![json!({ "HandshakeResult": { "compression": false } })]
+        );
+        assert!(server.state.users["alice"].handshake == HandshakeState::Completed);
+
+        for kind in ["Registration", "Authentication"] {
+            request(
+                &mut server,
+                "alice",
+                json!({ kind: credentials("AliceAlice") }),
+            );
+        }
+        assert!(server.state.users["alice"].authenticated);
+    }
+
+    #[test]
+    fn optional_handshake_can_be_skipped_by_legacy_clients() {
+        let mut server = chat_server(options());
+
+        log_in(&mut server, "alice", "AliceAlice");
+
+        assert!(server.state.users["alice"].handshake == HandshakeState::Legacy);
+    }
+
+    fn online_count(server: &mut TestChatServer, user_id: &str) -> Value {
+        let commands = request(server, user_id, json!("OnlineCount"));
+        received(&commands, user_id)[0]["OnlineCount"]["online_count"].clone()
+    }
+
+    /// Online count announced to `user_id` by a leave among the commands
+    fn announced_count(commands: &[ChatServerResponseCommand], user_id: &str) -> Value {
+        let received = received(commands, user_id);
+        let connection = received
+            .iter()
+            .find_map(|response| response.get("Connection"))
+            .expect("leave should be announced");
+        assert_eq!(connection["is_connected"], false);
+        connection["online_count"].clone()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn online_count_follows_every_way_of_leaving() {
+        let mut server = chat_server(with_session_limit(false));
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+        log_in(&mut server, "carol", "CarolCarol");
+        server.on_user_connect("guest".to_string(), "127.0.0.1:4000".parse().unwrap());
+        assert_eq!(online_count(&mut server, "alice"), 3);
+
+        let command = server.on_user_disconnect("carol".to_string()).unwrap();
+        assert_eq!(announced_count(&[command], "alice"), 2);
+        assert_eq!(online_count(&mut server, "alice"), 2);
+
+        // Leave of bob is held back by the grace window, bob is not counted meanwhile though
+        server.options.rejoin_grace = Some(Duration::from_secs(30));
+        assert!(server.on_user_disconnect("bob".to_string()).is_none());
+        assert_eq!(online_count(&mut server, "alice"), 1);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        let commands = server.expire_departures();
+        assert_eq!(announced_count(&commands, "alice"), 1);
+        assert_eq!(online_count(&mut server, "alice"), 1);
+
+        log_in(&mut server, "dave", "DaveDaveDave");
+        assert_eq!(online_count(&mut server, "dave"), 2);
+        tokio::time::advance(Duration::from_secs(60 * 60 - 30)).await;
+        let commands = server.expire_sessions();
+        assert_eq!(announced_count(&commands, "dave"), 1);
+        assert_eq!(online_count(&mut server, "dave"), 1);
+        assert_eq!(server.online_users_count(), 1);
+    }
+
+    fn join(server: &mut TestChatServer, user_id: &str, room: &str) -> Value {
+        let commands = request(server, user_id, json!({ "JoinRoom": { "room": room } }));
+        received(&commands, user_id).remove(0)
+    }
+
+    #[test]
+    fn leaving_a_room_frees_a_slot_under_the_room_limit() {
+        let mut server = chat_server(ChatServerOptions {
+            rooms: vec![
+                "lobby".to_string(),
+                "games".to_string(),
+                "attic".to_string(),
+            ],
+            max_rooms_per_user: 2,
+            ..options()
+        });
+        log_in(&mut server, "alice", "AliceAlice");
+
+        assert_eq!(
+            join(&mut server, "alice", "lobby"),
+            json!({ "RoomJoined": { "room": "lobby" } })
+        );
+        assert_eq!(
+            join(&mut server, "alice", "games"),
+            json!({ "RoomJoined": { "room": "games" } })
+        );
+        let refused = join(&mut server, "alice", "attic");
+        assert_eq!(refused["Error"]["code"], "RoomLimitReached");
+        assert!(!server.state.users["alice"].rooms.contains("attic"));
+        // Joining a room again takes no further slot
+        assert_eq!(
+            join(&mut server, "alice", "lobby"),
+            json!({ "RoomJoined": { "room": "lobby" } })
+        );
+
+        let commands = request(
+            &mut server,
+            "alice",
+            json!({ "LeaveRoom": { "room": "lobby" } }),
+        );
+        assert_eq!(
+            received(&commands, "alice"),
+            vec![json!({ "RoomLeft": { "room": "lobby" } })]
+        );
+        assert_eq!(
+            join(&mut server, "alice", "attic"),
+            json!({ "RoomJoined": { "room": "attic" } })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resent_message_is_recognized_only_within_the_dedup_window() {
+        let mut server = chat_server(ChatServerOptions {
+            message_dedup: Some(MessageDedup {
+                window: Duration::from_secs(60),
+                max_entries: 100,
+            }),
+            ..options()
+        });
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+        let message = json!({ "Message": { "message": "hi", "client_msg_id": "c1" } });
+        let accepted_id = |commands: &[ChatServerResponseCommand]| {
+            received(commands, "alice")
+                .iter()
+                .find_map(|response| response.get("MessageAccepted").cloned())
+                .expect("message should be acknowledged")["server_msg_id"]
+                .clone()
+        };
+
+        let commands = request(&mut server, "alice", message.clone());
+        let first_id = accepted_id(&commands);
+        assert_eq!(received(&commands, "bob").len(), 1);
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(
+            server.find_seen_client_message("alice", "c1"),
+            first_id.as_u64()
+        );
+        let commands = request(&mut server, "alice", message.clone());
+        assert_eq!(accepted_id(&commands), first_id);
+        assert!(received(&commands, "bob").is_empty());
+
+        tokio::time::advance(Duration::from_secs(31)).await;
+        assert_eq!(server.find_seen_client_message("alice", "c1"), None);
+        let commands = request(&mut server, "alice", message);
+        assert_ne!(accepted_id(&commands), first_id);
+        assert_eq!(received(&commands, "bob").len(), 1);
+    }
+
+    /// Connects a new session and registers, without logging in explicitly
+    fn register_only(
+        server: &mut TestChatServer,
+        user_id: &str,
+        name: &str,
+    ) -> Vec<ChatServerResponseCommand> {
+        server.on_user_connect(user_id.to_string(), "127.0.0.1:4000".parse().unwrap());
+        request(
+            server,
+            user_id,
+            json!({ "Registration": credentials(name) }),
+        )
+    }
+
+    #[test]
+    fn registration_logs_in_when_auto_login_is_enabled() {
+        let mut server = chat_server(ChatServerOptions {
+            auto_login_on_register: true,
+            ..options()
+        });
+        log_in(&mut server, "bob", "BobBobBob");
+
+        let commands = register_only(&mut server, "alice", "AliceAlice");
+
+        let alice = received(&commands, "alice");
+        assert_eq!(
+            alice[0],
+            json!({ "RegistrationResult": { "result": true, "error": null } })
+        );
+        assert_eq!(
+            alice[1],
+            json!({ "AuthenticationResult": { "result": true, "error": null } })
+        );
+        assert_eq!(
+            alice[2]["Roster"]["users"],
+            json!(["AliceAlice", "BobBobBob"])
+        );
+        assert_eq!(
+            received(&commands, "bob"),
+            vec![json!({ "Connection": {
+                "user_name": "AliceAlice",
+                "is_connected": true,
+                "online_count": 2,
+            } })]
+        );
+        let user_data = &server.state.users["alice"];
+        assert!(user_data.authenticated);
+        assert_eq!(user_data.name.as_deref(), Some("AliceAlice"));
+    }
+
+    #[test]
+    fn registration_alone_does_not_log_in_by_default() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "bob", "BobBobBob");
+
+        let commands = register_only(&mut server, "alice", "AliceAlice");
+
+        assert_eq!(
+            received(&commands, "alice"),
+            vec![json!({ "RegistrationResult": { "result": true, "error": null } })]
+        );
+        assert!(received(&commands, "bob").is_empty());
+        let user_data = &server.state.users["alice"];
+        assert!(!user_data.authenticated);
+        assert_eq!(user_data.name, None);
+    }
+
+    /// Logs in without asserting, returns what the login sent
+    fn log_in_commands(
+        server: &mut TestChatServer,
+        user_id: &str,
+        name: &str,
+    ) -> Vec<ChatServerResponseCommand> {
+        server.on_user_connect(user_id.to_string(), "127.0.0.1:4000".parse().unwrap());
+        request(
+            server,
+            user_id,
+            json!({ "Registration": credentials(name) }),
+        );
+        request(
+            server,
+            user_id,
+            json!({ "Authentication": credentials(name) }),
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn presence_burst_is_sent_in_a_bounded_number_of_frames() {
+        let mut server = chat_server(ChatServerOptions {
+            presence_batch_window: Some(Duration::from_secs(1)),
+            ..options()
+        });
+        log_in(&mut server, "observer", "Observer");
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(server.flush_presence_batch().is_empty());
+
+        let mut frames = Vec::new();
+        for index in 0..50 {
+            let user_id = format!("user{index}");
+            let commands = log_in_commands(&mut server, &user_id, &format!("User{index:03}"));
+            frames.extend(received(&commands, "observer"));
+        }
+        // Leaving within the batch that announced the join cancels both out
+        for index in 40..50 {
+            let command = server.on_user_disconnect(format!("user{index}"));
+            frames.extend(received(command.as_slice(), "observer"));
+        }
+        assert_eq!(
+            frames.len(),
+            1,
+            "only the join opening the batch is sent at once"
+        );
+        assert_eq!(frames[0]["Connection"]["user_name"], "User000");
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        let batch = received(&server.flush_presence_batch(), "observer");
+        assert_eq!(batch.len(), 1);
+        let batch = &batch[0]["ConnectionBatch"];
+        assert_eq!(batch["joined"].as_array().unwrap().len(), 39);
+        assert_eq!(batch["left"], json!([]));
+        assert_eq!(batch["online_count"], 41);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(server.flush_presence_batch().is_empty());
+    }
+
+    #[test]
+    fn invalid_utf8_is_reported_with_its_byte_offset() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "alice", "AliceAlice");
+        // Multi-byte characters before the bad byte count by their bytes, not as characters
+        let mut frame = "{\"Message\":{\"message\":\"héllo".as_bytes().to_vec();
+        let offset = frame.len();
+        frame.extend_from_slice(&[0xff, b'"', b'}', b'}']);
+
+        let commands: Vec<_> = server
+            .on_user_message("alice".to_string(), &frame)
+            .into_iter()
+            .flatten()
+            .collect();
+
+        let error = &received(&commands, "alice")[0]["Error"];
+        assert_eq!(error["code"], "ProtocolError");
+        assert_eq!(error["context"], format!("invalid UTF-8 at byte {offset}"));
+        assert_eq!(offset, 29);
+    }
+
+    #[test]
+    fn room_history_is_trimmed_to_the_limit_of_that_room() {
+        let mut server = chat_server(ChatServerOptions {
+            persist_messages: true,
+            rooms: vec!["lobby".to_string(), "games".to_string()],
+            room_history: RoomHistoryLimits {
+                default: 5,
+                limits: HashMap::from([("lobby".to_string(), 2)]),
+            },
+            ..options()
+        });
+        log_in(&mut server, "alice", "AliceAlice");
+        for room in ["lobby", "games"] {
+            request(
+                &mut server,
+                "alice",
+                json!({ "JoinRoom": { "room": room } }),
+            );
+            for index in 0..4 {
+                request(
+                    &mut server,
+                    "alice",
+                    json!({ "Message": { "message": format!("{room} {index}"), "room": room } }),
+                );
+            }
+        }
+        request(
+            &mut server,
+            "alice",
+            json!({ "Message": { "message": "everyone" } }),
+        );
+
+        let persisted = |room: &str| -> Vec<String> {
+            server
+                .user_service
+                .get_room_messages(room, 10)
+                .unwrap()
+                .into_iter()
+                .map(|message| message.text)
+                .collect()
+        };
+        assert_eq!(persisted("lobby"), vec!["lobby 2", "lobby 3"]);
+        assert_eq!(
+            persisted("games"),
+            vec!["games 0", "games 1", "games 2", "games 3"]
+        );
+        let outside_rooms = server
+            .user_service
+            .get_messages_before(None, None, 10)
+            .unwrap();
+        assert_eq!(outside_rooms.len(), 1);
+        assert_eq!(outside_rooms[0].1.text, "everyone");
+    }
+
+    fn room_members(server: &mut TestChatServer, user_id: &str, room: &str) -> Value {
+        let commands = request(server, user_id, json!({ "RoomMembers": { "room": room } }));
+        received(&commands, user_id).remove(0)
+    }
+
+    #[test]
+    fn room_members_are_listed_once_per_name() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "alice-phone", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+        log_in(&mut server, "carol", "CarolCarol");
+        for user_id in ["alice", "alice-phone", "bob"] {
+            join(&mut server, user_id, "lobby");
+        }
+
+        assert_eq!(
+            room_members(&mut server, "carol", "lobby"),
+            json!({ "RoomMembers": {
+                "room": "lobby",
+                "members": ["AliceAlice", "BobBobBob"],
+            } })
+        );
+        let missing = room_members(&mut server, "carol", "nowhere");
+        assert_eq!(missing["Error"]["code"], "RoomNotFound");
+    }
+
+    #[test]
+    fn members_of_a_private_room_are_shown_to_its_members_only() {
+        let mut server = chat_server(ChatServerOptions {
+            rooms: vec!["lobby".to_string(), "staff".to_string()],
+            private_rooms: vec!["staff".to_string()],
+            ..options()
+        });
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+        join(&mut server, "alice", "staff");
+
+        let refused = room_members(&mut server, "bob", "staff");
+        assert_eq!(refused["Error"]["code"], "NotRoomMember");
+        assert_eq!(refused["Error"]["context"], "staff");
+
+        assert_eq!(
+            room_members(&mut server, "alice", "staff")["RoomMembers"]["members"],
+            json!(["AliceAlice"])
+        );
+    }
+
+    #[test]
+    fn history_pages_through_thousands_of_messages_without_gaps() {
+        const MESSAGES: u64 = 3000;
+        let mut server = chat_server(ChatServerOptions {
+            persist_messages: true,
+            ..options()
+        });
+        log_in(&mut server, "alice", "AliceAlice");
+        for server_msg_id in 1..=MESSAGES {
+            server.user_service.append_message(PersistedMessage {
+                server_msg_id: Some(server_msg_id),
+                timestamp: server_msg_id,
+                author: "AliceAlice".to_string(),
+                text: format!("message {server_msg_id}"),
+                room: None,
+            });
+        }
+
+        let mut pages = 0;
+        let mut before_id = Value::Null;
+        let mut server_msg_ids = Vec::new();
+        loop {
+            let commands = request(
+                &mut server,
+                "alice",
+                json!({ "History": { "before_id": before_id, "limit": 1000 } }),
+            );
+            let page = received(&commands, "alice").remove(0)["History"].take();
+            let messages = page["messages"].as_array().unwrap();
+            assert!(!messages.is_empty() && messages.len() <= 50);
+            // Oldest first within a page, pages go backwards
+            let page_ids: Vec<u64> = messages
+                .iter()
+                .map(|message| message["server_msg_id"].as_u64().unwrap())
+                .collect();
+            assert!(page_ids.windows(2).all(|pair| pair[0] < pair[1]));
+            server_msg_ids.splice(0..0, page_ids);
+            pages += 1;
+
+            if page["has_more"] == false {
+                assert_eq!(page["next_before_id"], Value::Null);
+                break;
+            }
+            before_id = page["next_before_id"].clone();
+        }
+
+        assert_eq!(pages, MESSAGES / 50);
+        assert_eq!(server_msg_ids, (1..=MESSAGES).collect::<Vec<_>>());
+    }
+
+    fn report(
+        server: &mut TestChatServer,
+        user_id: &str,
+        reason: &str,
+    ) -> Vec<ChatServerResponseCommand> {
+        request(
+            server,
+            user_id,
+            json!({ "Report": { "target_user": "MalloryMallory", "reason": reason, "message_id": 7 } }),
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_are_persisted_alerted_and_throttled() {
+        let database = ServerSQLiteDatabase::try_open(Path::new(":memory:")).unwrap();
+        let mut server = chat_server_with_database(
+            ChatServerOptions {
+                report_limit: Some(ReportLimit {
+                    max_reports: 2,
+                    window: Duration::from_secs(60),
+                }),
+                ..options()
+            },
+            database.clone(),
+        );
+        log_in(&mut server, "admin", ADMIN);
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+        log_in(&mut server, "mallory", "MalloryMallory");
+
+        let commands = report(&mut server, "alice", "spam");
+        assert_eq!(received(&commands, "alice"), vec![json!("ReportAccepted")]);
+        let alert = &received(&commands, "admin")[0]["ModAlert"]["report"];
+        assert_eq!(alert["reporter"], "AliceAlice");
+        assert_eq!(alert["target_user"], "MalloryMallory");
+        assert_eq!(alert["reason"], "spam");
+        assert_eq!(alert["message_id"], 7);
+        for user_id in ["bob", "mallory"] {
+            assert!(received(&commands, user_id).is_empty());
+        }
+
+        report(&mut server, "alice", "more spam");
+        let commands = report(&mut server, "alice", "even more spam");
+        assert_eq!(
+            received(&commands, "alice")[0]["Error"]["code"],
+            "TooManyReports"
+        );
+        assert!(received(&commands, "admin").is_empty());
+        // Limit is kept per reporter
+        let commands = report(&mut server, "bob", "rude");
+        assert_eq!(received(&commands, "bob"), vec![json!("ReportAccepted")]);
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        let commands = report(&mut server, "alice", "still spamming");
+        assert_eq!(received(&commands, "alice"), vec![json!("ReportAccepted")]);
+
+        let reasons: Vec<(String, String)> = server_database::tests::reports(&database)
+            .into_iter()
+            .map(|(reporter, target_user, reason, message_id)| {
+                assert_eq!(target_user, "MalloryMallory");
+                assert_eq!(message_id, Some(7));
+                (reporter, reason)
+            })
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                ("AliceAlice", "spam"),
+                ("AliceAlice", "more spam"),
+                ("BobBobBob", "rude"),
+                ("AliceAlice", "still spamming"),
+            ]
+            .map(|(reporter, reason)| (reporter.to_string(), reason.to_string()))
+        );
+    }
+
+    #[test]
+    fn invisible_user_is_left_out_of_the_roster_and_the_online_count() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+
+        let commands = request(
+            &mut server,
+            "bob",
+            json!({ "SetVisible": { "visible": false } }),
+        );
+        assert_eq!(
+            received(&commands, "bob"),
+            vec![json!({ "VisibilityChanged": { "visible": false } })]
+        );
+        assert_eq!(announced_count(&commands, "alice"), 1);
+        assert_eq!(online_count(&mut server, "alice"), 1);
+
+        let commands = log_in_commands(&mut server, "carol", "CarolCarol");
+        let roster = received(&commands, "carol")
+            .into_iter()
+            .find_map(|response| response.get("Roster").cloned())
+            .unwrap();
+        let users: Vec<String> = serde_json::from_value(roster["users"].clone()).unwrap();
+        assert_eq!(sorted(users), vec!["AliceAlice", "CarolCarol"]);
+        assert_eq!(roster["total_count"], 2);
+        assert!(received(&commands, "bob")
+            .iter()
+            .all(|response| response["Connection"]["user_name"] == "CarolCarol"));
+        assert_eq!(online_count(&mut server, "carol"), 2);
+        assert_eq!(online_count(&mut server, "bob"), 2);
+
+        request(
+            &mut server,
+            "bob",
+            json!({ "SetVisible": { "visible": true } }),
+        );
+        assert_eq!(online_count(&mut server, "alice"), 3);
+    }
+
+    #[test]
+    fn sender_receives_its_own_message_with_the_server_id() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+
+        let commands = request(
+            &mut server,
+            "alice",
+            json!({ "Message": { "message": "hi", "client_msg_id": "c1" } }),
+        );
+
+        let alice = received(&commands, "alice");
+        let own = alice
+            .iter()
+            .find_map(|response| response.get("Message"))
+            .expect("sender should get its own message");
+        assert_eq!(own["user_name"], "AliceAlice");
+        assert_eq!(own["message"], "hi");
+        let server_msg_id = own["server_msg_id"].as_u64().unwrap();
+        let accepted = alice
+            .iter()
+            .find_map(|response| response.get("MessageAccepted"))
+            .unwrap();
+        assert_eq!(accepted["server_msg_id"], server_msg_id);
+        assert_eq!(
+            received(&commands, "bob")[0]["Message"]["server_msg_id"],
+            server_msg_id
+        );
+    }
+
+    #[test]
+    fn sessions_of_the_user_are_listed_and_only_those_can_be_revoked() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "alice", "AliceAlice");
+        reconnect(&mut server, "alice-phone", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+
+        let commands = request(&mut server, "alice", json!("ListSessions"));
+        let mut sessions: Vec<(String, String, bool)> = received(&commands, "alice")[0]["Sessions"]
+            ["sessions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|session| {
+                (
+                    session["connection_id"].as_str().unwrap().to_string(),
+                    session["address"].as_str().unwrap().to_string(),
+                    session["current"].as_bool().unwrap(),
+                )
+            })
+            .collect();
+        sessions.sort();
+        assert_eq!(
+            sessions,
+            vec![
+                ("alice".to_string(), "127.0.0.1:4000".to_string(), true),
+                (
+                    "alice-phone".to_string(),
+                    "127.0.0.1:4001".to_string(),
+                    false
+                ),
+            ]
+        );
+
+        let commands = request(
+            &mut server,
+            "alice",
+            json!({ "RevokeSession": { "connection_id": "bob" } }),
+        );
+        let error = &received(&commands, "alice")[0]["Error"];
+        assert_eq!(error["code"], "PermissionDenied");
+        assert_eq!(error["context"], "bob");
+        assert!(!commands
+            .iter()
+            .any(|command| matches!(command, ChatServerResponseCommand::DisconnectUser(..))));
+
+        let commands = request(
+            &mut server,
+            "alice",
+            json!({ "RevokeSession": { "connection_id": "alice-phone" } }),
+        );
+        assert_eq!(
+            received(&commands, "alice"),
+            vec![json!({ "SessionRevoked": { "connection_id": "alice-phone" } })]
+        );
+        assert!(commands.iter().any(|command| matches!(
+            command,
+            ChatServerResponseCommand::DisconnectUser(user_id, Some(_)) if user_id == "alice-phone"
+        )));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn same_message_within_the_cooldown_is_dropped() {
+        let mut server = chat_server(ChatServerOptions {
+            duplicate_cooldown: Some(Duration::from_secs(10)),
+            ..options()
+        });
+        log_in(&mut server, "alice", "AliceAlice");
+        log_in(&mut server, "bob", "BobBobBob");
+        let send = |server: &mut TestChatServer, text: &str| {
+            let commands = request(server, "alice", json!({ "Message": { "message": text } }));
+            let delivered = received(&commands, "bob").len();
+            let error = received(&commands, "alice")
+                .iter()
+                .find_map(|response| response["Error"]["code"].as_str().map(str::to_string));
+            (delivered, error)
+        };
+        let duplicate = (0, Some("DuplicateMessage".to_string()));
+
+        assert_eq!(send(&mut server, "hello there"), (1, None));
+        // Case and spacing don't make it a different message
+        assert_eq!(send(&mut server, "  Hello   THERE "), duplicate);
+        assert_eq!(send(&mut server, "something else"), (1, None));
+        assert_eq!(send(&mut server, "hello there"), (1, None));
+        assert_eq!(send(&mut server, "hello there"), duplicate);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(send(&mut server, "hello there"), (1, None));
+    }
+
+    #[test]
+    fn requests_before_logging_in_are_not_authenticated() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "bob", "BobBobBob");
+        server.on_user_connect("guest".to_string(), "127.0.0.1:4000".parse().unwrap());
+
+        let commands = request(
+            &mut server,
+            "guest",
+            json!({ "Message": { "message": "hello?" } }),
+        );
+        assert!(received(&commands, "bob").is_empty());
+        let error = &received(&commands, "guest")[0]["Error"];
+        assert_eq!(error["code"], "NotAuthenticated");
+        assert_eq!(error["context"], "authenticate before sending messages");
+
+        for other in [
+            json!("OnlineCount"),
+            json!({ "JoinRoom": { "room": "lobby" } }),
+        ] {
+            let commands = request(&mut server, "guest", other);
+            let error = &received(&commands, "guest")[0]["Error"];
+            assert_eq!(error["code"], "NotAuthenticated");
+            assert_eq!(error["context"], Value::Null);
+        }
+    }
+
+    fn rename(server: &mut TestChatServer, user_id: &str, new_name: &str) -> Value {
+        let commands = request(
+            server,
+            user_id,
+            json!({ "RenameAccount": { "new_name": new_name, "password": PASSWORD } }),
+        );
+        received(&commands, user_id).remove(0)["RenameAccountResult"].take()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn renames_are_limited_by_cooldown_and_window() {
+        let mut server = chat_server(ChatServerOptions {
+            rename_limit: Some(RenameLimit {
+                cooldown: Duration::from_secs(60),
+                max_renames: Some(2),
+                window: Duration::from_secs(60 * 60),
+            }),
+            ..options()
+        });
+        log_in(&mut server, "alice", "AliceAlice");
+        let too_many = json!({ "result": false, "error": "TooManyRenames" });
+
+        assert_eq!(rename(&mut server, "alice", "AliceBravo")["result"], true);
+        tokio::time::advance(Duration::from_secs(59)).await;
+        assert_eq!(rename(&mut server, "alice", "AliceCharlie"), too_many);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(rename(&mut server, "alice", "AliceCharlie")["result"], true);
+        // Past the cooldown, but both renames of the window are used up
+        tokio::time::advance(Duration::from_secs(60 * 30)).await;
+        assert_eq!(rename(&mut server, "alice", "AliceDelta"), too_many);
+
+        // First rename has left the window, the second one is still in it
+        tokio::time::advance(Duration::from_secs(60 * 30 - 60)).await;
+        assert_eq!(rename(&mut server, "alice", "AliceDelta")["result"], true);
+        assert_eq!(rename(&mut server, "alice", "AliceEcho"), too_many);
+        assert_eq!(
+            server.state.users["alice"].name.as_deref(),
+            Some("AliceDelta")
+        );
+    }
+
+    fn subscribe_stats(server: &mut TestChatServer, user_id: &str, enabled: bool) -> Vec<Value> {
+        let commands = request(
+            server,
+            user_id,
+            json!({ "SubscribeStats": { "enabled": enabled } }),
+        );
+        received(&commands, user_id)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn live_stats_are_pushed_to_subscribed_admins_only() {
+        let mut server = chat_server(options());
+        log_in(&mut server, "admin", ADMIN);
+        log_in(&mut server, "alice", "AliceAlice");
+
+        let refused = subscribe_stats(&mut server, "alice", true);
+        assert_eq!(refused[0]["Error"]["code"], "PermissionDenied");
+        let subscribed = subscribe_stats(&mut server, "admin", true);
+        assert_eq!(
+            subscribed,
+            vec![
+                json!({ "StatsSubscription": { "enabled": true } }),
+                json!({ "LiveStats": {
+                    "connections": 2,
+                    "authenticated_users": 2,
+                    "messages_per_sec": 0.0,
+                } }),
+            ]
+        );
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(server.broadcast_live_stats().is_some());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(
+            server.broadcast_live_stats().is_none(),
+            "nothing has changed"
+        );
+
+        log_in(&mut server, "bob", "BobBobBob");
+        server.on_user_connect("guest".to_string(), "127.0.0.1:4000".parse().unwrap());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        let command = server.broadcast_live_stats();
+        let commands = command.as_slice();
+        let live_stats = received(commands, "admin");
+        assert_eq!(live_stats.len(), 1);
+        assert_eq!(live_stats[0]["LiveStats"]["connections"], 4);
+        assert_eq!(live_stats[0]["LiveStats"]["authenticated_users"], 3);
+        for user_id in ["alice", "bob", "guest"] {
+            assert!(received(commands, user_id).is_empty());
+        }
+
+        subscribe_stats(&mut server, "admin", false);
+        server.on_user_disconnect("guest".to_string());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(
+            server.broadcast_live_stats().is_none(),
+            "nobody is subscribed"
+        );
     }
 }
@@ -1,72 +1,894 @@
-use std::fs;
+use std::{error::Error, fmt, fs, net::IpAddr, path::Path, sync::Arc};
 
+use log::error;
 use serde::{Deserialize, Serialize};
-use sqlite::{Connection, State};
+use sqlite::{Connection, ConnectionThreadSafe, OpenFlags, State};
+
+#[derive(Debug)]
+pub struct DatabaseError(sqlite::Error);
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "database error: {}", self.0)
+    }
+}
+
+impl Error for DatabaseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+impl From<sqlite::Error> for DatabaseError {
+    fn from(value: sqlite::Error) -> Self {
+        Self(value)
+    }
+}
 
 pub struct UserCredentials {
     pub name: String,
     pub password_hash: String,
+    pub email: Option<String>,
+    /// Unix timestamp, absent for accounts registered before it was recorded
+    pub registered_at: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct UserCredentialsRaw {
     pub name: String,
     pub password: String,
+    pub email: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum AuditAction {
+    Login,
+    Registration,
+    Rename,
+    AccountDeletion,
+    Kick,
+}
+
+impl AuditAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Login => "login",
+            Self::Registration => "registration",
+            Self::Rename => "rename",
+            Self::AccountDeletion => "account_deletion",
+            Self::Kick => "kick",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
+impl AuditOutcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::Failure => "failure",
+        }
+    }
+}
+
+pub struct AuditEvent {
+    pub timestamp: u64,
+    pub action: AuditAction,
+    pub user_name: String,
+    /// Peer address of the connection, absent for actions issued by the administrator
+    pub ip: Option<IpAddr>,
+    pub outcome: AuditOutcome,
+}
+
+pub struct AbuseReport {
+    pub timestamp: u64,
+    pub reporter: String,
+    pub target_user: String,
+    pub reason: String,
+    /// Message the report is about, as numbered by the chat server
+    pub message_id: Option<u64>,
+}
+
+pub struct Profile {
+    pub name: String,
+    /// Unix timestamp, absent for accounts registered before it was recorded
+    pub registered_at: Option<u64>,
+}
+
+pub struct PersistedMessage {
+    /// Absent for messages persisted before the id was recorded
+    pub server_msg_id: Option<u64>,
+    pub timestamp: u64,
+    pub author: String,
+    pub text: String,
+    pub room: Option<String>,
 }
 
 pub trait ServerDatabase {
-    fn get_user_by_name(&self, name: &str) -> Option<UserCredentials>;
-    fn add_new_user(&self, user_credentials: &UserCredentials);
+    fn get_user_by_name(&self, name: &str) -> Result<Option<UserCredentials>, DatabaseError>;
+    fn add_new_user(&self, user_credentials: &UserCredentials) -> Result<(), DatabaseError>;
+    fn rename_user(&self, old_name: &str, new_name: &str) -> Result<(), DatabaseError>;
+    fn delete_user(&self, name: &str) -> Result<(), DatabaseError>;
+    fn get_user_names(&self) -> Result<Vec<String>, DatabaseError>;
+    fn get_profile(&self, name: &str) -> Result<Option<Profile>, DatabaseError>;
+    /// Returns every user in the order they were registered
+    fn list_users(&self) -> Result<Vec<UserCredentials>, DatabaseError>;
+    /// Adds all users or none of them, existing users of the same name are replaced if `replace` is set
+    fn add_users(&self, users: &[UserCredentials], replace: bool) -> Result<(), DatabaseError>;
+    fn append_audit(&self, event: &AuditEvent) -> Result<(), DatabaseError>;
+    fn add_report(&self, report: &AbuseReport) -> Result<(), DatabaseError>;
+    fn append_message(&self, message: &PersistedMessage) -> Result<(), DatabaseError>;
+    fn update_message(&self, server_msg_id: u64, text: &str) -> Result<(), DatabaseError>;
+    fn delete_message(&self, server_msg_id: u64) -> Result<(), DatabaseError>;
+    /// Highest server message id persisted, new ids continue after it
+    fn get_last_server_msg_id(&self) -> Result<Option<u64>, DatabaseError>;
+    /// Returns the number of removed messages
+    fn prune_messages_older_than(&self, timestamp: u64) -> Result<usize, DatabaseError>;
+    /// Keeps only the newest `keep` messages of the room, returns the number of removed messages
+    fn trim_room_messages(&self, room: &str, keep: usize) -> Result<usize, DatabaseError>;
+    /// Returns at most `limit` newest messages of the room, oldest first
+    fn get_room_messages(
+        &self,
+        room: &str,
+        limit: usize,
+    ) -> Result<Vec<PersistedMessage>, DatabaseError>;
+    /// Returns at most `limit` messages of the room, or of no room, older than `before_id`,
+    /// newest first and paired with their ids
+    fn get_messages_before(
+        &self,
+        room: Option<&str>,
+        before_id: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<(u64, PersistedMessage)>, DatabaseError>;
 }
 
+const DATABASE_PATH: &str = "data/database.sqlite";
+
+/// Clones share the connection, so an auth backend can keep its own handle to the same database
+#[derive(Clone)]
 pub struct ServerSQLiteDatabase {
-    db: Connection,
+    db: Arc<ConnectionThreadSafe>,
 }
 
 impl Default for ServerSQLiteDatabase {
+    /// Panics if the database can't be opened, `try_new` lets the caller handle that
     fn default() -> Self {
-        fs::create_dir_all("data").expect("should have rights to access the working directory");
-        let connection = sqlite::open("data/database.sqlite").unwrap();
+        Self::try_new().expect("database should be usable")
+    }
+}
+
+impl ServerSQLiteDatabase {
+    pub fn try_new() -> Result<Self, DatabaseError> {
+        Self::try_open(Path::new(DATABASE_PATH))
+    }
+
+    /// Creates the database and its directory if they don't exist yet
+    pub fn try_open(path: &Path) -> Result<Self, DatabaseError> {
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory).map_err(|e| {
+                DatabaseError(sqlite::Error {
+                    code: None,
+                    message: Some(format!(
+                        "could not create directory '{}': {e}",
+                        directory.display()
+                    )),
+                })
+            })?;
+        }
+        let mut connection = Connection::open_thread_safe(path)?;
+
+        // WAL lets readers proceed while a registration is being written
+        connection.execute("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")?;
+        connection.set_busy_timeout(5000)?;
+
+        let duplicate_names = Self::migrate(&connection)?;
+        if !duplicate_names.is_empty() {
+            error!(
+                "User names differing only in case have to be resolved manually: {}.",
+                duplicate_names.join(", ")
+            );
+        }
+
+        Ok(Self {
+            db: Arc::new(connection),
+        })
+    }
+
+    /// Runs the migrations against the existing database without keeping their changes.
+    ///
+    /// Returns the user names differing only in case, which the migrations cannot resolve.
+    pub fn check_migrations() -> Result<Vec<String>, DatabaseError> {
+        // A missing database is created on startup, there is nothing to migrate yet
+        if !Path::new(DATABASE_PATH).exists() {
+            return Ok(Vec::new());
+        }
 
+        let mut connection =
+            Connection::open_with_flags(DATABASE_PATH, OpenFlags::new().with_read_write())?;
+        connection.set_busy_timeout(5000)?;
+
+        connection.execute("BEGIN;")?;
+        let duplicate_names = Self::migrate(&connection);
+        connection.execute("ROLLBACK;")?;
+
+        duplicate_names
+    }
+
+    /// Returns the user names differing only in case, which prevent the case-insensitive index
+    fn migrate(connection: &Connection) -> Result<Vec<String>, DatabaseError> {
         let create_tables_query = "
             CREATE TABLE IF NOT EXISTS user_credentials (
                 id INTEGER PRIMARY KEY AUTOINCREMENT, 
                 name TEXT UNIQUE NOT NULL, 
-                password_hash TEXT NOT NULL
+                password_hash TEXT NOT NULL,
+                email TEXT
+            );
+            CREATE TABLE IF NOT EXISTS audit_log (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                action TEXT NOT NULL,
+                user_name TEXT NOT NULL,
+                ip TEXT,
+                outcome TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                author TEXT NOT NULL,
+                text TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS messages_timestamp ON messages (timestamp);
+            CREATE TABLE IF NOT EXISTS reports (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                reporter TEXT NOT NULL,
+                target_user TEXT NOT NULL,
+                reason TEXT NOT NULL,
+                message_id INTEGER
             );
         ";
 
-        connection.execute(create_tables_query).unwrap();
+        connection.execute(create_tables_query)?;
+
+        // Messages persisted before rooms were introduced were all sent to everyone
+        let has_room_column = {
+            let mut statement = connection
+                .prepare("SELECT 1 FROM pragma_table_info('messages') WHERE name = 'room';")?;
+            matches!(statement.next(), Ok(State::Row))
+        };
+        if !has_room_column {
+            connection.execute("ALTER TABLE messages ADD COLUMN room TEXT;")?;
+        }
+        connection.execute("CREATE INDEX IF NOT EXISTS messages_room ON messages (room, id);")?;
+
+        // Edits and deletions find persisted messages by the id clients know them by
+        let has_server_msg_id_column = {
+            let mut statement = connection.prepare(
+                "SELECT 1 FROM pragma_table_info('messages') WHERE name = 'server_msg_id';",
+            )?;
+            matches!(statement.next(), Ok(State::Row))
+        };
+        if !has_server_msg_id_column {
+            connection.execute("ALTER TABLE messages ADD COLUMN server_msg_id INTEGER;")?;
+        }
+        connection.execute(
+            "CREATE INDEX IF NOT EXISTS messages_server_msg_id ON messages (server_msg_id);",
+        )?;
 
-        Self { db: connection }
+        // Databases created before emails were introduced lack the column
+        let has_email_column = {
+            let mut statement = connection.prepare(
+                "SELECT 1 FROM pragma_table_info('user_credentials') WHERE name = 'email';",
+            )?;
+            matches!(statement.next(), Ok(State::Row))
+        };
+        if !has_email_column {
+            connection.execute("ALTER TABLE user_credentials ADD COLUMN email TEXT;")?;
+        }
+
+        // Registration time of accounts created before it was recorded stays unknown
+        let has_registered_at_column = {
+            let mut statement = connection.prepare(
+                "SELECT 1 FROM pragma_table_info('user_credentials') WHERE name = 'registered_at';",
+            )?;
+            matches!(statement.next(), Ok(State::Row))
+        };
+        if !has_registered_at_column {
+            connection.execute("ALTER TABLE user_credentials ADD COLUMN registered_at INTEGER;")?;
+        }
+
+        // Names are unique regardless of case, but older databases may already hold such duplicates
+        let duplicate_names = {
+            let mut statement = connection.prepare(
+                "SELECT name FROM user_credentials WHERE lower(name) IN (
+                    SELECT lower(name) FROM user_credentials
+                    GROUP BY lower(name) HAVING COUNT(*) > 1
+                ) ORDER BY lower(name), id;",
+            )?;
+            let mut names = Vec::<String>::new();
+            while let State::Row = statement.next()? {
+                names.push(statement.read::<String, _>("name")?);
+            }
+            names
+        };
+        if duplicate_names.is_empty() {
+            connection.execute(
+                "CREATE UNIQUE INDEX IF NOT EXISTS user_credentials_name_nocase
+                    ON user_credentials (name COLLATE NOCASE);",
+            )?;
+        }
+
+        Ok(duplicate_names)
     }
 }
 
 impl ServerDatabase for ServerSQLiteDatabase {
-    fn get_user_by_name(&self, name: &str) -> Option<UserCredentials> {
-        let query = "SELECT * FROM user_credentials WHERE name = ?;";
+    fn get_user_by_name(&self, name: &str) -> Result<Option<UserCredentials>, DatabaseError> {
+        let query = "SELECT * FROM user_credentials WHERE name = ? COLLATE NOCASE ORDER BY id;";
 
-        let mut statement = self.db.prepare(query).unwrap();
-        statement.bind((1, name)).unwrap();
-        if let Ok(State::Row) = statement.next() {
+        let mut statement = self.db.prepare(query)?;
+        statement.bind((1, name))?;
+        if let State::Row = statement.next()? {
             let user_credentials = UserCredentials {
-                name: statement.read::<String, _>("name").unwrap(),
-                password_hash: statement.read::<String, _>("password_hash").unwrap(),
+                name: statement.read::<String, _>("name")?,
+                password_hash: statement.read::<String, _>("password_hash")?,
+                email: statement.read::<Option<String>, _>("email")?,
+                registered_at: statement
+                    .read::<Option<i64>, _>("registered_at")?
+                    .map(|registered_at| registered_at as u64),
             };
-            Some(user_credentials)
+            Ok(Some(user_credentials))
         } else {
-            None
+            Ok(None)
         }
     }
 
-    fn add_new_user(&self, user_credentials: &UserCredentials) {
-        let query = "INSERT INTO user_credentials (name, password_hash) VALUES (?, ?);";
+    fn add_new_user(&self, user_credentials: &UserCredentials) -> Result<(), DatabaseError> {
+        let query = "INSERT INTO user_credentials (name, password_hash, email, registered_at) VALUES (?, ?, ?, ?);";
 
-        let mut statement = self.db.prepare(query).unwrap();
-        statement.bind((1, user_credentials.name.as_str())).unwrap();
-        statement
-            .bind((2, user_credentials.password_hash.as_str()))
-            .unwrap();
+        let mut statement = self.db.prepare(query)?;
+        statement.bind((1, user_credentials.name.as_str()))?;
+        statement.bind((2, user_credentials.password_hash.as_str()))?;
+        statement.bind((3, user_credentials.email.as_deref()))?;
+        statement.bind((
+            4,
+            user_credentials
+                .registered_at
+                .map(|registered_at| registered_at as i64),
+        ))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    fn rename_user(&self, old_name: &str, new_name: &str) -> Result<(), DatabaseError> {
+        // Everything referring to the user by name follows the account, or nothing does
+        const QUERIES: [&str; 5] = [
+            "UPDATE user_credentials SET name = ? WHERE name = ? COLLATE NOCASE;",
+            "UPDATE messages SET author = ? WHERE author = ? COLLATE NOCASE;",
+            "UPDATE reports SET reporter = ? WHERE reporter = ? COLLATE NOCASE;",
+            "UPDATE reports SET target_user = ? WHERE target_user = ? COLLATE NOCASE;",
+            "UPDATE audit_log SET user_name = ? WHERE user_name = ? COLLATE NOCASE;",
+        ];
+
+        self.db.execute("BEGIN;")?;
+        let result = QUERIES.iter().try_for_each(|query| {
+            let mut statement = self.db.prepare(*query)?;
+            statement.bind((1, new_name))?;
+            statement.bind((2, old_name))?;
+            statement.next()?;
+            Ok(())
+        });
+        match result {
+            Ok(_) => self.db.execute("COMMIT;")?,
+            Err(_) => self.db.execute("ROLLBACK;")?,
+        }
+        result
+    }
+
+    fn delete_user(&self, name: &str) -> Result<(), DatabaseError> {
+        let query = "DELETE FROM user_credentials WHERE name = ? COLLATE NOCASE;";
+
+        let mut statement = self.db.prepare(query)?;
+        statement.bind((1, name))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    fn get_user_names(&self) -> Result<Vec<String>, DatabaseError> {
+        let query = "SELECT name FROM user_credentials ORDER BY name COLLATE NOCASE;";
+
+        let mut statement = self.db.prepare(query)?;
+        let mut names = Vec::new();
+        while let State::Row = statement.next()? {
+            names.push(statement.read::<String, _>("name")?);
+        }
+        Ok(names)
+    }
+
+    fn get_profile(&self, name: &str) -> Result<Option<Profile>, DatabaseError> {
+        let query = "SELECT name, registered_at FROM user_credentials WHERE name = ? COLLATE NOCASE ORDER BY id;";
+
+        let mut statement = self.db.prepare(query)?;
+        statement.bind((1, name))?;
+        if let State::Row = statement.next()? {
+            Ok(Some(Profile {
+                name: statement.read::<String, _>("name")?,
+                registered_at: statement
+                    .read::<Option<i64>, _>("registered_at")?
+                    .map(|registered_at| registered_at as u64),
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn list_users(&self) -> Result<Vec<UserCredentials>, DatabaseError> {
+        let query = "SELECT * FROM user_credentials ORDER BY id;";
+
+        let mut statement = self.db.prepare(query)?;
+        let mut users = Vec::new();
+        while let State::Row = statement.next()? {
+            users.push(UserCredentials {
+                name: statement.read::<String, _>("name")?,
+                password_hash: statement.read::<String, _>("password_hash")?,
+                email: statement.read::<Option<String>, _>("email")?,
+                registered_at: statement
+                    .read::<Option<i64>, _>("registered_at")?
+                    .map(|registered_at| registered_at as u64),
+            });
+        }
+        Ok(users)
+    }
+
+    fn add_users(&self, users: &[UserCredentials], replace: bool) -> Result<(), DatabaseError> {
+        self.db.execute("BEGIN;")?;
+        let result = users.iter().try_for_each(|user_credentials| {
+            if replace {
+                self.delete_user(&user_credentials.name)?;
+            }
+            self.add_new_user(user_credentials)
+        });
+        match result {
+            Ok(_) => self.db.execute("COMMIT;")?,
+            Err(_) => self.db.execute("ROLLBACK;")?,
+        }
+        result
+    }
+
+    fn append_audit(&self, event: &AuditEvent) -> Result<(), DatabaseError> {
+        let query = "INSERT INTO audit_log (timestamp, action, user_name, ip, outcome) VALUES (?, ?, ?, ?, ?);";
+
+        let mut statement = self.db.prepare(query)?;
+        statement.bind((1, event.timestamp as i64))?;
+        statement.bind((2, event.action.as_str()))?;
+        statement.bind((3, event.user_name.as_str()))?;
+        statement.bind((4, event.ip.map(|ip| ip.to_string()).as_deref()))?;
+        statement.bind((5, event.outcome.as_str()))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    fn add_report(&self, report: &AbuseReport) -> Result<(), DatabaseError> {
+        let query = "INSERT INTO reports (timestamp, reporter, target_user, reason, message_id) VALUES (?, ?, ?, ?, ?);";
+
+        let mut statement = self.db.prepare(query)?;
+        statement.bind((1, report.timestamp as i64))?;
+        statement.bind((2, report.reporter.as_str()))?;
+        statement.bind((3, report.target_user.as_str()))?;
+        statement.bind((4, report.reason.as_str()))?;
+        statement.bind((5, report.message_id.map(|id| id as i64)))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    fn append_message(&self, message: &PersistedMessage) -> Result<(), DatabaseError> {
+        let query = "INSERT INTO messages (timestamp, author, text, room, server_msg_id) VALUES (?, ?, ?, ?, ?);";
+
+        let mut statement = self.db.prepare(query)?;
+        statement.bind((1, message.timestamp as i64))?;
+        statement.bind((2, message.author.as_str()))?;
+        statement.bind((3, message.text.as_str()))?;
+        statement.bind((4, message.room.as_deref()))?;
+        statement.bind((5, message.server_msg_id.map(|id| id as i64)))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    fn update_message(&self, server_msg_id: u64, text: &str) -> Result<(), DatabaseError> {
+        let query = "UPDATE messages SET text = ? WHERE server_msg_id = ?;";
+
+        let mut statement = self.db.prepare(query)?;
+        statement.bind((1, text))?;
+        statement.bind((2, server_msg_id as i64))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    fn delete_message(&self, server_msg_id: u64) -> Result<(), DatabaseError> {
+        let query = "DELETE FROM messages WHERE server_msg_id = ?;";
+
+        let mut statement = self.db.prepare(query)?;
+        statement.bind((1, server_msg_id as i64))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    fn get_last_server_msg_id(&self) -> Result<Option<u64>, DatabaseError> {
+        let query = "SELECT MAX(server_msg_id) AS last_id FROM messages;";
+
+        let mut statement = self.db.prepare(query)?;
+        statement.next()?;
+        Ok(statement
+            .read::<Option<i64>, _>("last_id")?
+            .map(|id| id as u64))
+    }
+
+    fn prune_messages_older_than(&self, timestamp: u64) -> Result<usize, DatabaseError> {
+        let query = "DELETE FROM messages WHERE timestamp < ?;";
+
+        let mut statement = self.db.prepare(query)?;
+        statement.bind((1, timestamp as i64))?;
+        statement.next()?;
+        Ok(self.db.change_count())
+    }
+
+    fn trim_room_messages(&self, room: &str, keep: usize) -> Result<usize, DatabaseError> {
+        let query = "DELETE FROM messages WHERE room = ? AND id NOT IN (
+            SELECT id FROM messages WHERE room = ? ORDER BY id DESC LIMIT ?
+        );";
+
+        let mut statement = self.db.prepare(query)?;
+        statement.bind((1, room))?;
+        statement.bind((2, room))?;
+        statement.bind((3, keep as i64))?;
+        statement.next()?;
+        Ok(self.db.change_count())
+    }
+
+    fn get_room_messages(
+        &self,
+        room: &str,
+        limit: usize,
+    ) -> Result<Vec<PersistedMessage>, DatabaseError> {
+        let query = "SELECT * FROM (
+            SELECT * FROM messages WHERE room = ? ORDER BY id DESC LIMIT ?
+        ) ORDER BY id;";
+
+        let mut statement = self.db.prepare(query)?;
+        statement.bind((1, room))?;
+        statement.bind((2, limit as i64))?;
+
+        let mut messages = Vec::<PersistedMessage>::new();
+        while let State::Row = statement.next()? {
+            messages.push(PersistedMessage {
+                server_msg_id: statement
+                    .read::<Option<i64>, _>("server_msg_id")?
+                    .map(|id| id as u64),
+                timestamp: statement.read::<i64, _>("timestamp")? as u64,
+                author: statement.read::<String, _>("author")?,
+                text: statement.read::<String, _>("text")?,
+                room: statement.read::<Option<String>, _>("room")?,
+            });
+        }
+        Ok(messages)
+    }
+
+    fn get_messages_before(
+        &self,
+        room: Option<&str>,
+        before_id: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<(u64, PersistedMessage)>, DatabaseError> {
+        // Served by the (room, id) index, `IS` matches the messages without a room as well
+        let query = "SELECT * FROM messages WHERE room IS ? AND id < ? ORDER BY id DESC LIMIT ?;";
+
+        let mut statement = self.db.prepare(query)?;
+        statement.bind((1, room))?;
+        statement.bind((2, before_id.map_or(i64::MAX, |id| id as i64)))?;
+        statement.bind((3, limit as i64))?;
+
+        let mut messages = Vec::<(u64, PersistedMessage)>::new();
+        while let State::Row = statement.next()? {
+            messages.push((
+                statement.read::<i64, _>("id")? as u64,
+                PersistedMessage {
+                    server_msg_id: statement
+                        .read::<Option<i64>, _>("server_msg_id")?
+                        .map(|id| id as u64),
+                    timestamp: statement.read::<i64, _>("timestamp")? as u64,
+                    author: statement.read::<String, _>("author")?,
+                    text: statement.read::<String, _>("text")?,
+                    room: statement.read::<Option<String>, _>("room")?,
+                },
+            ));
+        }
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        env,
+        path::PathBuf,
+        process, thread,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    #[test]
+    fn in_memory_database_opens() {
+        let database = ServerSQLiteDatabase::try_open(Path::new(":memory:")).unwrap();
+
+        assert!(database.get_user_names().unwrap().is_empty());
+    }
+
+    #[test]
+    fn unwritable_path_returns_err() {
+        // Directory can't be created where a file already is
+        let blocker = env::temp_dir().join(format!("chat-db-blocker-{}", process::id()));
+        fs::write(&blocker, b"").unwrap();
+
+        let result = ServerSQLiteDatabase::try_open(&blocker.join("database.sqlite"));
+        fs::remove_file(&blocker).unwrap();
+
+        assert!(result.is_err());
+    }
+
+    fn count(database: &ServerSQLiteDatabase, query: &str, name: &str) -> i64 {
+        let mut statement = database.db.prepare(query).unwrap();
+        statement.bind((1, name)).unwrap();
         statement.next().unwrap();
+        statement.read::<i64, _>(0).unwrap()
+    }
+
+    /// Action, user name, address and outcome of every audit entry, oldest first
+    pub(crate) fn audit_log(
+        database: &ServerSQLiteDatabase,
+    ) -> Vec<(String, String, Option<String>, String)> {
+        let query = "SELECT action, user_name, ip, outcome FROM audit_log ORDER BY rowid;";
+        let mut statement = database.db.prepare(query).unwrap();
+        let mut entries = Vec::new();
+        while statement.next().unwrap() == State::Row {
+            entries.push((
+                statement.read::<String, _>(0).unwrap(),
+                statement.read::<String, _>(1).unwrap(),
+                statement.read::<Option<String>, _>(2).unwrap(),
+                statement.read::<String, _>(3).unwrap(),
+            ));
+        }
+        entries
+    }
+
+    /// Reporter, reported user, reason and message id of every report, oldest first
+    pub(crate) fn reports(
+        database: &ServerSQLiteDatabase,
+    ) -> Vec<(String, String, String, Option<i64>)> {
+        let query = "SELECT reporter, target_user, reason, message_id FROM reports ORDER BY id;";
+        let mut statement = database.db.prepare(query).unwrap();
+        let mut reports = Vec::new();
+        while statement.next().unwrap() == State::Row {
+            reports.push((
+                statement.read::<String, _>(0).unwrap(),
+                statement.read::<String, _>(1).unwrap(),
+                statement.read::<String, _>(2).unwrap(),
+                statement.read::<Option<i64>, _>(3).unwrap(),
+            ));
+        }
+        reports
+    }
+
+    #[test]
+    fn renamed_user_keeps_messages_reports_and_audit_entries() {
+        let database = ServerSQLiteDatabase::try_open(Path::new(":memory:")).unwrap();
+        database
+            .add_new_user(&UserCredentials {
+                name: "alice01".to_string(),
+                password_hash: "hash".to_string(),
+                email: None,
+                registered_at: None,
+            })
+            .unwrap();
+        database
+            .append_message(&PersistedMessage {
+                server_msg_id: Some(1),
+                timestamp: 1,
+                author: "alice01".to_string(),
+                text: "hello".to_string(),
+                room: None,
+            })
+            .unwrap();
+        database
+            .add_report(&AbuseReport {
+                timestamp: 2,
+                reporter: "alice01".to_string(),
+                target_user: "ALICE01".to_string(),
+                reason: "testing".to_string(),
+                message_id: None,
+            })
+            .unwrap();
+        database
+            .append_audit(&AuditEvent {
+                timestamp: 3,
+                action: AuditAction::Login,
+                user_name: "alice01".to_string(),
+                ip: None,
+                outcome: AuditOutcome::Success,
+            })
+            .unwrap();
+
+        database.rename_user("Alice01", "alice02").unwrap();
+
+        assert!(database.get_user_by_name("alice01").unwrap().is_none());
+        assert!(database.get_user_by_name("alice02").unwrap().is_some());
+        for (query, expected) in [
+            ("SELECT COUNT(*) FROM messages WHERE author = ?;", 1),
+            ("SELECT COUNT(*) FROM reports WHERE reporter = ?;", 1),
+            ("SELECT COUNT(*) FROM reports WHERE target_user = ?;", 1),
+            ("SELECT COUNT(*) FROM audit_log WHERE user_name = ?;", 1),
+        ] {
+            assert_eq!(count(&database, query, "alice02"), expected, "{query}");
+        }
+    }
+
+    fn temp_database_path(test: &str) -> PathBuf {
+        env::temp_dir()
+            .join(format!("chat-db-{test}-{}", process::id()))
+            .join("database.sqlite")
+    }
+
+    /// Writes from every connection at once, each on its own thread, returns how long it took
+    fn write_concurrently(path: &Path, writers: u64, messages_per_writer: u64) -> Duration {
+        // Opened up front, so only the writes themselves contend for the database
+        let databases: Vec<ServerSQLiteDatabase> = (0..writers)
+            .map(|_| ServerSQLiteDatabase::try_open(path).unwrap())
+            .collect();
+
+        let started = Instant::now();
+        thread::scope(|scope| {
+            for (writer, database) in (0..writers).zip(&databases) {
+                scope.spawn(move || {
+                    for index in 0..messages_per_writer {
+                        let server_msg_id = writer * messages_per_writer + index + 1;
+                        database
+                            .append_message(&PersistedMessage {
+                                server_msg_id: Some(server_msg_id),
+                                timestamp: server_msg_id,
+                                author: format!("writer{writer}"),
+                                text: format!("message {index}"),
+                                room: None,
+                            })
+                            .unwrap();
+                    }
+                    database
+                        .add_new_user(&UserCredentials {
+                            name: format!("writer{writer}"),
+                            password_hash: "hash".to_string(),
+                            email: None,
+                            registered_at: None,
+                        })
+                        .unwrap();
+                });
+            }
+        });
+        started.elapsed()
+    }
+
+    #[test]
+    fn concurrent_writers_wait_for_each_other() {
+        let path = temp_database_path("concurrent-writers");
+
+        write_concurrently(&path, 4, 50);
+        let database = ServerSQLiteDatabase::try_open(&path).unwrap();
+        let last_id = database.get_last_server_msg_id().unwrap();
+        let user_count = database.get_user_names().unwrap().len();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+        assert_eq!(last_id, Some(200));
+        assert_eq!(user_count, 4);
+    }
+
+    /// Run with `cargo test -- --ignored --nocapture` to see the write throughput
+    #[test]
+    #[ignore]
+    fn concurrent_write_throughput() {
+        let path = temp_database_path("write-throughput");
+        let (writers, messages_per_writer) = (8, 500);
+
+        let elapsed = write_concurrently(&path, writers, messages_per_writer);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+        let writes = writers * (messages_per_writer + 1);
+        println!(
+            "{writes} writes from {writers} connections in {elapsed:?}, {:.0} writes/s",
+            writes as f64 / elapsed.as_secs_f64()
+        );
+    }
+
+    fn credentials(name: &str) -> UserCredentials {
+        UserCredentials {
+            name: name.to_string(),
+            password_hash: "hash".to_string(),
+            email: None,
+            registered_at: None,
+        }
+    }
+
+    #[test]
+    fn names_differing_only_in_case_are_refused() {
+        let database = ServerSQLiteDatabase::try_open(Path::new(":memory:")).unwrap();
+        database.add_new_user(&credentials("alice01")).unwrap();
+
+        assert!(database.add_new_user(&credentials("ALICE01")).is_err());
+        assert_eq!(database.get_user_names().unwrap(), vec!["alice01"]);
+    }
+
+    #[test]
+    fn migration_reports_names_differing_only_in_case() {
+        // Users table as it was before names became unique regardless of case
+        let connection = Connection::open(":memory:").unwrap();
+        connection
+            .execute(
+                "CREATE TABLE user_credentials (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    name TEXT UNIQUE NOT NULL,
+                    password_hash TEXT NOT NULL
+                );
+                INSERT INTO user_credentials (name, password_hash)
+                    VALUES ('alice01', 'hash'), ('bob01', 'hash'), ('Alice01', 'hash');",
+            )
+            .unwrap();
+
+        let duplicate_names = ServerSQLiteDatabase::migrate(&connection).unwrap();
+        assert_eq!(duplicate_names, vec!["alice01", "Alice01"]);
+        // Index is left out until the duplicates are resolved
+        connection
+            .execute("INSERT INTO user_credentials (name, password_hash) VALUES ('BOB01', 'hash');")
+            .unwrap();
+
+        connection
+            .execute("DELETE FROM user_credentials WHERE name IN ('Alice01', 'BOB01');")
+            .unwrap();
+        assert!(ServerSQLiteDatabase::migrate(&connection)
+            .unwrap()
+            .is_empty());
+        assert!(connection
+            .execute(
+                "INSERT INTO user_credentials (name, password_hash) VALUES ('ALICE01', 'hash');"
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn messages_older_than_the_cutoff_are_pruned() {
+        let database = ServerSQLiteDatabase::try_open(Path::new(":memory:")).unwrap();
+        for (server_msg_id, timestamp) in [(1, 100), (2, 199), (3, 200), (4, 300)] {
+            database
+                .append_message(&PersistedMessage {
+                    server_msg_id: Some(server_msg_id),
+                    timestamp,
+                    author: "alice".to_string(),
+                    text: format!("message {server_msg_id}"),
+                    room: None,
+                })
+                .unwrap();
+        }
+
+        assert_eq!(database.prune_messages_older_than(200).unwrap(), 2);
+
+        let kept: Vec<u64> = database
+            .get_messages_before(None, None, 10)
+            .unwrap()
+            .into_iter()
+            .map(|(_, message)| message.timestamp)
+            .collect();
+        assert_eq!(kept, vec![300, 200]);
+        assert_eq!(database.prune_messages_older_than(200).unwrap(), 0);
     }
 }
@@ -1,27 +1,367 @@
-use std::{collections::HashMap, io, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::{hash_map::Entry, HashMap},
+    future::Future,
+    io::{self, Read, Write},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use log::{error, info, warn};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use futures::{future::join_all, stream, Stream, StreamExt};
+use hmac::{Hmac, Mac};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use sha2::Sha256;
+use socket2::{SockRef, TcpKeepalive};
 use tokio::{
     net::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
         TcpListener, TcpStream,
     },
-    signal, spawn,
-    sync::Mutex,
-    task::yield_now,
+    signal,
+    sync::{mpsc, oneshot, watch, Mutex, Notify},
+    task::{yield_now, AbortHandle},
+    time::{interval, sleep, sleep_until, timeout},
 };
+use tracing::{error, field, info, info_span, warn, Instrument};
 use uuid::Uuid;
 
 use crate::{
-    server::{ChatServer, ChatServerResponseCommand},
-    server_database::ServerDatabase,
+    admin_server::{run_admin_server, AdminCommandReceiver},
+    console::console_loop,
+    health_server::run_health_server,
+    server::{
+        delivery_report_message, remove_expired_attachments, ChatServer, ChatServerResponseCommand,
+    },
+    server_database::{ServerDatabase, ServerSQLiteDatabase},
 };
 
+const ACCEPT_BACKOFF_BASE: Duration = Duration::from_millis(100);
+const ACCEPT_BACKOFF_CAP: Duration = Duration::from_secs(5);
+const ACCEPT_FAILURES_SUMMARY_INTERVAL: Duration = Duration::from_secs(30);
+
+const SESSION_EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+const PRESENCE_FLUSH_INTERVAL: Duration = Duration::from_millis(50);
+const LIVE_STATS_INTERVAL: Duration = Duration::from_secs(1);
+const SLOW_CONSUMER_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+const FRAME_TAG_LEN: usize = 32;
+/// Capacity a connection's read buffer keeps between frames, enough for typical chat messages
+const READ_BUFFER_RETAINED_CAPACITY: usize = 16 * 1024;
+
+#[derive(PartialEq)]
+pub struct TcpServerOptions {
+    pub compression_threshold: Option<usize>,
+    pub health_address: Option<String>,
+    pub admin: Option<AdminOptions>,
+    pub idle_timeout: Option<Duration>,
+    /// How long before the idle timeout the client is warned, any frame it sends meanwhile keeps it
+    pub idle_warning: Option<Duration>,
+    pub max_connections_per_ip: Option<usize>,
+    pub socket_options: SocketOptions,
+    pub slow_consumer_limit: Option<SlowConsumerLimit>,
+    pub history_retention: Option<HistoryRetention>,
+    pub attachment_retention: Option<AttachmentRetention>,
+    pub frame_format: FrameFormat,
+    pub stats_broadcast: Option<StatsBroadcast>,
+    /// How long the shutdown waits for queued frames to be written before dropping them
+    pub shutdown_grace: Option<Duration>,
+    /// Reads console commands from stdin, embedded servers leave stdin to their host
+    pub console: bool,
+}
+
+/// Applied to every accepted connection
+#[derive(PartialEq)]
+pub struct SocketOptions {
+    pub nodelay: bool,
+    /// Idle time before the OS starts probing the peer, keepalive is off without it
+    pub keepalive: Option<Duration>,
+}
+
+impl SocketOptions {
+    fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(keepalive) = self.keepalive {
+            SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(keepalive))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(PartialEq)]
+pub struct StatsBroadcast {
+    pub interval: Duration,
+    pub admins_only: bool,
+}
+
+/// Width of the little-endian frame header.
+///
+/// The highest bit of the header marks a gzip-compressed body, the rest is the body length.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HeaderSize {
+    Two,
+    Four,
+    Eight,
+}
+
+impl HeaderSize {
+    fn len(self) -> usize {
+        match self {
+            Self::Two => 2,
+            Self::Four => 4,
+            Self::Eight => 8,
+        }
+    }
+
+    fn compressed_flag(self) -> u64 {
+        1 << (self.len() * 8 - 1)
+    }
+
+    pub fn max_body_size(self) -> u64 {
+        self.compressed_flag() - 1
+    }
+
+    fn encode(self, body_size: usize, is_compressed: bool) -> io::Result<Vec<u8>> {
+        let mut header = body_size as u64;
+        if header > self.max_body_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "message of {body_size} bytes does not fit a {} byte header",
+                    self.len()
+                ),
+            ));
+        }
+        if is_compressed {
+            header |= self.compressed_flag();
+        }
+        Ok(header.to_le_bytes()[..self.len()].to_vec())
+    }
+
+    /// Returns the body size and whether the body is compressed
+    fn decode(self, header_bytes: &[u8]) -> (u64, bool) {
+        let mut bytes = [0; 8];
+        bytes[..self.len()].copy_from_slice(header_bytes);
+        let header = u64::from_le_bytes(bytes);
+        (
+            header & !self.compressed_flag(),
+            header & self.compressed_flag() != 0,
+        )
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct FrameFormat {
+    pub header_size: HeaderSize,
+    /// Larger incoming frames are rejected before their body is read
+    pub max_body_size: usize,
+    /// Pre-shared key of the HMAC-SHA256 tag following each body, frames are not tagged without it
+    pub signing_key: Option<Arc<[u8]>>,
+    /// Longest time an incoming frame may take from its first byte to its last
+    pub max_read_duration: Option<Duration>,
+}
+
+impl FrameFormat {
+    fn tag_len(&self) -> usize {
+        if self.signing_key.is_some() {
+            FRAME_TAG_LEN
+        } else {
+            0
+        }
+    }
+}
+
+fn frame_mac(key: &[u8], body: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    mac
+}
+
+#[derive(PartialEq)]
+pub struct HistoryRetention {
+    pub max_age: Duration,
+    pub prune_interval: Duration,
+}
+
+#[derive(PartialEq)]
+pub struct AttachmentRetention {
+    pub storage_dir: PathBuf,
+    pub max_age: Duration,
+    pub cleanup_interval: Duration,
+}
+
+#[derive(PartialEq)]
+pub struct SlowConsumerLimit {
+    pub max_pending_frames: usize,
+    /// How long the pending frames may stay above the limit before the connection is dropped
+    pub grace: Duration,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct AdminOptions {
+    pub address: String,
+    pub token: String,
+}
+
+#[derive(Clone)]
+struct Connection {
+    // Frames are written by the connection's writer task in the order they are queued
+    sender: mpsc::UnboundedSender<Outgoing>,
+    // Frames queued but not written yet
+    pending: Arc<AtomicUsize>,
+    // Since when the pending frames have been above the slow consumer limit
+    slow_since: Option<Instant>,
+    writer: Arc<AbortHandle>,
+    closed: Arc<Notify>,
+    compression: bool,
+}
+
+impl Connection {
+    /// Queues a frame for the writer task, it counts as pending until the writer is done with it
+    fn queue_frame(
+        &self,
+        bytes: Arc<[u8]>,
+        is_compressed: bool,
+        written: Option<oneshot::Sender<bool>>,
+    ) -> bool {
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        let frame = Outgoing::Frame {
+            bytes,
+            is_compressed,
+            written,
+        };
+        if self.sender.send(frame).is_err() {
+            // Writer is gone and will never take the frame off the count
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+}
+
+enum Outgoing {
+    Frame {
+        bytes: Arc<[u8]>,
+        is_compressed: bool,
+        /// Told whether the frame has been written
+        written: Option<oneshot::Sender<bool>>,
+    },
+    /// Closes the connection once everything queued before it has been written
+    Close,
+    /// Answered once everything queued before it has been written
+    Flush(oneshot::Sender<()>),
+}
+
+/// Live connection count of a peer, decremented when the slot is dropped
+struct ConnectionSlot {
+    ip: IpAddr,
+    // Updated from `Drop`, so it can't be an async mutex
+    connections_per_ip: Arc<StdMutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ConnectionSlot {
+    fn acquire(
+        connections_per_ip: &Arc<StdMutex<HashMap<IpAddr, usize>>>,
+        ip: IpAddr,
+        limit: Option<usize>,
+    ) -> Option<Self> {
+        let mut counts = connections_per_ip.lock().unwrap();
+        let count = counts.entry(ip).or_default();
+        if limit.is_some_and(|limit| *count >= limit) {
+            return None;
+        }
+        *count += 1;
+
+        Some(Self {
+            ip,
+            connections_per_ip: connections_per_ip.clone(),
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut counts = self.connections_per_ip.lock().unwrap();
+        if let Entry::Occupied(mut count) = counts.entry(self.ip) {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                count.remove();
+            }
+        }
+    }
+}
+
 pub struct ChatTcpServer<T: ServerDatabase> {
     address: String,
-    listener: Arc<TcpListener>,
-    connections: Arc<Mutex<HashMap<String, Arc<OwnedWriteHalf>>>>,
+    local_address: SocketAddr,
+    listener: TcpListener,
+    connections: Arc<Mutex<HashMap<String, Connection>>>,
+    connections_per_ip: Arc<StdMutex<HashMap<IpAddr, usize>>>,
     chat_server: Arc<Mutex<ChatServer<T>>>,
+    options: Arc<TcpServerOptions>,
+}
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum ServerState {
+    Running,
+    ShutdownRequested,
+    /// Clients have been told goodbye, connection tasks should end
+    Closing,
+    Stopped,
+}
+
+/// Lets connection tasks see the shutdown, and lets the shutdown wait for them to end
+#[derive(Clone)]
+struct ShutdownSignal {
+    state: watch::Receiver<ServerState>,
+    // Every connection task holds a sender, so the channel closes once all of them have ended
+    _alive_sender: mpsc::Sender<()>,
+}
+
+impl ShutdownSignal {
+    async fn closing(&mut self) {
+        let _ = self
+            .state
+            .wait_for(|state| *state >= ServerState::Closing)
+            .await;
+    }
+}
+
+/// Controls a started server, can be cloned and sent to other tasks
+#[derive(Clone)]
+pub struct ServerHandle {
+    address: SocketAddr,
+    state: Arc<watch::Sender<ServerState>>,
+}
+
+impl ServerHandle {
+    pub fn addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Stops the server and resolves once all connection tasks have ended.
+    ///
+    /// Calling it again only waits for the shutdown which is already in progress.
+    pub async fn shutdown(&self) {
+        self.state.send_if_modified(|state| {
+            if *state != ServerState::Running {
+                return false;
+            }
+            *state = ServerState::ShutdownRequested;
+            true
+        });
+
+        let _ = self
+            .state
+            .subscribe()
+            .wait_for(|state| *state == ServerState::Stopped)
+            .await;
+    }
 }
 
 impl<T: ServerDatabase + Send + 'static> ChatTcpServer<T> {
@@ -29,6 +369,7 @@ impl<T: ServerDatabase + Send + 'static> ChatTcpServer<T> {
         host: &str,
         port: u16,
         chat_server: ChatServer<T>,
+        options: TcpServerOptions,
     ) -> Result<Self, ()> {
         let address = format!("{host}:{port}");
 
@@ -36,279 +377,3012 @@ impl<T: ServerDatabase + Send + 'static> ChatTcpServer<T> {
         let listener = TcpListener::bind(address_ref).await.map_err(|err| {
             error!("Could not bind {address_ref} to the server ({err}).");
         })?;
+        let local_address = listener.local_addr().map_err(|err| {
+            error!("Could not get the local address of {address_ref} ({err}).");
+        })?;
 
         Ok(Self {
             address,
-            listener: Arc::new(listener),
+            local_address,
+            listener,
             connections: Arc::new(Mutex::new(HashMap::new())),
+            connections_per_ip: Arc::new(StdMutex::new(HashMap::new())),
             chat_server: Arc::new(Mutex::new(chat_server)),
+            options: Arc::new(options),
         })
     }
 
+    pub fn chat_server(&self) -> Arc<Mutex<ChatServer<T>>> {
+        self.chat_server.clone()
+    }
+
+    /// Runs the server until CTRL^C is pressed
     pub async fn run(self) {
+        let handle = self.start();
+
+        let signals = stream::unfold((), |()| async {
+            signal::ctrl_c().await.unwrap();
+            Some(((), ()))
+        });
+        coordinate_shutdown(Box::pin(signals), handle.shutdown()).await;
+    }
+
+    /// Starts serving in the background, the server runs until the handle shuts it down
+    pub fn start(self) -> ServerHandle {
+        let (state, _) = watch::channel(ServerState::Running);
+        let state = Arc::new(state);
+
+        let handle = ServerHandle {
+            address: self.local_address,
+            state: state.clone(),
+        };
+
         info!(
             "** Started accepting connections at {address}. **",
-            address = self.address
+            address = handle.addr()
         );
 
+        tokio::spawn(self.serve(state));
+        handle
+    }
+
+    async fn serve(self, state: Arc<watch::Sender<ServerState>>) {
+        let (alive_sender, mut alive_receiver) = mpsc::channel::<()>(1);
+
         let listener_handle = tokio::spawn(tcp_listener_loop(
-            Arc::clone(&self.listener),
+            self.listener,
+            self.address.clone(),
+            self.connections.clone(),
+            self.connections_per_ip.clone(),
+            self.chat_server.clone(),
+            self.options.clone(),
+            ShutdownSignal {
+                state: state.subscribe(),
+                _alive_sender: alive_sender.clone(),
+            },
+        ));
+
+        let health_handle = self
+            .options
+            .health_address
+            .clone()
+            .map(|address| tokio::spawn(run_health_server(address, self.chat_server.clone())));
+
+        let announcements_changed = Arc::new(Notify::new());
+        let scheduled_announcement_handle = tokio::spawn(scheduled_announcement_loop(
+            self.connections.clone(),
+            self.chat_server.clone(),
+            self.options.clone(),
+            announcements_changed.clone(),
+        ));
+
+        let admin_handles = self.options.admin.clone().map(|admin| {
+            let (sender, receiver) = mpsc::channel(32);
+            (
+                tokio::spawn(run_admin_server(admin.address, admin.token, sender)),
+                tokio::spawn(admin_command_loop(
+                    receiver,
+                    self.connections.clone(),
+                    self.chat_server.clone(),
+                    self.options.clone(),
+                    announcements_changed,
+                )),
+            )
+        });
+
+        let console_handle = self
+            .options
+            .console
+            .then(|| tokio::spawn(console_loop(self.chat_server.clone())));
+
+        let session_expiry_handle = tokio::spawn(session_expiry_loop(
+            self.connections.clone(),
+            self.chat_server.clone(),
+            self.options.clone(),
+        ));
+
+        let presence_flush_handle = tokio::spawn(presence_flush_loop(
+            self.connections.clone(),
+            self.chat_server.clone(),
+            self.options.clone(),
+        ));
+
+        let live_stats_handle = tokio::spawn(live_stats_loop(
             self.connections.clone(),
             self.chat_server.clone(),
+            self.options.clone(),
         ));
 
-        signal::ctrl_c().await.unwrap();
+        let stats_broadcast_handle = self.options.stats_broadcast.as_ref().map(|broadcast| {
+            tokio::spawn(stats_broadcast_loop(
+                self.connections.clone(),
+                self.chat_server.clone(),
+                self.options.clone(),
+                broadcast.interval,
+                broadcast.admins_only,
+            ))
+        });
+
+        let history_pruning_handle = self.options.history_retention.as_ref().map(|retention| {
+            tokio::spawn(history_pruning_loop(
+                retention.max_age,
+                retention.prune_interval,
+            ))
+        });
 
-        warn!("** Detected CTRL^C, stopping the server... **");
+        let attachment_cleanup_handle =
+            self.options.attachment_retention.as_ref().map(|retention| {
+                tokio::spawn(attachment_cleanup_loop(
+                    retention.storage_dir.clone(),
+                    retention.max_age,
+                    retention.cleanup_interval,
+                ))
+            });
+
+        let slow_consumer_handle = self.options.slow_consumer_limit.as_ref().map(|limit| {
+            tokio::spawn(slow_consumer_loop(
+                self.connections.clone(),
+                limit.max_pending_frames,
+                limit.grace,
+            ))
+        });
+
+        let _ = state
+            .subscribe()
+            .wait_for(|state| *state != ServerState::Running)
+            .await;
 
         yield_now().await;
 
         listener_handle.abort();
 
-        info!("** Server has stopped successfully **");
-    }
-}
-
-async fn tcp_listener_loop<T: ServerDatabase + Send + 'static>(
-    listener: Arc<TcpListener>,
-    connections: Arc<Mutex<HashMap<String, Arc<OwnedWriteHalf>>>>,
-    chat_server: Arc<Mutex<ChatServer<T>>>,
-) {
-    loop {
-        match listener.accept().await {
-            Ok((stream, _)) => {
-                tokio::spawn(handle_incoming_tcp_stream(
-                    stream,
-                    connections.clone(),
-                    chat_server.clone(),
-                ));
+        // Requests being processed are finished before clients are told goodbye
+        let command = self.chat_server.lock().await.on_shutdown();
+        process_command(self.connections.clone(), &self.options, command).await;
+        let flushed = match self.options.shutdown_grace {
+            Some(shutdown_grace) => timeout(shutdown_grace, flush_connections(&self.connections))
+                .await
+                .is_ok(),
+            None => {
+                flush_connections(&self.connections).await;
+                true
             }
-            Err(err) => {
-                error!("Could not accept an incoming connection ({err}).");
+        };
+        if !flushed {
+            warn!("Frames not written within the shutdown grace period are dropped.");
+            for connection in self.connections.lock().await.values() {
+                connection.writer.abort();
             }
         }
+
+        state.send_replace(ServerState::Closing);
+        drop(alive_sender);
+        let _ = alive_receiver.recv().await;
+
+        let mut handles = vec![
+            listener_handle,
+            session_expiry_handle,
+            presence_flush_handle,
+            live_stats_handle,
+            scheduled_announcement_handle,
+        ];
+        handles.extend(console_handle);
+        handles.extend(health_handle);
+        handles.extend(slow_consumer_handle);
+        handles.extend(history_pruning_handle);
+        handles.extend(attachment_cleanup_handle);
+        handles.extend(stats_broadcast_handle);
+        if let Some((admin_server_handle, admin_command_handle)) = admin_handles {
+            handles.push(admin_server_handle);
+            handles.push(admin_command_handle);
+        }
+        for handle in &handles {
+            handle.abort();
+        }
+        // Aborted tasks release the chat server and their ports only once they have been dropped
+        join_all(handles).await;
+
+        info!("** Server has stopped successfully **");
+
+        state.send_replace(ServerState::Stopped);
     }
 }
 
-async fn process_command(
-    connections: Arc<Mutex<HashMap<String, Arc<OwnedWriteHalf>>>>,
-    command: ChatServerResponseCommand,
-) {
-    let message_to_send: Option<Vec<u8>>;
-    let mut users_list: Option<Vec<String>> = None;
+/// Seam around the listener of the accept loop, so tests can inject accept failures
+trait ConnectionListener: Sized {
+    async fn bind(address: &str) -> io::Result<Self>;
+    async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)>;
+}
 
-    match command {
-        ChatServerResponseCommand::SendToAll(message) => message_to_send = Some(message),
-        ChatServerResponseCommand::SendToAllExcept(connection_id_exception, message) => {
-            message_to_send = Some(message);
+impl ConnectionListener for TcpListener {
+    async fn bind(address: &str) -> io::Result<Self> {
+        TcpListener::bind(address).await
+    }
 
-            info!("Requested message distribution to all except self.");
+    async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        TcpListener::accept(self).await
+    }
+}
 
-            let connections = connections.lock().await;
+enum AcceptErrorKind {
+    /// Only the connection being accepted has failed
+    Connection,
+    /// Process or system is out of file descriptors or memory, it takes a while to recover
+    ResourceExhaustion,
+    /// Listener itself is broken and has to be bound again
+    Listener,
+}
 
-            if connections.len() == 1 {
-                info!("There is no users to send the message.");
-                return;
-            }
+fn classify_accept_error(err: &io::Error) -> AcceptErrorKind {
+    match err.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::TimedOut => return AcceptErrorKind::Connection,
+        io::ErrorKind::OutOfMemory => return AcceptErrorKind::ResourceExhaustion,
+        _ => {}
+    }
 
-            let mut users_list_new = Vec::<String>::new();
-            connections.keys().for_each(|k| {
-                if k != &connection_id_exception {
-                    users_list_new.push(k.to_string());
-                }
-            });
-            users_list = Some(users_list_new);
-        }
-        ChatServerResponseCommand::SendToSome(connection_id_exceptions, message) => {
-            message_to_send = Some(message);
-            users_list = Some(connection_id_exceptions);
-        }
-        ChatServerResponseCommand::DisconnectUser(connection_id) => {
-            let mut connections = connections.lock().await;
-            connections.remove(&connection_id);
-            return;
+    #[cfg(unix)]
+    match err.raw_os_error() {
+        // Linux reports these for a single connection, like the kinds above
+        Some(libc::EPROTO | libc::EPERM) => return AcceptErrorKind::Connection,
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM) => {
+            return AcceptErrorKind::ResourceExhaustion
         }
+        _ => {}
     }
 
-    let final_users_list = match users_list {
-        Some(list) => list,
-        None => {
-            let connections = connections.lock().await;
-            connections.keys().map(|k| k.to_string()).collect()
-        }
-    };
+    AcceptErrorKind::Listener
+}
 
-    let message_bytes = message_to_send.unwrap();
+/// Counts accept failures so that a long streak is summarized instead of logged one by one
+#[derive(Default)]
+struct AcceptFailures {
+    connection: u64,
+    resource_exhaustion: u64,
+    listener: u64,
+    last_summary: Option<Instant>,
+}
 
-    let mut join_handles = Vec::new();
+impl AcceptFailures {
+    fn total(&self) -> u64 {
+        self.connection + self.resource_exhaustion + self.listener
+    }
 
-    for connection_id in &final_users_list {
-        let connections = connections.lock().await;
-        let connection = if let Some(connection) = connections.get(connection_id) {
-            connection.clone()
-        } else {
-            continue;
-        };
+    fn record(&mut self, kind: &AcceptErrorKind, err: &io::Error) {
+        match kind {
+            AcceptErrorKind::Connection => self.connection += 1,
+            AcceptErrorKind::ResourceExhaustion => self.resource_exhaustion += 1,
+            AcceptErrorKind::Listener => self.listener += 1,
+        }
 
-        info!("Sending to {connection_id}...");
-        join_handles.push(spawn(write_message(connection, message_bytes.clone())));
+        let summary_due = self
+            .last_summary
+            .is_none_or(|last_summary| last_summary.elapsed() >= ACCEPT_FAILURES_SUMMARY_INTERVAL);
+        if summary_due {
+            error!(
+                "Could not accept incoming connections ({err}), {} failures so far ({} connection, {} resource exhaustion, {} listener).",
+                self.total(),
+                self.connection,
+                self.resource_exhaustion,
+                self.listener
+            );
+            self.last_summary = Some(Instant::now());
+        }
     }
 
-    let mut i = 0;
-    for handle in join_handles {
-        let write_result = if let Ok(result) = handle.await {
-            result
-        } else {
-            i += 1;
-            continue;
-        };
-        let connection_id = &final_users_list[i];
-        if let Err(e) = write_result {
-            error!("Could not send message to connection {connection_id} ({e}).");
-        } else {
-            info!("Sent successfully to {connection_id}.");
+    fn recover(&mut self) {
+        if self.total() > 0 {
+            info!(
+                "Accepting connections again after {} failures ({} connection, {} resource exhaustion, {} listener).",
+                self.total(),
+                self.connection,
+                self.resource_exhaustion,
+                self.listener
+            );
         }
-
-        i += 1;
+        *self = Self::default();
     }
 }
 
-async fn handle_incoming_tcp_stream<T: ServerDatabase>(
-    stream: TcpStream,
-    connections: Arc<Mutex<HashMap<String, Arc<OwnedWriteHalf>>>>,
-    chat_server: Arc<Mutex<ChatServer<T>>>,
+/// Starts the shutdown on the first signal, a second signal stops waiting for it to finish
+async fn coordinate_shutdown(
+    mut signals: impl Stream<Item = ()> + Unpin,
+    shutdown: impl Future<Output = ()>,
 ) {
-    let connection_id = Uuid::new_v4().to_string();
+    signals.next().await;
 
-    let (read_stream, write_stream) = stream.into_split();
+    warn!("** Detected CTRL^C, stopping the server, press CTRL^C again to force it... **");
 
-    connections
-        .lock()
-        .await
-        .insert(connection_id.clone(), Arc::new(write_stream));
+    tokio::select! {
+        _ = shutdown => {}
+        Some(()) = signals.next() => {
+            warn!("** Detected second CTRL^C, forced shutdown. **");
+        }
+    }
+}
 
-    chat_server
-        .lock()
-        .await
-        .on_user_connect(connection_id.clone());
+async fn tcp_listener_loop<T: ServerDatabase + Send + 'static, L: ConnectionListener>(
+    listener: L,
+    address: String,
+    connections: Arc<Mutex<HashMap<String, Connection>>>,
+    connections_per_ip: Arc<StdMutex<HashMap<IpAddr, usize>>>,
+    chat_server: Arc<Mutex<ChatServer<T>>>,
+    options: Arc<TcpServerOptions>,
+    shutdown: ShutdownSignal,
+) {
+    let mut listener = Some(listener);
+    let mut failures = AcceptFailures::default();
+    let mut consecutive_failures = 0u32;
+    // Retries of servers started together shouldn't line up
+    let mut rng = StdRng::from_entropy();
 
     loop {
-        let message = read_message(connection_id.clone(), &read_stream).await;
-        if message.is_err() {
-            break;
-        }
-        let message = message.unwrap();
-        if message.is_empty() {
-            break;
-        }
+        let Some(active_listener) = &listener else {
+            consecutive_failures = consecutive_failures.saturating_add(1);
+            sleep(with_jitter(accept_backoff(consecutive_failures), &mut rng)).await;
 
-        let response_commands = chat_server
-            .lock()
-            .await
-            .on_user_message(connection_id.clone(), &message);
-        if let Some(commands) = response_commands {
-            for command in commands {
-                process_command(connections.clone(), command).await;
+            match L::bind(&address).await {
+                Ok(new_listener) => {
+                    warn!("Listener has been bound to {address} again.");
+                    listener = Some(new_listener);
+                }
+                Err(err) => error!("Could not bind {address} to the server again ({err})."),
             }
-        }
-    }
+            continue;
+        };
 
-    connections.lock().await.remove(&connection_id);
+        match active_listener.accept().await {
+            Ok((stream, address)) => {
+                consecutive_failures = 0;
+                failures.recover();
 
-    let response_command = chat_server
-        .lock()
-        .await
-        .on_user_disconnect(connection_id.clone());
+                // Connection still works with the OS defaults, so it is kept
+                if let Err(e) = options.socket_options.apply(&stream) {
+                    warn!("Could not set socket options of connection from {address} ({e}).");
+                }
+
+                let Some(slot) = ConnectionSlot::acquire(
+                    &connections_per_ip,
+                    address.ip(),
+                    options.max_connections_per_ip,
+                ) else {
+                    tokio::spawn(refuse_connection::<T>(
+                        stream,
+                        address,
+                        options.frame_format.clone(),
+                    ));
+                    continue;
+                };
+
+                tokio::spawn(handle_incoming_tcp_stream(
+                    stream,
+                    address,
+                    slot,
+                    connections.clone(),
+                    chat_server.clone(),
+                    options.clone(),
+                    shutdown.clone(),
+                ));
+            }
+            Err(err) => {
+                let kind = classify_accept_error(&err);
+                failures.record(&kind, &err);
 
-    if let Some(command) = response_command {
-        process_command(connections.clone(), command).await;
+                match kind {
+                    AcceptErrorKind::Connection => {}
+                    AcceptErrorKind::ResourceExhaustion => {
+                        // Running out of file descriptors persists for a while, don't spin on it
+                        consecutive_failures = consecutive_failures.saturating_add(1);
+                        sleep(with_jitter(accept_backoff(consecutive_failures), &mut rng)).await;
+                    }
+                    AcceptErrorKind::Listener => {
+                        // Old listener has to be closed for the address to be bound again
+                        listener = None;
+                    }
+                }
+            }
+        }
     }
 }
 
-async fn read_message(connection_id: String, stream: &OwnedReadHalf) -> io::Result<Vec<u8>> {
-    let mut header_buffer: [u8; 4] = [0; 4];
-    let header_result = read_from_stream(stream, &mut header_buffer).await;
-    if header_result.is_err() {
-        let e = header_result.err().unwrap();
-        error!("Could not read header of the message from {connection_id} ({e}).");
-        return Err(e);
+fn accept_backoff(consecutive_failures: u32) -> Duration {
+    if consecutive_failures == 0 {
+        return Duration::ZERO;
     }
 
-    // Header is 4 bytes long integer, representing message length
-    let header = u32::from_le_bytes(header_buffer);
-
-    let mut buffer: Vec<u8> = vec![0; header as usize];
-
-    let body_result = read_from_stream(stream, &mut buffer).await;
-    if body_result.is_err() {
-        let e = header_result.err().unwrap();
-        error!("Could not read body of the message from {connection_id} ({e}).");
-        return Err(e);
-    }
+    let exponent = (consecutive_failures - 1).min(16);
+    ACCEPT_BACKOFF_BASE
+        .saturating_mul(1 << exponent)
+        .min(ACCEPT_BACKOFF_CAP)
+}
 
-    Ok(buffer)
+/// Keeps at least half of the backoff and randomizes the rest
+fn with_jitter(backoff: Duration, rng: &mut impl Rng) -> Duration {
+    rng.gen_range(backoff / 2..=backoff)
 }
 
-async fn write_message(stream: Arc<OwnedWriteHalf>, buf: Vec<u8>) -> io::Result<()> {
-    let header = (buf.len() as u32).to_le_bytes();
+async fn session_expiry_loop<T: ServerDatabase>(
+    connections: Arc<Mutex<HashMap<String, Connection>>>,
+    chat_server: Arc<Mutex<ChatServer<T>>>,
+    options: Arc<TcpServerOptions>,
+) {
+    // Session limit and rejoin grace can be changed by reloading the configuration, so the sweep always runs
+    let mut interval = interval(SESSION_EXPIRY_INTERVAL);
 
-    let write_result = write_to_stream(&stream, &header).await;
-    if write_result.is_err() {
-        let e = write_result.err().unwrap();
-        return Err(e);
-    }
+    loop {
+        interval.tick().await;
 
-    let write_result = write_to_stream(&stream, &buf).await;
-    if write_result.is_err() {
-        let e = write_result.err().unwrap();
-        return Err(e);
+        // Commands are queued before the lock is released, so they keep the order they were made in
+        let mut chat_server = chat_server.lock().await;
+        let mut response_commands = chat_server.expire_sessions();
+        response_commands.extend(chat_server.expire_departures());
+        process_commands(&connections, &options, &mut chat_server, response_commands).await;
     }
-    Ok(())
 }
 
-async fn read_from_stream(stream: &OwnedReadHalf, buf: &mut [u8]) -> io::Result<usize> {
-    let mut cursor: usize = 0;
+async fn presence_flush_loop<T: ServerDatabase>(
+    connections: Arc<Mutex<HashMap<String, Connection>>>,
+    chat_server: Arc<Mutex<ChatServer<T>>>,
+    options: Arc<TcpServerOptions>,
+) {
+    // Batch window can be enabled by reloading the configuration, so the flush always runs
+    let mut interval = interval(PRESENCE_FLUSH_INTERVAL);
+
     loop {
-        if cursor >= buf.len() {
-            return Ok(buf.len());
+        interval.tick().await;
+
+        let mut chat_server = chat_server.lock().await;
+        let response_commands = chat_server.flush_presence_batch();
+        process_commands(&connections, &options, &mut chat_server, response_commands).await;
+    }
+}
+
+async fn live_stats_loop<T: ServerDatabase>(
+    connections: Arc<Mutex<HashMap<String, Connection>>>,
+    chat_server: Arc<Mutex<ChatServer<T>>>,
+    options: Arc<TcpServerOptions>,
+) {
+    // Admins subscribe at any time, so the sampling always runs
+    let mut interval = interval(LIVE_STATS_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let mut chat_server = chat_server.lock().await;
+        let response_commands = chat_server.broadcast_live_stats();
+        process_commands(&connections, &options, &mut chat_server, response_commands).await;
+    }
+}
+
+async fn stats_broadcast_loop<T: ServerDatabase>(
+    connections: Arc<Mutex<HashMap<String, Connection>>>,
+    chat_server: Arc<Mutex<ChatServer<T>>>,
+    options: Arc<TcpServerOptions>,
+    period: Duration,
+    admins_only: bool,
+) {
+    let mut interval = interval(period);
+    // First tick completes immediately, there is nothing worth reporting right at the start
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let mut chat_server = chat_server.lock().await;
+        let command = chat_server.broadcast_stats(admins_only);
+        process_commands(&connections, &options, &mut chat_server, [command]).await;
+    }
+}
+
+async fn slow_consumer_loop(
+    connections: Arc<Mutex<HashMap<String, Connection>>>,
+    max_pending_frames: usize,
+    grace: Duration,
+) {
+    let mut interval = interval(SLOW_CONSUMER_CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let mut connections = connections.lock().await;
+        let now = Instant::now();
+        let mut slow_connection_ids = Vec::<String>::new();
+        for (connection_id, connection) in connections.iter_mut() {
+            if connection.pending.load(Ordering::Relaxed) <= max_pending_frames {
+                connection.slow_since = None;
+                continue;
+            }
+            let slow_since = *connection.slow_since.get_or_insert(now);
+            if now.duration_since(slow_since) >= grace {
+                slow_connection_ids.push(connection_id.clone());
+            }
         }
 
-        stream.readable().await?;
+        for connection_id in slow_connection_ids {
+            let Some(connection) = connections.remove(&connection_id) else {
+                continue;
+            };
+            warn!(
+                "Connection {connection_id} has had more than {max_pending_frames} pending frames for too long, dropping it."
+            );
 
-        let current_slice = &mut buf[cursor..];
+            // Writer is stuck on the full socket, so nothing queued behind it would ever be sent
+            connection.writer.abort();
+            connection.closed.notify_one();
+        }
+    }
+}
 
-        match stream.try_read(current_slice) {
-            Ok(0) => break,
-            Ok(n) => {
-                cursor += n;
+async fn history_pruning_loop(max_age: Duration, prune_interval: Duration) {
+    // Pruning has its own database connection and runs on the blocking pool, so chat goes on
+    let database = match tokio::task::spawn_blocking(ServerSQLiteDatabase::try_new).await {
+        Ok(Ok(database)) => Arc::new(StdMutex::new(database)),
+        Ok(Err(e)) => {
+            error!("Could not open the database for history pruning ({e}).");
+            warn!("History is not pruned.");
+            return;
+        }
+        Err(e) => {
+            error!("Could not open the database for history pruning ({e}).");
+            return;
+        }
+    };
+    let mut interval = interval(prune_interval);
+
+    loop {
+        interval.tick().await;
+
+        let cutoff = SystemTime::now()
+            .checked_sub(max_age)
+            .and_then(|cutoff| cutoff.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |cutoff| cutoff.as_secs());
+
+        let database = database.clone();
+        let pruned = tokio::task::spawn_blocking(move || {
+            database.lock().unwrap().prune_messages_older_than(cutoff)
+        })
+        .await;
+        match pruned {
+            Ok(Ok(0)) => {}
+            Ok(Ok(count)) => info!("Pruned {count} messages from the history."),
+            Ok(Err(e)) => error!("Could not prune the history ({e})."),
+            Err(e) => error!("History pruning has failed ({e})."),
+        }
+    }
+}
+
+async fn attachment_cleanup_loop(
+    storage_dir: PathBuf,
+    max_age: Duration,
+    cleanup_interval: Duration,
+) {
+    let mut interval = interval(cleanup_interval);
+
+    loop {
+        interval.tick().await;
+
+        let storage_dir = storage_dir.clone();
+        let removed =
+            tokio::task::spawn_blocking(move || remove_expired_attachments(&storage_dir, max_age))
+                .await;
+        match removed {
+            Ok(Ok(0)) => {}
+            Ok(Ok(count)) => info!("Removed {count} expired attachments."),
+            Ok(Err(e)) => error!("Could not remove expired attachments ({e})."),
+            Err(e) => error!("Attachment cleanup has failed ({e})."),
+        }
+    }
+}
+
+async fn scheduled_announcement_loop<T: ServerDatabase>(
+    connections: Arc<Mutex<HashMap<String, Connection>>>,
+    chat_server: Arc<Mutex<ChatServer<T>>>,
+    options: Arc<TcpServerOptions>,
+    announcements_changed: Arc<Notify>,
+) {
+    loop {
+        let deadline = chat_server.lock().await.next_announcement_deadline();
+        // A change stores a permit if nobody waits yet, so scheduling right after the check isn't missed
+        match deadline {
+            Some(deadline) => {
+                tokio::select! {
+                    _ = sleep_until(deadline) => {}
+                    _ = announcements_changed.notified() => continue,
+                }
             }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+            None => {
+                announcements_changed.notified().await;
                 continue;
             }
+        }
+
+        let mut chat_server = chat_server.lock().await;
+        let response_commands = chat_server.deliver_scheduled_announcements();
+        process_commands(&connections, &options, &mut chat_server, response_commands).await;
+    }
+}
+
+async fn admin_command_loop<T: ServerDatabase>(
+    mut receiver: AdminCommandReceiver,
+    connections: Arc<Mutex<HashMap<String, Connection>>>,
+    chat_server: Arc<Mutex<ChatServer<T>>>,
+    options: Arc<TcpServerOptions>,
+    announcements_changed: Arc<Notify>,
+) {
+    while let Some((command, result_sender)) = receiver.recv().await {
+        let mut chat_server_guard = chat_server.lock().await;
+        let (result, response_commands) = chat_server_guard.on_admin_command(command);
+        // Any command might have scheduled or cancelled an announcement, waking up once too often is cheap
+        announcements_changed.notify_one();
+
+        process_commands(
+            &connections,
+            &options,
+            &mut chat_server_guard,
+            response_commands,
+        )
+        .await;
+        drop(chat_server_guard);
+
+        let _ = result_sender.send(result);
+    }
+}
+
+/// Processes the commands and lets the chat server forget the recipients whose connections are gone,
+/// until no commands are left
+async fn process_commands<T: ServerDatabase>(
+    connections: &Arc<Mutex<HashMap<String, Connection>>>,
+    options: &TcpServerOptions,
+    chat_server: &mut ChatServer<T>,
+    commands: impl IntoIterator<Item = ChatServerResponseCommand>,
+) {
+    let mut commands: Vec<ChatServerResponseCommand> = commands.into_iter().collect();
+
+    while !commands.is_empty() {
+        let mut missing = Vec::<String>::new();
+        for command in commands {
+            missing.extend(process_command(connections.clone(), options, command).await);
+        }
+        missing.sort_unstable();
+        missing.dedup();
+
+        // Every forgotten user is removed from the chat state, so this ends
+        commands = chat_server.forget_users(missing);
+    }
+}
+
+/// Returns the ids of the addressed connections which no longer exist
+async fn process_command(
+    connections: Arc<Mutex<HashMap<String, Connection>>>,
+    options: &TcpServerOptions,
+    command: ChatServerResponseCommand,
+) -> Vec<String> {
+    let message_to_send: Option<Arc<[u8]>>;
+    let mut users_list: Option<Vec<String>> = None;
+    let mut report: Option<(String, u64)> = None;
+
+    match command {
+        ChatServerResponseCommand::SendToAll(message) => message_to_send = Some(message),
+        ChatServerResponseCommand::SendToAllExcept(connection_id_exception, message) => {
+            message_to_send = Some(message);
+
+            info!("Requested message distribution to all except self.");
+
+            let connections = connections.lock().await;
+
+            if connections.len() == 1 {
+                info!("There is no users to send the message.");
+                return vec![];
+            }
+
+            let mut users_list_new = Vec::<String>::new();
+            connections.keys().for_each(|k| {
+                if k != &connection_id_exception {
+                    users_list_new.push(k.to_string());
+                }
+            });
+            users_list = Some(users_list_new);
+        }
+        ChatServerResponseCommand::SendToSome(connection_id_exceptions, message) => {
+            message_to_send = Some(message);
+            users_list = Some(connection_id_exceptions);
+        }
+        ChatServerResponseCommand::SendToSomeWithReport {
+            recipients,
+            message,
+            sender_id,
+            server_msg_id,
+        } => {
+            message_to_send = Some(message);
+            users_list = Some(recipients);
+            report = Some((sender_id, server_msg_id));
+        }
+        ChatServerResponseCommand::DisconnectUser(connection_id, reason) => {
+            let Some(connection) = connections.lock().await.remove(&connection_id) else {
+                return vec![connection_id];
+            };
+
+            if let Some(reason) = reason {
+                connection.queue_frame(reason, false, None);
+            }
+            let _ = connection.sender.send(Outgoing::Close);
+            return vec![];
+        }
+        ChatServerResponseCommand::EnableCompression(connection_id) => {
+            let mut connections = connections.lock().await;
+            return match connections.get_mut(&connection_id) {
+                Some(connection) => {
+                    connection.compression = options.compression_threshold.is_some();
+                    vec![]
+                }
+                None => vec![connection_id],
+            };
+        }
+    }
+
+    let message_bytes = message_to_send.unwrap();
+
+    let mut missing = Vec::<String>::new();
+
+    // Frames are only queued here, writer tasks of the connections write them concurrently
+    let recipients: Vec<(String, Connection)> = {
+        let connections = connections.lock().await;
+        match users_list {
+            Some(users_list) => users_list
+                .into_iter()
+                .filter_map(|connection_id| match connections.get(&connection_id) {
+                    Some(connection) => Some((connection_id, connection.clone())),
+                    None => {
+                        missing.push(connection_id);
+                        None
+                    }
+                })
+                .collect(),
+            None => connections
+                .iter()
+                .map(|(connection_id, connection)| (connection_id.clone(), connection.clone()))
+                .collect(),
+        }
+    };
+
+    let should_compress_any = options
+        .compression_threshold
+        .is_some_and(|threshold| message_bytes.len() > threshold)
+        && recipients
+            .iter()
+            .any(|(_, connection)| connection.compression);
+    let compressed_message_bytes: Option<Arc<[u8]>> = if should_compress_any {
+        match compress(&message_bytes) {
+            Ok(compressed) => Some(compressed.into()),
             Err(e) => {
-                return Err(e);
+                error!("Could not compress message ({e}).");
+                None
             }
         }
+    } else {
+        None
+    };
+
+    let mut written_receivers = Vec::<oneshot::Receiver<bool>>::new();
+    for (connection_id, connection) in recipients {
+        let (bytes, is_compressed) = match &compressed_message_bytes {
+            Some(compressed) if connection.compression => (compressed.clone(), true),
+            _ => (message_bytes.clone(), false),
+        };
+        let written = match &report {
+            Some((sender_id, _)) if *sender_id != connection_id => {
+                let (written, written_receiver) = oneshot::channel();
+                written_receivers.push(written_receiver);
+                Some(written)
+            }
+            _ => None,
+        };
+
+        if !connection.queue_frame(bytes, is_compressed, written) {
+            info!("Connection {connection_id} is closing, message has not been queued.");
+        }
     }
 
-    Ok(0)
+    if let Some((sender_id, server_msg_id)) = report {
+        let not_found = missing.iter().filter(|id| **id != sender_id).count();
+        // Writes finish at the pace of the slowest recipient, which must not hold up this one
+        tokio::spawn(async move {
+            let results = join_all(written_receivers).await;
+            let delivered = results.iter().filter(|result| **result == Ok(true)).count();
+            let failed = results.len() - delivered + not_found;
+
+            let Some(connection) = connections.lock().await.get(&sender_id).cloned() else {
+                return;
+            };
+            connection.queue_frame(
+                delivery_report_message(server_msg_id, delivered, failed),
+                false,
+                None,
+            );
+        });
+    }
+
+    missing
 }
 
-async fn write_to_stream(stream: &OwnedWriteHalf, buf: &[u8]) -> io::Result<()> {
-    loop {
-        stream.writable().await?;
+async fn flush_connections(connections: &Mutex<HashMap<String, Connection>>) {
+    let flushed: Vec<oneshot::Receiver<()>> = connections
+        .lock()
+        .await
+        .values()
+        .filter_map(|connection| {
+            let (sender, receiver) = oneshot::channel();
+            connection.sender.send(Outgoing::Flush(sender)).ok()?;
+            Some(receiver)
+        })
+        .collect();
+
+    join_all(flushed).await;
+}
 
-        match stream.try_write(buf) {
-            Ok(_) => {
+async fn connection_writer_loop(
+    connection_id: String,
+    stream: OwnedWriteHalf,
+    frame_format: FrameFormat,
+    mut receiver: mpsc::UnboundedReceiver<Outgoing>,
+    pending: Arc<AtomicUsize>,
+    // Tells the connection task to stop reading once the server has dropped the connection
+    closed: Arc<Notify>,
+    // Held until the queued frames are written, so shutdown waits for them
+    _shutdown: ShutdownSignal,
+) {
+    while let Some(outgoing) = receiver.recv().await {
+        match outgoing {
+            Outgoing::Frame {
+                bytes,
+                is_compressed,
+                written,
+            } => {
+                info!("Sending to {connection_id}...");
+                let result = write_message(&stream, &frame_format, &bytes, is_compressed).await;
+                match &result {
+                    Ok(_) => info!("Sent successfully to {connection_id}."),
+                    Err(e) => error!("Could not send message to connection {connection_id} ({e})."),
+                }
+                if let Some(written) = written {
+                    let _ = written.send(result.is_ok());
+                }
+                pending.fetch_sub(1, Ordering::Relaxed);
+            }
+            Outgoing::Close => {
+                // Write half is shut down once it is dropped
+                closed.notify_one();
                 break;
             }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                continue;
+            Outgoing::Flush(flushed) => {
+                let _ = flushed.send(());
             }
-            Err(e) => {
-                return Err(e);
+        }
+    }
+}
+
+/// Tells the peer why and closes the connection, which never reaches the chat server
+async fn refuse_connection<T: ServerDatabase>(
+    stream: TcpStream,
+    address: SocketAddr,
+    frame_format: FrameFormat,
+) {
+    warn!("Refusing connection from {address}, too many connections from its address.");
+
+    let (_read_stream, write_stream) = stream.into_split();
+    let message = ChatServer::<T>::too_many_connections_message();
+    if let Err(e) = write_message(&write_stream, &frame_format, &message, false).await {
+        error!("Could not tell {address} about the refused connection ({e}).");
+    }
+}
+
+async fn handle_incoming_tcp_stream<T: ServerDatabase>(
+    stream: TcpStream,
+    address: SocketAddr,
+    // Held until the connection has been cleaned up, whichever way it ends
+    _slot: ConnectionSlot,
+    connections: Arc<Mutex<HashMap<String, Connection>>>,
+    chat_server: Arc<Mutex<ChatServer<T>>>,
+    options: Arc<TcpServerOptions>,
+    mut shutdown: ShutdownSignal,
+) {
+    let (read_stream, write_stream) = stream.into_split();
+    let closed = Arc::new(Notify::new());
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let pending = Arc::new(AtomicUsize::new(0));
+
+    // Id is picked and taken under one lock, so two connections can never share it
+    let (connection_id, span) = {
+        let mut connections = connections.lock().await;
+        let connection_id = loop {
+            let connection_id = Uuid::new_v4().to_string();
+            if !connections.contains_key(&connection_id) {
+                break connection_id;
+            }
+            warn!("Connection id {connection_id} is already in use, picking another one.");
+        };
+
+        // Fields are filled once here, events of the connection only refer to the span
+        let span = info_span!(
+            "connection",
+            connection_id = %connection_id,
+            address = %address,
+            user_name = field::Empty,
+        );
+
+        let writer = tokio::spawn(
+            connection_writer_loop(
+                connection_id.clone(),
+                write_stream,
+                options.frame_format.clone(),
+                receiver,
+                pending.clone(),
+                closed.clone(),
+                shutdown.clone(),
+            )
+            .instrument(span.clone()),
+        );
+        connections.insert(
+            connection_id.clone(),
+            Connection {
+                sender,
+                pending,
+                slow_since: None,
+                writer: Arc::new(writer.abort_handle()),
+                closed: closed.clone(),
+                compression: false,
+            },
+        );
+        (connection_id, span)
+    };
+
+    async {
+        // Commands are queued before the lock is released, so they keep the order they were made in
+        {
+            let mut chat_server = chat_server.lock().await;
+            let response_commands = chat_server.on_user_connect(connection_id.clone(), address);
+            process_commands(&connections, &options, &mut chat_server, response_commands).await;
+        }
+
+        // Reused for every frame, it only grows when a frame larger than any before arrives and
+        // gives the memory back once such a frame is processed
+        let mut read_buffer = Vec::<u8>::new();
+
+        loop {
+            // Only a client that has negotiated compression may send compressed frames
+            let allow_compression = connections
+                .lock()
+                .await
+                .get(&connection_id)
+                .is_some_and(|connection| connection.compression);
+            let message = read_message(
+                connection_id.clone(),
+                &read_stream,
+                &options.frame_format,
+                allow_compression,
+                &mut read_buffer,
+            );
+            let message = async {
+                let Some(idle_timeout) = options.idle_timeout else {
+                    return Some(message.await);
+                };
+                let Some(idle_warning) = options
+                    .idle_warning
+                    .filter(|idle_warning| *idle_warning < idle_timeout)
+                else {
+                    return timeout(idle_timeout, message).await.ok();
+                };
+
+                // Same read goes on after the warning, so no partially read frame is lost
+                tokio::pin!(message);
+                if let Ok(message) = timeout(idle_timeout - idle_warning, &mut message).await {
+                    return Some(message);
+                }
+                info!("Connection {connection_id} is idle, warning it before dropping it.");
+                let warning = ChatServer::<T>::idle_warning_message(idle_warning);
+                process_command(
+                    connections.clone(),
+                    &options,
+                    ChatServerResponseCommand::SendToSome(vec![connection_id.clone()], warning),
+                )
+                .await;
+                timeout(idle_warning, message).await.ok()
+            };
+            let message = tokio::select! {
+                biased;
+                _ = closed.notified() => {
+                    info!("Connection {connection_id} has been closed by the server.");
+                    break;
+                }
+                _ = shutdown.closing() => {
+                    info!("Connection {connection_id} is closed as the server is stopping.");
+                    break;
+                }
+                message = message => message,
+            };
+            let Some(message) = message else {
+                warn!("Connection {connection_id} from {address} has been idle for too long, dropping it.");
+                break;
+            };
+            if message.is_err() {
+                break;
+            }
+            let message = message.unwrap();
+            if message.is_empty() {
+                break;
+            }
+
+            let pending_attachments = {
+                let mut chat_server = chat_server.lock().await;
+                let response_commands =
+                    chat_server.on_user_message(connection_id.clone(), &message);
+                process_commands(
+                    &connections,
+                    &options,
+                    &mut chat_server,
+                    response_commands.into_iter().flatten(),
+                )
+                .await;
+                chat_server.take_pending_attachments()
+            };
+            // Written on the blocking pool after the chat server is released, so chat goes on
+            for attachment in pending_attachments {
+                let stored = tokio::task::spawn_blocking(move || {
+                    let result = attachment.store();
+                    (attachment, result)
+                })
+                .await;
+                let Ok((attachment, result)) = stored else {
+                    error!("Storing an attachment from connection {connection_id} has failed.");
+                    continue;
+                };
+
+                let mut chat_server = chat_server.lock().await;
+                let response_commands = chat_server.on_attachment_stored(attachment, result);
+                process_commands(&connections, &options, &mut chat_server, response_commands)
+                    .await;
             }
+
+            shrink_read_buffer(&mut read_buffer);
         }
+
+        // Dropping the last sender stops the writer task once the queued frames are written
+        connections.lock().await.remove(&connection_id);
+
+        let mut chat_server = chat_server.lock().await;
+        let response_command = chat_server.on_user_disconnect(connection_id.clone());
+        process_commands(&connections, &options, &mut chat_server, response_command).await;
+    }
+    .instrument(span)
+    .await;
+}
+
+/// Shrinks the buffer after an unusually large frame, so it isn't kept for the rest of the connection
+fn shrink_read_buffer(buffer: &mut Vec<u8>) {
+    if buffer.capacity() > READ_BUFFER_RETAINED_CAPACITY * 2 {
+        buffer.clear();
+        buffer.shrink_to(READ_BUFFER_RETAINED_CAPACITY);
+    }
+}
+
+/// Reads the next frame into the buffer, only decompressed bodies are returned in a new allocation
+async fn read_message<'a>(
+    connection_id: String,
+    stream: &OwnedReadHalf,
+    frame_format: &FrameFormat,
+    allow_compression: bool,
+    buffer: &'a mut Vec<u8>,
+) -> io::Result<Cow<'a, [u8]>> {
+    let Some(max_read_duration) = frame_format.max_read_duration else {
+        return read_frame(
+            &connection_id,
+            stream,
+            frame_format,
+            allow_compression,
+            buffer,
+        )
+        .await;
+    };
+
+    // Waiting for the next frame is up to the idle timeout, only the frame itself is bounded
+    stream.readable().await?;
+    let frame = read_frame(
+        &connection_id,
+        stream,
+        frame_format,
+        allow_compression,
+        buffer,
+    );
+    match timeout(max_read_duration, frame).await {
+        Ok(result) => result,
+        Err(_) => {
+            error!(
+                "Message from {connection_id} has taken longer than {} seconds to arrive.",
+                max_read_duration.as_secs_f32()
+            );
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "message is arriving too slowly",
+            ))
+        }
+    }
+}
+
+async fn read_frame<'a>(
+    connection_id: &str,
+    stream: &OwnedReadHalf,
+    frame_format: &FrameFormat,
+    allow_compression: bool,
+    buffer: &'a mut Vec<u8>,
+) -> io::Result<Cow<'a, [u8]>> {
+    let mut header_buffer: [u8; 8] = [0; 8];
+    let header_buffer = &mut header_buffer[..frame_format.header_size.len()];
+    let header_result = read_from_stream(stream, header_buffer).await;
+    if header_result.is_err() {
+        let e = header_result.err().unwrap();
+        error!("Could not read header of the message from {connection_id} ({e}).");
+        return Err(e);
+    }
+    if header_result.unwrap() == 0 {
+        // Peer has closed the connection at a frame boundary
+        return Ok(Cow::Borrowed(&[]));
+    }
+
+    let (length, is_compressed) = frame_format.header_size.decode(header_buffer);
+
+    if length > (frame_format.max_body_size + frame_format.tag_len()) as u64 {
+        error!(
+            "Received message of {length} bytes from {connection_id}, more than the {} bytes allowed.",
+            frame_format.max_body_size
+        );
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message is too large",
+        ));
+    }
+
+    if is_compressed && !allow_compression {
+        error!("Received compressed message from {connection_id}, but compression is disabled.");
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "compression is disabled",
+        ));
+    }
+
+    // Length is capped above, so the buffer never grows beyond the largest frame allowed
+    buffer.clear();
+    buffer.resize(length as usize, 0);
+
+    let body_result = match read_from_stream(stream, buffer).await {
+        Ok(0) if !buffer.is_empty() => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        body_result => body_result,
+    };
+    if body_result.is_err() {
+        let e = body_result.err().unwrap();
+        error!("Could not read body of the message from {connection_id} ({e}).");
+        return Err(e);
+    }
+
+    let mut body_len = buffer.len();
+    if let Some(key) = &frame_format.signing_key {
+        let Some(tagged_body_len) = buffer.len().checked_sub(FRAME_TAG_LEN) else {
+            error!("Received message from {connection_id} without its tag.");
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message tag is missing",
+            ));
+        };
+        let (body, tag) = buffer.split_at(tagged_body_len);
+        if frame_mac(key, body).verify_slice(tag).is_err() {
+            error!("Received message from {connection_id} whose tag does not match its body.");
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message tag does not match",
+            ));
+        }
+        body_len = tagged_body_len;
+    }
+    let body = &buffer[..body_len];
+
+    if is_compressed {
+        return decompress(body, frame_format.max_body_size)
+            .map(Cow::Owned)
+            .map_err(|e| {
+                error!("Could not decompress the message from {connection_id} ({e}).");
+                e
+            });
+    }
+
+    Ok(Cow::Borrowed(body))
+}
+
+async fn write_message(
+    stream: &OwnedWriteHalf,
+    frame_format: &FrameFormat,
+    buf: &[u8],
+    is_compressed: bool,
+) -> io::Result<()> {
+    let tag = frame_format
+        .signing_key
+        .as_ref()
+        .map(|key| frame_mac(key, buf).finalize().into_bytes());
+    let header = frame_format
+        .header_size
+        .encode(buf.len() + frame_format.tag_len(), is_compressed)?;
+
+    let write_result = write_to_stream(stream, &header).await;
+    if write_result.is_err() {
+        let e = write_result.err().unwrap();
+        return Err(e);
+    }
+
+    let write_result = write_to_stream(stream, buf).await;
+    if write_result.is_err() {
+        let e = write_result.err().unwrap();
+        return Err(e);
+    }
+
+    if let Some(tag) = tag {
+        write_to_stream(stream, &tag).await?;
+    }
+    Ok(())
+}
+
+fn compress(buf: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(buf)?;
+    encoder.finish()
+}
+
+/// Inflates at most one byte past the limit, so a small compressed body can't expand into gigabytes
+fn decompress(buf: &[u8], max_size: usize) -> io::Result<Vec<u8>> {
+    let mut decoder = GzDecoder::new(buf).take(max_size as u64 + 1);
+    let mut decompressed = Vec::new();
+    decoder.read_to_end(&mut decompressed)?;
+    if decompressed.len() > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("decompressed message is larger than the {max_size} bytes allowed"),
+        ));
+    }
+    Ok(decompressed)
+}
+
+// Returns `Ok(0)` if the peer has closed the connection before sending anything, and an
+// `UnexpectedEof` error if it has closed the connection in the middle of the buffer.
+async fn read_from_stream(stream: &OwnedReadHalf, buf: &mut [u8]) -> io::Result<usize> {
+    let mut cursor: usize = 0;
+    loop {
+        if cursor >= buf.len() {
+            return Ok(buf.len());
+        }
+
+        stream.readable().await?;
+
+        let current_slice = &mut buf[cursor..];
+
+        match stream.try_read(current_slice) {
+            Ok(0) if cursor == 0 => break,
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(n) => {
+                cursor += n;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                continue;
+            }
+            Err(e) => {
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(0)
+}
+
+async fn write_to_stream(stream: &OwnedWriteHalf, buf: &[u8]) -> io::Result<()> {
+    let mut cursor: usize = 0;
+    while cursor < buf.len() {
+        stream.writable().await?;
+
+        // A full socket buffer accepts only part of the slice
+        match stream.try_write(&buf[cursor..]) {
+            Ok(n) => {
+                cursor += n;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                continue;
+            }
+            Err(e) => {
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use socket2::Socket;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::server::{tests as chat, ChatServerOptions, Motd};
+
+    fn frame_format(max_body_size: usize) -> FrameFormat {
+        FrameFormat {
+            header_size: HeaderSize::Four,
+            max_body_size,
+            signing_key: None,
+            max_read_duration: None,
+        }
+    }
+
+    async fn connected_pair() -> (OwnedReadHalf, OwnedWriteHalf) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (read_half, _) = server.into_split();
+        let (_, write_half) = client.into_split();
+        (read_half, write_half)
+    }
+
+    async fn read(
+        stream: &OwnedReadHalf,
+        frame_format: &FrameFormat,
+        allow_compression: bool,
+    ) -> io::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        read_message(
+            "test".to_string(),
+            stream,
+            frame_format,
+            allow_compression,
+            &mut buffer,
+        )
+        .await
+        .map(Cow::into_owned)
+    }
+
+    #[tokio::test]
+    async fn compressed_frame_round_trips() {
+        let frame_format = frame_format(1024);
+        let (reader, writer) = connected_pair().await;
+        let body = b"hello hello hello hello hello".repeat(10);
+
+        write_message(&writer, &frame_format, &compress(&body).unwrap(), true)
+            .await
+            .unwrap();
+
+        assert_eq!(read(&reader, &frame_format, true).await.unwrap(), body);
+    }
+
+    #[tokio::test]
+    async fn oversize_inflated_frame_is_rejected() {
+        let frame_format = frame_format(4096);
+        let (reader, writer) = connected_pair().await;
+        let compressed = compress(&vec![0; 1024 * 1024]).unwrap();
+        assert!(compressed.len() <= frame_format.max_body_size);
+
+        write_message(&writer, &frame_format, &compressed, true)
+            .await
+            .unwrap();
+
+        let err = read(&reader, &frame_format, true).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn signed_frame_round_trips() {
+        let frame_format = FrameFormat {
+            signing_key: Some(Arc::from(&b"secret"[..])),
+            ..frame_format(1024)
+        };
+        let (reader, writer) = connected_pair().await;
+
+        write_message(&writer, &frame_format, b"signed body", false)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            read(&reader, &frame_format, false).await.unwrap(),
+            b"signed body"
+        );
+    }
+
+    #[tokio::test]
+    async fn tampered_signed_frame_is_rejected() {
+        let frame_format = FrameFormat {
+            signing_key: Some(Arc::from(&b"secret"[..])),
+            ..frame_format(1024)
+        };
+        let (reader, writer) = connected_pair().await;
+        let body = b"signed body";
+        let tag = frame_mac(b"secret", body).finalize().into_bytes();
+        let header = HeaderSize::Four
+            .encode(body.len() + FRAME_TAG_LEN, false)
+            .unwrap();
+
+        // Tag of the original body, sent after a body altered on the way
+        write_to_stream(&writer, &header).await.unwrap();
+        write_to_stream(&writer, b"signed bodY").await.unwrap();
+        write_to_stream(&writer, &tag).await.unwrap();
+
+        let err = read(&reader, &frame_format, false).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn frame_signed_with_another_key_is_rejected() {
+        let (reader, writer) = connected_pair().await;
+        let sender_format = FrameFormat {
+            signing_key: Some(Arc::from(&b"other"[..])),
+            ..frame_format(1024)
+        };
+        let receiver_format = FrameFormat {
+            signing_key: Some(Arc::from(&b"secret"[..])),
+            ..frame_format(1024)
+        };
+
+        write_message(&writer, &sender_format, b"signed body", false)
+            .await
+            .unwrap();
+
+        assert!(read(&reader, &receiver_format, false).await.is_err());
+    }
+
+    #[test]
+    fn decompress_allows_body_at_limit() {
+        let body = vec![7; 100];
+
+        assert_eq!(decompress(&compress(&body).unwrap(), 100).unwrap(), body);
+        assert!(decompress(&compress(&body).unwrap(), 99).is_err());
+    }
+
+    /// Fails the first accepts with the queued errors, then accepts from the real listener
+    #[cfg(unix)]
+    struct FailingListener {
+        listener: TcpListener,
+        errors: StdMutex<Vec<io::Error>>,
+        accepts: Arc<AtomicUsize>,
+    }
+
+    #[cfg(unix)]
+    impl ConnectionListener for FailingListener {
+        async fn bind(_address: &str) -> io::Result<Self> {
+            panic!("listener should not be bound again");
+        }
+
+        async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+            self.accepts.fetch_add(1, Ordering::Relaxed);
+            let error = self.errors.lock().unwrap().pop();
+            match error {
+                Some(error) => Err(error),
+                None => self.listener.accept().await,
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(start_paused = true)]
+    async fn accept_loop_survives_running_out_of_file_descriptors() {
+        let frame_format = frame_format(1024);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let accepts = Arc::new(AtomicUsize::new(0));
+        let errors = [libc::EMFILE, libc::ENFILE, libc::EMFILE]
+            .map(io::Error::from_raw_os_error)
+            .into();
+        let (state, _) = watch::channel(ServerState::Running);
+        let (alive_sender, _alive_receiver) = mpsc::channel(1);
+        let started = tokio::time::Instant::now();
+        let listener_loop = tokio::spawn(tcp_listener_loop(
+            FailingListener {
+                listener,
+                errors: StdMutex::new(errors),
+                accepts: accepts.clone(),
+            },
+            address.to_string(),
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(StdMutex::new(HashMap::new())),
+            Arc::new(Mutex::new(chat::chat_server(chat::options()))),
+            Arc::new(server_options(frame_format.clone())),
+            ShutdownSignal {
+                state: state.subscribe(),
+                _alive_sender: alive_sender,
+            },
+        ));
+
+        let (reader, writer) = TcpStream::connect(address).await.unwrap().into_split();
+        write_message(&writer, &frame_format, b"not json", false)
+            .await
+            .unwrap();
+        let response = timeout(Duration::from_secs(60), read(&reader, &frame_format, false))
+            .await
+            .expect("connection should be accepted once descriptors are available")
+            .unwrap();
+
+        assert_eq!(json_frame(&response)["Error"]["code"], "ProtocolError");
+        // Every failure has been retried, the last call is the accept which has succeeded
+        assert!(accepts.load(Ordering::Relaxed) >= 4);
+        // Backed off after each failure instead of spinning, at least half of 100, 200 and 400 ms
+        assert!(started.elapsed() >= Duration::from_millis(350));
+        listener_loop.abort();
+    }
+
+    /// Accepts from the real listener, keeping a handle to every accepted socket
+    struct RecordingListener {
+        listener: TcpListener,
+        accepted: Arc<StdMutex<Vec<Socket>>>,
+    }
+
+    impl ConnectionListener for RecordingListener {
+        async fn bind(_address: &str) -> io::Result<Self> {
+            panic!("listener should not be bound again");
+        }
+
+        async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+            let (stream, address) = self.listener.accept().await?;
+            self.accepted
+                .lock()
+                .unwrap()
+                .push(SockRef::from(&stream).try_clone()?);
+            Ok((stream, address))
+        }
+    }
+
+    #[tokio::test]
+    async fn socket_options_are_set_on_accepted_connections() {
+        let frame_format = frame_format(1024);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let accepted = Arc::new(StdMutex::new(Vec::new()));
+        let (state, _) = watch::channel(ServerState::Running);
+        let (alive_sender, _alive_receiver) = mpsc::channel(1);
+        let listener_loop = tokio::spawn(tcp_listener_loop(
+            RecordingListener {
+                listener,
+                accepted: accepted.clone(),
+            },
+            address.to_string(),
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(StdMutex::new(HashMap::new())),
+            Arc::new(Mutex::new(chat::chat_server(chat::options()))),
+            Arc::new(TcpServerOptions {
+                socket_options: SocketOptions {
+                    nodelay: true,
+                    keepalive: Some(Duration::from_secs(30)),
+                },
+                ..server_options(frame_format.clone())
+            }),
+            ShutdownSignal {
+                state: state.subscribe(),
+                _alive_sender: alive_sender,
+            },
+        ));
+
+        // Answered only after the options have been applied
+        let (reader, writer) = TcpStream::connect(address).await.unwrap().into_split();
+        write_message(&writer, &frame_format, b"not json", false)
+            .await
+            .unwrap();
+        read(&reader, &frame_format, false).await.unwrap();
+
+        let accepted = accepted.lock().unwrap();
+        assert_eq!(accepted.len(), 1);
+        assert!(accepted[0].nodelay().unwrap());
+        assert!(accepted[0].keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            accepted[0].keepalive_time().unwrap(),
+            Duration::from_secs(30)
+        );
+        listener_loop.abort();
+    }
+
+    #[test]
+    fn accept_backoff_doubles_up_to_the_cap() {
+        let backoffs: Vec<Duration> = (0..=8).map(accept_backoff).collect();
+
+        assert_eq!(
+            backoffs,
+            [0, 100, 200, 400, 800, 1600, 3200, 5000, 5000].map(Duration::from_millis)
+        );
+        assert_eq!(accept_backoff(u32::MAX), ACCEPT_BACKOFF_CAP);
+    }
+
+    #[test]
+    fn jitter_keeps_at_least_half_of_the_backoff() {
+        let mut rng = StdRng::seed_from_u64(841);
+        let backoff = Duration::from_millis(800);
+
+        let jittered: Vec<Duration> = (0..1000).map(|_| with_jitter(backoff, &mut rng)).collect();
+
+        assert!(jittered
+            .iter()
+            .all(|jittered| (backoff / 2..=backoff).contains(jittered)));
+        // Spread over the whole range rather than stuck at one end
+        assert!(jittered.iter().any(|jittered| *jittered < backoff * 6 / 10));
+        assert!(jittered.iter().any(|jittered| *jittered > backoff * 9 / 10));
+        assert_eq!(with_jitter(Duration::ZERO, &mut rng), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn read_buffer_shrinks_after_a_large_frame() {
+        let format = frame_format(1024 * 1024);
+        let (read_stream, write_stream) = connected_pair().await;
+        let large = vec![b'x'; 512 * 1024];
+        // Written concurrently, the large frame doesn't fit into the socket buffers
+        let writer = tokio::spawn({
+            let format = format.clone();
+            let large = large.clone();
+            async move {
+                write_message(&write_stream, &format, &large, false).await?;
+                write_message(&write_stream, &format, b"small", false).await
+            }
+        });
+        let mut buffer = Vec::new();
+
+        let frame = read_message(
+            "test".to_string(),
+            &read_stream,
+            &format,
+            false,
+            &mut buffer,
+        )
+        .await
+        .unwrap();
+        assert_eq!(frame.len(), large.len());
+        assert!(buffer.capacity() >= large.len());
+        shrink_read_buffer(&mut buffer);
+        assert!(buffer.capacity() <= READ_BUFFER_RETAINED_CAPACITY);
+
+        let frame = read_message(
+            "test".to_string(),
+            &read_stream,
+            &format,
+            false,
+            &mut buffer,
+        )
+        .await
+        .unwrap();
+        assert_eq!(&*frame, b"small");
+        writer.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn frames_are_read_into_the_reused_buffer() {
+        let frame_format = frame_format(1024);
+        let (reader, writer) = connected_pair().await;
+        let bodies: [&[u8]; 3] = [b"first", b"second, a little longer", b"third"];
+        for body in bodies {
+            write_message(&writer, &frame_format, body, false)
+                .await
+                .unwrap();
+        }
+        let compressed_body = b"compressed ".repeat(10);
+        write_message(
+            &writer,
+            &frame_format,
+            &compress(&compressed_body).unwrap(),
+            true,
+        )
+        .await
+        .unwrap();
+        let mut buffer = Vec::with_capacity(READ_BUFFER_RETAINED_CAPACITY);
+        let allocation = buffer.as_ptr();
+
+        for body in bodies {
+            let frame = read_message(
+                "test".to_string(),
+                &reader,
+                &frame_format,
+                false,
+                &mut buffer,
+            )
+            .await
+            .unwrap();
+            assert!(matches!(frame, Cow::Borrowed(_)));
+            assert_eq!(frame.as_ptr(), allocation);
+            assert_eq!(&*frame, body);
+            shrink_read_buffer(&mut buffer);
+            assert_eq!(buffer.as_ptr(), allocation);
+        }
+
+        let frame = read_message(
+            "test".to_string(),
+            &reader,
+            &frame_format,
+            true,
+            &mut buffer,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(frame, Cow::Owned(_)));
+        assert_eq!(&*frame, compressed_body);
+    }
+
+    #[test]
+    fn read_buffer_of_typical_frames_is_kept() {
+        let mut buffer = Vec::with_capacity(READ_BUFFER_RETAINED_CAPACITY);
+        buffer.extend_from_slice(b"message");
+
+        shrink_read_buffer(&mut buffer);
+
+        assert_eq!(buffer.capacity(), READ_BUFFER_RETAINED_CAPACITY);
+    }
+
+    fn server_options(frame_format: FrameFormat) -> TcpServerOptions {
+        TcpServerOptions {
+            compression_threshold: None,
+            health_address: None,
+            admin: None,
+            idle_timeout: None,
+            idle_warning: None,
+            max_connections_per_ip: None,
+            socket_options: SocketOptions {
+                nodelay: true,
+                keepalive: None,
+            },
+            slow_consumer_limit: None,
+            history_retention: None,
+            attachment_retention: None,
+            frame_format,
+            stats_broadcast: None,
+            shutdown_grace: None,
+            console: false,
+        }
+    }
+
+    /// Connection whose writer task writes into the returned stream's peer
+    fn spawn_connection(stream: OwnedWriteHalf, frame_format: FrameFormat) -> Connection {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let closed = Arc::new(Notify::new());
+        let (state, _) = watch::channel(ServerState::Running);
+        let (alive_sender, _) = mpsc::channel(1);
+        let writer = tokio::spawn(connection_writer_loop(
+            "test".to_string(),
+            stream,
+            frame_format,
+            receiver,
+            pending.clone(),
+            closed.clone(),
+            ShutdownSignal {
+                state: state.subscribe(),
+                _alive_sender: alive_sender,
+            },
+        ));
+        Connection {
+            sender,
+            pending,
+            slow_since: None,
+            writer: Arc::new(writer.abort_handle()),
+            closed,
+            compression: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn disconnect_reason_is_written_before_eof() {
+        let frame_format = frame_format(1024);
+        let (reader, writer) = connected_pair().await;
+        let connection = spawn_connection(writer, frame_format.clone());
+        let pending = connection.pending.clone();
+        let closed = connection.closed.clone();
+        let connections = Arc::new(Mutex::new(HashMap::from([(
+            "user".to_string(),
+            connection,
+        )])));
+
+        let missing = process_command(
+            connections.clone(),
+            &server_options(frame_format.clone()),
+            ChatServerResponseCommand::DisconnectUser("user".to_string(), Some(b"bye"[..].into())),
+        )
+        .await;
+        closed.notified().await;
+
+        assert!(missing.is_empty());
+        assert!(connections.lock().await.is_empty());
+        assert_eq!(read(&reader, &frame_format, false).await.unwrap(), b"bye");
+        // Peer closing at a frame boundary reads as an empty message
+        assert!(read(&reader, &frame_format, false)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(pending.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn frames_to_gone_connections_are_not_counted() {
+        let (_reader, writer) = connected_pair().await;
+        let connection = spawn_connection(writer, frame_format(1024));
+        connection.writer.abort();
+        // Writer drops its receiver once the abort has taken effect
+        while !connection.sender.is_closed() {
+            yield_now().await;
+        }
+
+        assert!(!connection.queue_frame(b"lost"[..].into(), false, None));
+        assert_eq!(connection.pending.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn missing_recipients_are_reported() {
+        let frame_format = frame_format(1024);
+        let (reader, writer) = connected_pair().await;
+        let connections = Arc::new(Mutex::new(HashMap::from([(
+            "user".to_string(),
+            spawn_connection(writer, frame_format.clone()),
+        )])));
+
+        let missing = process_command(
+            connections,
+            &server_options(frame_format.clone()),
+            ChatServerResponseCommand::SendToSome(
+                vec!["user".to_string(), "gone".to_string()],
+                b"hello"[..].into(),
+            ),
+        )
+        .await;
+
+        assert_eq!(missing, vec!["gone".to_string()]);
+        assert_eq!(read(&reader, &frame_format, false).await.unwrap(), b"hello");
+    }
+
+    /// Connections without writer tasks, their frames stay queued in the returned receivers
+    fn queue_only_connections(
+        count: usize,
+    ) -> (
+        HashMap<String, Connection>,
+        Vec<mpsc::UnboundedReceiver<Outgoing>>,
+    ) {
+        let mut receivers = Vec::new();
+        let mut connections = HashMap::new();
+        for index in 0..count {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            receivers.push(receiver);
+            connections.insert(
+                format!("user{index}"),
+                Connection {
+                    sender,
+                    pending: Arc::new(AtomicUsize::new(0)),
+                    slow_since: None,
+                    writer: Arc::new(tokio::spawn(async {}).abort_handle()),
+                    closed: Arc::new(Notify::new()),
+                    compression: false,
+                },
+            );
+        }
+        (connections, receivers)
+    }
+
+    #[tokio::test]
+    async fn fanned_out_payload_is_shared_by_all_recipients() {
+        const RECIPIENTS: usize = 100;
+        let (connections, receivers) = queue_only_connections(RECIPIENTS);
+        let payload: Arc<[u8]> = vec![b'x'; 64 * 1024].into();
+
+        process_command(
+            Arc::new(Mutex::new(connections)),
+            &server_options(frame_format(1024 * 1024)),
+            ChatServerResponseCommand::SendToAll(payload.clone()),
+        )
+        .await;
+
+        // Every queued frame holds the same allocation, the payload is never copied per recipient
+        assert_eq!(Arc::strong_count(&payload), RECIPIENTS + 1);
+        for mut receiver in receivers {
+            let Ok(Outgoing::Frame { bytes, .. }) = receiver.try_recv() else {
+                panic!("frame should be queued");
+            };
+            assert!(Arc::ptr_eq(&bytes, &payload));
+        }
+    }
+
+    #[tokio::test]
+    async fn fan_out_queues_every_frame_under_one_lock() {
+        const RECIPIENTS: usize = 1000;
+        let (connections, _receivers) = queue_only_connections(RECIPIENTS);
+        let pending: Vec<Arc<AtomicUsize>> = connections
+            .values()
+            .map(|connection| connection.pending.clone())
+            .collect();
+        let connections = Arc::new(Mutex::new(connections));
+
+        // Lock is fair, so whoever waits behind the fan-out gets it as soon as the fan-out lets go
+        let guard = connections.lock().await;
+        let fan_out = tokio::spawn({
+            let connections = connections.clone();
+            async move {
+                let options = server_options(frame_format(1024));
+                process_command(
+                    connections,
+                    &options,
+                    ChatServerResponseCommand::SendToAll(b"hello"[..].into()),
+                )
+                .await
+            }
+        });
+        yield_now().await;
+        let queued_when_released = tokio::spawn({
+            let connections = connections.clone();
+            async move {
+                let _guard = connections.lock().await;
+                pending
+                    .iter()
+                    .map(|pending| pending.load(Ordering::Relaxed))
+                    .sum::<usize>()
+            }
+        });
+        yield_now().await;
+        drop(guard);
+
+        assert_eq!(queued_when_released.await.unwrap(), RECIPIENTS);
+        assert!(fan_out.await.unwrap().is_empty());
+    }
+
+    /// Run with `cargo test -- --ignored --nocapture` to see how long a large broadcast takes
+    #[tokio::test]
+    #[ignore]
+    async fn fan_out_throughput() {
+        const RECIPIENTS: usize = 10_000;
+        const BROADCASTS: usize = 100;
+        let (connections, _receivers) = queue_only_connections(RECIPIENTS);
+        let connections = Arc::new(Mutex::new(connections));
+        let options = server_options(frame_format(1024));
+
+        let started = Instant::now();
+        for _ in 0..BROADCASTS {
+            process_command(
+                connections.clone(),
+                &options,
+                ChatServerResponseCommand::SendToAll(b"hello"[..].into()),
+            )
+            .await;
+        }
+        let elapsed = started.elapsed();
+
+        println!(
+            "{BROADCASTS} broadcasts to {RECIPIENTS} connections in {elapsed:?}, {:?} each",
+            elapsed / BROADCASTS as u32
+        );
+    }
+
+    #[tokio::test]
+    async fn eof_inside_a_header_is_an_error() {
+        let frame_format = frame_format(1024);
+        let (reader, writer) = connected_pair().await;
+
+        write_to_stream(&writer, &[0, 0]).await.unwrap();
+        drop(writer);
+
+        let err = read(&reader, &frame_format, false).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn eof_inside_a_body_is_an_error() {
+        let frame_format = frame_format(1024);
+        let (reader, writer) = connected_pair().await;
+        let header = frame_format.header_size.encode(10, false).unwrap();
+
+        write_to_stream(&writer, &header).await.unwrap();
+        write_to_stream(&writer, b"short").await.unwrap();
+        drop(writer);
+
+        let err = read(&reader, &frame_format, false).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    /// Chat server on a free port of the loopback interface
+    async fn start_server(options: TcpServerOptions) -> ServerHandle {
+        start_chat_server(options, chat::options()).await
+    }
+
+    async fn start_chat_server(
+        options: TcpServerOptions,
+        chat_options: ChatServerOptions,
+    ) -> ServerHandle {
+        let chat_server = chat::chat_server(chat_options);
+        ChatTcpServer::create_async("127.0.0.1", 0, chat_server, options)
+            .await
+            .unwrap()
+            .start()
+    }
+
+    async fn connect(handle: &ServerHandle) -> (OwnedReadHalf, OwnedWriteHalf) {
+        TcpStream::connect(handle.addr())
+            .await
+            .unwrap()
+            .into_split()
+    }
+
+    /// Reads frames until the server closes the connection, which has to happen within `within`
+    async fn frames_until_closed(
+        stream: &OwnedReadHalf,
+        frame_format: &FrameFormat,
+        within: Duration,
+    ) -> Vec<Vec<u8>> {
+        timeout(within, async {
+            let mut frames = Vec::new();
+            loop {
+                let frame = read(stream, frame_format, false).await.unwrap();
+                if frame.is_empty() {
+                    return frames;
+                }
+                frames.push(frame);
+            }
+        })
+        .await
+        .expect("connection should be closed")
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_connection_is_dropped() {
+        let frame_format = frame_format(1024);
+        let server = ChatTcpServer::create_async(
+            "127.0.0.1",
+            0,
+            chat::chat_server(chat::options()),
+            TcpServerOptions {
+                idle_timeout: Some(Duration::from_secs(60)),
+                ..server_options(frame_format.clone())
+            },
+        )
+        .await
+        .unwrap();
+        let chat_server = server.chat_server();
+        let handle = server.start();
+        let connection = connect(&handle).await;
+        log_in(&connection, &frame_format, "AliceAlice").await;
+        assert_eq!(chat_server.lock().await.online_users_count(), 1);
+        let idle_since = tokio::time::Instant::now();
+
+        frames_until_closed(&connection.0, &frame_format, Duration::from_secs(120)).await;
+
+        assert!(idle_since.elapsed() >= Duration::from_secs(60));
+        // Leaving is processed right after the socket is closed
+        timeout(Duration::from_secs(5), async {
+            while chat_server.lock().await.online_users_count() > 0 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("idle user should leave the roster");
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn frames_keep_connection_from_idling() {
+        let frame_format = frame_format(1024);
+        let handle = start_server(TcpServerOptions {
+            idle_timeout: Some(Duration::from_millis(300)),
+            ..server_options(frame_format.clone())
+        })
+        .await;
+        let (reader, writer) = connect(&handle).await;
+
+        // Together longer than the idle timeout, but never idle for that long
+        for _ in 0..6 {
+            sleep(Duration::from_millis(100)).await;
+            write_message(&writer, &frame_format, b"not json", false)
+                .await
+                .unwrap();
+            let response = read(&reader, &frame_format, false).await.unwrap();
+            assert!(!response.is_empty(), "connection should still be open");
+        }
+
+        handle.shutdown().await;
+    }
+
+    fn json_frame(frame: &[u8]) -> serde_json::Value {
+        serde_json::from_slice(frame).unwrap()
+    }
+
+    /// Registers and logs in over the connection, frames sent before the login result are skipped
+    async fn log_in(
+        (reader, writer): &(OwnedReadHalf, OwnedWriteHalf),
+        frame_format: &FrameFormat,
+        name: &str,
+    ) {
+        let credentials = serde_json::json!({
+            "user_credentials_raw": { "name": name, "password": "password1", "email": null }
+        });
+        for request in ["Registration", "Authentication"] {
+            let request = serde_json::json!({ request: credentials });
+            write_message(writer, frame_format, request.to_string().as_bytes(), false)
+                .await
+                .unwrap();
+        }
+        loop {
+            let frame = read(reader, frame_format, false).await.unwrap();
+            if json_frame(&frame).get("AuthenticationResult").is_some() {
+                return;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn queued_frames_are_written_whole_and_in_order() {
+        let frame_format = frame_format(1024 * 1024);
+        let (reader, writer) = connected_pair().await;
+        let connection = spawn_connection(writer, frame_format.clone());
+        // Larger than the socket buffers, so it is written in several parts
+        let large: Arc<[u8]> = vec![b'x'; 512 * 1024].into();
+
+        assert!(connection.queue_frame(large.clone(), false, None));
+        for index in 0..10u8 {
+            assert!(connection.queue_frame(Arc::from([index]), false, None));
+        }
+
+        assert_eq!(read(&reader, &frame_format, false).await.unwrap(), &*large);
+        for index in 0..10u8 {
+            assert_eq!(read(&reader, &frame_format, false).await.unwrap(), [index]);
+        }
+    }
+
+    #[tokio::test]
+    async fn chat_messages_arrive_in_the_order_they_were_sent() {
+        const SENDERS: usize = 4;
+        const MESSAGES_PER_SENDER: usize = 250;
+        let frame_format = frame_format(1024);
+        let handle = start_server(server_options(frame_format.clone())).await;
+        let mut observers = Vec::new();
+        for name in ["ObserverOne", "ObserverTwo"] {
+            let observer = connect(&handle).await;
+            log_in(&observer, &frame_format, name).await;
+            observers.push(observer);
+        }
+        let mut senders = Vec::new();
+        for index in 0..SENDERS {
+            let sender = connect(&handle).await;
+            log_in(&sender, &frame_format, &format!("Sender{index}")).await;
+            senders.push(sender);
+        }
+
+        // Senders write concurrently, so their messages interleave on the server
+        let writes = senders.iter().enumerate().map(|(sender, (_, writer))| {
+            let frame_format = &frame_format;
+            async move {
+                for index in 0..MESSAGES_PER_SENDER {
+                    let message = format!("{sender} {index}");
+                    let request = serde_json::json!({ "Message": { "message": message } });
+                    write_message(writer, frame_format, request.to_string().as_bytes(), false)
+                        .await
+                        .unwrap();
+                    yield_now().await;
+                }
+            }
+        });
+        join_all(writes).await;
+
+        let mut sequences = Vec::new();
+        for (reader, _) in &observers {
+            let mut received = Vec::new();
+            while received.len() < SENDERS * MESSAGES_PER_SENDER {
+                let frame = timeout(Duration::from_secs(5), read(reader, &frame_format, false))
+                    .await
+                    .expect("messages should arrive")
+                    .unwrap();
+                if let Some(message) = json_frame(&frame).get("Message") {
+                    let (sender, index) = message["message"]
+                        .as_str()
+                        .unwrap()
+                        .split_once(' ')
+                        .unwrap();
+                    received.push((
+                        sender.parse::<usize>().unwrap(),
+                        index.parse::<usize>().unwrap(),
+                    ));
+                }
+            }
+            sequences.push(received);
+        }
+
+        for sender in 0..SENDERS {
+            let sent: Vec<usize> = sequences[0]
+                .iter()
+                .filter(|(from, _)| *from == sender)
+                .map(|(_, index)| *index)
+                .collect();
+            assert_eq!(sent, (0..MESSAGES_PER_SENDER).collect::<Vec<_>>());
+        }
+        assert!(
+            sequences[0].windows(2).any(|pair| pair[0].0 != pair[1].0),
+            "messages of the senders should interleave"
+        );
+        // Every recipient sees the same order
+        assert_eq!(sequences[0], sequences[1]);
+
+        handle.shutdown().await;
+    }
+
+    /// Server compressing frames over 256 bytes for clients that negotiate it
+    async fn start_compressing_server(frame_format: FrameFormat) -> ServerHandle {
+        start_chat_server(
+            TcpServerOptions {
+                compression_threshold: Some(256),
+                ..server_options(frame_format)
+            },
+            ChatServerOptions {
+                compression: true,
+                ..chat::options()
+            },
+        )
+        .await
+    }
+
+    async fn negotiate_compression(
+        (reader, writer): &(OwnedReadHalf, OwnedWriteHalf),
+        frame_format: &FrameFormat,
+    ) {
+        let request = serde_json::json!({ "Handshake": { "compression": true } });
+        write_message(writer, frame_format, request.to_string().as_bytes(), false)
+            .await
+            .unwrap();
+        loop {
+            let frame = read(reader, frame_format, false).await.unwrap();
+            if let Some(result) = json_frame(&frame).get("HandshakeResult") {
+                assert_eq!(result["compression"], true);
+                return;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn compressed_frames_are_refused_before_compression_is_negotiated() {
+        let frame_format = frame_format(1024);
+        let handle = start_compressing_server(frame_format.clone()).await;
+        let negotiated = connect(&handle).await;
+        let (reader, writer) = connect(&handle).await;
+        negotiate_compression(&negotiated, &frame_format).await;
+
+        let body = compress(b"not json").unwrap();
+        write_message(&negotiated.1, &frame_format, &body, true)
+            .await
+            .unwrap();
+        let response = read(&negotiated.0, &frame_format, false).await.unwrap();
+        assert!(!response.is_empty(), "connection should still be open");
+
+        write_message(&writer, &frame_format, &body, true)
+            .await
+            .unwrap();
+        frames_until_closed(&reader, &frame_format, Duration::from_secs(5)).await;
+
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn small_frames_are_never_compressed() {
+        let frame_format = frame_format(4096);
+        let handle = start_compressing_server(frame_format.clone()).await;
+        let connection = connect(&handle).await;
+        negotiate_compression(&connection, &frame_format).await;
+        // Frames read without allowing compression fail if they arrive compressed
+        log_in(&connection, &frame_format, "AliceAlice").await;
+
+        for message in ["short".to_string(), "long ".repeat(100)] {
+            let request = serde_json::json!({ "Message": { "message": message } });
+            write_message(
+                &connection.1,
+                &frame_format,
+                request.to_string().as_bytes(),
+                false,
+            )
+            .await
+            .unwrap();
+        }
+
+        loop {
+            let frame = read(&connection.0, &frame_format, false).await.unwrap();
+            if let Some(message) = json_frame(&frame).get("Message") {
+                assert_eq!(message["message"], "short");
+                break;
+            }
+        }
+        let error = read(&connection.0, &frame_format, false).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn motd_is_the_first_frame_after_connecting() {
+        let frame_format = frame_format(1024);
+        let handle = start_chat_server(
+            server_options(frame_format.clone()),
+            ChatServerOptions {
+                motd: Some(Motd::Text("Welcome!".to_string())),
+                ..chat::options()
+            },
+        )
+        .await;
+        let (reader, _writer) = connect(&handle).await;
+
+        let frame = timeout(Duration::from_secs(5), read(&reader, &frame_format, false))
+            .await
+            .expect("MOTD should be sent right away")
+            .unwrap();
+
+        assert_eq!(
+            json_frame(&frame),
+            serde_json::json!({ "Motd": { "text": "Welcome!" } })
+        );
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn disconnect_request_is_answered_with_goodbye_before_closing() {
+        let frame_format = frame_format(1024);
+        let handle = start_server(server_options(frame_format.clone())).await;
+        let connection = connect(&handle).await;
+        log_in(&connection, &frame_format, "AliceAlice").await;
+
+        write_message(&connection.1, &frame_format, b"\"Disconnect\"", false)
+            .await
+            .unwrap();
+
+        let frames =
+            frames_until_closed(&connection.0, &frame_format, Duration::from_secs(5)).await;
+        let last = frames.last().expect("goodbye should be written");
+        assert_eq!(json_frame(last), serde_json::json!("Goodbye"));
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_says_goodbye_and_closes_connections() {
+        let frame_format = frame_format(1024);
+        let handle = start_server(server_options(frame_format.clone())).await;
+        let (reader, _writer) = connect(&handle).await;
+
+        handle.shutdown().await;
+
+        let frames = frames_until_closed(&reader, &frame_format, Duration::from_secs(5)).await;
+        let frames: Vec<serde_json::Value> = frames.iter().map(|frame| json_frame(frame)).collect();
+        assert_eq!(frames, vec![serde_json::json!("Goodbye")]);
+        assert!(TcpStream::connect(handle.addr()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn shutdown_can_be_requested_from_several_handles() {
+        let frame_format = frame_format(1024);
+        let handle = start_server(server_options(frame_format.clone())).await;
+        let _connection = connect(&handle).await;
+        let other_handle = handle.clone();
+
+        timeout(Duration::from_secs(5), async {
+            tokio::join!(handle.shutdown(), other_handle.shutdown());
+            // Already stopped, so it resolves right away
+            handle.shutdown().await;
+        })
+        .await
+        .expect("shutdown should finish");
+    }
+
+    #[tokio::test]
+    async fn stopped_server_frees_its_port() {
+        let frame_format = frame_format(1024);
+        let handle = start_server(server_options(frame_format.clone())).await;
+        let port = handle.addr().port();
+        let _connection = connect(&handle).await;
+        handle.shutdown().await;
+
+        let chat_server = chat::chat_server(chat::options());
+        let restarted = ChatTcpServer::create_async(
+            "127.0.0.1",
+            port,
+            chat_server,
+            server_options(frame_format.clone()),
+        )
+        .await
+        .expect("port should be free again")
+        .start();
+
+        assert_eq!(restarted.addr().port(), port);
+        restarted.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn first_signal_shuts_the_server_down() {
+        let frame_format = frame_format(1024);
+        let handle = start_server(server_options(frame_format.clone())).await;
+        let (reader, _writer) = connect(&handle).await;
+        let (signals, signal_receiver) = futures::channel::mpsc::unbounded();
+        let coordinator = tokio::spawn({
+            let handle = handle.clone();
+            async move { coordinate_shutdown(signal_receiver, handle.shutdown()).await }
+        });
+
+        signals.unbounded_send(()).unwrap();
+
+        let frames = frames_until_closed(&reader, &frame_format, Duration::from_secs(5)).await;
+        assert_eq!(frames.len(), 1);
+        timeout(Duration::from_secs(5), coordinator)
+            .await
+            .expect("coordinator should return once the server has stopped")
+            .unwrap();
+        assert!(TcpStream::connect(handle.addr()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn second_signal_stops_waiting_for_the_shutdown() {
+        let (signals, signal_receiver) = futures::channel::mpsc::unbounded();
+        let shutdown_started = Arc::new(Notify::new());
+        // Never finishes, like a shutdown stuck on a client which doesn't read
+        let shutdown = {
+            let shutdown_started = shutdown_started.clone();
+            async move {
+                shutdown_started.notify_one();
+                future::pending::<()>().await
+            }
+        };
+        let coordinator = tokio::spawn(coordinate_shutdown(signal_receiver, shutdown));
+
+        signals.unbounded_send(()).unwrap();
+        timeout(Duration::from_secs(5), shutdown_started.notified())
+            .await
+            .expect("first signal should start the shutdown");
+        assert!(!coordinator.is_finished());
+
+        signals.unbounded_send(()).unwrap();
+        timeout(Duration::from_secs(5), coordinator)
+            .await
+            .expect("second signal should force the shutdown")
+            .unwrap();
+    }
+
+    #[test]
+    fn header_widths_have_their_limits() {
+        assert_eq!(HeaderSize::Two.max_body_size(), (1 << 15) - 1);
+        assert_eq!(HeaderSize::Four.max_body_size(), (1 << 31) - 1);
+        assert_eq!(HeaderSize::Eight.max_body_size(), (1 << 63) - 1);
+    }
+
+    #[test]
+    fn headers_round_trip_up_to_their_limit() {
+        for header_size in [HeaderSize::Two, HeaderSize::Four, HeaderSize::Eight] {
+            let max = header_size.max_body_size();
+            let Ok(max_usize) = usize::try_from(max) else {
+                continue;
+            };
+            for is_compressed in [false, true] {
+                let header = header_size.encode(max_usize, is_compressed).unwrap();
+                assert_eq!(header.len(), header_size.len());
+                assert_eq!(header_size.decode(&header), (max, is_compressed));
+            }
+            let header = header_size.encode(0, true).unwrap();
+            assert_eq!(header_size.decode(&header), (0, true));
+        }
+    }
+
+    #[test]
+    fn bodies_over_the_header_limit_are_not_encoded() {
+        for header_size in [HeaderSize::Two, HeaderSize::Four] {
+            let too_large = header_size.max_body_size() as usize + 1;
+            let err = header_size.encode(too_large, false).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip_with_every_header_width() {
+        for header_size in [HeaderSize::Two, HeaderSize::Four, HeaderSize::Eight] {
+            let frame_format = FrameFormat {
+                header_size,
+                ..frame_format(1024)
+            };
+            let (reader, writer) = connected_pair().await;
+
+            write_message(&writer, &frame_format, b"hello", false)
+                .await
+                .unwrap();
+            write_message(&writer, &frame_format, b"", false)
+                .await
+                .unwrap();
+            write_message(&writer, &frame_format, &[7; 1024], false)
+                .await
+                .unwrap();
+
+            assert_eq!(read(&reader, &frame_format, false).await.unwrap(), b"hello");
+            // Empty body reads like the peer closing the connection, so it never carries a request
+            assert!(read(&reader, &frame_format, false)
+                .await
+                .unwrap()
+                .is_empty());
+            assert_eq!(
+                read(&reader, &frame_format, false).await.unwrap(),
+                [7; 1024]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn frame_over_the_configured_limit_is_rejected() {
+        let frame_format = FrameFormat {
+            header_size: HeaderSize::Two,
+            ..frame_format(100)
+        };
+        let (reader, writer) = connected_pair().await;
+
+        write_message(&writer, &frame_format, &[0; 101], false)
+            .await
+            .unwrap();
+
+        let err = read(&reader, &frame_format, false).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn slow_frame_format() -> FrameFormat {
+        FrameFormat {
+            max_read_duration: Some(Duration::from_millis(200)),
+            ..frame_format(1024)
+        }
+    }
+
+    #[tokio::test]
+    async fn frame_arriving_too_slowly_is_rejected() {
+        let frame_format = slow_frame_format();
+        let (reader, writer) = connected_pair().await;
+        let header = frame_format.header_size.encode(10, false).unwrap();
+
+        // Rest of the body never comes
+        write_to_stream(&writer, &header).await.unwrap();
+        write_to_stream(&writer, b"slow").await.unwrap();
+
+        let err = read(&reader, &frame_format, false).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn waiting_for_a_frame_is_not_bounded() {
+        let frame_format = slow_frame_format();
+        let (reader, writer) = connected_pair().await;
+
+        let writer_task = tokio::spawn({
+            let frame_format = frame_format.clone();
+            async move {
+                // Longer than a frame may take, but the frame itself arrives at once
+                sleep(Duration::from_millis(400)).await;
+                write_message(&writer, &frame_format, b"late", false).await
+            }
+        });
+
+        assert_eq!(read(&reader, &frame_format, false).await.unwrap(), b"late");
+        writer_task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn frame_within_the_limit_is_read() {
+        let frame_format = slow_frame_format();
+        let (reader, writer) = connected_pair().await;
+        let header = frame_format.header_size.encode(10, false).unwrap();
+
+        let writer_task = tokio::spawn(async move {
+            write_to_stream(&writer, &header).await?;
+            sleep(Duration::from_millis(50)).await;
+            write_to_stream(&writer, b"in pieces!").await
+        });
+
+        assert_eq!(
+            read(&reader, &frame_format, false).await.unwrap(),
+            b"in pieces!"
+        );
+        writer_task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn session_address_is_the_peer_address_of_the_connection() {
+        let frame_format = frame_format(1024);
+        let handle = start_server(server_options(frame_format.clone())).await;
+        let stream = TcpStream::connect(handle.addr()).await.unwrap();
+        let local_address = stream.local_addr().unwrap();
+        let connection = stream.into_split();
+        log_in(&connection, &frame_format, "AliceAlice").await;
+
+        write_message(&connection.1, &frame_format, b"\"ListSessions\"", false)
+            .await
+            .unwrap();
+        let sessions = loop {
+            let frame = json_frame(&read(&connection.0, &frame_format, false).await.unwrap());
+            if let Some(sessions) = frame.get("Sessions") {
+                break sessions["sessions"].clone();
+            }
+        };
+
+        assert_eq!(sessions[0]["address"], local_address.to_string());
+        assert_ne!(local_address.port(), handle.addr().port());
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_per_address_limit_are_refused() {
+        const LIMIT: usize = 3;
+        let frame_format = frame_format(1024);
+        let handle = start_server(TcpServerOptions {
+            max_connections_per_ip: Some(LIMIT),
+            ..server_options(frame_format.clone())
+        })
+        .await;
+
+        let mut accepted = Vec::new();
+        for _ in 0..LIMIT {
+            let connection = connect(&handle).await;
+            write_message(&connection.1, &frame_format, b"not json", false)
+                .await
+                .unwrap();
+            let response = json_frame(&read(&connection.0, &frame_format, false).await.unwrap());
+            assert_eq!(response["Error"]["code"], "ProtocolError");
+            accepted.push(connection);
+        }
+
+        for _ in 0..2 {
+            let (reader, _writer) = connect(&handle).await;
+            let frames = frames_until_closed(&reader, &frame_format, Duration::from_secs(5)).await;
+            assert_eq!(frames.len(), 1);
+            assert_eq!(
+                json_frame(&frames[0])["Error"]["code"],
+                "TooManyConnections"
+            );
+        }
+
+        // Closing an accepted connection frees its slot
+        drop(accepted.pop());
+        timeout(Duration::from_secs(5), async {
+            loop {
+                let (reader, writer) = connect(&handle).await;
+                write_message(&writer, &frame_format, b"not json", false)
+                    .await
+                    .unwrap();
+                let response = json_frame(&read(&reader, &frame_format, false).await.unwrap());
+                if response["Error"]["code"] == "ProtocolError" {
+                    return;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("freed slot should be taken by a new connection");
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn slow_consumer_is_dropped_while_healthy_connections_are_kept() {
+        const FRAMES: usize = 64;
+        let frame_format = frame_format(1024 * 1024);
+        // Never read, so the writer gets stuck once the socket buffers are full
+        let (_slow_reader, slow_writer) = connected_pair().await;
+        let (healthy_reader, healthy_writer) = connected_pair().await;
+        let connections = Arc::new(Mutex::new(HashMap::from([
+            (
+                "slow".to_string(),
+                spawn_connection(slow_writer, frame_format.clone()),
+            ),
+            (
+                "healthy".to_string(),
+                spawn_connection(healthy_writer, frame_format.clone()),
+            ),
+        ])));
+        let healthy_reads = tokio::spawn({
+            let frame_format = frame_format.clone();
+            async move {
+                for _ in 0..FRAMES {
+                    read(&healthy_reader, &frame_format, false).await.unwrap();
+                }
+            }
+        });
+
+        let large: Arc<[u8]> = vec![b'x'; 256 * 1024].into();
+        for connection in connections.lock().await.values() {
+            for _ in 0..FRAMES {
+                assert!(connection.queue_frame(large.clone(), false, None));
+            }
+        }
+        let reaper = tokio::spawn(slow_consumer_loop(
+            connections.clone(),
+            8,
+            Duration::from_millis(500),
+        ));
+
+        timeout(Duration::from_secs(10), async {
+            while connections.lock().await.contains_key("slow") {
+                sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("slow consumer should be dropped");
+
+        timeout(Duration::from_secs(10), healthy_reads)
+            .await
+            .expect("healthy connection should get every frame")
+            .unwrap();
+        assert!(connections.lock().await.contains_key("healthy"));
+        reaper.abort();
+    }
+
+    #[tokio::test]
+    async fn interleaved_connects_and_disconnects_leave_no_stale_sessions() {
+        let frame_format = frame_format(1024);
+        let server = ChatTcpServer::create_async(
+            "127.0.0.1",
+            0,
+            chat::chat_server(chat::options()),
+            server_options(frame_format.clone()),
+        )
+        .await
+        .unwrap();
+        let connections = server.connections.clone();
+        let chat_server = server.chat_server();
+        let handle = server.start();
+        let observer = connect(&handle).await;
+        log_in(&observer, &frame_format, "Observer").await;
+
+        let clients = (0..20).map(|client| {
+            let address = handle.addr();
+            let frame_format = frame_format.clone();
+            tokio::spawn(async move {
+                for round in 0..5 {
+                    let connection = TcpStream::connect(address).await.unwrap().into_split();
+                    // Some connections are gone before they ever log in
+                    if (client + round) % 3 == 0 {
+                        continue;
+                    }
+                    log_in(&connection, &frame_format, &format!("User{client:04}")).await;
+                    for index in 0..3 {
+                        let message = serde_json::json!({
+                            "Message": { "message": format!("{client} {round} {index}") }
+                        });
+                        let _ = write_message(
+                            &connection.1,
+                            &frame_format,
+                            message.to_string().as_bytes(),
+                            false,
+                        )
+                        .await;
+                    }
+                    // Dropped without reading, while broadcasts to it may still be in flight
+                }
+            })
+        });
+        for client in future::join_all(clients).await {
+            client.unwrap();
+        }
+
+        timeout(Duration::from_secs(10), async {
+            loop {
+                let connection_count = connections.lock().await.len();
+                let session_count = chat::session_count(&*chat_server.lock().await);
+                if connection_count == 1 && session_count == 1 {
+                    return;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("only the observer should be left");
+        assert_eq!(chat_server.lock().await.online_users_count(), 1);
+
+        write_message(&observer.1, &frame_format, b"\"OnlineCount\"", false)
+            .await
+            .unwrap();
+        let online_count = loop {
+            let frame = json_frame(&read(&observer.0, &frame_format, false).await.unwrap());
+            if let Some(online_count) = frame.get("OnlineCount") {
+                break online_count["online_count"].clone();
+            }
+        };
+        assert_eq!(online_count, 1);
+        handle.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stats_are_broadcast_once_per_interval() {
+        let frame_format = frame_format(1024);
+        let started = tokio::time::Instant::now();
+        let handle = start_server(TcpServerOptions {
+            stats_broadcast: Some(StatsBroadcast {
+                interval: Duration::from_secs(10),
+                admins_only: false,
+            }),
+            ..server_options(frame_format.clone())
+        })
+        .await;
+        let connection = connect(&handle).await;
+        log_in(&connection, &frame_format, "AliceAlice").await;
+
+        let mut broadcast_at = Vec::new();
+        while broadcast_at.len() < 3 {
+            let frame = json_frame(&read(&connection.0, &frame_format, false).await.unwrap());
+            if let Some(stats) = frame.get("Stats") {
+                assert_eq!(stats["online_users"], 1);
+                broadcast_at.push(started.elapsed());
+            }
+        }
+
+        // Nothing at the start, then exactly one broadcast every interval
+        assert!(broadcast_at[0] >= Duration::from_secs(10));
+        for pair in broadcast_at.windows(2) {
+            assert_eq!(pair[1] - pair[0], Duration::from_secs(10));
+        }
+        handle.shutdown().await;
+    }
+
+    fn with_idle_warning(frame_format: FrameFormat) -> TcpServerOptions {
+        TcpServerOptions {
+            idle_timeout: Some(Duration::from_secs(60)),
+            idle_warning: Some(Duration::from_secs(15)),
+            ..server_options(frame_format)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_warning_is_sent_before_the_connection_is_dropped() {
+        let frame_format = frame_format(1024);
+        let handle = start_server(with_idle_warning(frame_format.clone())).await;
+        let (reader, _writer) = connect(&handle).await;
+        let idle_since = tokio::time::Instant::now();
+
+        let warning = json_frame(&read(&reader, &frame_format, false).await.unwrap());
+        let warned_after = idle_since.elapsed();
+        let frames = frames_until_closed(&reader, &frame_format, Duration::from_secs(120)).await;
+        let closed_after = idle_since.elapsed();
+
+        assert_eq!(
+            warning,
+            serde_json::json!({ "IdleWarning": { "seconds_remaining": 15 } })
+        );
+        assert!(frames.is_empty());
+        assert!(warned_after >= Duration::from_secs(45) && warned_after < Duration::from_secs(60));
+        assert!(closed_after >= Duration::from_secs(60));
+        handle.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn activity_after_the_idle_warning_keeps_the_connection() {
+        let frame_format = frame_format(1024);
+        let handle = start_server(with_idle_warning(frame_format.clone())).await;
+        let (reader, writer) = connect(&handle).await;
+
+        let warning = json_frame(&read(&reader, &frame_format, false).await.unwrap());
+        assert!(warning.get("IdleWarning").is_some());
+        write_message(&writer, &frame_format, b"not json", false)
+            .await
+            .unwrap();
+        let response = json_frame(&read(&reader, &frame_format, false).await.unwrap());
+        assert_eq!(response["Error"]["code"], "ProtocolError");
+        let active_since = tokio::time::Instant::now();
+
+        // Warned again a whole idle period after the activity, instead of being dropped
+        let warning = json_frame(&read(&reader, &frame_format, false).await.unwrap());
+        assert!(warning.get("IdleWarning").is_some());
+        assert!(active_since.elapsed() >= Duration::from_secs(45));
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn delivery_report_counts_delivered_and_failed_recipients() {
+        let frame_format = frame_format(1024);
+        let server = ChatTcpServer::create_async(
+            "127.0.0.1",
+            0,
+            chat::chat_server(ChatServerOptions {
+                delivery_reports: true,
+                ..chat::options()
+            }),
+            server_options(frame_format.clone()),
+        )
+        .await
+        .unwrap();
+        let chat_server = server.chat_server();
+        let handle = server.start();
+        let alice = connect(&handle).await;
+        log_in(&alice, &frame_format, "AliceAlice").await;
+        let mut recipients = Vec::new();
+        for name in ["BobBobBob", "CarolCarol"] {
+            let connection = connect(&handle).await;
+            log_in(&connection, &frame_format, name).await;
+            recipients.push(connection);
+        }
+        // Logged in as far as the chat is concerned, but without a connection to write to
+        {
+            let mut chat_server = chat_server.lock().await;
+            chat_server.on_user_connect("ghost".to_string(), "127.0.0.1:4000".parse().unwrap());
+            for request in ["Registration", "Authentication"] {
+                let request = serde_json::json!({ request: {
+                    "user_credentials_raw": { "name": "GhostGhost", "password": "password1", "email": null }
+                } });
+                chat_server.on_user_message("ghost".to_string(), request.to_string().as_bytes());
+            }
+            assert_eq!(chat_server.online_users_count(), 4);
+        }
+
+        let message = serde_json::json!({ "Message": { "message": "anyone there?" } });
+        write_message(
+            &alice.1,
+            &frame_format,
+            message.to_string().as_bytes(),
+            false,
+        )
+        .await
+        .unwrap();
+
+        for (reader, _) in &recipients {
+            let message = loop {
+                let frame = json_frame(&read(reader, &frame_format, false).await.unwrap());
+                if let Some(message) = frame.get("Message") {
+                    break message.clone();
+                }
+            };
+            assert_eq!(message["message"], "anyone there?");
+        }
+        let report = loop {
+            let frame = json_frame(&read(&alice.0, &frame_format, false).await.unwrap());
+            if let Some(report) = frame.get("DeliveryReport") {
+                break report.clone();
+            }
+        };
+        assert_eq!(report["delivered"], 2);
+        assert_eq!(report["failed"], 1);
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn queued_messages_are_delivered_within_the_shutdown_grace() {
+        const MESSAGES: usize = 200;
+        let frame_format = frame_format(64 * 1024);
+        let handle = start_server(TcpServerOptions {
+            shutdown_grace: Some(Duration::from_secs(5)),
+            ..server_options(frame_format.clone())
+        })
+        .await;
+        let alice = connect(&handle).await;
+        log_in(&alice, &frame_format, "AliceAlice").await;
+        let bob = connect(&handle).await;
+        log_in(&bob, &frame_format, "BobBobBob").await;
+
+        // Bob reads nothing yet, so more than the socket buffers hold is still queued for bob
+        let text = "x".repeat(16 * 1024);
+        for _ in 0..MESSAGES {
+            let message = serde_json::json!({ "Message": { "message": text } });
+            write_message(
+                &alice.1,
+                &frame_format,
+                message.to_string().as_bytes(),
+                false,
+            )
+            .await
+            .unwrap();
+        }
+        let mut echoed = 0;
+        while echoed < MESSAGES {
+            let frame = json_frame(&read(&alice.0, &frame_format, false).await.unwrap());
+            if frame.get("Message").is_some() {
+                echoed += 1;
+            }
+        }
+        let shutdown = tokio::spawn({
+            let handle = handle.clone();
+            async move { handle.shutdown().await }
+        });
+
+        let frames = frames_until_closed(&bob.0, &frame_format, Duration::from_secs(5)).await;
+        let frames: Vec<serde_json::Value> = frames.iter().map(|frame| json_frame(frame)).collect();
+        let goodbye = frames
+            .iter()
+            .position(|frame| *frame == serde_json::json!("Goodbye"))
+            .expect("goodbye should be written");
+        // Leaves of others closing at the same time may still follow the goodbye
+        let messages = frames[..goodbye]
+            .iter()
+            .filter(|frame| frame["Message"]["message"] == text)
+            .count();
+        assert_eq!(messages, MESSAGES);
+        shutdown.await.unwrap();
     }
-    Ok(())
 }
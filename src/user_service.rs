@@ -1,20 +1,43 @@
-use std::fmt;
+use std::{
+    collections::HashSet,
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use pwhash::bcrypt;
+use log::{error, info};
+use pwhash::bcrypt::{self, BcryptSetup};
 use serde::{Deserialize, Serialize};
 
-use crate::server_database::{ServerDatabase, UserCredentials, UserCredentialsRaw};
+use crate::server_database::{
+    AbuseReport, AuditEvent, DatabaseError, PersistedMessage, Profile, ServerDatabase,
+    UserCredentials, UserCredentialsRaw,
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum AuthenticationError {
     WrongNameOrPassword,
+    InternalError,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RegistrationError {
     IncorrectName(UserNameError),
     IncorrectPassword(PasswordError),
+    IncorrectEmail(EmailError),
     NameAlreadyInUse,
+    NameReserved,
+    TooManyRegistrations,
+    InternalError,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RenameError {
+    WrongPassword,
+    IncorrectName(UserNameError),
+    NameAlreadyInUse,
+    NameReserved,
+    TooManyRenames,
+    InternalError,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -31,10 +54,18 @@ pub enum PasswordError {
     UnallowedCharacter,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub enum EmailError {
+    Missing,
+    IncorrectLength(u32),
+    IncorrectFormat,
+}
+
 impl fmt::Display for AuthenticationError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             AuthenticationError::WrongNameOrPassword => write!(f, "wrong user name or password"),
+            AuthenticationError::InternalError => write!(f, "internal server error"),
         }
     }
 }
@@ -48,7 +79,30 @@ impl fmt::Display for RegistrationError {
             RegistrationError::IncorrectPassword(password_error) => {
                 write!(f, "password error: {password_error}")
             }
+            RegistrationError::IncorrectEmail(email_error) => {
+                write!(f, "email error: {email_error}")
+            }
             RegistrationError::NameAlreadyInUse => write!(f, "name is already taken"),
+            RegistrationError::NameReserved => write!(f, "name is reserved"),
+            RegistrationError::TooManyRegistrations => {
+                write!(f, "too many registrations from this address")
+            }
+            RegistrationError::InternalError => write!(f, "internal server error"),
+        }
+    }
+}
+
+impl fmt::Display for RenameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenameError::WrongPassword => write!(f, "wrong password"),
+            RenameError::IncorrectName(user_name_error) => {
+                write!(f, "user name error: {user_name_error}")
+            }
+            RenameError::NameAlreadyInUse => write!(f, "name is already taken"),
+            RenameError::NameReserved => write!(f, "name is reserved"),
+            RenameError::TooManyRenames => write!(f, "renamed too often, try again later"),
+            RenameError::InternalError => write!(f, "internal server error"),
         }
     }
 }
@@ -86,88 +140,563 @@ impl fmt::Display for PasswordError {
     }
 }
 
+impl fmt::Display for EmailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmailError::Missing => write!(f, "email is required"),
+            EmailError::IncorrectLength(max) => {
+                write!(f, "incorrect length, should be at most {max}")
+            }
+            EmailError::IncorrectFormat => write!(f, "incorrect email format"),
+        }
+    }
+}
+
 impl From<UserNameError> for RegistrationError {
     fn from(value: UserNameError) -> Self {
         Self::IncorrectName(value)
     }
 }
 
+impl From<EmailError> for RegistrationError {
+    fn from(value: EmailError) -> Self {
+        Self::IncorrectEmail(value)
+    }
+}
+
+impl From<DatabaseError> for AuthenticationError {
+    fn from(_: DatabaseError) -> Self {
+        Self::InternalError
+    }
+}
+
+impl From<DatabaseError> for RegistrationError {
+    fn from(_: DatabaseError) -> Self {
+        Self::InternalError
+    }
+}
+
+impl From<DatabaseError> for RenameError {
+    fn from(_: DatabaseError) -> Self {
+        Self::InternalError
+    }
+}
+
+impl From<UserNameError> for RenameError {
+    fn from(value: UserNameError) -> Self {
+        Self::IncorrectName(value)
+    }
+}
+
 impl From<PasswordError> for RegistrationError {
     fn from(value: PasswordError) -> Self {
         Self::IncorrectPassword(value)
     }
 }
 
-pub struct UserService<T: ServerDatabase> {
-    db: T,
+/// What to do with imported users whose name is already registered
+#[derive(Clone, Copy)]
+pub enum OnConflict {
+    Skip,
+    Fail,
+    Overwrite,
 }
 
-impl<T: ServerDatabase> UserService<T> {
-    pub fn new(database: T) -> Self {
-        Self { db: database }
+#[derive(Debug)]
+pub enum ImportError {
+    IncorrectName(String, UserNameError),
+    NameAlreadyInUse(String),
+    DuplicateName(String),
+    InvalidPasswordHash(String),
+    InternalError,
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::IncorrectName(name, user_name_error) => {
+                write!(f, "user name '{name}' error: {user_name_error}")
+            }
+            ImportError::NameAlreadyInUse(name) => write!(f, "name '{name}' is already taken"),
+            ImportError::DuplicateName(name) => {
+                write!(f, "name '{name}' is imported more than once")
+            }
+            ImportError::InvalidPasswordHash(name) => {
+                write!(f, "password hash of '{name}' is not a bcrypt hash")
+            }
+            ImportError::InternalError => write!(f, "internal server error"),
+        }
     }
+}
 
-    pub fn check_user(&self, name: &str) {
-        if let Some(user_credentials) = self.db.get_user_by_name(name) {
-            println!(
-                "User found: {}:{}",
-                user_credentials.name, user_credentials.password_hash
-            );
-        } else {
-            println!("User not found");
+impl From<DatabaseError> for ImportError {
+    fn from(_: DatabaseError) -> Self {
+        Self::InternalError
+    }
+}
+
+pub struct ImportSummary {
+    pub imported: usize,
+    pub skipped: Vec<String>,
+}
+
+pub struct UserServiceOptions {
+    pub bcrypt_cost: u32,
+    pub require_email: bool,
+    pub reserved_names: ReservedNames,
+}
+
+/// Names and glob patterns (`*` and `?`) which cannot be registered, compared case-insensitively
+#[derive(Default)]
+pub struct ReservedNames {
+    patterns: Vec<String>,
+}
+
+impl ReservedNames {
+    pub fn new(patterns: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            patterns: patterns
+                .into_iter()
+                .map(|pattern| pattern.trim().to_ascii_lowercase())
+                .filter(|pattern| !pattern.is_empty())
+                .collect(),
         }
     }
 
-    pub fn authenticate_user(
+    pub fn is_reserved(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        self.patterns
+            .iter()
+            .any(|pattern| matches_glob(pattern.as_bytes(), name.as_bytes()))
+    }
+}
+
+fn matches_glob(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Position after the last star and the name position it has been matched up to
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                backtrack = Some((p, n));
+            }
+            Some(&ch) if ch == b'?' || ch == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_n)) => {
+                    // Let the last star swallow one more character
+                    p = star_p;
+                    n = star_n + 1;
+                    backtrack = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&ch| ch == b'*')
+}
+
+#[derive(PartialEq)]
+pub struct ValidationRules {
+    pub name_min: usize,
+    pub name_max: usize,
+    pub password_min: usize,
+    pub password_max: usize,
+    pub allow_dots: bool,
+    pub allow_underscores: bool,
+}
+
+impl Default for ValidationRules {
+    fn default() -> Self {
+        Self {
+            name_min: 7,
+            name_max: 32,
+            password_min: 8,
+            password_max: 32,
+            allow_dots: true,
+            allow_underscores: true,
+        }
+    }
+}
+
+/// Verifies credentials and creates accounts, with validation and name checks left to `UserService`.
+///
+/// Backends keep their accounts wherever they like, like LDAP, and own whatever handle they need for it.
+pub trait AuthBackend {
+    fn authenticate(
+        &self,
+        user_credentials_raw: &UserCredentialsRaw,
+    ) -> Result<(), AuthenticationError>;
+    fn register(
+        &self,
+        user_credentials_raw: &UserCredentialsRaw,
+        options: &UserServiceOptions,
+    ) -> Result<(), RegistrationError>;
+}
+
+/// Bcrypt hashes kept in the server database
+pub struct LocalAuthBackend<D: ServerDatabase> {
+    db: D,
+}
+
+impl<D: ServerDatabase> LocalAuthBackend<D> {
+    pub fn new(db: D) -> Self {
+        Self { db }
+    }
+}
+
+impl<D: ServerDatabase> AuthBackend for LocalAuthBackend<D> {
+    fn authenticate(
         &self,
         user_credentials_raw: &UserCredentialsRaw,
     ) -> Result<(), AuthenticationError> {
-        let user_credentials = self.db.get_user_by_name(&user_credentials_raw.name);
+        let user_credentials = self
+            .db
+            .get_user_by_name(&user_credentials_raw.name)
+            .inspect_err(|e| error!("Could not get user '{}' ({e}).", user_credentials_raw.name))?;
         match user_credentials {
-            Some(user_credentials) => {
+            Some(user_credentials)
                 if bcrypt::verify(
-                    user_credentials_raw.password.clone(),
+                    &user_credentials_raw.password,
                     &user_credentials.password_hash,
-                ) {
-                    Ok(())
-                } else {
-                    Err(AuthenticationError::WrongNameOrPassword)
-                }
+                ) =>
+            {
+                Ok(())
             }
-            None => Err(AuthenticationError::WrongNameOrPassword),
+            _ => Err(AuthenticationError::WrongNameOrPassword),
         }
     }
 
+    fn register(
+        &self,
+        user_credentials_raw: &UserCredentialsRaw,
+        options: &UserServiceOptions,
+    ) -> Result<(), RegistrationError> {
+        let bcrypt_setup = BcryptSetup {
+            cost: Some(options.bcrypt_cost),
+            ..Default::default()
+        };
+        let password_hash = bcrypt::hash_with(bcrypt_setup, &user_credentials_raw.password)
+            .expect("system rng should be available");
+
+        let user_credentials = UserCredentials {
+            name: user_credentials_raw.name.clone(),
+            password_hash,
+            email: user_credentials_raw.email.clone(),
+            registered_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .ok(),
+        };
+
+        self.db
+            .add_new_user(&user_credentials)
+            .inspect_err(|e| error!("Could not add user '{}' ({e}).", user_credentials.name))?;
+
+        Ok(())
+    }
+}
+
+pub struct UserService<T: ServerDatabase> {
+    db: T,
+    auth_backend: Box<dyn AuthBackend + Send>,
+    options: UserServiceOptions,
+    validation_rules: ValidationRules,
+}
+
+impl<T: ServerDatabase + Clone + Send + 'static> UserService<T> {
+    pub fn new(
+        database: T,
+        options: UserServiceOptions,
+        validation_rules: ValidationRules,
+    ) -> Self {
+        Self {
+            auth_backend: Box::new(LocalAuthBackend::new(database.clone())),
+            db: database,
+            options,
+            validation_rules,
+        }
+    }
+}
+
+impl<T: ServerDatabase> UserService<T> {
+    /// Replaces the default `LocalAuthBackend`
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn with_auth_backend(mut self, auth_backend: Box<dyn AuthBackend + Send>) -> Self {
+        self.auth_backend = auth_backend;
+        self
+    }
+
+    pub fn set_options(&mut self, options: UserServiceOptions) {
+        self.options = options;
+    }
+
+    /// Applies to registrations and renames from now on, existing accounts are left as they are
+    pub fn set_validation_rules(&mut self, validation_rules: ValidationRules) {
+        self.validation_rules = validation_rules;
+    }
+
+    pub fn get_user(&self, name: &str) -> Result<Option<UserCredentials>, DatabaseError> {
+        self.db
+            .get_user_by_name(name)
+            .inspect_err(|e| error!("Could not get user '{name}' ({e})."))
+    }
+
+    pub fn user_exists(&self, name: &str) -> Result<bool, DatabaseError> {
+        Ok(self.get_user(name)?.is_some())
+    }
+
+    pub fn get_user_names(&self) -> Result<Vec<String>, DatabaseError> {
+        self.db
+            .get_user_names()
+            .inspect_err(|e| error!("Could not get user names ({e})."))
+    }
+
+    pub fn get_profile(&self, name: &str) -> Result<Option<Profile>, DatabaseError> {
+        self.db
+            .get_profile(name)
+            .inspect_err(|e| error!("Could not get profile of user '{name}' ({e})."))
+    }
+
+    pub fn add_report(&self, report: &AbuseReport) -> Result<(), DatabaseError> {
+        self.db.add_report(report).inspect_err(|e| {
+            error!(
+                "Could not save report of user '{}' about '{}' ({e}).",
+                report.reporter, report.target_user
+            )
+        })
+    }
+
+    /// Audit log is best effort, failing to write it must not interrupt the request
+    pub fn append_audit(&self, event: AuditEvent) {
+        if let Err(e) = self.db.append_audit(&event) {
+            error!(
+                "Could not append {:?} of user '{}' to the audit log ({e}).",
+                event.action, event.user_name
+            );
+        }
+    }
+
+    pub fn append_message(&self, message: PersistedMessage) {
+        if let Err(e) = self.db.append_message(&message) {
+            error!(
+                "Could not persist message of user '{}' ({e}).",
+                message.author
+            );
+        }
+    }
+
+    /// Best effort like persisting, history keeps the old text if the update fails
+    pub fn update_message(&self, server_msg_id: u64, text: &str) {
+        if let Err(e) = self.db.update_message(server_msg_id, text) {
+            error!("Could not update persisted message {server_msg_id} ({e}).");
+        }
+    }
+
+    pub fn delete_message(&self, server_msg_id: u64) {
+        if let Err(e) = self.db.delete_message(server_msg_id) {
+            error!("Could not delete persisted message {server_msg_id} ({e}).");
+        }
+    }
+
+    pub fn get_last_server_msg_id(&self) -> Result<Option<u64>, DatabaseError> {
+        self.db
+            .get_last_server_msg_id()
+            .inspect_err(|e| error!("Could not get the last persisted message id ({e})."))
+    }
+
+    /// Trimming is best effort as well, the limit is enforced again with the next message
+    pub fn trim_room_messages(&self, room: &str, keep: usize) {
+        match self.db.trim_room_messages(room, keep) {
+            Ok(0) => {}
+            Ok(removed) => info!("Trimmed {removed} old messages of room '{room}'."),
+            Err(e) => error!("Could not trim messages of room '{room}' ({e})."),
+        }
+    }
+
+    pub fn get_room_messages(
+        &self,
+        room: &str,
+        limit: usize,
+    ) -> Result<Vec<PersistedMessage>, DatabaseError> {
+        self.db
+            .get_room_messages(room, limit)
+            .inspect_err(|e| error!("Could not get messages of room '{room}' ({e})."))
+    }
+
+    pub fn get_messages_before(
+        &self,
+        room: Option<&str>,
+        before_id: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<(u64, PersistedMessage)>, DatabaseError> {
+        self.db
+            .get_messages_before(room, before_id, limit)
+            .inspect_err(|e| error!("Could not get message history ({e})."))
+    }
+
+    pub fn delete_user(&self, name: &str) -> Result<bool, DatabaseError> {
+        if !self.user_exists(name)? {
+            return Ok(false);
+        }
+        self.db
+            .delete_user(name)
+            .inspect_err(|e| error!("Could not delete user '{name}' ({e})."))?;
+        Ok(true)
+    }
+
+    /// Returns the name with the casing it was registered with
+    pub fn authenticate_user(
+        &self,
+        user_credentials_raw: &UserCredentialsRaw,
+    ) -> Result<String, AuthenticationError> {
+        // No password longer than allowed could have been registered, so don't spend time on hashing it
+        if self.is_password_too_long(&user_credentials_raw.password) {
+            return Err(AuthenticationError::WrongNameOrPassword);
+        }
+
+        self.auth_backend.authenticate(user_credentials_raw)?;
+
+        // Backends may know the user by another casing, or not keep the name at all
+        Ok(self
+            .get_user(&user_credentials_raw.name)
+            .ok()
+            .flatten()
+            .map_or_else(|| user_credentials_raw.name.clone(), |user| user.name))
+    }
+
+    /// Reserved names can only be taken with `allow_reserved`, which is meant for administrators
     pub fn add_user(
         &self,
         user_credentials_raw: &UserCredentialsRaw,
+        allow_reserved: bool,
     ) -> Result<(), RegistrationError> {
-        Self::verify_name(&user_credentials_raw.name)?;
-        if self
-            .db
-            .get_user_by_name(&user_credentials_raw.name)
-            .is_some()
+        self.verify_password(&user_credentials_raw.password)?;
+        self.verify_name(&user_credentials_raw.name)?;
+        if !allow_reserved
+            && self
+                .options
+                .reserved_names
+                .is_reserved(&user_credentials_raw.name)
         {
+            return Err(RegistrationError::NameReserved);
+        }
+        if self.user_exists(&user_credentials_raw.name)? {
             return Err(RegistrationError::NameAlreadyInUse);
         }
-        Self::verify_password(&user_credentials_raw.password)?;
+        match &user_credentials_raw.email {
+            Some(email) => Self::verify_email(email)?,
+            None if self.options.require_email => return Err(EmailError::Missing.into()),
+            None => {}
+        }
 
-        let password_hash = bcrypt::hash(user_credentials_raw.password.clone())
-            .expect("system rng should be available");
+        self.auth_backend
+            .register(user_credentials_raw, &self.options)
+    }
 
-        let user_credentials = UserCredentials {
-            name: user_credentials_raw.name.clone(),
-            password_hash,
+    pub fn export_users(&self) -> Result<Vec<UserCredentials>, DatabaseError> {
+        self.db
+            .list_users()
+            .inspect_err(|e| error!("Could not list users ({e})."))
+    }
+
+    /// Nothing is imported unless every user can be
+    pub fn import_users(
+        &self,
+        users: Vec<UserCredentials>,
+        on_conflict: OnConflict,
+    ) -> Result<ImportSummary, ImportError> {
+        let mut seen_names = HashSet::<String>::new();
+        let mut accepted = Vec::<UserCredentials>::new();
+        let mut skipped = Vec::<String>::new();
+
+        for user_credentials in users {
+            let name = &user_credentials.name;
+            self.verify_name(name)
+                .map_err(|e| ImportError::IncorrectName(name.clone(), e))?;
+            if !seen_names.insert(name.to_ascii_lowercase()) {
+                return Err(ImportError::DuplicateName(name.clone()));
+            }
+            // A hash which can't be verified would lock the user out for good
+            if !Self::is_bcrypt_hash(&user_credentials.password_hash) {
+                return Err(ImportError::InvalidPasswordHash(name.clone()));
+            }
+
+            if self.user_exists(name)? {
+                match on_conflict {
+                    OnConflict::Skip => {
+                        skipped.push(name.clone());
+                        continue;
+                    }
+                    OnConflict::Fail => return Err(ImportError::NameAlreadyInUse(name.clone())),
+                    OnConflict::Overwrite => {}
+                }
+            }
+            accepted.push(user_credentials);
+        }
+
+        let replace = matches!(on_conflict, OnConflict::Overwrite);
+        self.db
+            .add_users(&accepted, replace)
+            .inspect_err(|e| error!("Could not import users ({e})."))?;
+
+        Ok(ImportSummary {
+            imported: accepted.len(),
+            skipped,
+        })
+    }
+
+    pub fn rename_user(
+        &self,
+        old_name: &str,
+        new_name: &str,
+        password: &str,
+    ) -> Result<(), RenameError> {
+        if self.is_password_too_long(password) {
+            return Err(RenameError::WrongPassword);
+        }
+
+        let user_credentials_raw = UserCredentialsRaw {
+            name: old_name.to_string(),
+            password: password.to_string(),
+            email: None,
         };
+        match self.auth_backend.authenticate(&user_credentials_raw) {
+            Ok(_) => {}
+            Err(AuthenticationError::WrongNameOrPassword) => {
+                return Err(RenameError::WrongPassword)
+            }
+            Err(AuthenticationError::InternalError) => return Err(RenameError::InternalError),
+        }
 
-        self.db.add_new_user(&user_credentials);
+        self.verify_name(new_name)?;
+        if self.options.reserved_names.is_reserved(new_name) {
+            return Err(RenameError::NameReserved);
+        }
+        // Changing only the casing of the own name is not a collision
+        if !new_name.eq_ignore_ascii_case(old_name) && self.user_exists(new_name)? {
+            return Err(RenameError::NameAlreadyInUse);
+        }
+
+        self.db
+            .rename_user(old_name, new_name)
+            .inspect_err(|e| error!("Could not rename user '{old_name}' ({e})."))?;
 
         Ok(())
     }
 
-    fn verify_name(name: &str) -> Result<(), UserNameError> {
-        if name.len() < 7 || name.len() > 32 {
-            return Err(UserNameError::IncorrectLength(7, 32));
+    fn verify_name(&self, name: &str) -> Result<(), UserNameError> {
+        let rules = &self.validation_rules;
+        if !(rules.name_min..=rules.name_max).contains(&name.len()) {
+            return Err(UserNameError::IncorrectLength(
+                rules.name_min as u32,
+                rules.name_max as u32,
+            ));
         }
 
         let mut was_dot = false;
@@ -179,7 +708,7 @@ impl<T: ServerDatabase> UserService<T> {
                 continue;
             }
 
-            if ch == '.' {
+            if ch == '.' && rules.allow_dots {
                 if was_dot {
                     return Err(UserNameError::MultipleDots);
                 } else {
@@ -187,7 +716,7 @@ impl<T: ServerDatabase> UserService<T> {
                     continue;
                 }
             }
-            if ch == '_' {
+            if ch == '_' && rules.allow_underscores {
                 if was_underscore {
                     return Err(UserNameError::MultipleUnderscores);
                 } else {
@@ -202,9 +731,19 @@ impl<T: ServerDatabase> UserService<T> {
         Ok(())
     }
 
-    fn verify_password(password: &str) -> Result<(), PasswordError> {
-        if password.len() < 8 || password.len() > 32 {
-            return Err(PasswordError::IncorrectLength(8, 32));
+    fn is_password_too_long(&self, password: &str) -> bool {
+        let password_max = self.validation_rules.password_max;
+        // Byte length is checked first so huge inputs are rejected without walking them
+        password.len() > password_max * 4 || password.chars().count() > password_max
+    }
+
+    fn verify_password(&self, password: &str) -> Result<(), PasswordError> {
+        let rules = &self.validation_rules;
+        if self.is_password_too_long(password) || password.chars().count() < rules.password_min {
+            return Err(PasswordError::IncorrectLength(
+                rules.password_min as u32,
+                rules.password_max as u32,
+            ));
         }
 
         for ch in password.chars() {
@@ -217,4 +756,408 @@ impl<T: ServerDatabase> UserService<T> {
 
         Ok(())
     }
+
+    fn verify_email(email: &str) -> Result<(), EmailError> {
+        if email.len() > 254 {
+            return Err(EmailError::IncorrectLength(254));
+        }
+
+        let Some((local, domain)) = email.split_once('@') else {
+            return Err(EmailError::IncorrectFormat);
+        };
+        if local.is_empty() || domain.contains('@') {
+            return Err(EmailError::IncorrectFormat);
+        }
+        if email.chars().any(|ch| !ch.is_ascii_graphic()) {
+            return Err(EmailError::IncorrectFormat);
+        }
+
+        let labels: Vec<&str> = domain.split('.').collect();
+        if labels.len() < 2 || labels.iter().any(|label| label.is_empty()) {
+            return Err(EmailError::IncorrectFormat);
+        }
+
+        Ok(())
+    }
+
+    /// Matches `$2b$<cost>$<salt and hash>` as produced by bcrypt
+    fn is_bcrypt_hash(password_hash: &str) -> bool {
+        let mut parts = password_hash.split('$');
+        let (Some(""), Some(variant), Some(cost), Some(salt_and_hash), None) = (
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+        ) else {
+            return false;
+        };
+
+        matches!(variant, "2a" | "2b" | "2y")
+            && cost.len() == 2
+            && cost
+                .parse::<u32>()
+                .is_ok_and(|cost| (4..=31).contains(&cost))
+            && salt_and_hash.len() == 53
+            && salt_and_hash
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || byte == b'.' || byte == b'/')
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::Path,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::server_database::ServerSQLiteDatabase;
+
+    fn options() -> UserServiceOptions {
+        UserServiceOptions {
+            // Lowest cost bcrypt accepts, tests don't need strong hashes
+            bcrypt_cost: 4,
+            require_email: false,
+            reserved_names: ReservedNames::default(),
+        }
+    }
+
+    fn user_service() -> UserService<ServerSQLiteDatabase> {
+        user_service_with(options())
+    }
+
+    fn user_service_with(options: UserServiceOptions) -> UserService<ServerSQLiteDatabase> {
+        let database = ServerSQLiteDatabase::try_open(Path::new(":memory:")).unwrap();
+        UserService::new(database, options, ValidationRules::default())
+    }
+
+    fn credentials(name: &str, password: &str) -> UserCredentialsRaw {
+        UserCredentialsRaw {
+            name: name.to_string(),
+            password: password.to_string(),
+            email: None,
+        }
+    }
+
+    fn credentials_with_email(name: &str, email: &str) -> UserCredentialsRaw {
+        UserCredentialsRaw {
+            email: Some(email.to_string()),
+            ..credentials(name, "password1")
+        }
+    }
+
+    /// Fails the test if a request gets as far as the backend
+    struct UnreachableAuthBackend;
+
+    impl AuthBackend for UnreachableAuthBackend {
+        fn authenticate(&self, _: &UserCredentialsRaw) -> Result<(), AuthenticationError> {
+            panic!("authentication should not reach the backend");
+        }
+
+        fn register(
+            &self,
+            _: &UserCredentialsRaw,
+            _: &UserServiceOptions,
+        ) -> Result<(), RegistrationError> {
+            panic!("registration should not reach the backend");
+        }
+    }
+
+    /// Fails every query, like a database whose file has become unreadable
+    #[derive(Clone)]
+    struct UnreachableDatabase;
+
+    fn unreachable() -> DatabaseError {
+        sqlite::Error {
+            code: Some(14),
+            message: Some("unable to open database file".to_string()),
+        }
+        .into()
+    }
+
+    impl ServerDatabase for UnreachableDatabase {
+        fn get_user_by_name(&self, _: &str) -> Result<Option<UserCredentials>, DatabaseError> {
+            Err(unreachable())
+        }
+        fn add_new_user(&self, _: &UserCredentials) -> Result<(), DatabaseError> {
+            Err(unreachable())
+        }
+        fn rename_user(&self, _: &str, _: &str) -> Result<(), DatabaseError> {
+            Err(unreachable())
+        }
+        fn delete_user(&self, _: &str) -> Result<(), DatabaseError> {
+            Err(unreachable())
+        }
+        fn get_user_names(&self) -> Result<Vec<String>, DatabaseError> {
+            Err(unreachable())
+        }
+        fn get_profile(&self, _: &str) -> Result<Option<Profile>, DatabaseError> {
+            Err(unreachable())
+        }
+        fn list_users(&self) -> Result<Vec<UserCredentials>, DatabaseError> {
+            Err(unreachable())
+        }
+        fn add_users(&self, _: &[UserCredentials], _: bool) -> Result<(), DatabaseError> {
+            Err(unreachable())
+        }
+        fn append_audit(&self, _: &AuditEvent) -> Result<(), DatabaseError> {
+            Err(unreachable())
+        }
+        fn add_report(&self, _: &AbuseReport) -> Result<(), DatabaseError> {
+            Err(unreachable())
+        }
+        fn append_message(&self, _: &PersistedMessage) -> Result<(), DatabaseError> {
+            Err(unreachable())
+        }
+        fn update_message(&self, _: u64, _: &str) -> Result<(), DatabaseError> {
+            Err(unreachable())
+        }
+        fn delete_message(&self, _: u64) -> Result<(), DatabaseError> {
+            Err(unreachable())
+        }
+        fn get_last_server_msg_id(&self) -> Result<Option<u64>, DatabaseError> {
+            Err(unreachable())
+        }
+        fn prune_messages_older_than(&self, _: u64) -> Result<usize, DatabaseError> {
+            Err(unreachable())
+        }
+        fn trim_room_messages(&self, _: &str, _: usize) -> Result<usize, DatabaseError> {
+            Err(unreachable())
+        }
+        fn get_room_messages(
+            &self,
+            _: &str,
+            _: usize,
+        ) -> Result<Vec<PersistedMessage>, DatabaseError> {
+            Err(unreachable())
+        }
+        fn get_messages_before(
+            &self,
+            _: Option<&str>,
+            _: Option<u64>,
+            _: usize,
+        ) -> Result<Vec<(u64, PersistedMessage)>, DatabaseError> {
+            Err(unreachable())
+        }
+    }
+
+    /// Accepts a single password for everyone and remembers who it has registered
+    struct MockAuthBackend {
+        registered: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl AuthBackend for MockAuthBackend {
+        fn authenticate(
+            &self,
+            user_credentials_raw: &UserCredentialsRaw,
+        ) -> Result<(), AuthenticationError> {
+            if user_credentials_raw.password == "open sesame" {
+                Ok(())
+            } else {
+                Err(AuthenticationError::WrongNameOrPassword)
+            }
+        }
+
+        fn register(
+            &self,
+            user_credentials_raw: &UserCredentialsRaw,
+            _options: &UserServiceOptions,
+        ) -> Result<(), RegistrationError> {
+            self.registered
+                .lock()
+                .unwrap()
+                .push(user_credentials_raw.name.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn local_backend_verifies_registered_password() {
+        let user_service = user_service();
+        user_service
+            .add_user(&credentials("RegisteredUser", "password1"), false)
+            .unwrap();
+
+        assert_eq!(
+            user_service
+                .authenticate_user(&credentials("registereduser", "password1"))
+                .unwrap(),
+            "RegisteredUser"
+        );
+        assert!(matches!(
+            user_service.authenticate_user(&credentials("RegisteredUser", "password2")),
+            Err(AuthenticationError::WrongNameOrPassword)
+        ));
+    }
+
+    #[test]
+    fn custom_backend_decides_authentication_and_registration() {
+        let registered = Arc::new(Mutex::new(Vec::new()));
+        let user_service = user_service().with_auth_backend(Box::new(MockAuthBackend {
+            registered: registered.clone(),
+        }));
+
+        user_service
+            .add_user(&credentials("ExternalUser", "password1"), false)
+            .unwrap();
+        assert_eq!(
+            *registered.lock().unwrap(),
+            vec!["ExternalUser".to_string()]
+        );
+
+        // Nothing has been stored locally, so the name is taken as given
+        assert_eq!(
+            user_service
+                .authenticate_user(&credentials("ExternalUser", "open sesame"))
+                .unwrap(),
+            "ExternalUser"
+        );
+        assert!(user_service
+            .authenticate_user(&credentials("ExternalUser", "password1"))
+            .is_err());
+    }
+
+    #[test]
+    fn valid_email_is_accepted() {
+        let user_service = user_service();
+
+        user_service
+            .add_user(
+                &credentials_with_email("MailUser", "mail.user@example.com"),
+                false,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn malformed_emails_are_refused() {
+        let user_service = user_service();
+
+        for email in [
+            "example.com",
+            "@example.com",
+            "user@localhost",
+            "user@example@com",
+            "user@example..com",
+            "mail user@example.com",
+        ] {
+            assert!(
+                matches!(
+                    user_service.add_user(&credentials_with_email("MailUser", email), false),
+                    Err(RegistrationError::IncorrectEmail(
+                        EmailError::IncorrectFormat
+                    ))
+                ),
+                "{email} should be refused"
+            );
+        }
+        let too_long = format!("{}@example.com", "a".repeat(250));
+        assert!(matches!(
+            user_service.add_user(&credentials_with_email("MailUser", &too_long), false),
+            Err(RegistrationError::IncorrectEmail(
+                EmailError::IncorrectLength(254)
+            ))
+        ));
+    }
+
+    #[test]
+    fn missing_email_is_refused_only_when_required() {
+        let optional = user_service();
+        optional
+            .add_user(&credentials("NoMailUser", "password1"), false)
+            .unwrap();
+
+        let required = user_service_with(UserServiceOptions {
+            require_email: true,
+            ..options()
+        });
+        assert!(matches!(
+            required.add_user(&credentials("NoMailUser", "password1"), false),
+            Err(RegistrationError::IncorrectEmail(EmailError::Missing))
+        ));
+        required
+            .add_user(
+                &credentials_with_email("MailUser", "mail.user@example.com"),
+                false,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn registered_user_is_found_by_name() {
+        let user_service = user_service();
+        user_service
+            .add_user(
+                &credentials_with_email("LookupUser", "lookup@example.com"),
+                false,
+            )
+            .unwrap();
+
+        let user = user_service.get_user("lookupuser").unwrap().unwrap();
+        assert_eq!(user.name, "LookupUser");
+        assert_eq!(user.email.as_deref(), Some("lookup@example.com"));
+        assert!(UserService::<ServerSQLiteDatabase>::is_bcrypt_hash(
+            &user.password_hash
+        ));
+        assert!(user.registered_at.is_some());
+        assert!(user_service.user_exists("LookupUser").unwrap());
+        assert!(user_service.user_exists("LOOKUPUSER").unwrap());
+    }
+
+    #[test]
+    fn unknown_user_is_not_found() {
+        let user_service = user_service();
+        user_service
+            .add_user(&credentials("LookupUser", "password1"), false)
+            .unwrap();
+
+        assert!(user_service.get_user("LookupUser2").unwrap().is_none());
+        assert!(!user_service.user_exists("LookupUser2").unwrap());
+        assert!(!user_service.user_exists("").unwrap());
+    }
+
+    #[test]
+    fn lookup_failure_is_not_reported_as_wrong_password() {
+        let working = user_service();
+        working
+            .add_user(&credentials("SomeUser", "password1"), false)
+            .unwrap();
+        assert!(matches!(
+            working.authenticate_user(&credentials("SomeUser", "password2")),
+            Err(AuthenticationError::WrongNameOrPassword)
+        ));
+
+        let failing = UserService::new(UnreachableDatabase, options(), ValidationRules::default());
+        assert!(matches!(
+            failing.authenticate_user(&credentials("SomeUser", "password1")),
+            Err(AuthenticationError::InternalError)
+        ));
+        assert!(matches!(
+            failing.add_user(&credentials("OtherUser", "password1"), false),
+            Err(RegistrationError::InternalError)
+        ));
+    }
+
+    #[test]
+    fn huge_password_is_rejected_before_hashing() {
+        let user_service = user_service().with_auth_backend(Box::new(UnreachableAuthBackend));
+        let password = "p".repeat(10 * 1024 * 1024);
+        let started = Instant::now();
+
+        assert!(matches!(
+            user_service.authenticate_user(&credentials("SomeUser", &password)),
+            Err(AuthenticationError::WrongNameOrPassword)
+        ));
+        assert!(matches!(
+            user_service.add_user(&credentials("SomeUser", &password), false),
+            Err(RegistrationError::IncorrectPassword(
+                PasswordError::IncorrectLength(_, _)
+            ))
+        ));
+        // Only the byte length is looked at, so it takes no time at all compared to hashing
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
 }
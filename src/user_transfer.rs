@@ -0,0 +1,257 @@
+//! Moves registered users between databases through a JSON file:
+//!
+//! ```json
+//! {
+//!   "version": 2,
+//!   "users": [
+//!     { "name": "alice01", "password_hash": "$2b$10$...", "email": null, "registered_at": 1700000000 }
+//!   ]
+//! }
+//! ```
+//!
+//! Users are listed in the order they were registered. Only bcrypt hashes are stored, never passwords.
+//! Version 1 files, which have no registration times, can still be imported.
+
+use std::{fmt, fs, io};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    server_database::{DatabaseError, ServerDatabase, UserCredentials},
+    user_service::{ImportError, ImportSummary, OnConflict, UserService},
+};
+
+const FORMAT_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize)]
+struct UsersFile {
+    version: u32,
+    users: Vec<ExportedUser>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportedUser {
+    name: String,
+    password_hash: String,
+    email: Option<String>,
+    #[serde(default)]
+    registered_at: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum TransferError {
+    Io(io::Error),
+    MalformedFile(serde_json::Error),
+    UnsupportedVersion(u32),
+    Database(DatabaseError),
+    Import(ImportError),
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::Io(e) => write!(f, "{e}"),
+            TransferError::MalformedFile(e) => write!(f, "malformed users file: {e}"),
+            TransferError::UnsupportedVersion(version) => {
+                write!(f, "users file version {version} is not supported")
+            }
+            TransferError::Database(e) => write!(f, "{e}"),
+            TransferError::Import(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<io::Error> for TransferError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<DatabaseError> for TransferError {
+    fn from(value: DatabaseError) -> Self {
+        Self::Database(value)
+    }
+}
+
+impl From<ImportError> for TransferError {
+    fn from(value: ImportError) -> Self {
+        Self::Import(value)
+    }
+}
+
+/// Returns the number of exported users
+pub fn export_users<T: ServerDatabase>(
+    user_service: &UserService<T>,
+    path: &str,
+) -> Result<usize, TransferError> {
+    let users: Vec<ExportedUser> = user_service
+        .export_users()?
+        .into_iter()
+        .map(|user_credentials| ExportedUser {
+            name: user_credentials.name,
+            password_hash: user_credentials.password_hash,
+            email: user_credentials.email,
+            registered_at: user_credentials.registered_at,
+        })
+        .collect();
+    let count = users.len();
+
+    let users_file = UsersFile {
+        version: FORMAT_VERSION,
+        users,
+    };
+    let json = serde_json::to_string_pretty(&users_file).map_err(TransferError::MalformedFile)?;
+    fs::write(path, json)?;
+
+    Ok(count)
+}
+
+pub fn import_users<T: ServerDatabase>(
+    user_service: &UserService<T>,
+    path: &str,
+    on_conflict: OnConflict,
+) -> Result<ImportSummary, TransferError> {
+    let json = fs::read_to_string(path)?;
+    let users_file: UsersFile =
+        serde_json::from_str(&json).map_err(TransferError::MalformedFile)?;
+    if !(1..=FORMAT_VERSION).contains(&users_file.version) {
+        return Err(TransferError::UnsupportedVersion(users_file.version));
+    }
+
+    let users = users_file
+        .users
+        .into_iter()
+        .map(|exported_user| UserCredentials {
+            name: exported_user.name,
+            password_hash: exported_user.password_hash,
+            email: exported_user.email,
+            registered_at: exported_user.registered_at,
+        })
+        .collect();
+
+    Ok(user_service.import_users(users, on_conflict)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::Path, process};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        server_database::{ServerSQLiteDatabase, UserCredentialsRaw},
+        user_service::{ReservedNames, UserServiceOptions, ValidationRules},
+    };
+
+    fn user_service() -> UserService<ServerSQLiteDatabase> {
+        let database = ServerSQLiteDatabase::try_open(Path::new(":memory:")).unwrap();
+        let options = UserServiceOptions {
+            // Lowest cost bcrypt accepts, tests don't need strong hashes
+            bcrypt_cost: 4,
+            require_email: false,
+            reserved_names: ReservedNames::default(),
+        };
+        UserService::new(database, options, ValidationRules::default())
+    }
+
+    fn credentials(name: &str) -> UserCredentialsRaw {
+        UserCredentialsRaw {
+            name: name.to_string(),
+            password: "password1".to_string(),
+            email: None,
+        }
+    }
+
+    fn users_file_path(test: &str) -> String {
+        env::temp_dir()
+            .join(format!("chat-users-{test}-{}.json", process::id()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn exported_users_can_log_in_after_import() {
+        let source = user_service();
+        source.add_user(&credentials("alice01"), false).unwrap();
+        source.add_user(&credentials("bobbob1"), false).unwrap();
+        let path = users_file_path("round-trip");
+
+        let exported = export_users(&source, &path).unwrap();
+        let target = user_service();
+        let summary = import_users(&target, &path, OnConflict::Fail);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(exported, 2);
+        assert_eq!(summary.unwrap().imported, 2);
+        assert_eq!(
+            target.authenticate_user(&credentials("alice01")).unwrap(),
+            "alice01"
+        );
+        assert_eq!(
+            target.authenticate_user(&credentials("bobbob1")).unwrap(),
+            "bobbob1"
+        );
+
+        let registered_at = source
+            .get_profile("alice01")
+            .unwrap()
+            .unwrap()
+            .registered_at;
+        assert!(registered_at.is_some());
+        assert_eq!(
+            target
+                .get_profile("alice01")
+                .unwrap()
+                .unwrap()
+                .registered_at,
+            registered_at
+        );
+    }
+
+    #[test]
+    fn version_1_file_imports_without_registration_times() {
+        let source = user_service();
+        source.add_user(&credentials("alice01"), false).unwrap();
+        let password_hash = source.get_user("alice01").unwrap().unwrap().password_hash;
+        let path = users_file_path("version-1");
+        let users_file = json!({
+            "version": 1,
+            "users": [{ "name": "alice01", "password_hash": password_hash, "email": null }],
+        });
+        fs::write(&path, users_file.to_string()).unwrap();
+
+        let target = user_service();
+        let summary = import_users(&target, &path, OnConflict::Fail);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(summary.unwrap().imported, 1);
+        assert_eq!(
+            target
+                .get_profile("alice01")
+                .unwrap()
+                .unwrap()
+                .registered_at,
+            None
+        );
+    }
+
+    #[test]
+    fn malformed_password_hash_is_not_imported() {
+        let path = users_file_path("bad-hash");
+        let users_file = r#"{ "version": 1, "users": [
+            { "name": "alice01", "password_hash": "$2b$10$tooshort", "email": null }
+        ] }"#;
+        fs::write(&path, users_file).unwrap();
+
+        let target = user_service();
+        let result = import_users(&target, &path, OnConflict::Fail);
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            result,
+            Err(TransferError::Import(ImportError::InvalidPasswordHash(name))) if name == "alice01"
+        ));
+        assert!(!target.user_exists("alice01").unwrap());
+    }
+}